@@ -0,0 +1,83 @@
+//! Reports heap bytes per stream entry for the macro-node layout next to the
+//! previous one-BTreeMap-node-per-entry layout.
+//!
+//! Run with `cargo bench --bench stream_memory`.
+
+use bytes::Bytes;
+use redis::stream_node::StreamEntries;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ENTRIES: u64 = 1_000_000;
+
+fn fields(i: u64) -> Vec<(Bytes, Bytes)> {
+    vec![
+        (Bytes::from("sensor"), Bytes::from(format!("s{}", i % 64))),
+        (
+            Bytes::from("temperature"),
+            Bytes::from(format!("{}", i % 40)),
+        ),
+    ]
+}
+
+// Simulated XADD rate: ten entries per millisecond
+fn id(i: u64) -> (u64, u64) {
+    (1_700_000_000_000 + i / 10, i % 10)
+}
+
+fn measure<T>(name: &str, build: impl FnOnce() -> T) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = std::time::Instant::now();
+    let value = build();
+    let elapsed = start.elapsed();
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!(
+        "{:<12} {:>10} entries  {:>12} bytes  {:>7.1} bytes/entry  {:>8.1?}",
+        name,
+        ENTRIES,
+        bytes,
+        bytes as f64 / ENTRIES as f64,
+        elapsed
+    );
+    drop(value);
+}
+
+fn main() {
+    measure("macro-node", || {
+        let mut entries = StreamEntries::new();
+        for i in 0..ENTRIES {
+            entries.push(id(i), &fields(i));
+        }
+        entries
+    });
+
+    measure("per-entry", || {
+        let mut map: BTreeMap<(Bytes, Bytes), Vec<(Bytes, Bytes)>> = BTreeMap::new();
+        for i in 0..ENTRIES {
+            let (ms, seq) = id(i);
+            let key = (Bytes::from(ms.to_string()), Bytes::from(seq.to_string()));
+            map.insert(key, fields(i));
+        }
+        map
+    });
+}
@@ -0,0 +1,981 @@
+use crate::client::{self, Client};
+use crate::rdb::KeyValue;
+use crate::redis::Redis;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+
+const DEFAULT_USER: &str = "default";
+
+// Denials of the same kind within this window are counted in one ACL LOG entry
+const LOG_GROUP_WINDOW: Duration = Duration::from_secs(60);
+
+/// Categories reported by ACL CAT.
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "string",
+    "list",
+    "set",
+    "sortedset",
+    "hash",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+// Every command with its categories. Subcommands of container commands are
+// listed as "name|sub".
+const COMMAND_TABLE: &[(&str, &[&str])] = &[
+    ("ping", &["fast", "connection"]),
+    ("echo", &["fast", "connection"]),
+    ("set", &["write", "string", "slow"]),
+    ("get", &["read", "string", "fast"]),
+    ("incr", &["write", "string", "fast"]),
+    ("rpush", &["write", "list", "fast"]),
+    ("lpush", &["write", "list", "fast"]),
+    ("llen", &["read", "list", "fast"]),
+    ("lrange", &["read", "list", "slow"]),
+    ("lpop", &["write", "list", "fast"]),
+    ("blpop", &["write", "list", "slow", "blocking"]),
+    ("smembers", &["read", "set", "slow"]),
+    ("scard", &["read", "set", "fast"]),
+    ("sismember", &["read", "set", "fast"]),
+    ("zrange", &["read", "sortedset", "slow"]),
+    ("zscore", &["read", "sortedset", "fast"]),
+    ("zcard", &["read", "sortedset", "fast"]),
+    ("hget", &["read", "hash", "fast"]),
+    ("hgetall", &["read", "hash", "slow"]),
+    ("hlen", &["read", "hash", "fast"]),
+    ("type", &["keyspace", "read", "fast"]),
+    ("keys", &["keyspace", "read", "slow", "dangerous"]),
+    ("select", &["fast", "connection"]),
+    ("move", &["keyspace", "write", "fast"]),
+    ("swapdb", &["keyspace", "write", "fast", "dangerous"]),
+    ("xadd", &["write", "stream", "fast"]),
+    ("xrange", &["read", "stream", "slow"]),
+    ("xread", &["read", "stream", "slow", "blocking"]),
+    ("xinfo|stream", &["read", "stream", "slow"]),
+    ("xinfo|groups", &["read", "stream", "slow"]),
+    ("xinfo|consumers", &["read", "stream", "slow"]),
+    ("xgroup|create", &["write", "stream", "slow"]),
+    ("xgroup|destroy", &["write", "stream", "slow"]),
+    ("multi", &["fast", "transaction"]),
+    ("exec", &["slow", "transaction"]),
+    ("discard", &["fast", "transaction"]),
+    ("watch", &["fast", "transaction"]),
+    ("unwatch", &["fast", "transaction"]),
+    ("config|get", &["admin", "slow", "dangerous"]),
+    ("config|set", &["admin", "slow", "dangerous"]),
+    ("info", &["slow", "dangerous"]),
+    ("replconf", &["admin", "slow", "dangerous"]),
+    ("psync", &["admin", "slow", "dangerous"]),
+    ("subscribe", &["pubsub", "slow"]),
+    ("unsubscribe", &["pubsub", "slow"]),
+    ("psubscribe", &["pubsub", "slow"]),
+    ("punsubscribe", &["pubsub", "slow"]),
+    ("ssubscribe", &["pubsub", "slow"]),
+    ("sunsubscribe", &["pubsub", "slow"]),
+    ("publish", &["pubsub", "fast"]),
+    ("spublish", &["pubsub", "fast"]),
+    ("pubsub|channels", &["pubsub", "slow"]),
+    ("pubsub|numsub", &["pubsub", "slow"]),
+    ("pubsub|numpat", &["pubsub", "slow"]),
+    ("pubsub|shardchannels", &["pubsub", "slow"]),
+    ("pubsub|shardnumsub", &["pubsub", "slow"]),
+    ("reset", &["fast", "connection"]),
+    ("hello", &["fast", "connection"]),
+    ("auth", &["fast", "connection"]),
+    ("client|id", &["slow", "connection"]),
+    ("client|info", &["slow", "connection"]),
+    ("client|setname", &["slow", "connection"]),
+    ("client|getname", &["slow", "connection"]),
+    ("client|setinfo", &["slow", "connection"]),
+    ("client|reply", &["slow", "connection"]),
+    ("client|tracking", &["slow", "connection"]),
+    ("client|caching", &["slow", "connection"]),
+    ("client|getredir", &["slow", "connection"]),
+    ("client|list", &["admin", "slow", "dangerous", "connection"]),
+    ("client|kill", &["admin", "slow", "dangerous", "connection"]),
+    (
+        "client|pause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|unpause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|no-evict",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    ("eval", &["slow", "scripting"]),
+    ("evalsha", &["slow", "scripting"]),
+    ("eval_ro", &["slow", "scripting"]),
+    ("evalsha_ro", &["slow", "scripting"]),
+    ("script|load", &["slow", "scripting"]),
+    ("script|exists", &["slow", "scripting"]),
+    ("script|flush", &["slow", "scripting"]),
+    ("script|kill", &["slow", "scripting"]),
+    ("fcall", &["slow", "scripting"]),
+    ("fcall_ro", &["slow", "scripting"]),
+    ("function|load", &["write", "slow", "scripting"]),
+    ("function|delete", &["write", "slow", "scripting"]),
+    ("function|flush", &["write", "slow", "scripting"]),
+    ("function|kill", &["slow", "scripting"]),
+    ("function|restore", &["write", "slow", "scripting"]),
+    ("function|list", &["slow", "scripting"]),
+    ("function|dump", &["slow", "scripting"]),
+    ("module|list", &["admin", "slow", "dangerous"]),
+    ("save", &["admin", "slow", "dangerous"]),
+    ("bgsave", &["admin", "slow", "dangerous"]),
+    ("lastsave", &["admin", "fast", "dangerous"]),
+    ("acl|whoami", &["slow"]),
+    ("acl|cat", &["slow"]),
+    ("acl|setuser", &["admin", "slow", "dangerous"]),
+    ("acl|getuser", &["admin", "slow", "dangerous"]),
+    ("acl|deluser", &["admin", "slow", "dangerous"]),
+    ("acl|list", &["admin", "slow", "dangerous"]),
+    ("acl|users", &["admin", "slow", "dangerous"]),
+    ("acl|log", &["admin", "slow", "dangerous"]),
+    ("acl|load", &["admin", "slow", "dangerous"]),
+    ("acl|save", &["admin", "slow", "dangerous"]),
+];
+
+#[derive(Clone)]
+struct KeyPattern {
+    pattern: Bytes,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        let flags = match (self.read, self.write) {
+            (true, true) => "",
+            (true, false) => "%R",
+            _ => "%W",
+        };
+        format!("{}~{}", flags, String::from_utf8_lossy(&self.pattern))
+    }
+}
+
+#[derive(Clone)]
+struct User {
+    enabled: bool,
+    nopass: bool,
+    // SHA-256 of each password, hex encoded
+    passwords: Vec<String>,
+    commands: HashSet<&'static str>,
+    // The +/- command rules as given, for ACL LIST and GETUSER
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<Bytes>,
+}
+
+impl User {
+    // New users start disabled and can't do anything
+    fn new() -> Self {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: HashSet::new(),
+            command_rules: Vec::new(),
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    fn superuser() -> Self {
+        let mut user = User::new();
+        for rule in ["on", "nopass", "allkeys", "allchannels", "allcommands"] {
+            user.apply(rule.as_bytes()).unwrap();
+        }
+        user
+    }
+
+    fn apply(&mut self, rule: &[u8]) -> Result<(), &'static str> {
+        let lower = String::from_utf8_lossy(rule).to_lowercase();
+        match lower.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.apply(b"~*")?,
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.apply(b"&*")?,
+            "resetchannels" => self.channels.clear(),
+            "allcommands" | "+@all" => {
+                self.commands = COMMAND_TABLE.iter().map(|(name, _)| *name).collect();
+                self.command_rules = vec!["+@all".to_string()];
+            }
+            "nocommands" | "-@all" => {
+                self.commands.clear();
+                self.command_rules.clear();
+            }
+            "reset" => *self = User::new(),
+            _ => match rule.first() {
+                Some(b'>') => {
+                    self.add_password(hash_password(&rule[1..]));
+                }
+                Some(b'#') => {
+                    let hash = parse_hash(&rule[1..])?;
+                    self.add_password(hash);
+                }
+                Some(b'<') => self.remove_password(&hash_password(&rule[1..]))?,
+                Some(b'!') => {
+                    let hash = parse_hash(&rule[1..])?;
+                    self.remove_password(&hash)?;
+                }
+                Some(b'~') => self.keys.push(KeyPattern {
+                    pattern: Bytes::copy_from_slice(&rule[1..]),
+                    read: true,
+                    write: true,
+                }),
+                Some(b'%') => {
+                    let tilde = rule.iter().position(|&b| b == b'~').ok_or("Syntax error")?;
+                    let flags = &lower[1..tilde];
+                    if flags.is_empty() || !flags.chars().all(|c| c == 'r' || c == 'w') {
+                        return Err("Syntax error");
+                    }
+                    self.keys.push(KeyPattern {
+                        pattern: Bytes::copy_from_slice(&rule[tilde + 1..]),
+                        read: flags.contains('r'),
+                        write: flags.contains('w'),
+                    });
+                }
+                Some(b'&') => self.channels.push(Bytes::copy_from_slice(&rule[1..])),
+                Some(b'+') => {
+                    for name in matching_commands(&lower[1..])? {
+                        self.commands.insert(name);
+                    }
+                    self.command_rules.push(lower);
+                }
+                Some(b'-') => {
+                    for name in matching_commands(&lower[1..])? {
+                        self.commands.remove(name);
+                    }
+                    self.command_rules.push(lower);
+                }
+                _ => return Err("Syntax error"),
+            },
+        }
+        Ok(())
+    }
+
+    // Module commands aren't in the command table; only +@all covers them
+    fn can_run(&self, name: &str) -> bool {
+        if COMMAND_TABLE.iter().any(|(cmd, _)| *cmd == name) {
+            self.commands.contains(name)
+        } else {
+            self.command_rules.first().map(String::as_str) == Some("+@all")
+        }
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), &'static str> {
+        let before = self.passwords.len();
+        self.passwords.retain(|p| p != hash);
+        if self.passwords.len() == before {
+            return Err("The password you are trying to remove from the user does not exist");
+        }
+        Ok(())
+    }
+
+    fn describe_commands(&self) -> String {
+        let mut rules = Vec::new();
+        if self.command_rules.first().map(String::as_str) != Some("+@all") {
+            rules.push("-@all".to_string());
+        }
+        rules.extend(self.command_rules.iter().cloned());
+        rules.join(" ")
+    }
+
+    fn describe_keys(&self) -> String {
+        let keys: Vec<String> = self.keys.iter().map(KeyPattern::describe).collect();
+        keys.join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        let channels: Vec<String> = self
+            .channels
+            .iter()
+            .map(|c| format!("&{}", String::from_utf8_lossy(c)))
+            .collect();
+        channels.join(" ")
+    }
+
+    // One ACL LIST line, without the leading "user <name>"
+    fn describe(&self) -> String {
+        let mut parts = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|p| format!("#{}", p)));
+        if !self.keys.is_empty() {
+            parts.push(self.describe_keys());
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    fn can_access_key(&self, key: &Bytes, write: bool) -> bool {
+        let key = String::from_utf8_lossy(key);
+        self.keys.iter().any(|p| {
+            (if write { p.write } else { p.read })
+                && KeyValue::match_pattern(&String::from_utf8_lossy(&p.pattern), &key)
+        })
+    }
+
+    fn can_access_channel(&self, channel: &Bytes) -> bool {
+        let channel = String::from_utf8_lossy(channel);
+        self.channels
+            .iter()
+            .any(|p| KeyValue::match_pattern(&String::from_utf8_lossy(p), &channel))
+    }
+
+    // PSUBSCRIBE patterns must be granted literally, unless every channel is
+    fn can_access_pattern(&self, pattern: &Bytes) -> bool {
+        self.channels
+            .iter()
+            .any(|p| p.as_ref() == b"*" || p == pattern)
+    }
+}
+
+// Commands a +/- rule names: a category, a command with all of its
+// subcommands, or a single subcommand
+fn matching_commands(name: &str) -> Result<Vec<&'static str>, &'static str> {
+    let found: Vec<&'static str> = match name.strip_prefix('@') {
+        Some(category) => COMMAND_TABLE
+            .iter()
+            .filter(|(_, categories)| categories.contains(&category))
+            .map(|(name, _)| *name)
+            .collect(),
+        None => COMMAND_TABLE
+            .iter()
+            .map(|(cmd, _)| *cmd)
+            .filter(|cmd| {
+                *cmd == name || (!name.contains('|') && cmd.split('|').next() == Some(name))
+            })
+            .collect(),
+    };
+    let known_category = name
+        .strip_prefix('@')
+        .is_some_and(|category| CATEGORIES.contains(&category));
+    if found.is_empty() && !known_category {
+        return Err("Unknown command or category name in ACL");
+    }
+    Ok(found)
+}
+
+fn hash_password(password: &[u8]) -> String {
+    hex::encode(Sha256::digest(password))
+}
+
+fn parse_hash(hash: &[u8]) -> Result<String, &'static str> {
+    if hash.len() != 64 || !hash.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters");
+    }
+    Ok(String::from_utf8_lossy(hash).to_string())
+}
+
+// Builds a user from "user <name> <rules...>" style arguments
+fn parse_user(rules: &[Bytes], existing: Option<&User>) -> Result<User, String> {
+    let mut user = existing.cloned().unwrap_or_else(User::new);
+    for rule in rules {
+        user.apply(rule).map_err(|e| {
+            format!(
+                "Error in ACL SETUSER modifier '{}': {}",
+                String::from_utf8_lossy(rule),
+                e
+            )
+        })?;
+    }
+    Ok(user)
+}
+
+/// What a command is about to touch, checked against the client's user.
+pub struct Request<'a> {
+    pub name: &'a str,
+    pub keys: Vec<Bytes>,
+    pub write: bool,
+    pub channels: Vec<Bytes>,
+    pub patterns: Vec<Bytes>,
+}
+
+enum Denial {
+    Command,
+    Key(Bytes),
+    Channel(Bytes),
+}
+
+struct LogEntry {
+    count: u64,
+    reason: &'static str,
+    context: &'static str,
+    object: String,
+    username: Bytes,
+    client_info: String,
+    entry_id: u64,
+    created_ms: u128,
+    updated_ms: u128,
+    updated: Instant,
+}
+
+/// Users, their permissions and the log of denied requests.
+pub struct Acl {
+    users: RwLock<BTreeMap<Bytes, User>>,
+    log: RwLock<VecDeque<LogEntry>>,
+    next_entry_id: AtomicU64,
+    log_max_len: AtomicUsize,
+    requirepass: RwLock<String>,
+    file: RwLock<Option<String>>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        let mut users = BTreeMap::new();
+        users.insert(Bytes::from(DEFAULT_USER), User::superuser());
+        Acl {
+            users: RwLock::new(users),
+            log: RwLock::new(VecDeque::new()),
+            next_entry_id: AtomicU64::new(0),
+            log_max_len: AtomicUsize::new(128),
+            requirepass: RwLock::new(String::new()),
+            file: RwLock::new(None),
+        }
+    }
+
+    /// Whether new connections start out logged in as the default user.
+    pub async fn default_nopass(&self) -> bool {
+        let users = self.users.read().await;
+        users
+            .get(DEFAULT_USER.as_bytes())
+            .is_some_and(|u| u.enabled && u.nopass)
+    }
+
+    async fn authenticate(&self, username: &Bytes, password: &Bytes) -> bool {
+        let users = self.users.read().await;
+        match users.get(username) {
+            Some(user) if user.enabled => {
+                user.nopass || user.passwords.contains(&hash_password(password))
+            }
+            _ => false,
+        }
+    }
+
+    async fn check(&self, username: &Bytes, req: &Request<'_>) -> Result<(), Denial> {
+        let users = self.users.read().await;
+        let Some(user) = users.get(username) else {
+            return Err(Denial::Command);
+        };
+        if !user.can_run(req.name) {
+            return Err(Denial::Command);
+        }
+        if let Some(key) = req.keys.iter().find(|k| !user.can_access_key(k, req.write)) {
+            return Err(Denial::Key(key.clone()));
+        }
+        if let Some(channel) = req.channels.iter().find(|c| !user.can_access_channel(c)) {
+            return Err(Denial::Channel(channel.clone()));
+        }
+        if let Some(pattern) = req.patterns.iter().find(|p| !user.can_access_pattern(p)) {
+            return Err(Denial::Channel(pattern.clone()));
+        }
+        Ok(())
+    }
+
+    // A replica gets every key in the full resync and every write after it,
+    // so PSYNC needs access to all keys and channels, not just the command
+    async fn check_sync(&self, username: &Bytes) -> Result<(), Denial> {
+        let users = self.users.read().await;
+        let Some(user) = users.get(username) else {
+            return Err(Denial::Command);
+        };
+        if !user.can_run("psync") {
+            return Err(Denial::Command);
+        }
+        if !user
+            .keys
+            .iter()
+            .any(|p| p.read && p.pattern.as_ref() == b"*")
+        {
+            return Err(Denial::Key(Bytes::from("*")));
+        }
+        if !user.channels.iter().any(|c| c.as_ref() == b"*") {
+            return Err(Denial::Channel(Bytes::from("*")));
+        }
+        Ok(())
+    }
+
+    async fn add_log_entry(
+        &self,
+        reason: &'static str,
+        context: &'static str,
+        object: String,
+        username: Bytes,
+        client_info: String,
+    ) {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut log = self.log.write().await;
+
+        // Repeats of a recent denial bump its count instead of adding a new entry
+        if let Some(pos) = log.iter().position(|e| {
+            e.reason == reason
+                && e.context == context
+                && e.object == object
+                && e.username == username
+                && e.updated.elapsed() < LOG_GROUP_WINDOW
+        }) {
+            let mut entry = log.remove(pos).unwrap();
+            entry.count += 1;
+            entry.client_info = client_info;
+            entry.updated_ms = now_ms;
+            entry.updated = Instant::now();
+            log.push_front(entry);
+            return;
+        }
+
+        log.push_front(LogEntry {
+            count: 1,
+            reason,
+            context,
+            object,
+            username,
+            client_info,
+            entry_id: self.next_entry_id.fetch_add(1, Ordering::Relaxed),
+            created_ms: now_ms,
+            updated_ms: now_ms,
+            updated: Instant::now(),
+        });
+        log.truncate(self.log_max_len.load(Ordering::Relaxed));
+    }
+
+    pub async fn requirepass(&self) -> String {
+        self.requirepass.read().await.clone()
+    }
+
+    /// requirepass is a shortcut for the default user's password.
+    pub async fn set_requirepass(&self, password: &str) {
+        *self.requirepass.write().await = password.to_string();
+        let mut users = self.users.write().await;
+        let user = users
+            .entry(Bytes::from(DEFAULT_USER))
+            .or_insert_with(User::superuser);
+        user.apply(b"resetpass").unwrap();
+        if password.is_empty() {
+            user.apply(b"nopass").unwrap();
+        } else {
+            user.add_password(hash_password(password.as_bytes()));
+        }
+    }
+
+    pub fn log_max_len(&self) -> usize {
+        self.log_max_len.load(Ordering::Relaxed)
+    }
+
+    pub async fn set_log_max_len(&self, len: usize) {
+        self.log_max_len.store(len, Ordering::Relaxed);
+        self.log.write().await.truncate(len);
+    }
+
+    pub async fn file(&self) -> Option<String> {
+        self.file.read().await.clone()
+    }
+
+    /// Reads users from `path` at startup and remembers it for ACL LOAD/SAVE.
+    pub async fn load_file(&self, path: String) -> Result<(), String> {
+        let users = read_acl_file(&path)?;
+        *self.users.write().await = users;
+        *self.file.write().await = Some(path);
+        Ok(())
+    }
+
+    pub async fn setuser(&self, name: Bytes, rules: &[Bytes]) -> RedisValueRef {
+        let mut users = self.users.write().await;
+        match parse_user(rules, users.get(&name)) {
+            Ok(user) => {
+                users.insert(name, user);
+                RedisValueRef::String(Bytes::from("OK"))
+            }
+            Err(e) => RedisValueRef::Error(Bytes::from(format!("ERR {}", e))),
+        }
+    }
+
+    pub async fn getuser(&self, name: &Bytes, protocol: u8) -> RedisValueRef {
+        let users = self.users.read().await;
+        let Some(user) = users.get(name) else {
+            return RedisValueRef::NullBulkString;
+        };
+        let mut flags = vec![RedisValueRef::BulkString(Bytes::from(if user.enabled {
+            "on"
+        } else {
+            "off"
+        }))];
+        if user.nopass {
+            flags.push(RedisValueRef::BulkString(Bytes::from("nopass")));
+        }
+        let bulk = |s: String| RedisValueRef::BulkString(Bytes::from(s));
+        map_reply(
+            protocol,
+            vec![
+                ("flags", RedisValueRef::Array(flags)),
+                (
+                    "passwords",
+                    RedisValueRef::Array(user.passwords.iter().cloned().map(bulk).collect()),
+                ),
+                ("commands", bulk(user.describe_commands())),
+                ("keys", bulk(user.describe_keys())),
+                ("channels", bulk(user.describe_channels())),
+                ("selectors", RedisValueRef::Array(Vec::new())),
+            ],
+        )
+    }
+
+    pub async fn list(&self) -> RedisValueRef {
+        let users = self.users.read().await;
+        RedisValueRef::Array(
+            users
+                .iter()
+                .map(|(name, user)| {
+                    RedisValueRef::BulkString(Bytes::from(format!(
+                        "user {} {}",
+                        String::from_utf8_lossy(name),
+                        user.describe()
+                    )))
+                })
+                .collect(),
+        )
+    }
+
+    pub async fn users(&self) -> RedisValueRef {
+        let users = self.users.read().await;
+        RedisValueRef::Array(
+            users
+                .keys()
+                .map(|name| RedisValueRef::BulkString(name.clone()))
+                .collect(),
+        )
+    }
+
+    /// ACL CAT: every category, or the commands in one.
+    pub fn cat(&self, category: Option<Bytes>) -> RedisValueRef {
+        let Some(category) = category else {
+            return RedisValueRef::Array(
+                CATEGORIES
+                    .iter()
+                    .map(|c| RedisValueRef::BulkString(Bytes::from(*c)))
+                    .collect(),
+            );
+        };
+        let category = String::from_utf8_lossy(&category).to_lowercase();
+        if !CATEGORIES.contains(&category.as_str()) {
+            return RedisValueRef::Error(Bytes::from(format!(
+                "ERR Unknown category '{}'",
+                category
+            )));
+        }
+        RedisValueRef::Array(
+            COMMAND_TABLE
+                .iter()
+                .filter(|(_, categories)| categories.contains(&category.as_str()))
+                .map(|(name, _)| RedisValueRef::BulkString(Bytes::from(*name)))
+                .collect(),
+        )
+    }
+
+    /// ACL LOG [count], newest first.
+    pub async fn log(&self, count: Option<usize>, protocol: u8) -> RedisValueRef {
+        let log = self.log.read().await;
+        let bulk = |s: String| RedisValueRef::BulkString(Bytes::from(s));
+        RedisValueRef::Array(
+            log.iter()
+                .take(count.unwrap_or(10))
+                .map(|e| {
+                    map_reply(
+                        protocol,
+                        vec![
+                            ("count", RedisValueRef::Int(e.count as i64)),
+                            ("reason", bulk(e.reason.to_string())),
+                            ("context", bulk(e.context.to_string())),
+                            ("object", bulk(e.object.clone())),
+                            ("username", RedisValueRef::BulkString(e.username.clone())),
+                            (
+                                "age-seconds",
+                                bulk(format!("{:.3}", e.updated.elapsed().as_secs_f64())),
+                            ),
+                            ("client-info", bulk(e.client_info.clone())),
+                            ("entry-id", RedisValueRef::Int(e.entry_id as i64)),
+                            ("timestamp-created", RedisValueRef::Int(e.created_ms as i64)),
+                            (
+                                "timestamp-last-updated",
+                                RedisValueRef::Int(e.updated_ms as i64),
+                            ),
+                        ],
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    pub async fn log_reset(&self) -> RedisValueRef {
+        self.log.write().await.clear();
+        RedisValueRef::String(Bytes::from("OK"))
+    }
+}
+
+// A RESP3 map, or a flat array of alternating keys and values for RESP2
+pub(crate) fn map_reply(protocol: u8, fields: Vec<(&str, RedisValueRef)>) -> RedisValueRef {
+    let fields = fields
+        .into_iter()
+        .map(|(k, v)| (RedisValueRef::BulkString(Bytes::from(k.to_string())), v));
+    if protocol == 3 {
+        RedisValueRef::Map(fields.collect())
+    } else {
+        RedisValueRef::Array(fields.flat_map(|(k, v)| [k, v]).collect())
+    }
+}
+
+// Parses an ACL file, every line must be "user <name> <rules...>"
+fn read_acl_file(path: &str) -> Result<BTreeMap<Bytes, User>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error loading ACLs, opening file '{}': {}", path, e))?;
+    let mut users = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<Bytes> = line
+            .split_whitespace()
+            .map(|w| Bytes::from(w.to_string()))
+            .collect();
+        if words[0].as_ref() != b"user" || words.len() < 2 {
+            return Err(format!(
+                "{}:{}: should start with user keyword",
+                path,
+                i + 1
+            ));
+        }
+        let name = words[1].clone();
+        if users.contains_key(&name) {
+            return Err(format!(
+                "{}:{}: Duplicate user '{}' found",
+                path,
+                i + 1,
+                String::from_utf8_lossy(&name)
+            ));
+        }
+        let user =
+            parse_user(&words[2..], None).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        users.insert(name, user);
+    }
+    // The default user always exists
+    users
+        .entry(Bytes::from(DEFAULT_USER))
+        .or_insert_with(User::superuser);
+    Ok(users)
+}
+
+/// AUTH and HELLO AUTH. Failed attempts are recorded in ACL LOG.
+pub async fn login(
+    redis: &Redis,
+    client: &Client,
+    username: Option<Bytes>,
+    password: Bytes,
+) -> Result<(), RedisValueRef> {
+    if username.is_none() && redis.acl.default_nopass().await {
+        return Err(RedisValueRef::Error(Bytes::from("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?")));
+    }
+    let username = username.unwrap_or_else(|| Bytes::from(DEFAULT_USER));
+    if redis.acl.authenticate(&username, &password).await {
+        client.set_user(username, true).await;
+        return Ok(());
+    }
+    redis
+        .acl
+        .add_log_entry(
+            "auth",
+            "toplevel",
+            "AUTH".to_string(),
+            username,
+            client::info_line(redis, client).await,
+        )
+        .await;
+    Err(RedisValueRef::Error(Bytes::from(
+        "WRONGPASS invalid username-password pair or user is disabled.",
+    )))
+}
+
+/// Checks a command against the client's user, logging and returning the
+/// NOPERM error when it is denied.
+pub async fn enforce(
+    redis: &Redis,
+    client: &Client,
+    req: Request<'_>,
+    context: &'static str,
+) -> Result<(), RedisValueRef> {
+    let username = client.user().await;
+    match redis.acl.check(&username, &req).await {
+        Ok(()) => Ok(()),
+        Err(denial) => Err(deny(redis, client, username, req.name, denial, context).await),
+    }
+}
+
+/// Checks that the client's user may sync as a replica before it is sent
+/// the dataset.
+pub async fn enforce_sync(redis: &Redis, client: &Client) -> Result<(), RedisValueRef> {
+    let username = client.user().await;
+    match redis.acl.check_sync(&username).await {
+        Ok(()) => Ok(()),
+        Err(denial) => Err(deny(redis, client, username, "psync", denial, "toplevel").await),
+    }
+}
+
+// Logs a denial in ACL LOG and builds its NOPERM error
+async fn deny(
+    redis: &Redis,
+    client: &Client,
+    username: Bytes,
+    name: &str,
+    denial: Denial,
+    context: &'static str,
+) -> RedisValueRef {
+    let (reason, object, msg) = match denial {
+        Denial::Command => (
+            "command",
+            name.to_string(),
+            format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                String::from_utf8_lossy(&username),
+                name
+            ),
+        ),
+        Denial::Key(key) => (
+            "key",
+            String::from_utf8_lossy(&key).to_string(),
+            "NOPERM No permissions to access a key".to_string(),
+        ),
+        Denial::Channel(channel) => (
+            "channel",
+            String::from_utf8_lossy(&channel).to_string(),
+            "NOPERM No permissions to access a channel".to_string(),
+        ),
+    };
+    redis
+        .acl
+        .add_log_entry(
+            reason,
+            context,
+            object,
+            username,
+            client::info_line(redis, client).await,
+        )
+        .await;
+    RedisValueRef::Error(Bytes::from(msg))
+}
+
+// Connections logged in as a user that no longer exists are closed
+async fn kill_orphans(redis: &Redis) {
+    let users = redis.acl.users.read().await;
+    for client in redis.clients.all().await {
+        if !client.is_master() && !users.contains_key(&client.user().await) {
+            client.kill();
+        }
+    }
+}
+
+pub async fn deluser(redis: &Redis, names: &[Bytes]) -> RedisValueRef {
+    if names.iter().any(|n| n.as_ref() == DEFAULT_USER.as_bytes()) {
+        return RedisValueRef::Error(Bytes::from("ERR The 'default' user cannot be removed"));
+    }
+    let deleted = {
+        let mut users = redis.acl.users.write().await;
+        names.iter().filter(|n| users.remove(*n).is_some()).count()
+    };
+    kill_orphans(redis).await;
+    RedisValueRef::Int(deleted as i64)
+}
+
+/// ACL LOAD replaces every user with the contents of the ACL file.
+pub async fn load(redis: &Redis) -> RedisValueRef {
+    let Some(path) = redis.acl.file().await else {
+        return no_acl_file();
+    };
+    match read_acl_file(&path) {
+        Ok(users) => {
+            *redis.acl.users.write().await = users;
+            kill_orphans(redis).await;
+            RedisValueRef::String(Bytes::from("OK"))
+        }
+        Err(e) => RedisValueRef::Error(Bytes::from(format!("ERR {}", e))),
+    }
+}
+
+/// ACL SAVE writes the users to the ACL file through a temp file, so a
+/// crash never leaves it half written.
+pub async fn save(redis: &Redis) -> RedisValueRef {
+    let Some(path) = redis.acl.file().await else {
+        return no_acl_file();
+    };
+    let mut contents = String::new();
+    for (name, user) in redis.acl.users.read().await.iter() {
+        contents.push_str(&format!(
+            "user {} {}\n",
+            String::from_utf8_lossy(name),
+            user.describe()
+        ));
+    }
+    let tmp = format!("{}.tmp", path);
+    let res = std::fs::write(&tmp, contents).and_then(|_| std::fs::rename(&tmp, &path));
+    match res {
+        Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+        Err(e) => RedisValueRef::Error(Bytes::from(format!(
+            "ERR There was an error trying to save the ACLs. Please check the server logs for more information: {}",
+            e
+        ))),
+    }
+}
+
+fn no_acl_file() -> RedisValueRef {
+    RedisValueRef::Error(Bytes::from("ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration."))
+}
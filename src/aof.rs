@@ -0,0 +1,332 @@
+use crate::client::Client;
+use crate::commands::{handle_command, serialize_to_resp};
+use crate::log;
+use crate::persistence;
+use crate::rdb::parse_rdb_prefix;
+use crate::redis::Redis;
+use crate::resp::{RedisValueRef, RespParser};
+use bytes::{Bytes, BytesMut};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio_util::codec::Decoder;
+
+/// When appended writes reach the disk, like appendfsync.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    // After every write, before it is acknowledged
+    Always,
+    // Once a second, from a background task
+    EverySec,
+    // Whenever the OS flushes its buffers
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+// The file writes are appended to
+struct AofFile {
+    file: std::fs::File,
+    // Database the file last selected, usize::MAX before any
+    db: usize,
+}
+
+/// The append-only file: every write that reaches replicas is also logged
+/// here in RESP form, and replayed at startup.
+pub struct Aof {
+    enabled: AtomicBool,
+    fsync: RwLock<AppendFsync>,
+    filename: RwLock<String>,
+    // None until logging starts
+    file: Mutex<Option<AofFile>>,
+    // Something was written since the last fsync
+    unsynced: AtomicBool,
+    last_write_ok: AtomicBool,
+}
+
+impl Default for Aof {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Aof {
+    pub fn new() -> Self {
+        Aof {
+            enabled: AtomicBool::new(false),
+            fsync: RwLock::new(AppendFsync::EverySec),
+            filename: RwLock::new("appendonly.aof".to_string()),
+            file: Mutex::new(None),
+            unsynced: AtomicBool::new(false),
+            last_write_ok: AtomicBool::new(true),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Only takes effect at startup.
+    pub fn set_enabled(&self, on: bool) {
+        self.enabled.store(on, Ordering::SeqCst);
+    }
+
+    pub async fn fsync(&self) -> AppendFsync {
+        *self.fsync.read().await
+    }
+
+    pub async fn set_fsync(&self, fsync: AppendFsync) {
+        *self.fsync.write().await = fsync;
+    }
+
+    pub async fn filename(&self) -> String {
+        self.filename.read().await.clone()
+    }
+
+    /// Only takes effect at startup.
+    pub async fn set_filename(&self, filename: String) -> Result<(), String> {
+        if filename.is_empty() || filename.contains('/') {
+            return Err("appendfilename can't be a path, just a filename".to_string());
+        }
+        *self.filename.write().await = filename;
+        Ok(())
+    }
+
+    /// Where the file lives in `dir`, the directory RDB files go to.
+    pub async fn path(&self, dir: &str) -> PathBuf {
+        let dir = if dir.is_empty() { "." } else { dir };
+        Path::new(dir).join(self.filename().await)
+    }
+
+    /// Logs a write made in database `db`, selecting it first if the file
+    /// was last left in another one.
+    pub async fn feed(&self, db: usize, args: &[RedisValueRef]) {
+        let mut guard = self.file.lock().await;
+        let Some(aof) = guard.as_mut() else {
+            return;
+        };
+        let mut buf = Vec::new();
+        if aof.db != db {
+            buf = serialize_to_resp(&[
+                RedisValueRef::String(Bytes::from("SELECT")),
+                RedisValueRef::String(Bytes::from(db.to_string())),
+            ]);
+            aof.db = db;
+        }
+        buf.extend_from_slice(&serialize_to_resp(args));
+
+        let always = self.fsync().await == AppendFsync::Always;
+        let res = tokio::task::block_in_place(|| {
+            aof.file.write_all(&buf)?;
+            if always {
+                aof.file.sync_data()?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+        match res {
+            Ok(()) => {
+                self.unsynced.store(!always, Ordering::SeqCst);
+                self.last_write_ok.store(true, Ordering::SeqCst);
+            }
+            Err(e) => {
+                log::warn!("Error writing to the AOF file: {}", e);
+                // A partial write may have left the SELECT out
+                aof.db = usize::MAX;
+                self.last_write_ok.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Flushes the file to disk when appendfsync is everysec and something
+    /// was written since the last time. Called once a second.
+    pub async fn fsync_cron(&self) {
+        if self.fsync().await != AppendFsync::EverySec
+            || !self.unsynced.swap(false, Ordering::SeqCst)
+        {
+            return;
+        }
+        let file = match self.file.lock().await.as_ref() {
+            Some(aof) => aof.file.try_clone(),
+            None => return,
+        };
+        let res = match file {
+            Ok(file) => tokio::task::spawn_blocking(move || file.sync_data())
+                .await
+                .map_err(std::io::Error::other)
+                .and_then(|res| res),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            log::warn!("Error syncing the AOF file: {}", e);
+            self.unsynced.store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub fn info(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "aof_enabled:{}", self.enabled() as u8).unwrap();
+        writeln!(
+            s,
+            "aof_last_write_status:{}",
+            if self.last_write_ok.load(Ordering::SeqCst) {
+                "ok"
+            } else {
+                "err"
+            }
+        )
+        .unwrap();
+        s
+    }
+}
+
+/// Starts logging writes. A missing file is first created with the current
+/// dataset as an RDB preamble, so nothing loaded from an RDB file is lost.
+pub async fn start(redis: &Redis) -> std::io::Result<()> {
+    let path = redis.aof.path(&redis.persistence.dir().await).await;
+    if !path.exists() {
+        return rewrite(redis).await;
+    }
+    let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+    *redis.aof.file.lock().await = Some(AofFile {
+        file,
+        db: usize::MAX,
+    });
+    Ok(())
+}
+
+/// Replaces the file with the current dataset as an RDB preamble, e.g. after
+/// a replica loads its master's data. The caller must keep writers out, by
+/// holding `exec_lock` exclusively or before clients connect.
+pub async fn rewrite(redis: &Redis) -> std::io::Result<()> {
+    let path = redis.aof.path(&redis.persistence.dir().await).await;
+    // Held throughout, so no write lands in the old file meanwhile
+    let mut guard = redis.aof.file.lock().await;
+    let data = persistence::snapshot(redis).await.data;
+
+    let temp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+    let res = tokio::task::block_in_place(|| {
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        std::fs::OpenOptions::new().append(true).open(&path)
+    });
+    match res {
+        Ok(file) => {
+            *guard = Some(AofFile {
+                file,
+                db: usize::MAX,
+            });
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Replays the file at startup: its RDB preamble if it has one, then every
+/// logged command. A command or transaction cut short at the end, as a crash
+/// can leave it, is dropped and truncated from the file.
+pub async fn load(redis: &Arc<Redis>) -> Result<(), String> {
+    let path = redis.aof.path(&redis.persistence.dir().await).await;
+    let data = std::fs::read(&path)
+        .map_err(|e| format!("Can't read the append only file {}: {}", path.display(), e))?;
+    redis.loading.set_total(data.len() as u64);
+
+    let mut offset = 0;
+    if data.starts_with(b"REDIS") {
+        let (rdb_file, len) = parse_rdb_prefix(&data, redis.persistence.rdbchecksum())
+            .map_err(|e| format!("Bad RDB preamble in the append only file: {}", e))?;
+        redis
+            .restore_rdb(rdb_file)
+            .await
+            .map_err(|e| format!("Bad RDB preamble in the append only file: {}", e))?;
+        offset = len;
+        redis.loading.set_loaded(offset as u64);
+    }
+
+    // Replayed like the master link's stream: already authorised, never
+    // paused or turned away while loading
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let (push_tx, _) = mpsc::unbounded_channel();
+    let client = Client::new_master(unspecified, unspecified, push_tx);
+
+    let mut buf = BytesMut::from(&data[offset..]);
+    // Where a MULTI still waiting for its EXEC starts
+    let mut multi_at = None;
+    let mut res = Ok(());
+    loop {
+        let before = buf.len();
+        let value = match RespParser.decode(&mut buf) {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(e) => {
+                res = Err(format!(
+                    "Bad file format reading the append only file at offset {}: {:?}",
+                    offset, e
+                ));
+                break;
+            }
+        };
+        let start = offset;
+        offset += before - buf.len();
+        redis.loading.set_loaded(offset as u64);
+        match command_name(&value).as_deref() {
+            Some("multi") => multi_at = Some(start),
+            Some("exec") => multi_at = None,
+            _ => {}
+        }
+        handle_command(value, &client, redis).await;
+    }
+    // Also throws away the queue of an unfinished MULTI
+    redis.remove_client(client.id).await;
+    res?;
+
+    let valid = multi_at.unwrap_or(offset);
+    if valid < data.len() {
+        log::warn!(
+            "The append only file ends with an incomplete command or transaction; truncating it from {} to {} bytes",
+            data.len(),
+            valid
+        );
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(valid as u64))
+            .map_err(|e| format!("Can't truncate the append only file: {}", e))?;
+    }
+    Ok(())
+}
+
+fn command_name(value: &RedisValueRef) -> Option<String> {
+    match value {
+        RedisValueRef::Array(arr) => match arr.first() {
+            Some(RedisValueRef::String(name)) => Some(String::from_utf8_lossy(name).to_lowercase()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
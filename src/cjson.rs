@@ -0,0 +1,155 @@
+use mlua::{LightUserData, Lua, Table, Value};
+use serde_json::{Map, Number, Value as Json};
+
+// Tables nested deeper than this are refused rather than overflowing the
+// stack, as a table that contains itself would
+const MAX_DEPTH: usize = 1000;
+
+// An array may have at most this many nils for each value before it is
+// refused as too sparse, as in cjson
+const SPARSE_RATIO: usize = 2;
+// Arrays this short are never too sparse
+const SPARSE_SAFE: usize = 10;
+
+/// The cjson table scripts get: cjson.encode, cjson.decode and cjson.null,
+/// which is what JSON null decodes to.
+pub fn table(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cjson = lua.create_table()?;
+    cjson.set(
+        "encode",
+        lua.create_function(|_, value: Value| {
+            let json = to_json(&value, 0)?;
+            Ok(json.to_string())
+        })?,
+    )?;
+    cjson.set(
+        "decode",
+        lua.create_function(|lua, s: mlua::String| {
+            let json: Json = serde_json::from_slice(s.as_bytes())
+                .map_err(|e| mlua::Error::RuntimeError(format!("Expected value but {}", e)))?;
+            from_json(lua, json)
+        })?,
+    )?;
+    cjson.set("null", null())?;
+    Ok(cjson)
+}
+
+fn null() -> Value<'static> {
+    Value::LightUserData(LightUserData(std::ptr::null_mut()))
+}
+
+fn unsupported(kind: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("Cannot serialise {}: type not supported", kind))
+}
+
+fn to_json(value: &Value, depth: usize) -> mlua::Result<Json> {
+    Ok(match value {
+        Value::Nil => Json::Null,
+        Value::LightUserData(ud) if ud.0.is_null() => Json::Null,
+        Value::Boolean(b) => Json::Bool(*b),
+        Value::Integer(i) => Json::Number((*i).into()),
+        Value::Number(n) => number(*n)?,
+        Value::String(s) => Json::String(s.to_string_lossy().into_owned()),
+        Value::Table(t) => {
+            if depth >= MAX_DEPTH {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "Cannot serialise, excessive nesting ({})",
+                    depth + 1
+                )));
+            }
+            table_to_json(t, depth + 1)?
+        }
+        other => return Err(unsupported(other.type_name())),
+    })
+}
+
+// Whole numbers are written without a fraction, as Lua 5.1 has no integers
+fn number(n: f64) -> mlua::Result<Json> {
+    if n.is_nan() || n.is_infinite() {
+        return Err(mlua::Error::RuntimeError(format!(
+            "Cannot serialise number: must not be NaN or Inf ({})",
+            n
+        )));
+    }
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        return Ok(Json::Number((n as i64).into()));
+    }
+    Ok(Number::from_f64(n).map_or(Json::Null, Json::Number))
+}
+
+// A table whose keys are all positive integers is an array; any other is an
+// object, and an empty table is an object
+fn table_to_json(table: &Table, depth: usize) -> mlua::Result<Json> {
+    let mut entries = Vec::new();
+    let mut max_index = 0;
+    let mut is_array = true;
+    for pair in table.clone().pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        match array_index(&key) {
+            Some(i) => max_index = max_index.max(i),
+            None => is_array = false,
+        }
+        entries.push((key, value));
+    }
+    if is_array && !entries.is_empty() {
+        if max_index > SPARSE_SAFE && max_index > entries.len() * SPARSE_RATIO {
+            return Err(mlua::Error::RuntimeError(
+                "Cannot serialise table: excessively sparse array".to_string(),
+            ));
+        }
+        let mut items = vec![Json::Null; max_index];
+        for (key, value) in entries {
+            if let Some(i) = array_index(&key) {
+                items[i - 1] = to_json(&value, depth)?;
+            }
+        }
+        return Ok(Json::Array(items));
+    }
+    let mut object = Map::new();
+    for (key, value) in entries {
+        let key = match key {
+            Value::String(s) => s.to_string_lossy().into_owned(),
+            Value::Integer(i) => i.to_string(),
+            Value::Number(n) if n.fract() == 0.0 => (n as i64).to_string(),
+            Value::Number(n) => n.to_string(),
+            _ => {
+                return Err(mlua::Error::RuntimeError(
+                    "Cannot serialise table: table key must be a number or string".to_string(),
+                ))
+            }
+        };
+        object.insert(key, to_json(&value, depth)?);
+    }
+    Ok(Json::Object(object))
+}
+
+fn array_index(key: &Value) -> Option<usize> {
+    match key {
+        Value::Integer(i) if *i >= 1 => Some(*i as usize),
+        Value::Number(n) if *n >= 1.0 && n.fract() == 0.0 => Some(*n as usize),
+        _ => None,
+    }
+}
+
+fn from_json(lua: &Lua, json: Json) -> mlua::Result<Value<'_>> {
+    Ok(match json {
+        Json::Null => null(),
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(n) => Value::Number(n.as_f64().unwrap_or_default()),
+        Json::String(s) => Value::String(lua.create_string(&s)?),
+        Json::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, from_json(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        Json::Object(object) => {
+            let table = lua.create_table_with_capacity(0, object.len())?;
+            for (key, value) in object {
+                table.raw_set(key, from_json(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
@@ -0,0 +1,584 @@
+use crate::redis::Redis;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use tokio::time::{Duration, Instant};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// client-output-buffer-limit for a class of clients: one is disconnected
+/// once `hard` bytes are queued for it, or once it has stayed over `soft`
+/// bytes for more than `soft_seconds`. A limit of 0 is off.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Redis's default for pub/sub clients: 32mb, or 8mb for a minute.
+    pub const PUBSUB: OutputBufferLimit = OutputBufferLimit {
+        hard: 32 * 1024 * 1024,
+        soft: 8 * 1024 * 1024,
+        soft_seconds: 60,
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    // Drop the reply to the next command only
+    Skip,
+}
+
+// Settings a client can change about itself
+struct ClientState {
+    name: Option<Bytes>,
+    lib_name: Option<Bytes>,
+    lib_ver: Option<Bytes>,
+    user: Bytes,
+    // Set once AUTH succeeds, or on connect when the default user needs no password
+    authenticated: bool,
+    last_cmd: String,
+    last_interaction: Instant,
+    reply: ReplyMode,
+    no_evict: bool,
+}
+
+/// Per-connection context, created when a connection is accepted and passed
+/// to every command it sends.
+pub struct Client {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub laddr: SocketAddr,
+    // The link a replica applies its master's command stream from
+    master: bool,
+    created: Instant,
+    // 2 or 3, switched with HELLO
+    protocol: AtomicU8,
+    // The database commands run against, switched with SELECT
+    db: AtomicUsize,
+    // Out-of-band messages (pub/sub, invalidations) are queued here and
+    // written by the connection task
+    tx: mpsc::UnboundedSender<RedisValueRef>,
+    queued_pushes: AtomicUsize,
+    // Encoded size of the queued pushes, held against the output buffer limit
+    queued_bytes: AtomicUsize,
+    // When the queue last went over the soft limit, while it stays over
+    soft_limit_since: std::sync::Mutex<Option<Instant>>,
+    query_buf: AtomicUsize,
+    state: RwLock<ClientState>,
+    // Set while EXEC runs the queued commands
+    in_exec: AtomicBool,
+    // Writes the running command made, with the database each was made in,
+    // sent to replicas and the AOF once it finishes
+    propagate: Mutex<Vec<(usize, Vec<RedisValueRef>)>>,
+    killed: AtomicBool,
+    kill_signal: Notify,
+}
+
+impl Client {
+    pub fn new(
+        addr: SocketAddr,
+        laddr: SocketAddr,
+        tx: mpsc::UnboundedSender<RedisValueRef>,
+    ) -> Self {
+        Self::with_kind(addr, laddr, tx, false)
+    }
+
+    pub fn new_master(
+        addr: SocketAddr,
+        laddr: SocketAddr,
+        tx: mpsc::UnboundedSender<RedisValueRef>,
+    ) -> Self {
+        Self::with_kind(addr, laddr, tx, true)
+    }
+
+    fn with_kind(
+        addr: SocketAddr,
+        laddr: SocketAddr,
+        tx: mpsc::UnboundedSender<RedisValueRef>,
+        master: bool,
+    ) -> Self {
+        let now = Instant::now();
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            laddr,
+            master,
+            created: now,
+            protocol: AtomicU8::new(2),
+            db: AtomicUsize::new(0),
+            tx,
+            queued_pushes: AtomicUsize::new(0),
+            queued_bytes: AtomicUsize::new(0),
+            soft_limit_since: std::sync::Mutex::new(None),
+            query_buf: AtomicUsize::new(0),
+            state: RwLock::new(ClientState {
+                name: None,
+                lib_name: None,
+                lib_ver: None,
+                user: Bytes::from("default"),
+                authenticated: master,
+                last_cmd: "NULL".to_string(),
+                last_interaction: now,
+                reply: ReplyMode::On,
+                no_evict: false,
+            }),
+            in_exec: AtomicBool::new(false),
+            propagate: Mutex::new(Vec::new()),
+            killed: AtomicBool::new(false),
+            kill_signal: Notify::new(),
+        }
+    }
+
+    pub fn is_master(&self) -> bool {
+        self.master
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn set_protocol(&self, protocol: u8) {
+        self.protocol.store(protocol, Ordering::Relaxed);
+    }
+
+    pub fn db(&self) -> usize {
+        self.db.load(Ordering::Relaxed)
+    }
+
+    pub fn select(&self, db: usize) {
+        self.db.store(db, Ordering::Relaxed);
+    }
+
+    /// Queues an out-of-band message, as a push frame for RESP3 clients.
+    /// A killed client gets no more, as it may never read them.
+    pub fn push(&self, items: Vec<RedisValueRef>) -> bool {
+        if self.is_killed() {
+            return false;
+        }
+        let msg = if self.protocol() == 3 {
+            RedisValueRef::Push(items)
+        } else {
+            RedisValueRef::Array(items)
+        };
+        let len = msg.encoded_len();
+        let sent = self.tx.send(msg).is_ok();
+        if sent {
+            self.queued_pushes.fetch_add(1, Ordering::Relaxed);
+            self.queued_bytes.fetch_add(len, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Called by the connection task once a queued push of `len` encoded
+    /// bytes has been written.
+    pub fn push_sent(&self, len: usize) {
+        self.queued_pushes.fetch_sub(1, Ordering::Relaxed);
+        self.queued_bytes.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    /// Whether the queued pushes are over `limit`, so the client should be
+    /// disconnected. The soft limit's clock starts at the first check that
+    /// finds the queue over it and is reset by one that finds it below.
+    pub fn over_output_limit(&self, limit: &OutputBufferLimit) -> bool {
+        let used = self.queued_bytes.load(Ordering::Relaxed) as u64;
+        if limit.hard > 0 && used >= limit.hard {
+            return true;
+        }
+        let mut since = self.soft_limit_since.lock().unwrap();
+        if limit.soft == 0 || used < limit.soft {
+            *since = None;
+            return false;
+        }
+        match *since {
+            Some(at) => at.elapsed() > Duration::from_secs(limit.soft_seconds),
+            None => {
+                *since = Some(Instant::now());
+                false
+            }
+        }
+    }
+
+    pub fn set_query_buf(&self, len: usize) {
+        self.query_buf.store(len, Ordering::Relaxed);
+    }
+
+    /// Records the command about to run, for CLIENT LIST's cmd and idle.
+    pub async fn begin_command(&self, name: String) {
+        let mut state = self.state.write().await;
+        state.last_cmd = name;
+        state.last_interaction = Instant::now();
+    }
+
+    pub async fn name(&self) -> Option<Bytes> {
+        self.state.read().await.name.clone()
+    }
+
+    pub async fn set_name(&self, name: Bytes) -> Result<(), String> {
+        if !valid_info_value(&name) {
+            return Err(
+                "ERR Client names cannot contain spaces, newlines or special characters."
+                    .to_string(),
+            );
+        }
+        self.state.write().await.name = (!name.is_empty()).then_some(name);
+        Ok(())
+    }
+
+    pub async fn set_info(&self, attr: &Bytes, value: Bytes) -> Result<(), String> {
+        let attr = String::from_utf8_lossy(attr).to_lowercase();
+        if attr != "lib-name" && attr != "lib-ver" {
+            return Err(format!("ERR Unrecognized option '{}'", attr));
+        }
+        if !valid_info_value(&value) {
+            return Err(format!(
+                "ERR {} cannot contain spaces, newlines or special characters.",
+                attr
+            ));
+        }
+        let value = (!value.is_empty()).then_some(value);
+        let mut state = self.state.write().await;
+        if attr == "lib-name" {
+            state.lib_name = value;
+        } else {
+            state.lib_ver = value;
+        }
+        Ok(())
+    }
+
+    pub async fn user(&self) -> Bytes {
+        self.state.read().await.user.clone()
+    }
+
+    pub async fn is_authenticated(&self) -> bool {
+        self.state.read().await.authenticated
+    }
+
+    pub async fn set_user(&self, user: Bytes, authenticated: bool) {
+        let mut state = self.state.write().await;
+        state.user = user;
+        state.authenticated = authenticated;
+    }
+
+    pub async fn reply_mode(&self) -> ReplyMode {
+        self.state.read().await.reply
+    }
+
+    pub async fn set_reply_mode(&self, mode: ReplyMode) {
+        self.state.write().await.reply = mode;
+    }
+
+    pub async fn set_no_evict(&self, on: bool) {
+        self.state.write().await.no_evict = on;
+    }
+
+    pub fn in_exec(&self) -> bool {
+        self.in_exec.load(Ordering::Relaxed)
+    }
+
+    pub fn set_in_exec(&self, on: bool) {
+        self.in_exec.store(on, Ordering::Relaxed);
+    }
+
+    pub async fn also_propagate(&self, db: usize, args: Vec<RedisValueRef>) {
+        self.propagate.lock().await.push((db, args));
+    }
+
+    pub async fn take_propagated(&self) -> Vec<(usize, Vec<RedisValueRef>)> {
+        std::mem::take(&mut *self.propagate.lock().await)
+    }
+
+    /// Asks the connection task to close this connection.
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        self.kill_signal.notify_one();
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    pub async fn killed(&self) {
+        self.kill_signal.notified().await;
+    }
+}
+
+// Names and library info are printed space-separated in CLIENT LIST
+fn valid_info_value(value: &[u8]) -> bool {
+    value.iter().all(|&b| (b'!'..=b'~').contains(&b))
+}
+
+// CLIENT PAUSE state
+struct Pause {
+    until: Instant,
+    // ALL pauses every command, WRITE only the ones that may change data
+    all: bool,
+}
+
+/// Every live connection, by client ID.
+pub struct Clients {
+    clients: RwLock<HashMap<u64, Arc<Client>>>,
+    pause: RwLock<Option<Pause>>,
+    unpaused: Notify,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Clients {
+            clients: RwLock::new(HashMap::new()),
+            pause: RwLock::new(None),
+            unpaused: Notify::new(),
+        }
+    }
+
+    pub async fn register(&self, client: Arc<Client>) {
+        self.clients.write().await.insert(client.id, client);
+    }
+
+    pub async fn unregister(&self, id: u64) {
+        self.clients.write().await.remove(&id);
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Arc<Client>> {
+        self.clients.read().await.get(&id).cloned()
+    }
+
+    /// Every client, oldest first.
+    pub async fn all(&self) -> Vec<Arc<Client>> {
+        let mut clients: Vec<_> = self.clients.read().await.values().cloned().collect();
+        clients.sort_by_key(|c| c.id);
+        clients
+    }
+
+    pub async fn pause(&self, timeout: Duration, all: bool) {
+        let mut pause = self.pause.write().await;
+        let until = Instant::now() + timeout;
+        // A second PAUSE can only extend the pause or widen it to ALL
+        *pause = Some(match pause.take() {
+            Some(p) => Pause {
+                until: p.until.max(until),
+                all: p.all || all,
+            },
+            None => Pause { until, all },
+        });
+    }
+
+    pub async fn unpause(&self) {
+        *self.pause.write().await = None;
+        self.unpaused.notify_waiters();
+    }
+
+    pub async fn is_paused(&self) -> bool {
+        matches!(&*self.pause.read().await, Some(p) if p.until > Instant::now())
+    }
+
+    /// Holds the caller while clients are paused for this kind of command.
+    pub async fn wait_if_paused(&self, write: bool) {
+        loop {
+            let unpaused = self.unpaused.notified();
+            tokio::pin!(unpaused);
+            unpaused.as_mut().enable();
+
+            let until = match &*self.pause.read().await {
+                Some(p) if (p.all || write) && p.until > Instant::now() => p.until,
+                _ => return,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep_until(until) => {}
+                _ = unpaused => {}
+            }
+        }
+    }
+}
+
+/// Filters for CLIENT KILL. Every given filter must match.
+#[derive(Default)]
+pub struct KillFilter {
+    pub id: Option<u64>,
+    pub addr: Option<Bytes>,
+    pub laddr: Option<Bytes>,
+    pub user: Option<Bytes>,
+    pub kind: Option<Bytes>,
+    pub skipme: bool,
+}
+
+// normal, master or pubsub. Replicas leave the client registry once they
+// PSYNC, so TYPE replica never matches.
+async fn client_type(redis: &Redis, client: &Client) -> &'static str {
+    if client.is_master() {
+        "master"
+    } else if redis.pubsub.is_subscribed(client.id).await {
+        "pubsub"
+    } else {
+        "normal"
+    }
+}
+
+fn parse_type(kind: &Bytes) -> Result<&'static str, String> {
+    match String::from_utf8_lossy(kind).to_lowercase().as_str() {
+        "normal" => Ok("normal"),
+        "master" => Ok("master"),
+        "replica" | "slave" => Ok("replica"),
+        "pubsub" => Ok("pubsub"),
+        other => Err(format!("ERR Unknown client type '{}'", other)),
+    }
+}
+
+/// One CLIENT LIST line, in the same field order as Redis.
+pub async fn info_line(redis: &Redis, client: &Client) -> String {
+    let (name, lib_name, lib_ver, user, cmd, idle, no_evict) = {
+        let state = client.state.read().await;
+        (
+            state.name.clone().unwrap_or_default(),
+            state.lib_name.clone().unwrap_or_default(),
+            state.lib_ver.clone().unwrap_or_default(),
+            state.user.clone(),
+            state.last_cmd.clone(),
+            state.last_interaction.elapsed().as_secs(),
+            state.no_evict,
+        )
+    };
+    let (sub, psub, ssub) = redis.pubsub.counts(client.id).await;
+    let (multi, watch, dirty) = redis.tr.info(client.id).await;
+    let redir = redis.tracking.redirect(client.id).await;
+
+    let mut flags = String::new();
+    if client.is_master() {
+        flags.push('M');
+    }
+    if sub + psub + ssub > 0 {
+        flags.push('P');
+    }
+    if multi >= 0 {
+        flags.push('x');
+    }
+    if dirty {
+        flags.push('d');
+    }
+    if redir >= 0 {
+        flags.push('t');
+    }
+    if no_evict {
+        flags.push('e');
+    }
+    if flags.is_empty() {
+        flags.push('N');
+    }
+
+    format!(
+        "id={} addr={} laddr={} fd=-1 name={} age={} idle={} flags={} db={} sub={} psub={} ssub={} multi={} watch={} qbuf={} oll={} omem={} cmd={} user={} redir={} resp={} lib-name={} lib-ver={}",
+        client.id,
+        client.addr,
+        client.laddr,
+        String::from_utf8_lossy(&name),
+        client.created.elapsed().as_secs(),
+        idle,
+        flags,
+        client.db(),
+        sub,
+        psub,
+        ssub,
+        multi,
+        watch,
+        client.query_buf.load(Ordering::Relaxed),
+        client.queued_pushes.load(Ordering::Relaxed),
+        client.queued_bytes.load(Ordering::Relaxed),
+        cmd,
+        String::from_utf8_lossy(&user),
+        redir,
+        client.protocol(),
+        String::from_utf8_lossy(&lib_name),
+        String::from_utf8_lossy(&lib_ver),
+    )
+}
+
+/// CLIENT LIST [TYPE type] [ID id...]
+pub async fn list(redis: &Redis, kind: Option<Bytes>, ids: Vec<u64>) -> RedisValueRef {
+    let kind = match kind.as_ref().map(parse_type).transpose() {
+        Ok(kind) => kind,
+        Err(e) => return RedisValueRef::Error(Bytes::from(e)),
+    };
+    let mut out = String::new();
+    for client in redis.clients.all().await {
+        if !ids.is_empty() && !ids.contains(&client.id) {
+            continue;
+        }
+        if let Some(kind) = kind {
+            if client_type(redis, &client).await != kind {
+                continue;
+            }
+        }
+        out.push_str(&info_line(redis, &client).await);
+        out.push('\n');
+    }
+    RedisValueRef::BulkString(Bytes::from(out))
+}
+
+/// CLIENT KILL with filters, returning how many clients were killed.
+pub async fn kill(redis: &Redis, me: &Client, filter: KillFilter) -> RedisValueRef {
+    let kind = match filter.kind.as_ref().map(parse_type).transpose() {
+        Ok(kind) => kind,
+        Err(e) => return RedisValueRef::Error(Bytes::from(e)),
+    };
+    let mut killed = 0;
+    for client in redis.clients.all().await {
+        if filter.skipme && client.id == me.id {
+            continue;
+        }
+        if filter.id.is_some_and(|id| id != client.id) {
+            continue;
+        }
+        if filter
+            .addr
+            .as_ref()
+            .is_some_and(|addr| addr.as_ref() != client.addr.to_string().as_bytes())
+        {
+            continue;
+        }
+        if filter
+            .laddr
+            .as_ref()
+            .is_some_and(|laddr| laddr.as_ref() != client.laddr.to_string().as_bytes())
+        {
+            continue;
+        }
+        if let Some(user) = &filter.user {
+            if *user != client.user().await {
+                continue;
+            }
+        }
+        if let Some(kind) = kind {
+            if client_type(redis, &client).await != kind {
+                continue;
+            }
+        }
+        client.kill();
+        killed += 1;
+    }
+    RedisValueRef::Int(killed)
+}
+
+/// The old CLIENT KILL addr:port form.
+pub async fn kill_addr(redis: &Redis, addr: &Bytes) -> RedisValueRef {
+    for client in redis.clients.all().await {
+        if addr.as_ref() == client.addr.to_string().as_bytes() {
+            client.kill();
+            return RedisValueRef::String(Bytes::from("OK"));
+        }
+    }
+    RedisValueRef::Error(Bytes::from("ERR No such client"))
+}
@@ -1,7 +1,18 @@
+use crate::acl;
+use crate::client::{self, Client, KillFilter, ReplyMode};
+use crate::config;
+use crate::module::ModuleCall;
+use crate::notify::{NOTIFY_GENERIC, NOTIFY_KEY_MISS};
+use crate::persistence;
+use crate::pubsub::key_hash_slot;
 use crate::redis::Redis;
 use crate::resp::RedisValueRef;
+use crate::scripting::{self, RestorePolicy, RunKind};
+use crate::streams::XInfoSub;
+use crate::tracking::{TrackingOptions, CURRENT_CLIENT};
+use crate::transactions::ExecOutcome;
 use bytes::Bytes;
-use core::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::time::Duration;
 
@@ -36,7 +47,39 @@ pub enum Command {
         key: Bytes,
         timeout: Duration,
     },
+    SMEMBERS(Bytes),
+    SCARD(Bytes),
+    SISMEMBER {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZRANGE {
+        key: Bytes,
+        start: isize,
+        end: isize,
+        withscores: bool,
+    },
+    ZSCORE {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZCARD(Bytes),
+    HGET {
+        key: Bytes,
+        field: Bytes,
+    },
+    HGETALL(Bytes),
+    HLEN(Bytes),
     TYPE(Bytes),
+    SELECT(Bytes),
+    MOVE {
+        key: Bytes,
+        db: Bytes,
+    },
+    SWAPDB {
+        first: Bytes,
+        second: Bytes,
+    },
     XADD {
         key: Bytes,
         id: Bytes,
@@ -52,17 +95,150 @@ pub enum Command {
         timeout: Option<Duration>,
         key_stream_start: Vec<Bytes>,
     },
+    XINFO {
+        key: Bytes,
+        sub: XInfoSub,
+    },
+    XGROUPCREATE {
+        key: Bytes,
+        group: Bytes,
+        id: Bytes,
+        mkstream: bool,
+    },
+    XGROUPDESTROY {
+        key: Bytes,
+        group: Bytes,
+    },
     INCR(Bytes),
     MULTI,
     EXEC,
     DISCARD,
-    CONFIG {
-        dir: bool,
-        dbfilename: bool,
+    WATCH(Vec<Bytes>),
+    UNWATCH,
+    CONFIGGET(Bytes),
+    CONFIGSET {
+        param: Bytes,
+        value: Bytes,
     },
     KEYS(Bytes),
     INFO(Bytes),
     REPLCONF(Bytes),
+    SUBSCRIBE(Vec<Bytes>),
+    UNSUBSCRIBE(Vec<Bytes>),
+    PSUBSCRIBE(Vec<Bytes>),
+    PUNSUBSCRIBE(Vec<Bytes>),
+    PUBLISH {
+        channel: Bytes,
+        message: Bytes,
+    },
+    PUBSUBCHANNELS(Option<Bytes>),
+    PUBSUBNUMSUB(Vec<Bytes>),
+    PUBSUBNUMPAT,
+    SSUBSCRIBE(Vec<Bytes>),
+    SUNSUBSCRIBE(Vec<Bytes>),
+    SPUBLISH {
+        channel: Bytes,
+        message: Bytes,
+    },
+    PUBSUBSHARDCHANNELS(Option<Bytes>),
+    PUBSUBSHARDNUMSUB(Vec<Bytes>),
+    RESET,
+    HELLO {
+        protover: Option<Bytes>,
+        auth: Option<(Bytes, Bytes)>,
+        setname: Option<Bytes>,
+    },
+    AUTH {
+        username: Option<Bytes>,
+        password: Bytes,
+    },
+    ACLSETUSER {
+        name: Bytes,
+        rules: Vec<Bytes>,
+    },
+    ACLGETUSER(Bytes),
+    ACLDELUSER(Vec<Bytes>),
+    ACLLIST,
+    ACLUSERS,
+    ACLWHOAMI,
+    ACLCAT(Option<Bytes>),
+    ACLLOG(Option<usize>),
+    ACLLOGRESET,
+    ACLLOAD,
+    ACLSAVE,
+    EVAL {
+        script: Bytes,
+        numkeys: i64,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+    EVALSHA {
+        sha: Bytes,
+        numkeys: i64,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+    SCRIPTLOAD(Bytes),
+    SCRIPTEXISTS(Vec<Bytes>),
+    SCRIPTFLUSH,
+    SCRIPTKILL,
+    FUNCTIONLOAD {
+        code: Bytes,
+        replace: bool,
+    },
+    FUNCTIONDELETE(Bytes),
+    FUNCTIONFLUSH,
+    FUNCTIONKILL,
+    FUNCTIONLIST {
+        pattern: Option<Bytes>,
+        withcode: bool,
+    },
+    FUNCTIONDUMP,
+    FUNCTIONRESTORE {
+        payload: Bytes,
+        policy: RestorePolicy,
+    },
+    FCALL {
+        function: Bytes,
+        numkeys: i64,
+        args: Vec<Bytes>,
+        read_only: bool,
+    },
+    MODULELIST,
+    SAVE,
+    BGSAVE {
+        schedule: bool,
+    },
+    LASTSAVE,
+    // A command registered by a module
+    MODULECALL(ModuleCall),
+    CLIENTID,
+    CLIENTTRACKING {
+        on: bool,
+        opts: TrackingOptions,
+    },
+    CLIENTCACHING(bool),
+    CLIENTGETREDIR,
+    CLIENTLIST {
+        kind: Option<Bytes>,
+        ids: Vec<u64>,
+    },
+    CLIENTINFO,
+    CLIENTSETNAME(Bytes),
+    CLIENTGETNAME,
+    CLIENTKILL(KillFilter),
+    CLIENTKILLADDR(Bytes),
+    CLIENTPAUSE {
+        timeout: Duration,
+        all: bool,
+    },
+    CLIENTUNPAUSE,
+    CLIENTREPLY(ReplyMode),
+    CLIENTNOEVICT(bool),
+    CLIENTSETINFO {
+        attr: Bytes,
+        value: Bytes,
+    },
 }
 
 fn is_write_cmnd(cmd: &Command) -> bool {
@@ -72,12 +248,85 @@ fn is_write_cmnd(cmd: &Command) -> bool {
         | Command::Get(_)
         | Command::LLEN(_)
         | Command::LRANGE { .. }
+        | Command::SMEMBERS(_)
+        | Command::SCARD(_)
+        | Command::SISMEMBER { .. }
+        | Command::ZRANGE { .. }
+        | Command::ZSCORE { .. }
+        | Command::ZCARD(_)
+        | Command::HGET { .. }
+        | Command::HGETALL(_)
+        | Command::HLEN(_)
         | Command::TYPE(_)
+        | Command::SELECT(_)
         | Command::XRANGE { .. }
         | Command::XREAD { .. }
+        | Command::XINFO { .. }
         | Command::KEYS(_)
         | Command::INFO(_)
-        | Command::REPLCONF(_) => false,
+        | Command::CONFIGGET(_)
+        | Command::CONFIGSET { .. }
+        | Command::REPLCONF(_)
+        | Command::SUBSCRIBE(_)
+        | Command::UNSUBSCRIBE(_)
+        | Command::PSUBSCRIBE(_)
+        | Command::PUNSUBSCRIBE(_)
+        | Command::PUBSUBCHANNELS(_)
+        | Command::PUBSUBNUMSUB(_)
+        | Command::PUBSUBNUMPAT
+        | Command::SSUBSCRIBE(_)
+        | Command::SUNSUBSCRIBE(_)
+        | Command::PUBSUBSHARDCHANNELS(_)
+        | Command::PUBSUBSHARDNUMSUB(_)
+        | Command::RESET
+        | Command::HELLO { .. }
+        | Command::AUTH { .. }
+        | Command::ACLSETUSER { .. }
+        | Command::ACLGETUSER(_)
+        | Command::ACLDELUSER(_)
+        | Command::ACLLIST
+        | Command::ACLUSERS
+        | Command::ACLWHOAMI
+        | Command::ACLCAT(_)
+        | Command::ACLLOG(_)
+        | Command::ACLLOGRESET
+        | Command::ACLLOAD
+        | Command::ACLSAVE
+        // Scripts replicate the writes they make, not themselves
+        | Command::EVAL { .. }
+        | Command::EVALSHA { .. }
+        | Command::SCRIPTLOAD(_)
+        | Command::SCRIPTEXISTS(_)
+        | Command::SCRIPTFLUSH
+        | Command::SCRIPTKILL
+        | Command::FUNCTIONKILL
+        | Command::FUNCTIONLIST { .. }
+        | Command::FUNCTIONDUMP
+        | Command::FCALL { .. }
+        | Command::MODULELIST
+        | Command::SAVE
+        | Command::BGSAVE { .. }
+        | Command::LASTSAVE
+        | Command::CLIENTID
+        | Command::CLIENTTRACKING { .. }
+        | Command::CLIENTCACHING(_)
+        | Command::CLIENTGETREDIR
+        | Command::CLIENTLIST { .. }
+        | Command::CLIENTINFO
+        | Command::CLIENTSETNAME(_)
+        | Command::CLIENTGETNAME
+        | Command::CLIENTKILL(_)
+        | Command::CLIENTKILLADDR(_)
+        | Command::CLIENTPAUSE { .. }
+        | Command::CLIENTUNPAUSE
+        | Command::CLIENTREPLY(_)
+        | Command::CLIENTNOEVICT(_)
+        | Command::CLIENTSETINFO { .. }
+        | Command::WATCH(_)
+        | Command::UNWATCH => false,
+
+        // Replicated, but not writes: see may_replicate
+        Command::PUBLISH { .. } | Command::SPUBLISH { .. } => false,
 
         // Write commands
         Command::Set { .. }
@@ -86,16 +335,24 @@ fn is_write_cmnd(cmd: &Command) -> bool {
         | Command::LPOP { .. }
         | Command::BLPOP { .. }
         | Command::XADD { .. }
+        | Command::XGROUPCREATE { .. }
+        | Command::XGROUPDESTROY { .. }
         | Command::INCR(_)
+        | Command::MOVE { .. }
+        | Command::SWAPDB { .. }
         | Command::MULTI
         | Command::EXEC
         | Command::DISCARD
-        | Command::CONFIG { .. } => true,
+        | Command::FUNCTIONLOAD { .. }
+        | Command::FUNCTIONDELETE(_)
+        | Command::FUNCTIONFLUSH
+        | Command::FUNCTIONRESTORE { .. } => true,
+        Command::MODULECALL(call) => call.is_write(),
     }
 }
 
 fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
-    let cmd_name = match arr.get(0)? {
+    let cmd_name = match arr.first()? {
         RedisValueRef::String(cmd) => std::str::from_utf8(cmd).ok()?.to_uppercase(),
         _ => return None,
     };
@@ -125,10 +382,7 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
                     match (&arr[3], &arr[4]) {
                         (RedisValueRef::String(s), RedisValueRef::String(i)) => {
                             let s = s.clone();
-                            Some((
-                                Bytes::from(s),
-                                std::str::from_utf8(i).unwrap().parse::<i64>().unwrap(),
-                            ))
+                            Some((s, std::str::from_utf8(i).ok()?.parse::<i64>().ok()?))
                         }
                         _ => None,
                     }
@@ -152,8 +406,8 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
         "RPUSH" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
                 let mut values = Vec::new();
-                for j in 2..arr.len() {
-                    match &arr[j] {
+                for item in &arr[2..] {
+                    match item {
                         RedisValueRef::String(s) => values.push(s.clone()),
                         _ => return None,
                     }
@@ -176,11 +430,8 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
                         RedisValueRef::String(end),
                     ) => Some(Command::LRANGE {
                         key: key.clone(),
-                        start: std::str::from_utf8(start)
-                            .unwrap()
-                            .parse::<isize>()
-                            .unwrap(),
-                        end: std::str::from_utf8(end).unwrap().parse::<isize>().unwrap(),
+                        start: std::str::from_utf8(start).ok()?.parse::<isize>().ok()?,
+                        end: std::str::from_utf8(end).ok()?.parse::<isize>().ok()?,
                     }),
                     _ => None,
                 }
@@ -192,8 +443,8 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
         "LPUSH" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
                 let mut values = Vec::new();
-                for j in 2..arr.len() {
-                    match &arr[j] {
+                for item in &arr[2..] {
+                    match item {
                         RedisValueRef::String(s) => values.push(s.clone()),
                         _ => return None,
                     }
@@ -221,10 +472,7 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
                     match &arr[2] {
                         RedisValueRef::String(count) => Some(Command::LPOP {
                             key: k.clone(),
-                            count: std::str::from_utf8(count)
-                                .unwrap()
-                                .parse::<usize>()
-                                .unwrap(),
+                            count: std::str::from_utf8(count).ok()?.parse::<usize>().ok()?,
                         }),
                         _ => None,
                     }
@@ -242,19 +490,18 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
         "BLPOP" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
                 let duration_f64 = match arr.get(2) {
-                    Some(val) => match val {
-                        RedisValueRef::String(s) => {
-                            std::str::from_utf8(s).unwrap().parse::<f64>().unwrap()
-                        }
-                        _ => return None,
-                    },
-                    None => return None,
+                    Some(RedisValueRef::String(s)) => {
+                        std::str::from_utf8(s).ok()?.parse::<f64>().ok()?
+                    }
+                    _ => return None,
                 };
-                let duration = Duration::from_secs_f64(if duration_f64 == 0.0 {
+                // Negative, NaN or too long to be a Duration
+                let duration = Duration::try_from_secs_f64(if duration_f64 == 0.0 {
                     86400.0
                 } else {
                     duration_f64
-                });
+                })
+                .ok()?;
                 return Some(Command::BLPOP {
                     key: k.clone(),
                     timeout: duration,
@@ -263,6 +510,81 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
             None
         }
 
+        "SMEMBERS" | "SCARD" | "ZCARD" | "HGETALL" | "HLEN" => match &arr[1..] {
+            [RedisValueRef::String(key)] => Some(match cmd_name.as_str() {
+                "SMEMBERS" => Command::SMEMBERS(key.clone()),
+                "SCARD" => Command::SCARD(key.clone()),
+                "ZCARD" => Command::ZCARD(key.clone()),
+                "HGETALL" => Command::HGETALL(key.clone()),
+                _ => Command::HLEN(key.clone()),
+            }),
+            _ => None,
+        },
+
+        "SISMEMBER" | "ZSCORE" | "HGET" => match &arr[1..] {
+            [RedisValueRef::String(key), RedisValueRef::String(arg)] => {
+                let key = key.clone();
+                Some(match cmd_name.as_str() {
+                    "SISMEMBER" => Command::SISMEMBER {
+                        key,
+                        member: arg.clone(),
+                    },
+                    "ZSCORE" => Command::ZSCORE {
+                        key,
+                        member: arg.clone(),
+                    },
+                    _ => Command::HGET {
+                        key,
+                        field: arg.clone(),
+                    },
+                })
+            }
+            _ => None,
+        },
+
+        "SELECT" => match &arr[1..] {
+            [RedisValueRef::String(index)] => Some(Command::SELECT(index.clone())),
+            _ => None,
+        },
+
+        "MOVE" => match &arr[1..] {
+            [RedisValueRef::String(key), RedisValueRef::String(db)] => Some(Command::MOVE {
+                key: key.clone(),
+                db: db.clone(),
+            }),
+            _ => None,
+        },
+
+        "SWAPDB" => match &arr[1..] {
+            [RedisValueRef::String(first), RedisValueRef::String(second)] => {
+                Some(Command::SWAPDB {
+                    first: first.clone(),
+                    second: second.clone(),
+                })
+            }
+            _ => None,
+        },
+
+        "ZRANGE" => {
+            let (key, start, end, rest) = match &arr[1..] {
+                [RedisValueRef::String(key), RedisValueRef::String(start), RedisValueRef::String(end), rest @ ..] => {
+                    (key, start, end, rest)
+                }
+                _ => return None,
+            };
+            let withscores = match rest {
+                [] => false,
+                [RedisValueRef::String(opt)] if opt.eq_ignore_ascii_case(b"WITHSCORES") => true,
+                _ => return None,
+            };
+            Some(Command::ZRANGE {
+                key: key.clone(),
+                start: std::str::from_utf8(start).ok()?.parse().ok()?,
+                end: std::str::from_utf8(end).ok()?.parse().ok()?,
+                withscores,
+            })
+        }
+
         "TYPE" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
                 Some(Command::TYPE(k.clone()))
@@ -277,12 +599,16 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
                     match &arr[2] {
                         RedisValueRef::String(id) => {
                             let mut kv: Vec<Bytes> = Vec::new();
-                            for i in 3..arr.len() {
-                                match &arr[i] {
+                            for item in &arr[3..] {
+                                match item {
                                     RedisValueRef::String(b) => kv.push(b.clone()),
                                     _ => return None,
                                 }
                             }
+                            // At least one field, each with its value
+                            if kv.is_empty() || !kv.len().is_multiple_of(2) {
+                                return None;
+                            }
                             Some(Command::XADD {
                                 key: k.clone(),
                                 id: id.clone(),
@@ -330,13 +656,10 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
 
                 let timeout = if start == 4 {
                     let duration_u64 = match arr.get(2) {
-                        Some(val) => match val {
-                            RedisValueRef::String(s) => {
-                                std::str::from_utf8(s).unwrap().parse::<u64>().unwrap()
-                            }
-                            _ => return None,
-                        },
-                        None => return None,
+                        Some(RedisValueRef::String(s)) => {
+                            std::str::from_utf8(s).ok()?.parse::<u64>().ok()?
+                        }
+                        _ => return None,
                     };
                     Some(Duration::from_millis(if duration_u64 == 0 {
                         86400
@@ -347,7 +670,7 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
                     None
                 };
 
-                let n = (arr.len() - start) / 2;
+                let n = arr.len().checked_sub(start)? / 2;
                 for i in start..(start + n) {
                     match (&arr[i], &arr[n + i]) {
                         (
@@ -371,6 +694,59 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
             }
         }
 
+        "XINFO" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            let key = match arr.get(2)? {
+                RedisValueRef::String(k) => k.clone(),
+                _ => return None,
+            };
+            let sub = match sub.as_str() {
+                "STREAM" => XInfoSub::Stream(string_args(&arr[3..])?),
+                "GROUPS" => XInfoSub::Groups,
+                "CONSUMERS" => match arr.get(3)? {
+                    RedisValueRef::String(group) => XInfoSub::Consumers(group.clone()),
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            Some(Command::XINFO { key, sub })
+        }
+
+        "XGROUP" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            match (sub.as_str(), arr.get(2)?, arr.get(3)?) {
+                ("CREATE", RedisValueRef::String(key), RedisValueRef::String(group)) => {
+                    let id = match arr.get(4)? {
+                        RedisValueRef::String(id) => id.clone(),
+                        _ => return None,
+                    };
+                    let mkstream = matches!(
+                        arr.get(5),
+                        Some(RedisValueRef::String(opt)) if opt.eq_ignore_ascii_case(b"MKSTREAM")
+                    );
+                    Some(Command::XGROUPCREATE {
+                        key: key.clone(),
+                        group: group.clone(),
+                        id,
+                        mkstream,
+                    })
+                }
+                ("DESTROY", RedisValueRef::String(key), RedisValueRef::String(group)) => {
+                    Some(Command::XGROUPDESTROY {
+                        key: key.clone(),
+                        group: group.clone(),
+                    })
+                }
+                _ => None,
+            }
+        }
+
         "INCR" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
                 Some(Command::INCR(k.clone()))
@@ -379,19 +755,24 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
             }
         }
 
-        "CONFIG" => {
-            if let Some(RedisValueRef::String(k)) = arr.get(2) {
-                let (mut dir, mut dbfilename) = (false, false);
-                match k.as_ref() {
-                    b"dir" => dir = true,
-                    b"dbfilename" => dbfilename = true,
-                    _ => (),
+        "CONFIG" => match (arr.get(1)?, arr.get(2)?) {
+            (RedisValueRef::String(sub), RedisValueRef::String(param)) => {
+                if sub.eq_ignore_ascii_case(b"GET") {
+                    Some(Command::CONFIGGET(param.clone()))
+                } else if sub.eq_ignore_ascii_case(b"SET") {
+                    match arr.get(3)? {
+                        RedisValueRef::String(value) => Some(Command::CONFIGSET {
+                            param: param.clone(),
+                            value: value.clone(),
+                        }),
+                        _ => None,
+                    }
+                } else {
+                    None
                 }
-                Some(Command::CONFIG { dir, dbfilename })
-            } else {
-                None
             }
-        }
+            _ => None,
+        },
 
         "KEYS" => {
             if let Some(RedisValueRef::String(k)) = arr.get(1) {
@@ -401,13 +782,11 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
             }
         }
 
-        "INFO" => {
-            if let Some(RedisValueRef::String(repl)) = arr.get(1) {
-                Some(Command::INFO(repl.clone()))
-            } else {
-                None
-            }
-        }
+        "INFO" => match arr.get(1) {
+            Some(RedisValueRef::String(section)) => Some(Command::INFO(section.clone())),
+            None => Some(Command::INFO(Bytes::from("default"))),
+            _ => None,
+        },
 
         "REPLCONF" => {
             if let Some(RedisValueRef::String(port)) = arr.get(2) {
@@ -417,52 +796,581 @@ fn parse_command(arr: &[RedisValueRef]) -> Option<Command> {
             }
         }
 
+        "SUBSCRIBE" if arr.len() >= 2 => Some(Command::SUBSCRIBE(string_args(&arr[1..])?)),
+        "UNSUBSCRIBE" => Some(Command::UNSUBSCRIBE(string_args(&arr[1..])?)),
+        "PSUBSCRIBE" if arr.len() >= 2 => Some(Command::PSUBSCRIBE(string_args(&arr[1..])?)),
+        "PUNSUBSCRIBE" => Some(Command::PUNSUBSCRIBE(string_args(&arr[1..])?)),
+
+        "SSUBSCRIBE" if arr.len() >= 2 => Some(Command::SSUBSCRIBE(string_args(&arr[1..])?)),
+        "SUNSUBSCRIBE" => Some(Command::SUNSUBSCRIBE(string_args(&arr[1..])?)),
+
+        "SPUBLISH" => match (arr.get(1)?, arr.get(2)?) {
+            (RedisValueRef::String(channel), RedisValueRef::String(message)) => {
+                Some(Command::SPUBLISH {
+                    channel: channel.clone(),
+                    message: message.clone(),
+                })
+            }
+            _ => None,
+        },
+
+        "PUBLISH" => match (arr.get(1)?, arr.get(2)?) {
+            (RedisValueRef::String(channel), RedisValueRef::String(message)) => {
+                Some(Command::PUBLISH {
+                    channel: channel.clone(),
+                    message: message.clone(),
+                })
+            }
+            _ => None,
+        },
+
+        "PUBSUB" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            match sub.as_str() {
+                "CHANNELS" => match arr.get(2) {
+                    Some(RedisValueRef::String(pattern)) => {
+                        Some(Command::PUBSUBCHANNELS(Some(pattern.clone())))
+                    }
+                    None => Some(Command::PUBSUBCHANNELS(None)),
+                    _ => None,
+                },
+                "NUMSUB" => Some(Command::PUBSUBNUMSUB(string_args(&arr[2..])?)),
+                "NUMPAT" => Some(Command::PUBSUBNUMPAT),
+                "SHARDCHANNELS" => match arr.get(2) {
+                    Some(RedisValueRef::String(pattern)) => {
+                        Some(Command::PUBSUBSHARDCHANNELS(Some(pattern.clone())))
+                    }
+                    None => Some(Command::PUBSUBSHARDCHANNELS(None)),
+                    _ => None,
+                },
+                "SHARDNUMSUB" => Some(Command::PUBSUBSHARDNUMSUB(string_args(&arr[2..])?)),
+                _ => None,
+            }
+        }
+
+        "HELLO" => {
+            let args = string_args(&arr[1..])?;
+            let mut auth = None;
+            let mut setname = None;
+            // Options are only accepted after a protocol version
+            let mut i = 1;
+            while i < args.len() {
+                let opt = std::str::from_utf8(&args[i]).ok()?.to_uppercase();
+                match opt.as_str() {
+                    "AUTH" => {
+                        auth = Some((args.get(i + 1)?.clone(), args.get(i + 2)?.clone()));
+                        i += 3;
+                    }
+                    "SETNAME" => {
+                        setname = Some(args.get(i + 1)?.clone());
+                        i += 2;
+                    }
+                    _ => return None,
+                }
+            }
+            Some(Command::HELLO {
+                protover: args.first().cloned(),
+                auth,
+                setname,
+            })
+        }
+
+        "AUTH" => match string_args(&arr[1..])?.as_slice() {
+            [password] => Some(Command::AUTH {
+                username: None,
+                password: password.clone(),
+            }),
+            [username, password] => Some(Command::AUTH {
+                username: Some(username.clone()),
+                password: password.clone(),
+            }),
+            _ => None,
+        },
+
+        "ACL" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            let args = string_args(&arr[2..])?;
+            match sub.as_str() {
+                "SETUSER" if !args.is_empty() => Some(Command::ACLSETUSER {
+                    name: args[0].clone(),
+                    rules: args[1..].to_vec(),
+                }),
+                "GETUSER" if args.len() == 1 => Some(Command::ACLGETUSER(args[0].clone())),
+                "DELUSER" if !args.is_empty() => Some(Command::ACLDELUSER(args)),
+                "LIST" if args.is_empty() => Some(Command::ACLLIST),
+                "USERS" if args.is_empty() => Some(Command::ACLUSERS),
+                "WHOAMI" if args.is_empty() => Some(Command::ACLWHOAMI),
+                "CAT" if args.len() <= 1 => Some(Command::ACLCAT(args.first().cloned())),
+                "LOG" => match args.as_slice() {
+                    [] => Some(Command::ACLLOG(None)),
+                    [arg] if arg.eq_ignore_ascii_case(b"RESET") => Some(Command::ACLLOGRESET),
+                    [count] => Some(Command::ACLLOG(Some(
+                        std::str::from_utf8(count).ok()?.parse::<usize>().ok()?,
+                    ))),
+                    _ => None,
+                },
+                "LOAD" if args.is_empty() => Some(Command::ACLLOAD),
+                "SAVE" if args.is_empty() => Some(Command::ACLSAVE),
+                _ => None,
+            }
+        }
+
+        "CLIENT" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            match sub.as_str() {
+                "ID" => Some(Command::CLIENTID),
+                "GETREDIR" => Some(Command::CLIENTGETREDIR),
+                "INFO" if arr.len() == 2 => Some(Command::CLIENTINFO),
+                "GETNAME" if arr.len() == 2 => Some(Command::CLIENTGETNAME),
+                "UNPAUSE" if arr.len() == 2 => Some(Command::CLIENTUNPAUSE),
+                "SETNAME" if arr.len() == 3 => match &arr[2] {
+                    RedisValueRef::String(name) => Some(Command::CLIENTSETNAME(name.clone())),
+                    _ => None,
+                },
+                "SETINFO" if arr.len() == 4 => match (&arr[2], &arr[3]) {
+                    (RedisValueRef::String(attr), RedisValueRef::String(value)) => {
+                        Some(Command::CLIENTSETINFO {
+                            attr: attr.clone(),
+                            value: value.clone(),
+                        })
+                    }
+                    _ => None,
+                },
+                "LIST" => {
+                    let args = string_args(&arr[2..])?;
+                    let mut kind = None;
+                    let mut ids = Vec::new();
+                    let mut i = 0;
+                    while i < args.len() {
+                        let opt = std::str::from_utf8(&args[i]).ok()?.to_uppercase();
+                        match opt.as_str() {
+                            "TYPE" => {
+                                kind = Some(args.get(i + 1)?.clone());
+                                i += 2;
+                            }
+                            "ID" => {
+                                i += 1;
+                                while let Some(id) = args.get(i) {
+                                    ids.push(std::str::from_utf8(id).ok()?.parse::<u64>().ok()?);
+                                    i += 1;
+                                }
+                            }
+                            _ => return None,
+                        }
+                    }
+                    Some(Command::CLIENTLIST { kind, ids })
+                }
+                "KILL" => {
+                    let args = string_args(&arr[2..])?;
+                    if args.len() == 1 {
+                        return Some(Command::CLIENTKILLADDR(args[0].clone()));
+                    }
+                    if args.is_empty() || args.len() % 2 != 0 {
+                        return None;
+                    }
+                    let mut filter = KillFilter {
+                        skipme: true,
+                        ..Default::default()
+                    };
+                    for pair in args.chunks(2) {
+                        let opt = std::str::from_utf8(&pair[0]).ok()?.to_uppercase();
+                        let value = pair[1].clone();
+                        match opt.as_str() {
+                            "ID" => {
+                                filter.id =
+                                    Some(std::str::from_utf8(&value).ok()?.parse::<u64>().ok()?)
+                            }
+                            "ADDR" => filter.addr = Some(value),
+                            "LADDR" => filter.laddr = Some(value),
+                            "USER" => filter.user = Some(value),
+                            "TYPE" => filter.kind = Some(value),
+                            "SKIPME" if value.eq_ignore_ascii_case(b"YES") => filter.skipme = true,
+                            "SKIPME" if value.eq_ignore_ascii_case(b"NO") => filter.skipme = false,
+                            _ => return None,
+                        }
+                    }
+                    Some(Command::CLIENTKILL(filter))
+                }
+                "PAUSE" => {
+                    let timeout = match arr.get(2)? {
+                        RedisValueRef::String(ms) => {
+                            std::str::from_utf8(ms).ok()?.parse::<u64>().ok()?
+                        }
+                        _ => return None,
+                    };
+                    let all = match arr.get(3) {
+                        None => true,
+                        Some(RedisValueRef::String(m)) if m.eq_ignore_ascii_case(b"ALL") => true,
+                        Some(RedisValueRef::String(m)) if m.eq_ignore_ascii_case(b"WRITE") => false,
+                        _ => return None,
+                    };
+                    Some(Command::CLIENTPAUSE {
+                        timeout: Duration::from_millis(timeout),
+                        all,
+                    })
+                }
+                "REPLY" if arr.len() == 3 => {
+                    let mode = match &arr[2] {
+                        RedisValueRef::String(m) => std::str::from_utf8(m).ok()?.to_uppercase(),
+                        _ => return None,
+                    };
+                    match mode.as_str() {
+                        "ON" => Some(Command::CLIENTREPLY(ReplyMode::On)),
+                        "OFF" => Some(Command::CLIENTREPLY(ReplyMode::Off)),
+                        "SKIP" => Some(Command::CLIENTREPLY(ReplyMode::Skip)),
+                        _ => None,
+                    }
+                }
+                "NO-EVICT" if arr.len() == 3 => match &arr[2] {
+                    RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"ON") => {
+                        Some(Command::CLIENTNOEVICT(true))
+                    }
+                    RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"OFF") => {
+                        Some(Command::CLIENTNOEVICT(false))
+                    }
+                    _ => None,
+                },
+                "CACHING" => match arr.get(2)? {
+                    RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"YES") => {
+                        Some(Command::CLIENTCACHING(true))
+                    }
+                    RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"NO") => {
+                        Some(Command::CLIENTCACHING(false))
+                    }
+                    _ => None,
+                },
+                "TRACKING" => {
+                    let on = match arr.get(2)? {
+                        RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"ON") => true,
+                        RedisValueRef::String(v) if v.eq_ignore_ascii_case(b"OFF") => false,
+                        _ => return None,
+                    };
+                    let args = string_args(&arr[3..])?;
+                    let mut opts = TrackingOptions::default();
+                    let mut i = 0;
+                    while i < args.len() {
+                        let opt = std::str::from_utf8(&args[i]).ok()?.to_uppercase();
+                        match opt.as_str() {
+                            "REDIRECT" => {
+                                let id = args.get(i + 1)?;
+                                opts.redirect =
+                                    Some(std::str::from_utf8(id).ok()?.parse::<u64>().ok()?);
+                                i += 1;
+                            }
+                            "PREFIX" => {
+                                opts.prefixes.push(args.get(i + 1)?.clone());
+                                i += 1;
+                            }
+                            "BCAST" => opts.bcast = true,
+                            "OPTIN" => opts.optin = true,
+                            "OPTOUT" => opts.optout = true,
+                            "NOLOOP" => opts.noloop = true,
+                            _ => return None,
+                        }
+                        i += 1;
+                    }
+                    Some(Command::CLIENTTRACKING { on, opts })
+                }
+                _ => None,
+            }
+        }
+
+        "EVAL" | "EVAL_RO" | "EVALSHA" | "EVALSHA_RO" => {
+            let args = string_args(&arr[1..])?;
+            if args.len() < 2 {
+                return None;
+            }
+            let numkeys = std::str::from_utf8(&args[1]).ok()?.parse::<i64>().ok()?;
+            let read_only = cmd_name.ends_with("_RO");
+            if cmd_name.starts_with("EVALSHA") {
+                Some(Command::EVALSHA {
+                    sha: args[0].clone(),
+                    numkeys,
+                    args: args[2..].to_vec(),
+                    read_only,
+                })
+            } else {
+                Some(Command::EVAL {
+                    script: args[0].clone(),
+                    numkeys,
+                    args: args[2..].to_vec(),
+                    read_only,
+                })
+            }
+        }
+
+        "SCRIPT" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            let args = string_args(&arr[2..])?;
+            match sub.as_str() {
+                "LOAD" if args.len() == 1 => Some(Command::SCRIPTLOAD(args[0].clone())),
+                "EXISTS" if !args.is_empty() => Some(Command::SCRIPTEXISTS(args)),
+                "FLUSH" => match args.as_slice() {
+                    [] => Some(Command::SCRIPTFLUSH),
+                    [mode]
+                        if mode.eq_ignore_ascii_case(b"ASYNC")
+                            || mode.eq_ignore_ascii_case(b"SYNC") =>
+                    {
+                        Some(Command::SCRIPTFLUSH)
+                    }
+                    _ => None,
+                },
+                "KILL" if args.is_empty() => Some(Command::SCRIPTKILL),
+                _ => None,
+            }
+        }
+
+        "FUNCTION" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            let args = string_args(&arr[2..])?;
+            match sub.as_str() {
+                "LOAD" => match args.as_slice() {
+                    [code] => Some(Command::FUNCTIONLOAD {
+                        code: code.clone(),
+                        replace: false,
+                    }),
+                    [flag, code] if flag.eq_ignore_ascii_case(b"REPLACE") => {
+                        Some(Command::FUNCTIONLOAD {
+                            code: code.clone(),
+                            replace: true,
+                        })
+                    }
+                    _ => None,
+                },
+                "DELETE" if args.len() == 1 => Some(Command::FUNCTIONDELETE(args[0].clone())),
+                "FLUSH" => match args.as_slice() {
+                    [] => Some(Command::FUNCTIONFLUSH),
+                    [mode]
+                        if mode.eq_ignore_ascii_case(b"ASYNC")
+                            || mode.eq_ignore_ascii_case(b"SYNC") =>
+                    {
+                        Some(Command::FUNCTIONFLUSH)
+                    }
+                    _ => None,
+                },
+                "KILL" if args.is_empty() => Some(Command::FUNCTIONKILL),
+                "LIST" => {
+                    let mut pattern = None;
+                    let mut withcode = false;
+                    let mut i = 0;
+                    while i < args.len() {
+                        if args[i].eq_ignore_ascii_case(b"WITHCODE") {
+                            withcode = true;
+                        } else if args[i].eq_ignore_ascii_case(b"LIBRARYNAME") {
+                            i += 1;
+                            pattern = Some(args.get(i)?.clone());
+                        } else {
+                            return None;
+                        }
+                        i += 1;
+                    }
+                    Some(Command::FUNCTIONLIST { pattern, withcode })
+                }
+                "DUMP" if args.is_empty() => Some(Command::FUNCTIONDUMP),
+                "RESTORE" => {
+                    let policy = match args.get(1) {
+                        None => RestorePolicy::Append,
+                        Some(p) if p.eq_ignore_ascii_case(b"APPEND") => RestorePolicy::Append,
+                        Some(p) if p.eq_ignore_ascii_case(b"REPLACE") => RestorePolicy::Replace,
+                        Some(p) if p.eq_ignore_ascii_case(b"FLUSH") => RestorePolicy::Flush,
+                        Some(_) => return None,
+                    };
+                    if args.is_empty() || args.len() > 2 {
+                        return None;
+                    }
+                    Some(Command::FUNCTIONRESTORE {
+                        payload: args[0].clone(),
+                        policy,
+                    })
+                }
+                _ => None,
+            }
+        }
+
+        "MODULE" => {
+            let sub = match arr.get(1)? {
+                RedisValueRef::String(s) => std::str::from_utf8(s).ok()?.to_uppercase(),
+                _ => return None,
+            };
+            match sub.as_str() {
+                "LIST" if arr.len() == 2 => Some(Command::MODULELIST),
+                _ => None,
+            }
+        }
+
+        "SAVE" if arr.len() == 1 => Some(Command::SAVE),
+        "BGSAVE" => match arr.get(1) {
+            None => Some(Command::BGSAVE { schedule: false }),
+            Some(RedisValueRef::String(s))
+                if arr.len() == 2 && s.eq_ignore_ascii_case(b"SCHEDULE") =>
+            {
+                Some(Command::BGSAVE { schedule: true })
+            }
+            _ => None,
+        },
+        "LASTSAVE" if arr.len() == 1 => Some(Command::LASTSAVE),
+
+        "FCALL" | "FCALL_RO" => {
+            let args = string_args(&arr[1..])?;
+            if args.len() < 2 {
+                return None;
+            }
+            let numkeys = std::str::from_utf8(&args[1]).ok()?.parse::<i64>().ok()?;
+            Some(Command::FCALL {
+                function: args[0].clone(),
+                numkeys,
+                args: args[2..].to_vec(),
+                read_only: cmd_name == "FCALL_RO",
+            })
+        }
+
+        "RESET" => Some(Command::RESET),
         "MULTI" => Some(Command::MULTI),
         "EXEC" => Some(Command::EXEC),
         "DISCARD" => Some(Command::DISCARD),
+        "WATCH" if arr.len() >= 2 => Some(Command::WATCH(string_args(&arr[1..])?)),
+        "UNWATCH" => Some(Command::UNWATCH),
         _ => None,
     }
 }
 
-async fn execute_command(cmd: Command, redis: &Arc<Redis>) -> Option<RedisValueRef> {
+// Runs a parsed command. `args` is what it was parsed from, queued for
+// replicas and the AOF if the command writes.
+async fn execute_command(
+    cmd: Command,
+    args: &[RedisValueRef],
+    client: &Client,
+    redis: &Arc<Redis>,
+) -> Option<RedisValueRef> {
+    // Recorded before the read so a concurrent write can't slip in between
+    // and leave the client with a stale copy
+    redis
+        .tracking
+        .record_reads(client.id, read_keys(&cmd))
+        .await;
+    let replicated = is_write_cmnd(&cmd) || may_replicate(&cmd);
+    let db = client.db();
+    let response = run_command(cmd, client, redis).await;
+    if replicated {
+        if let Some(args) = rewrite_for_propagation(args, response.as_ref()) {
+            client.also_propagate(db, args).await;
+        }
+    }
+    response
+}
+
+// What replicas and the AOF get for a write, so that replaying it gives the
+// same result: relative TTLs become absolute, BLPOP becomes the LPOP it
+// ended up doing and XADD names the ID it generated. None for writes that
+// failed or changed nothing.
+fn rewrite_for_propagation(
+    args: &[RedisValueRef],
+    response: Option<&RedisValueRef>,
+) -> Option<Vec<RedisValueRef>> {
+    if matches!(response, Some(RedisValueRef::Error(_))) {
+        return None;
+    }
+    let name = match args.first() {
+        Some(RedisValueRef::String(name)) => String::from_utf8_lossy(name).to_uppercase(),
+        _ => return Some(args.to_vec()),
+    };
+    let nothing_done = matches!(
+        response,
+        Some(RedisValueRef::NullArray | RedisValueRef::NullBulkString)
+    );
+    match (name.as_str(), args) {
+        ("SET", [set, key, value, RedisValueRef::String(unit), RedisValueRef::String(time)]) => {
+            let time = std::str::from_utf8(time).ok()?.parse::<u64>().ok()?;
+            let ms = match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => now_ms() + time * 1000,
+                b"PX" => now_ms() + time,
+                _ => return Some(args.to_vec()),
+            };
+            Some(vec![
+                set.clone(),
+                key.clone(),
+                value.clone(),
+                RedisValueRef::String(Bytes::from("PXAT")),
+                RedisValueRef::String(Bytes::from(ms.to_string())),
+            ])
+        }
+        ("BLPOP", [_, key, ..]) if !nothing_done => Some(vec![
+            RedisValueRef::String(Bytes::from("LPOP")),
+            key.clone(),
+        ]),
+        ("BLPOP" | "LPOP", _) if nothing_done => None,
+        ("XADD", [xadd, key, _, rest @ ..]) => match response {
+            Some(RedisValueRef::BulkString(id)) => {
+                let mut args = vec![xadd.clone(), key.clone(), RedisValueRef::String(id.clone())];
+                args.extend_from_slice(rest);
+                Some(args)
+            }
+            _ => None,
+        },
+        _ => Some(args.to_vec()),
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+async fn run_command(cmd: Command, client: &Client, redis: &Arc<Redis>) -> Option<RedisValueRef> {
+    let db = redis.db(client.db()).await;
     match cmd {
         Command::Ping => Some(RedisValueRef::String(Bytes::from("PONG"))),
 
         Command::Echo(message) => Some(RedisValueRef::String(message)),
 
         Command::Set { key, value, expiry } => {
-            redis.kv.insert_entry(key, value, expiry).await;
+            db.kv.insert_entry(key, value, expiry).await;
             Some(RedisValueRef::String(Bytes::from("OK")))
         }
 
-        Command::Get(key) => match redis.kv.get_entry(&key).await {
+        Command::Get(key) => match db.kv.get_entry(&key).await {
             Some(s) => Some(RedisValueRef::BulkString(s)),
-            _ => Some(RedisValueRef::NullBulkString),
+            _ => {
+                redis
+                    .notifier
+                    .notify(db.id(), NOTIFY_KEY_MISS, "keymiss", &key)
+                    .await;
+                Some(RedisValueRef::NullBulkString)
+            }
         },
 
         Command::RPUSH { key, values } => {
-            let mut i = 0;
-            for value in values {
-                i = redis.lists.rpush(&key, value).await;
-            }
-            Some(RedisValueRef::Int(i))
+            Some(RedisValueRef::Int(db.lists.rpush(&key, values).await))
         }
 
         Command::LPUSH { key, values } => {
-            let mut i = 0;
-            for value in values {
-                i = redis.lists.lpush(&key, value).await;
-            }
-            Some(RedisValueRef::Int(i))
+            Some(RedisValueRef::Int(db.lists.lpush(&key, values).await))
         }
 
         Command::LRANGE { key, start, end } => Some(RedisValueRef::Array(
-            redis.lists.lrange(&key, start, end).await,
+            db.lists.lrange(&key, start, end).await,
         )),
 
-        Command::LLEN(key) => Some(RedisValueRef::Int(redis.lists.llen(&key).await)),
+        Command::LLEN(key) => Some(RedisValueRef::Int(db.lists.llen(&key).await)),
 
-        Command::LPOP { key, count } => match redis.lists.lpop(&key, count).await {
+        Command::LPOP { key, count } => match db.lists.lpop(&key, count).await {
             Some(v) => {
                 if v.len() == 1 {
                     Some(v[0].clone())
@@ -473,79 +1381,1171 @@ async fn execute_command(cmd: Command, redis: &Arc<Redis>) -> Option<RedisValueR
             None => Some(RedisValueRef::NullBulkString),
         },
 
-        Command::BLPOP { key, timeout } => Some(redis.lists.blpop(&key, timeout).await),
+        // One attempt: waiting for a push is left to the caller, and inside
+        // EXEC or a script blocking commands never block
+        Command::BLPOP { key, .. } => Some(match db.lists.lpop(&key, 1).await {
+            Some(mut v) => {
+                v.insert(0, RedisValueRef::BulkString(key));
+                RedisValueRef::Array(v)
+            }
+            None => RedisValueRef::NullArray,
+        }),
+
+        Command::SMEMBERS(key) => Some(RedisValueRef::Array(db.sets.smembers(&key).await)),
+
+        Command::SCARD(key) => Some(RedisValueRef::Int(db.sets.scard(&key).await)),
+
+        Command::SISMEMBER { key, member } => Some(RedisValueRef::Int(
+            db.sets.sismember(&key, &member).await as i64,
+        )),
+
+        Command::ZRANGE {
+            key,
+            start,
+            end,
+            withscores,
+        } => Some(RedisValueRef::Array(
+            db.zsets.zrange(&key, start, end, withscores).await,
+        )),
+
+        Command::ZSCORE { key, member } => Some(match db.zsets.zscore(&key, &member).await {
+            Some(score) => RedisValueRef::BulkString(score),
+            None => RedisValueRef::NullBulkString,
+        }),
+
+        Command::ZCARD(key) => Some(RedisValueRef::Int(db.zsets.zcard(&key).await)),
+
+        Command::HGET { key, field } => Some(match db.hashes.hget(&key, &field).await {
+            Some(value) => RedisValueRef::BulkString(value),
+            None => RedisValueRef::NullBulkString,
+        }),
+
+        Command::HGETALL(key) => Some(RedisValueRef::Array(db.hashes.hgetall(&key).await)),
+
+        Command::HLEN(key) => Some(RedisValueRef::Int(db.hashes.hlen(&key).await)),
 
         Command::TYPE(key) => {
-            if redis.kv.contains(&key).await {
+            if db.kv.contains(&key).await {
                 Some(RedisValueRef::String(Bytes::from("string")))
-            } else if redis.stream.contains(&key).await {
+            } else if db.lists.contains(&key).await {
+                Some(RedisValueRef::String(Bytes::from("list")))
+            } else if db.sets.contains(&key).await {
+                Some(RedisValueRef::String(Bytes::from("set")))
+            } else if db.zsets.contains(&key).await {
+                Some(RedisValueRef::String(Bytes::from("zset")))
+            } else if db.hashes.contains(&key).await {
+                Some(RedisValueRef::String(Bytes::from("hash")))
+            } else if db.stream.contains(&key).await {
                 Some(RedisValueRef::String(Bytes::from("stream")))
+            } else if let Some(name) = redis.modules.type_of(db.id(), &key).await {
+                Some(RedisValueRef::String(Bytes::from(name)))
             } else {
                 Some(RedisValueRef::String(Bytes::from("none")))
             }
         }
 
-        Command::XADD { key, id, kv } => Some(redis.stream.xadd(key, id, kv).await),
-
-        Command::XRANGE { key, start, end } => Some(RedisValueRef::Array(
-            redis.stream.xrange(&key, &start, &end).await,
-        )),
+        Command::SELECT(index) => Some(match parse_db_index(redis, &index).await {
+            Ok(index) => {
+                client.select(index);
+                RedisValueRef::String(Bytes::from("OK"))
+            }
+            Err(e) => RedisValueRef::Error(Bytes::from(e)),
+        }),
 
-        Command::XREAD {
-            to_block,
+        Command::MOVE { key, db: to } => {
+            let to = match parse_db_index(redis, &to).await {
+                Ok(to) => to,
+                Err(e) => return Some(RedisValueRef::Error(Bytes::from(e))),
+            };
+            if to == db.id() {
+                return Some(RedisValueRef::Error(Bytes::from(
+                    "ERR source and destination objects are the same",
+                )));
+            }
+            // Module keys are kept by their modules, not in the databases
+            let from = db.id();
+            let to_db = redis.db(to).await;
+            if to_db.contains(&key).await || redis.modules.type_of(to, &key).await.is_some() {
+                return Some(RedisValueRef::Int(0));
+            }
+            if redis.modules.move_key(from, to, &key).await {
+                redis
+                    .notifier
+                    .notify(from, NOTIFY_GENERIC, "move_from", &key)
+                    .await;
+                redis
+                    .notifier
+                    .notify(to, NOTIFY_GENERIC, "move_to", &key)
+                    .await;
+                return Some(RedisValueRef::Int(1));
+            }
+            Some(RedisValueRef::Int(db.move_key(&key, &to_db).await as i64))
+        }
+
+        Command::SWAPDB { first, second } => {
+            let databases = redis.databases().await;
+            let parse = |index: &Bytes, which: &str| {
+                std::str::from_utf8(index)
+                    .ok()
+                    .and_then(|s| s.parse::<i64>().ok())
+                    .ok_or_else(|| format!("ERR invalid {} DB index", which))
+                    .and_then(|index| {
+                        usize::try_from(index)
+                            .ok()
+                            .filter(|&index| index < databases)
+                            .ok_or_else(|| "ERR DB index is out of range".to_string())
+                    })
+            };
+            match parse(&first, "first").and_then(|a| Ok((a, parse(&second, "second")?))) {
+                Ok((a, b)) => {
+                    if a != b {
+                        redis.swap_dbs(a, b).await;
+                    }
+                    Some(RedisValueRef::String(Bytes::from("OK")))
+                }
+                Err(e) => Some(RedisValueRef::Error(Bytes::from(e))),
+            }
+        }
+
+        Command::XADD { key, id, kv } => Some(db.stream.xadd(key, id, kv).await),
+
+        Command::XRANGE { key, start, end } => Some(RedisValueRef::Array(
+            db.stream.xrange(&key, &start, &end).await,
+        )),
+
+        Command::XREAD {
+            to_block: _,
             timeout,
             key_stream_start,
         } => {
-            if let Some(duration) = timeout {
+            if timeout.is_some_and(|d| d.is_zero()) {
+                let res = db.stream.xread(&key_stream_start).await;
+                if res.is_empty() {
+                    Some(RedisValueRef::NullArray)
+                } else {
+                    Some(RedisValueRef::Array(res))
+                }
+            } else if let Some(duration) = timeout {
                 Some(
-                    redis
-                        .stream
-                        .blocking_xread(&key_stream_start, duration)
+                    db.stream
+                        .blocking_xread(&key_stream_start, duration, &redis.exec_lock)
                         .await,
                 )
             } else {
                 Some(RedisValueRef::Array(
-                    redis.stream.xread(&key_stream_start).await,
+                    db.stream.xread(&key_stream_start).await,
                 ))
             }
         }
 
-        Command::CONFIG { dir, dbfilename } => {
-            if dir {
-                let cmd = "dir";
-                Some(RedisValueRef::Array(vec![
-                    RedisValueRef::BulkString(Bytes::from(cmd)),
-                    RedisValueRef::BulkString(Bytes::from(redis.kv.get_dir().await)),
-                ]))
-            } else if dbfilename {
-                let cmd = "dbfilename";
-                Some(RedisValueRef::Array(vec![
-                    RedisValueRef::BulkString(Bytes::from(cmd)),
-                    RedisValueRef::BulkString(Bytes::from(redis.kv.get_filename().await)),
-                ]))
-            } else {
-                None
+        Command::XINFO { key, sub } => {
+            // Any other type holding the key is an error, not a missing stream
+            if !db.stream.contains(&key).await
+                && (db.contains(&key).await || redis.modules.type_of(db.id(), &key).await.is_some())
+            {
+                return Some(RedisValueRef::Error(Bytes::from(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value",
+                )));
             }
+            Some(db.stream.xinfo(&key, sub).await)
         }
 
-        Command::INCR(key) => Some(match redis.kv.incr(&key).await {
+        Command::XGROUPCREATE {
+            key,
+            group,
+            id,
+            mkstream,
+        } => Some(db.stream.xgroup_create(&key, group, &id, mkstream).await),
+
+        Command::XGROUPDESTROY { key, group } => Some(db.stream.xgroup_destroy(&key, &group).await),
+
+        Command::CONFIGGET(pattern) => Some(config::get(redis, &pattern).await),
+
+        Command::CONFIGSET { param, value } => Some(config::set(redis, &param, &value).await),
+
+        Command::INCR(key) => Some(match db.kv.incr(&key).await {
             Ok(num) => RedisValueRef::Int(num),
             Err(e) => RedisValueRef::Error(Bytes::from(e)),
         }),
 
-        Command::KEYS(pattern) => Some(redis.kv.keys(pattern).await),
+        Command::KEYS(pattern) => Some(db.kv.keys(pattern).await),
 
-        Command::INFO(_repl) => Some(redis.info.serialize().await),
+        Command::INFO(section) => Some(info(redis, &section).await),
 
         Command::REPLCONF(_) => Some(RedisValueRef::String(Bytes::from(String::from("OK")))),
+
+        Command::PUBLISH { channel, message } => Some(RedisValueRef::Int(
+            redis.pubsub.publish(&channel, &message).await,
+        )),
+
+        Command::PUBSUBCHANNELS(pattern) => Some(redis.pubsub.channels(pattern).await),
+
+        Command::PUBSUBNUMSUB(channels) => Some(redis.pubsub.numsub(channels).await),
+
+        Command::PUBSUBNUMPAT => Some(RedisValueRef::Int(redis.pubsub.numpat().await)),
+
+        Command::SPUBLISH { channel, message } => Some(RedisValueRef::Int(
+            redis.pubsub.spublish(&channel, &message).await,
+        )),
+
+        Command::PUBSUBSHARDCHANNELS(pattern) => Some(redis.pubsub.shard_channels(pattern).await),
+
+        Command::PUBSUBSHARDNUMSUB(channels) => Some(redis.pubsub.shard_numsub(channels).await),
+
+        Command::HELLO {
+            protover,
+            auth,
+            setname,
+        } => Some(hello(client, protover, auth, setname, redis).await),
+
+        Command::AUTH { username, password } => {
+            Some(match acl::login(redis, client, username, password).await {
+                Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+                Err(e) => e,
+            })
+        }
+
+        Command::ACLSETUSER { name, rules } => Some(redis.acl.setuser(name, &rules).await),
+
+        Command::ACLGETUSER(name) => Some(redis.acl.getuser(&name, client.protocol()).await),
+
+        Command::ACLDELUSER(names) => Some(acl::deluser(redis, &names).await),
+
+        Command::ACLLIST => Some(redis.acl.list().await),
+
+        Command::ACLUSERS => Some(redis.acl.users().await),
+
+        Command::ACLWHOAMI => Some(RedisValueRef::BulkString(client.user().await)),
+
+        Command::ACLCAT(category) => Some(redis.acl.cat(category)),
+
+        Command::ACLLOG(count) => Some(redis.acl.log(count, client.protocol()).await),
+
+        Command::ACLLOGRESET => Some(redis.acl.log_reset().await),
+
+        Command::ACLLOAD => Some(acl::load(redis).await),
+
+        Command::ACLSAVE => Some(acl::save(redis).await),
+
+        Command::EVAL {
+            script,
+            numkeys,
+            args,
+            read_only,
+        } => {
+            if let Err(e) = scripting::compile(&script) {
+                return Some(RedisValueRef::Error(Bytes::from(e)));
+            }
+            let sha = redis.scripts.load(script.clone()).await;
+            Some(run_script(
+                numkeys,
+                args,
+                read_only,
+                RunKind::Script,
+                client,
+                redis,
+                |keys, argv, call| {
+                    scripting::run(&script, &sha, keys, argv, &redis.running_script, call)
+                },
+            ))
+        }
+
+        Command::EVALSHA {
+            sha,
+            numkeys,
+            args,
+            read_only,
+        } => match redis.scripts.get(&sha).await {
+            Some(script) => {
+                let sha = String::from_utf8_lossy(&sha).to_lowercase();
+                Some(run_script(
+                    numkeys,
+                    args,
+                    read_only,
+                    RunKind::Script,
+                    client,
+                    redis,
+                    |keys, argv, call| {
+                        scripting::run(&script, &sha, keys, argv, &redis.running_script, call)
+                    },
+                ))
+            }
+            None => Some(RedisValueRef::Error(Bytes::from(
+                "NOSCRIPT No matching script. Please use EVAL.",
+            ))),
+        },
+
+        Command::SCRIPTLOAD(script) => Some(match scripting::compile(&script) {
+            Ok(()) => RedisValueRef::BulkString(Bytes::from(redis.scripts.load(script).await)),
+            Err(e) => RedisValueRef::Error(Bytes::from(e)),
+        }),
+
+        Command::SCRIPTEXISTS(shas) => {
+            let mut res = Vec::new();
+            for sha in shas {
+                res.push(RedisValueRef::Int(redis.scripts.exists(&sha).await as i64));
+            }
+            Some(RedisValueRef::Array(res))
+        }
+
+        Command::SCRIPTFLUSH => {
+            redis.scripts.flush().await;
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        Command::SCRIPTKILL => Some(redis.running_script.kill(RunKind::Script)),
+
+        Command::FUNCTIONLOAD { code, replace } => {
+            Some(match redis.functions.load(code, replace).await {
+                Ok(name) => {
+                    redis.notifier.add_dirty(1);
+                    RedisValueRef::BulkString(Bytes::from(name))
+                }
+                Err(e) => RedisValueRef::Error(Bytes::from(e)),
+            })
+        }
+
+        Command::FUNCTIONDELETE(name) => Some(match redis.functions.delete(&name).await {
+            Ok(()) => {
+                redis.notifier.add_dirty(1);
+                RedisValueRef::String(Bytes::from("OK"))
+            }
+            Err(e) => RedisValueRef::Error(Bytes::from(e)),
+        }),
+
+        Command::FUNCTIONFLUSH => {
+            redis.functions.flush().await;
+            redis.notifier.add_dirty(1);
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        Command::FUNCTIONKILL => Some(redis.running_script.kill(RunKind::Function)),
+
+        Command::FUNCTIONLIST { pattern, withcode } => Some(
+            redis
+                .functions
+                .list(pattern, withcode, client.protocol())
+                .await,
+        ),
+
+        Command::FUNCTIONDUMP => Some(RedisValueRef::BulkString(redis.functions.dump().await)),
+
+        Command::FUNCTIONRESTORE { payload, policy } => {
+            Some(match redis.functions.restore(&payload, policy).await {
+                Ok(()) => {
+                    redis.notifier.add_dirty(1);
+                    RedisValueRef::String(Bytes::from("OK"))
+                }
+                Err(e) => RedisValueRef::Error(Bytes::from(e)),
+            })
+        }
+
+        Command::FCALL {
+            function,
+            numkeys,
+            args,
+            read_only,
+        } => match redis.functions.get(&function).await {
+            Some((vm, info)) => {
+                let no_writes = info.has_flag("no-writes");
+                if read_only && !no_writes {
+                    return Some(RedisValueRef::Error(Bytes::from(
+                        "ERR Can not execute a script with write flag using *_ro command.",
+                    )));
+                }
+                Some(run_script(
+                    numkeys,
+                    args,
+                    read_only || no_writes,
+                    RunKind::Function,
+                    client,
+                    redis,
+                    |keys, argv, call| {
+                        scripting::call_function(
+                            &vm,
+                            &info.name,
+                            keys,
+                            argv,
+                            &redis.running_script,
+                            call,
+                        )
+                    },
+                ))
+            }
+            None => Some(RedisValueRef::Error(Bytes::from("ERR Function not found"))),
+        },
+
+        Command::MODULELIST => Some(redis.modules.list(client.protocol()).await),
+
+        Command::MODULECALL(call) => Some(call.run(client, redis).await),
+
+        Command::SAVE => Some(persistence::save(redis).await),
+
+        Command::BGSAVE { schedule } => Some(persistence::bgsave(redis, schedule)),
+
+        Command::LASTSAVE => Some(RedisValueRef::Int(redis.persistence.last_save() as i64)),
+
+        Command::CLIENTID => Some(RedisValueRef::Int(client.id as i64)),
+
+        Command::CLIENTTRACKING { on, opts } => {
+            if on {
+                if let Err(e) = redis.tracking.enable(client.id, opts).await {
+                    return Some(RedisValueRef::Error(Bytes::from(e)));
+                }
+            } else {
+                redis.tracking.disable(client.id).await;
+            }
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        Command::CLIENTGETREDIR => {
+            Some(RedisValueRef::Int(redis.tracking.redirect(client.id).await))
+        }
+
+        Command::CLIENTLIST { kind, ids } => Some(client::list(redis, kind, ids).await),
+
+        Command::CLIENTINFO => Some(RedisValueRef::BulkString(Bytes::from(
+            client::info_line(redis, client).await + "\n",
+        ))),
+
+        Command::CLIENTSETNAME(name) => Some(match client.set_name(name).await {
+            Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+            Err(e) => RedisValueRef::Error(Bytes::from(e)),
+        }),
+
+        Command::CLIENTGETNAME => Some(match client.name().await {
+            Some(name) => RedisValueRef::BulkString(name),
+            None => RedisValueRef::NullBulkString,
+        }),
+
+        Command::CLIENTSETINFO { attr, value } => Some(match client.set_info(&attr, value).await {
+            Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+            Err(e) => RedisValueRef::Error(Bytes::from(e)),
+        }),
+
+        Command::CLIENTKILL(filter) => Some(client::kill(redis, client, filter).await),
+
+        Command::CLIENTKILLADDR(addr) => Some(client::kill_addr(redis, &addr).await),
+
+        Command::CLIENTPAUSE { timeout, all } => {
+            redis.clients.pause(timeout, all).await;
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        Command::CLIENTUNPAUSE => {
+            redis.clients.unpause().await;
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        // Only ON is acknowledged, OFF and SKIP are silent
+        Command::CLIENTREPLY(mode) => {
+            client.set_reply_mode(mode).await;
+            match mode {
+                ReplyMode::On => Some(RedisValueRef::String(Bytes::from("OK"))),
+                ReplyMode::Off | ReplyMode::Skip => None,
+            }
+        }
+
+        Command::CLIENTNOEVICT(on) => {
+            client.set_no_evict(on).await;
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        Command::UNWATCH => {
+            redis.tr.unwatch(client.id).await;
+            Some(RedisValueRef::String(Bytes::from("OK")))
+        }
+
+        // Their replies go out as pushes
+        Command::SUBSCRIBE(channels) => {
+            redis.pubsub.subscribe(client.id, channels).await;
+            None
+        }
+        Command::UNSUBSCRIBE(channels) => {
+            redis.pubsub.unsubscribe(client.id, channels).await;
+            None
+        }
+        Command::PSUBSCRIBE(patterns) => {
+            redis.pubsub.psubscribe(client.id, patterns).await;
+            None
+        }
+        Command::PUNSUBSCRIBE(patterns) => {
+            redis.pubsub.punsubscribe(client.id, patterns).await;
+            None
+        }
+        Command::SSUBSCRIBE(channels) => {
+            if !same_slot(&channels) {
+                return Some(RedisValueRef::Error(Bytes::from(
+                    "CROSSSLOT Keys in request don't hash to the same slot",
+                )));
+            }
+            redis.pubsub.ssubscribe(client.id, channels).await;
+            None
+        }
+        Command::SUNSUBSCRIBE(channels) => {
+            if !same_slot(&channels) {
+                return Some(RedisValueRef::Error(Bytes::from(
+                    "CROSSSLOT Keys in request don't hash to the same slot",
+                )));
+            }
+            redis.pubsub.sunsubscribe(client.id, channels).await;
+            None
+        }
+        Command::CLIENTCACHING(yes) => {
+            Some(match redis.tracking.set_caching(client.id, yes).await {
+                Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+                Err(e) => RedisValueRef::Error(Bytes::from(e)),
+            })
+        }
+
         // Transaction commands should never reach here
-        Command::MULTI | Command::EXEC | Command::DISCARD => None,
+        Command::MULTI | Command::EXEC | Command::DISCARD | Command::WATCH(_) | Command::RESET => {
+            None
+        }
     }
 }
 
+// A database number for SELECT or MOVE
+async fn parse_db_index(redis: &Redis, index: &Bytes) -> Result<usize, String> {
+    let databases = redis.databases().await;
+    let index = std::str::from_utf8(index)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| "ERR value is not an integer or out of range".to_string())?;
+    usize::try_from(index)
+        .ok()
+        .filter(|&index| index < databases)
+        .ok_or_else(|| "ERR DB index is out of range".to_string())
+}
+
+// Blocking commands take the exec lock themselves, only while not waiting
+fn is_blocking(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::BLPOP { .. }
+            | Command::XREAD {
+                timeout: Some(_),
+                ..
+            }
+    )
+}
+
+// Scripts, SAVE and commands spanning two databases run with the exec lock
+// held exclusively, like EXEC
+fn is_exclusive(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::EVAL { .. }
+            | Command::EVALSHA { .. }
+            | Command::FCALL { .. }
+            | Command::SAVE
+            | Command::MOVE { .. }
+            | Command::SWAPDB { .. }
+    )
+}
+
+// Commands sent to replicas without writing anything, like Redis's
+// may-replicate flag: a publish reaches the replicas' subscribers too
+fn may_replicate(cmd: &Command) -> bool {
+    matches!(cmd, Command::PUBLISH { .. } | Command::SPUBLISH { .. })
+}
+
+// Commands that may replicate writes, held by CLIENT PAUSE WRITE
+fn may_write(cmd: &Command) -> bool {
+    is_write_cmnd(cmd)
+        || may_replicate(cmd)
+        || matches!(
+            cmd,
+            Command::EVAL {
+                read_only: false,
+                ..
+            } | Command::EVALSHA {
+                read_only: false,
+                ..
+            } | Command::FCALL {
+                read_only: false,
+                ..
+            }
+        )
+}
+
+// Commands that change connection state or block can't run from a script
+fn allowed_in_script(cmd: &Command) -> bool {
+    !matches!(
+        cmd,
+        Command::MULTI
+            | Command::EXEC
+            | Command::DISCARD
+            | Command::WATCH(_)
+            | Command::UNWATCH
+            | Command::SUBSCRIBE(_)
+            | Command::UNSUBSCRIBE(_)
+            | Command::PSUBSCRIBE(_)
+            | Command::PUNSUBSCRIBE(_)
+            | Command::SSUBSCRIBE(_)
+            | Command::SUNSUBSCRIBE(_)
+            | Command::RESET
+            | Command::HELLO { .. }
+            | Command::AUTH { .. }
+            | Command::CONFIGSET { .. }
+            | Command::REPLCONF(_)
+            | Command::EVAL { .. }
+            | Command::EVALSHA { .. }
+            | Command::SCRIPTLOAD(_)
+            | Command::SCRIPTEXISTS(_)
+            | Command::SCRIPTFLUSH
+            | Command::SCRIPTKILL
+            | Command::FUNCTIONLOAD { .. }
+            | Command::FUNCTIONDELETE(_)
+            | Command::FUNCTIONFLUSH
+            | Command::FUNCTIONKILL
+            | Command::FUNCTIONLIST { .. }
+            | Command::FUNCTIONDUMP
+            | Command::FUNCTIONRESTORE { .. }
+            | Command::FCALL { .. }
+            | Command::MODULELIST
+            | Command::SAVE
+            | Command::BGSAVE { .. }
+    ) && !is_client_command(cmd)
+        && !matches!(cmd, Command::MODULECALL(call) if !call.allowed_in_script())
+        && !is_acl_command(cmd)
+}
+
+fn is_acl_command(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::ACLSETUSER { .. }
+            | Command::ACLGETUSER(_)
+            | Command::ACLDELUSER(_)
+            | Command::ACLLIST
+            | Command::ACLUSERS
+            | Command::ACLWHOAMI
+            | Command::ACLCAT(_)
+            | Command::ACLLOG(_)
+            | Command::ACLLOGRESET
+            | Command::ACLLOAD
+            | Command::ACLSAVE
+    )
+}
+
+// Runs a script or function while the caller holds the exec lock
+// exclusively. Its redis.call comes back through execute_command on this
+// thread.
+fn run_script(
+    numkeys: i64,
+    mut args: Vec<Bytes>,
+    read_only: bool,
+    kind: RunKind,
+    client: &Client,
+    redis: &Arc<Redis>,
+    run: impl FnOnce(
+        Vec<Bytes>,
+        Vec<Bytes>,
+        &mut dyn FnMut(Vec<Bytes>) -> RedisValueRef,
+    ) -> RedisValueRef,
+) -> RedisValueRef {
+    if numkeys < 0 {
+        return RedisValueRef::Error(Bytes::from("ERR Number of keys can't be negative"));
+    }
+    if numkeys as usize > args.len() {
+        return RedisValueRef::Error(Bytes::from(
+            "ERR Number of keys can't be greater than number of args",
+        ));
+    }
+    let argv = args.split_off(numkeys as usize);
+    let keys = args;
+
+    // SELECT inside the script doesn't outlive it
+    let db = client.db();
+    redis.running_script.start(kind);
+    // Its writes are propagated one by one, as effects, once it returns
+    let reply = tokio::task::block_in_place(|| {
+        let handle = tokio::runtime::Handle::current();
+        run(keys, argv, &mut |cmd| {
+            handle.block_on(script_call(cmd, client, redis, read_only))
+        })
+    });
+    let killed = redis.running_script.finish();
+    client.select(db);
+    killed.unwrap_or(reply)
+}
+
+// One redis.call from a script
+async fn script_call(
+    cmd: Vec<Bytes>,
+    client: &Client,
+    redis: &Arc<Redis>,
+    read_only: bool,
+) -> RedisValueRef {
+    let arr: Vec<RedisValueRef> = cmd.into_iter().map(RedisValueRef::String).collect();
+    let name = full_command_name(&arr);
+    let Some(parsed) = parse_any(&arr, redis).await else {
+        let base = name.split('|').next().unwrap_or_default().to_uppercase();
+        let known =
+            COMMAND_NAMES.contains(&base.as_str()) || redis.modules.has_command(&base).await;
+        return RedisValueRef::Error(Bytes::from(if known {
+            "ERR Wrong number of args calling Redis command from script"
+        } else {
+            "ERR Unknown Redis command called from script"
+        }));
+    };
+    if !allowed_in_script(&parsed) {
+        return RedisValueRef::Error(Bytes::from(
+            "ERR This Redis command is not allowed from script",
+        ));
+    }
+    if read_only && is_write_cmnd(&parsed) {
+        return RedisValueRef::Error(Bytes::from(
+            "ERR Write commands are not allowed from read-only scripts.",
+        ));
+    }
+    if let Err(e) = acl::enforce(redis, client, acl_request(&parsed, &name), "lua").await {
+        return e;
+    }
+    // From here on the script can't be killed without leaving a write half done
+    if is_write_cmnd(&parsed) {
+        redis.running_script.mark_write();
+    }
+    execute_command(without_blocking(parsed), &arr, client, redis)
+        .await
+        .unwrap_or(RedisValueRef::NullBulkString)
+}
+
+// Inside EXEC blocking commands behave like their non-blocking forms
+fn without_blocking(cmd: Command) -> Command {
+    match cmd {
+        Command::BLPOP { key, .. } => Command::BLPOP {
+            key,
+            timeout: Duration::ZERO,
+        },
+        Command::XREAD {
+            to_block,
+            timeout: Some(_),
+            key_stream_start,
+        } => Command::XREAD {
+            to_block,
+            timeout: Some(Duration::ZERO),
+            key_stream_start,
+        },
+        cmd => cmd,
+    }
+}
+
+pub(crate) const COMMAND_NAMES: &[&str] = &[
+    "PING",
+    "ECHO",
+    "SET",
+    "GET",
+    "RPUSH",
+    "LRANGE",
+    "LPUSH",
+    "LLEN",
+    "LPOP",
+    "BLPOP",
+    "SMEMBERS",
+    "SCARD",
+    "SISMEMBER",
+    "ZRANGE",
+    "ZSCORE",
+    "ZCARD",
+    "HGET",
+    "HGETALL",
+    "HLEN",
+    "TYPE",
+    "SELECT",
+    "MOVE",
+    "SWAPDB",
+    "XADD",
+    "XRANGE",
+    "XREAD",
+    "XINFO",
+    "XGROUP",
+    "INCR",
+    "CONFIG",
+    "KEYS",
+    "INFO",
+    "REPLCONF",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "SSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "SPUBLISH",
+    "PUBLISH",
+    "PUBSUB",
+    "HELLO",
+    "AUTH",
+    "ACL",
+    "EVAL",
+    "EVAL_RO",
+    "EVALSHA",
+    "EVALSHA_RO",
+    "SCRIPT",
+    "FUNCTION",
+    "FCALL",
+    "FCALL_RO",
+    "MODULE",
+    "SAVE",
+    "BGSAVE",
+    "LASTSAVE",
+    "CLIENT",
+    "RESET",
+    "MULTI",
+    "EXEC",
+    "DISCARD",
+    "WATCH",
+    "UNWATCH",
+];
+
+// Commands whose first argument is a subcommand
+const CONTAINER_COMMANDS: &[&str] = &[
+    "XINFO", "XGROUP", "CONFIG", "PUBSUB", "CLIENT", "ACL", "SCRIPT", "FUNCTION", "MODULE",
+];
+
+// Error for a request parse_command rejected
+// Builtins first, then commands registered by modules
+async fn parse_any(arr: &[RedisValueRef], redis: &Redis) -> Option<Command> {
+    match parse_command(arr) {
+        Some(cmd) => Some(cmd),
+        None => redis.modules.parse(arr).await.map(Command::MODULECALL),
+    }
+}
+
+fn command_error(arr: &[RedisValueRef], module_command: bool) -> RedisValueRef {
+    let arg = |i: usize| match arr.get(i) {
+        Some(RedisValueRef::String(s)) => Some(String::from_utf8_lossy(s).to_string()),
+        _ => None,
+    };
+    let name = arg(0).unwrap_or_default();
+    let upper = name.to_uppercase();
+
+    let msg = if !COMMAND_NAMES.contains(&upper.as_str()) && !module_command {
+        let args: String = (1..arr.len())
+            .filter_map(arg)
+            .map(|a| format!("'{}' ", a))
+            .collect();
+        format!(
+            "ERR unknown command '{}', with args beginning with: {}",
+            name, args
+        )
+    } else {
+        format!(
+            "ERR wrong number of arguments for '{}' command",
+            full_command_name(arr)
+        )
+    };
+    RedisValueRef::Error(Bytes::from(msg))
+}
+
+// Lowercase name, with the subcommand for container commands ("client|list")
+fn full_command_name(arr: &[RedisValueRef]) -> String {
+    let arg = |i: usize| match arr.get(i) {
+        Some(RedisValueRef::String(s)) => Some(String::from_utf8_lossy(s).to_lowercase()),
+        _ => None,
+    };
+    let name = arg(0).unwrap_or_default();
+    match arg(1) {
+        Some(sub) if CONTAINER_COMMANDS.contains(&name.to_uppercase().as_str()) => {
+            format!("{}|{}", name, sub)
+        }
+        _ => name,
+    }
+}
+
+// Keys a read-only command looks at, recorded for client side caching
+fn read_keys(cmd: &Command) -> Vec<Bytes> {
+    match cmd {
+        Command::Get(key)
+        | Command::LLEN(key)
+        | Command::LRANGE { key, .. }
+        | Command::SMEMBERS(key)
+        | Command::SCARD(key)
+        | Command::SISMEMBER { key, .. }
+        | Command::ZRANGE { key, .. }
+        | Command::ZSCORE { key, .. }
+        | Command::ZCARD(key)
+        | Command::HGET { key, .. }
+        | Command::HGETALL(key)
+        | Command::HLEN(key)
+        | Command::TYPE(key)
+        | Command::XRANGE { key, .. }
+        | Command::XINFO { key, .. } => vec![key.clone()],
+        // Stream keys and IDs alternate
+        Command::XREAD {
+            key_stream_start, ..
+        } => key_stream_start.iter().step_by(2).cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Keys a command touches, checked against the user's ACL key patterns
+fn command_keys(cmd: &Command) -> Vec<Bytes> {
+    match cmd {
+        Command::MODULECALL(call) => call.keys(),
+        Command::Set { key, .. }
+        | Command::RPUSH { key, .. }
+        | Command::LPUSH { key, .. }
+        | Command::LPOP { key, .. }
+        | Command::BLPOP { key, .. }
+        | Command::XADD { key, .. }
+        | Command::XGROUPCREATE { key, .. }
+        | Command::XGROUPDESTROY { key, .. }
+        | Command::INCR(key)
+        | Command::MOVE { key, .. } => vec![key.clone()],
+        Command::WATCH(keys) => keys.clone(),
+        _ => read_keys(cmd),
+    }
+}
+
+// What the ACL needs to know about a command before it runs
+fn acl_request<'a>(cmd: &Command, name: &'a str) -> acl::Request<'a> {
+    let (channels, patterns) = match cmd {
+        Command::PUBLISH { channel, .. } | Command::SPUBLISH { channel, .. } => {
+            (vec![channel.clone()], Vec::new())
+        }
+        Command::SUBSCRIBE(channels) | Command::SSUBSCRIBE(channels) => {
+            (channels.clone(), Vec::new())
+        }
+        Command::PSUBSCRIBE(patterns) => (Vec::new(), patterns.clone()),
+        _ => (Vec::new(), Vec::new()),
+    };
+    acl::Request {
+        name,
+        keys: command_keys(cmd),
+        write: is_write_cmnd(cmd),
+        channels,
+        patterns,
+    }
+}
+
+// INFO with the sections we keep; unknown sections give an empty reply
+async fn info(redis: &Redis, section: &Bytes) -> RedisValueRef {
+    let section = String::from_utf8_lossy(section).to_lowercase();
+    let all = matches!(section.as_str(), "default" | "all" | "everything");
+    let mut sections = Vec::new();
+    if all || section == "persistence" {
+        let mut persistence = redis.persistence.info(redis.notifier.dirty()).await;
+        persistence.push_str(&redis.loading.info().await);
+        persistence.push_str(&redis.aof.info());
+        sections.push(persistence);
+    }
+    if all || section == "replication" {
+        sections.push(redis.info.serialize().await);
+    }
+    if all || section == "keyspace" {
+        let mut keyspace = String::from("# Keyspace\n");
+        for db in redis.dbs().await {
+            let (keys, expires) = db.counts().await;
+            if keys > 0 {
+                keyspace.push_str(&format!(
+                    "db{}:keys={},expires={},avg_ttl=0\n",
+                    db.id(),
+                    keys,
+                    expires
+                ));
+            }
+        }
+        sections.push(keyspace);
+    }
+    RedisValueRef::BulkString(Bytes::from(sections.join("\n")))
+}
+
+async fn hello(
+    client: &Client,
+    protover: Option<Bytes>,
+    auth: Option<(Bytes, Bytes)>,
+    setname: Option<Bytes>,
+    redis: &Arc<Redis>,
+) -> RedisValueRef {
+    let protocol = match protover {
+        Some(v) => match std::str::from_utf8(&v)
+            .ok()
+            .and_then(|v| v.parse::<u8>().ok())
+        {
+            Some(p @ (2 | 3)) => p,
+            Some(_) => {
+                return RedisValueRef::Error(Bytes::from("NOPROTO unsupported protocol version"))
+            }
+            None => {
+                return RedisValueRef::Error(Bytes::from(
+                    "ERR Protocol version is not an integer or out of range",
+                ))
+            }
+        },
+        None => client.protocol(),
+    };
+
+    match auth {
+        Some((username, password)) => {
+            if let Err(e) = acl::login(redis, client, Some(username), password).await {
+                return e;
+            }
+        }
+        None if !client.is_authenticated().await => {
+            return RedisValueRef::Error(Bytes::from("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time"));
+        }
+        None => {}
+    }
+    if let Some(name) = setname {
+        if let Err(e) = client.set_name(name).await {
+            return RedisValueRef::Error(Bytes::from(e));
+        }
+    }
+    client.set_protocol(protocol);
+
+    let fields = vec![
+        ("server", RedisValueRef::BulkString(Bytes::from("redis"))),
+        ("version", RedisValueRef::BulkString(Bytes::from("7.2.0"))),
+        ("proto", RedisValueRef::Int(protocol as i64)),
+        ("id", RedisValueRef::Int(client.id as i64)),
+        ("mode", RedisValueRef::BulkString(Bytes::from("standalone"))),
+        (
+            "role",
+            RedisValueRef::BulkString(Bytes::from(redis.info.role().await)),
+        ),
+        ("modules", RedisValueRef::Array(Vec::new())),
+    ];
+    if protocol == 3 {
+        RedisValueRef::Map(
+            fields
+                .into_iter()
+                .map(|(k, v)| (RedisValueRef::BulkString(Bytes::from(k)), v))
+                .collect(),
+        )
+    } else {
+        RedisValueRef::Array(
+            fields
+                .into_iter()
+                .flat_map(|(k, v)| [RedisValueRef::BulkString(Bytes::from(k)), v])
+                .collect(),
+        )
+    }
+}
+
+fn is_client_command(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::CLIENTID
+            | Command::CLIENTTRACKING { .. }
+            | Command::CLIENTCACHING(_)
+            | Command::CLIENTGETREDIR
+            | Command::CLIENTLIST { .. }
+            | Command::CLIENTINFO
+            | Command::CLIENTSETNAME(_)
+            | Command::CLIENTGETNAME
+            | Command::CLIENTKILL(_)
+            | Command::CLIENTKILLADDR(_)
+            | Command::CLIENTPAUSE { .. }
+            | Command::CLIENTUNPAUSE
+            | Command::CLIENTREPLY(_)
+            | Command::CLIENTNOEVICT(_)
+            | Command::CLIENTSETINFO { .. }
+    )
+}
+
+// Commands served while a script is past busy-reply-threshold
+fn allowed_while_busy(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::AUTH { .. } | Command::HELLO { .. } | Command::SCRIPTKILL | Command::FUNCTIONKILL
+    )
+}
+
+// Commands served while the dataset is still loading
+fn allowed_while_loading(cmd: &Command) -> bool {
+    is_client_command(cmd)
+        || matches!(
+            cmd,
+            Command::AUTH { .. }
+                | Command::HELLO { .. }
+                | Command::INFO(_)
+                | Command::CONFIGGET(_)
+                | Command::CONFIGSET { .. }
+                | Command::SELECT(_)
+                | Command::SUBSCRIBE(_)
+                | Command::UNSUBSCRIBE(_)
+                | Command::PSUBSCRIBE(_)
+                | Command::PUNSUBSCRIBE(_)
+                | Command::SSUBSCRIBE(_)
+                | Command::SUNSUBSCRIBE(_)
+                | Command::PUBLISH { .. }
+                | Command::SPUBLISH { .. }
+                | Command::PUBSUBCHANNELS(_)
+                | Command::PUBSUBNUMSUB(_)
+                | Command::PUBSUBNUMPAT
+                | Command::PUBSUBSHARDCHANNELS(_)
+                | Command::PUBSUBSHARDNUMSUB(_)
+                | Command::REPLCONF(_)
+                | Command::LASTSAVE
+                | Command::RESET
+        )
+}
+
+// Commands a client may run while it has active subscriptions
+fn allowed_when_subscribed(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::SUBSCRIBE(_)
+            | Command::UNSUBSCRIBE(_)
+            | Command::PSUBSCRIBE(_)
+            | Command::PUNSUBSCRIBE(_)
+            | Command::SSUBSCRIBE(_)
+            | Command::SUNSUBSCRIBE(_)
+            | Command::Ping
+            | Command::RESET
+    )
+}
+
+// Shard channels in one command must map to a single hash slot
+fn same_slot(channels: &[Bytes]) -> bool {
+    let mut slots = channels.iter().map(|c| key_hash_slot(c));
+    match slots.next() {
+        Some(first) => slots.all(|slot| slot == first),
+        None => true,
+    }
+}
+
+fn string_args(args: &[RedisValueRef]) -> Option<Vec<Bytes>> {
+    args.iter()
+        .map(|arg| match arg {
+            RedisValueRef::String(s) => Some(s.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
 pub async fn handle_command(
     value: RedisValueRef,
-    addr: SocketAddr,
+    client: &Client,
+    redis: &Arc<Redis>,
+) -> Option<RedisValueRef> {
+    if let RedisValueRef::Array(arr) = &value {
+        client.begin_command(full_command_name(arr)).await;
+    }
+
+    let mode = client.reply_mode().await;
+    let response = CURRENT_CLIENT
+        .scope(client.id, dispatch_command(value, client, redis))
+        .await;
+
+    // CLIENT REPLY OFF/SKIP drop replies, but CLIENT REPLY ON is always answered
+    match mode {
+        ReplyMode::On => response,
+        ReplyMode::Off if client.reply_mode().await == ReplyMode::On => response,
+        ReplyMode::Off => None,
+        ReplyMode::Skip => {
+            if client.reply_mode().await == ReplyMode::Skip {
+                client.set_reply_mode(ReplyMode::On).await;
+            }
+            None
+        }
+    }
+}
+
+async fn dispatch_command(
+    value: RedisValueRef,
+    client: &Client,
     redis: &Arc<Redis>,
 ) -> Option<RedisValueRef> {
     let arr = match value {
@@ -553,57 +2553,328 @@ pub async fn handle_command(
         _ => return Some(RedisValueRef::Error(Bytes::from("ERR expected array"))),
     };
 
-    let parsed_command = parse_command(arr)?;
+    // Until AUTH succeeds only AUTH and HELLO are served
+    if !client.is_authenticated().await {
+        let name = full_command_name(arr);
+        if name != "auth" && name != "hello" {
+            return Some(RedisValueRef::Error(Bytes::from(
+                "NOAUTH Authentication required.",
+            )));
+        }
+    }
+
+    let Some(parsed_command) = parse_any(arr, redis).await else {
+        // A bad command inside MULTI dooms the whole transaction
+        if redis.tr.in_transaction(client.id).await {
+            redis.tr.flag_error(client.id).await;
+        }
+        let name = full_command_name(arr);
+        return Some(command_error(arr, redis.modules.has_command(&name).await));
+    };
+
+    // The master link replays writes that were already allowed on the master
+    if !client.is_master() {
+        let name = full_command_name(arr);
+        if let Err(e) = acl::enforce(
+            redis,
+            client,
+            acl_request(&parsed_command, &name),
+            "toplevel",
+        )
+        .await
+        {
+            if redis.tr.in_transaction(client.id).await {
+                redis.tr.flag_error(client.id).await;
+            }
+            return Some(e);
+        }
+    }
+
+    // Until the dataset is loaded only commands that don't touch it are
+    // served. The loader itself replays the AOF as the master link.
+    if !client.is_master() && redis.loading.is_loading() && !allowed_while_loading(&parsed_command)
+    {
+        if redis.tr.in_transaction(client.id).await {
+            redis.tr.flag_error(client.id).await;
+        }
+        return Some(RedisValueRef::Error(Bytes::from(
+            "LOADING Redis is loading the dataset in memory",
+        )));
+    }
+
+    // A script that runs too long holds the lock, so anything that would
+    // wait for it is refused until the script ends or is killed
+    if !client.is_master() && !allowed_while_busy(&parsed_command) {
+        if let Some(e) = redis.running_script.busy_error() {
+            if redis.tr.in_transaction(client.id).await {
+                redis.tr.flag_error(client.id).await;
+            }
+            return Some(e);
+        }
+    }
+
+    // CLIENT PAUSE holds everyone but the master link, and CLIENT commands
+    // stay available so the pause can be inspected and lifted
+    if !client.is_master() && !is_client_command(&parsed_command) {
+        redis
+            .clients
+            .wait_if_paused(may_write(&parsed_command))
+            .await;
+    }
 
-    // Broadcast write commands to all slaves
-    if is_write_cmnd(&parsed_command) {
-        let redis = redis.clone();
-        let arr = arr.clone();
-        tokio::spawn(async move {
-            write_to_slaves(&redis, &arr).await;
-        });
+    // RESP3 clients can mix pushes with normal replies, so only RESP2 is restricted
+    if client.protocol() == 2 && redis.pubsub.is_subscribed(client.id).await {
+        if !allowed_when_subscribed(&parsed_command) {
+            let name = match &arr[0] {
+                RedisValueRef::String(name) => String::from_utf8_lossy(name).to_lowercase(),
+                _ => String::new(),
+            };
+            return Some(RedisValueRef::Error(Bytes::from(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name
+            ))));
+        }
+        if let Command::Ping = parsed_command {
+            return Some(RedisValueRef::Array(vec![
+                RedisValueRef::BulkString(Bytes::from("pong")),
+                RedisValueRef::BulkString(Bytes::new()),
+            ]));
+        }
     }
 
-    // Handle transaction control commands immediately
+    // Handle transaction control and subscription commands immediately.
+    // Subscription replies are pushed through the connection's message queue.
     match parsed_command {
         Command::MULTI => {
-            return Some(redis.tr.start_transaction(addr).await);
+            return Some(redis.tr.start_transaction(client.id).await);
         }
         Command::EXEC => {
-            let cmds = redis.tr.exec_transaction(addr).await;
-            if let Some(cmds) = cmds {
-                let mut results = Vec::new();
-                for cmd in cmds {
-                    if let Some(result) = execute_command(cmd, redis).await {
-                        results.push(result);
+            // Taken before the WATCH check, so no write can land between the
+            // check and the queued commands
+            let guard = tokio::select! {
+                biased;
+                guard = redis.exec_lock.write() => guard,
+                busy = redis.running_script.busy() => return Some(busy),
+            };
+            match redis.tr.exec_transaction(client.id).await {
+                ExecOutcome::Run(cmds) => {
+                    client.set_in_exec(true);
+                    // Runtime errors are returned in place and don't stop the rest
+                    let mut results = Vec::new();
+                    for (cmd, args) in cmds {
+                        if let Some(result) =
+                            execute_command(without_blocking(cmd), &args, client, redis).await
+                        {
+                            results.push(result);
+                        }
                     }
+                    client.set_in_exec(false);
+                    propagate_writes(client, redis).await;
+                    drop(guard);
+                    redis.tracking.command_done(client.id).await;
+                    return Some(RedisValueRef::Array(results));
+                }
+                ExecOutcome::QueueError => {
+                    return Some(RedisValueRef::Error(Bytes::from(
+                        "EXECABORT Transaction discarded because of previous errors.",
+                    )));
+                }
+                ExecOutcome::WatchedKeyChanged => return Some(RedisValueRef::NullArray),
+                ExecOutcome::NotInTransaction => {
+                    return Some(RedisValueRef::Error(Bytes::from("ERR EXEC without MULTI")));
                 }
-                return Some(RedisValueRef::Array(results));
-            } else {
-                return Some(RedisValueRef::Error(Bytes::from("ERR EXEC without MULTI")));
             }
         }
         Command::DISCARD => {
-            return Some(redis.tr.discard_transaction(addr).await);
+            return Some(redis.tr.discard_transaction(client.id).await);
+        }
+        Command::WATCH(keys) => {
+            return Some(redis.tr.watch(client.id, client.db(), keys).await);
+        }
+        Command::RESET => {
+            redis.pubsub.reset(client.id).await;
+            client.set_protocol(2);
+            redis.tracking.disable(client.id).await;
+            if redis.tr.in_transaction(client.id).await {
+                redis.tr.discard_transaction(client.id).await;
+            }
+            redis.tr.unwatch(client.id).await;
+            client.select(0);
+            client
+                .set_user(Bytes::from("default"), redis.acl.default_nopass().await)
+                .await;
+            return Some(RedisValueRef::String(Bytes::from("RESET")));
         }
         _ => {}
     }
 
-    if redis.tr.in_transaction(addr).await {
-        return Some(redis.tr.queue_command(addr, parsed_command).await);
+    // Everything else is queued inside MULTI, as in Redis
+    if redis.tr.in_transaction(client.id).await {
+        return Some(
+            redis
+                .tr
+                .queue_command(client.id, parsed_command, arr.clone())
+                .await,
+        );
     }
 
-    execute_command(parsed_command, redis).await
+    // Served without the lock: subscriptions only concern the connection,
+    // and the script being killed holds it. CLIENT CACHING applies to the
+    // command that follows, so it must not end its own scope.
+    if matches!(
+        parsed_command,
+        Command::SUBSCRIBE(_)
+            | Command::UNSUBSCRIBE(_)
+            | Command::PSUBSCRIBE(_)
+            | Command::PUNSUBSCRIBE(_)
+            | Command::SSUBSCRIBE(_)
+            | Command::SUNSUBSCRIBE(_)
+            | Command::SCRIPTKILL
+            | Command::FUNCTIONKILL
+            | Command::CLIENTCACHING(_)
+    ) {
+        return run_command(parsed_command, client, redis).await;
+    }
+
+    let response = match parsed_command {
+        Command::BLPOP { key, timeout } => blpop(key, timeout, arr, client, redis).await,
+        cmd if is_blocking(&cmd) => execute_command(cmd, arr, client, redis).await,
+        cmd => run_locked(cmd, arr, client, redis).await,
+    };
+    redis.tracking.command_done(client.id).await;
+    response
 }
 
-async fn write_to_slaves(redis: &Arc<Redis>, arr: &[RedisValueRef]) {
-    let resp_bytes = serialize_to_resp(arr);
+// Runs a command under the exec lock, and sends its writes on before the
+// lock is released. Writes sharing the lock also take `write_order` until
+// they are sent, so replicas and the AOF see them in the order they were
+// made. Waiting for the lock ends with -BUSY if a script holding it runs
+// too long.
+async fn run_locked(
+    cmd: Command,
+    arr: &[RedisValueRef],
+    client: &Client,
+    redis: &Arc<Redis>,
+) -> Option<RedisValueRef> {
+    if is_exclusive(&cmd) {
+        return tokio::select! {
+            biased;
+            _guard = redis.exec_lock.write() => {
+                let response = execute_command(cmd, arr, client, redis).await;
+                propagate_writes(client, redis).await;
+                response
+            }
+            busy = redis.running_script.busy() => Some(busy),
+        };
+    }
+    let write = may_write(&cmd);
+    tokio::select! {
+        biased;
+        _guard = redis.exec_lock.read() => {
+            let _order = if write {
+                Some(redis.write_order.lock().await)
+            } else {
+                None
+            };
+            let response = execute_command(cmd, arr, client, redis).await;
+            propagate_writes(client, redis).await;
+            response
+        }
+        busy = redis.running_script.busy() => Some(busy),
+    }
+}
 
+// BLPOP waits for a push without the exec lock, then pops as a write of its
+// own, so the pop is propagated in order. A client that loses the element
+// to another waits again for what is left of its timeout.
+async fn blpop(
+    key: Bytes,
+    timeout: Duration,
+    arr: &[RedisValueRef],
+    client: &Client,
+    redis: &Arc<Redis>,
+) -> Option<RedisValueRef> {
+    // None when too far off to represent, as good as never
+    let deadline = tokio::time::Instant::now().checked_add(timeout);
+    loop {
+        let pop = Command::BLPOP {
+            key: key.clone(),
+            timeout: Duration::ZERO,
+        };
+        let response = run_locked(pop, arr, client, redis).await;
+        if response != Some(RedisValueRef::NullArray) {
+            return response;
+        }
+        let left = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(tokio::time::Instant::now()),
+            None => timeout,
+        };
+        let db = redis.db(client.db()).await;
+        if left.is_zero() || !db.lists.wait_for_push(&key, left).await {
+            return response;
+        }
+    }
+}
+
+// Sends the writes the command just made to the AOF and replicas. More than
+// one, as from EXEC or a script, go as a transaction.
+async fn propagate_writes(client: &Client, redis: &Arc<Redis>) {
+    let writes = client.take_propagated().await;
+    // Publishes go to replicas only: replayed from the AOF, they would be
+    // published again on every startup
+    let logged: Vec<_> = writes
+        .iter()
+        .filter(|(_, args)| !is_publish(args))
+        .cloned()
+        .collect();
+    for (db, args) in wrap_in_multi(&logged) {
+        redis.aof.feed(db, &args).await;
+    }
+    for (db, args) in wrap_in_multi(&writes) {
+        write_to_slaves(redis, db, &args).await;
+    }
+}
+
+// The writes, between MULTI and EXEC when there is more than one
+fn wrap_in_multi(writes: &[(usize, Vec<RedisValueRef>)]) -> Vec<(usize, Vec<RedisValueRef>)> {
+    let (Some((first_db, _)), Some((last_db, _))) = (writes.first(), writes.last()) else {
+        return Vec::new();
+    };
+    if writes.len() == 1 {
+        return writes.to_vec();
+    }
+    let mut wrapped = vec![(*first_db, vec![RedisValueRef::String(Bytes::from("MULTI"))])];
+    wrapped.extend_from_slice(writes);
+    wrapped.push((*last_db, vec![RedisValueRef::String(Bytes::from("EXEC"))]));
+    wrapped
+}
+
+fn is_publish(args: &[RedisValueRef]) -> bool {
+    match args.first() {
+        Some(RedisValueRef::String(name)) => {
+            name.eq_ignore_ascii_case(b"PUBLISH") || name.eq_ignore_ascii_case(b"SPUBLISH")
+        }
+        _ => false,
+    }
+}
+
+// Sends a write made in database `db`, preceded by a SELECT when the
+// replicas were last sent another database
+async fn write_to_slaves(redis: &Arc<Redis>, db: usize, arr: &[RedisValueRef]) {
     let mut slaves = redis.connected_slaves.lock().await;
+    let mut resp_bytes = Vec::new();
+    if redis.repl_db.swap(db, Ordering::SeqCst) != db {
+        resp_bytes = serialize_to_resp(&[
+            RedisValueRef::String(Bytes::from("SELECT")),
+            RedisValueRef::String(Bytes::from(db.to_string())),
+        ]);
+    }
+    resp_bytes.extend_from_slice(&serialize_to_resp(arr));
+
     let mut dead_indices = Vec::new();
 
     for (idx, slave_tx) in slaves.iter_mut().enumerate() {
-        if slave_tx.send(resp_bytes.clone()).await.is_err() {
+        if slave_tx.send(resp_bytes.clone()).is_err() {
             dead_indices.push(idx);
         }
     }
@@ -616,7 +2887,7 @@ async fn write_to_slaves(redis: &Arc<Redis>, arr: &[RedisValueRef]) {
     }
 }
 
-fn serialize_to_resp(arr: &[RedisValueRef]) -> Vec<u8> {
+pub(crate) fn serialize_to_resp(arr: &[RedisValueRef]) -> Vec<u8> {
     let mut result = Vec::new();
     result.extend_from_slice(format!("*{}\r\n", arr.len()).as_bytes());
 
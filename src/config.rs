@@ -0,0 +1,200 @@
+use crate::aof::AppendFsync;
+use crate::client::OutputBufferLimit;
+use crate::log;
+use crate::rdb::KeyValue;
+use crate::redis::Redis;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+
+// Parameters CONFIG GET knows about, in the order they are reported
+const PARAMS: &[&str] = &[
+    "dir",
+    "dbfilename",
+    "save",
+    "rdbcompression",
+    "rdbchecksum",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "notify-keyspace-events",
+    "requirepass",
+    "aclfile",
+    "acllog-max-len",
+    "databases",
+    "busy-reply-threshold",
+    "lua-time-limit",
+    "loglevel",
+    "client-output-buffer-limit",
+];
+
+async fn read_param(redis: &Redis, name: &str) -> Option<String> {
+    match name {
+        "dir" => Some(redis.persistence.dir().await),
+        "dbfilename" => Some(redis.persistence.dbfilename().await),
+        "save" => Some(redis.persistence.save_params_string().await),
+        "rdbcompression" => Some(yes_no(redis.persistence.rdbcompression())),
+        "rdbchecksum" => Some(yes_no(redis.persistence.rdbchecksum())),
+        "appendonly" => Some(yes_no(redis.aof.enabled())),
+        "appendfilename" => Some(redis.aof.filename().await),
+        "appendfsync" => Some(redis.aof.fsync().await.as_str().to_string()),
+        "notify-keyspace-events" => Some(redis.notifier.flags_string().await),
+        "requirepass" => Some(redis.acl.requirepass().await),
+        "aclfile" => Some(redis.acl.file().await.unwrap_or_default()),
+        "acllog-max-len" => Some(redis.acl.log_max_len().to_string()),
+        "databases" => Some(redis.databases().await.to_string()),
+        // lua-time-limit is the old name
+        "busy-reply-threshold" | "lua-time-limit" => {
+            Some(redis.running_script.busy_reply_threshold().to_string())
+        }
+        "loglevel" => Some(log::level().as_str().to_string()),
+        // Only pub/sub clients are limited
+        "client-output-buffer-limit" => {
+            let limit = redis.pubsub.output_buffer_limit().await;
+            Some(format!(
+                "normal 0 0 0 replica 0 0 0 pubsub {} {} {}",
+                limit.hard, limit.soft, limit.soft_seconds
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// CONFIG GET: every parameter whose name matches the glob `pattern`.
+pub async fn get(redis: &Redis, pattern: &Bytes) -> RedisValueRef {
+    let pattern = String::from_utf8_lossy(pattern).to_lowercase();
+    let mut res = Vec::new();
+    for name in PARAMS {
+        if !KeyValue::match_pattern(&pattern, name) {
+            continue;
+        }
+        if let Some(value) = read_param(redis, name).await {
+            res.push(RedisValueRef::BulkString(Bytes::from(*name)));
+            res.push(RedisValueRef::BulkString(Bytes::from(value)));
+        }
+    }
+    RedisValueRef::Array(res)
+}
+
+/// CONFIG SET for the parameters that can change at runtime.
+pub async fn set(redis: &Redis, name: &Bytes, value: &Bytes) -> RedisValueRef {
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    let value = String::from_utf8_lossy(value);
+    let res = match name.as_str() {
+        "notify-keyspace-events" => redis.notifier.set_flags(&value).await,
+        "save" => redis.persistence.set_save_params(&value).await,
+        "rdbcompression" => parse_yes_no(&name, &value).map(|on| {
+            redis.persistence.set_rdbcompression(on);
+        }),
+        "rdbchecksum" => parse_yes_no(&name, &value).map(|on| {
+            redis.persistence.set_rdbchecksum(on);
+        }),
+        "appendfsync" => match AppendFsync::parse(&value) {
+            Some(fsync) => {
+                redis.aof.set_fsync(fsync).await;
+                Ok(())
+            }
+            None => Err("ERR CONFIG SET failed (possibly related to argument 'appendfsync') - argument(s) must be one of the following: always, everysec, no".to_string()),
+        },
+        "requirepass" => {
+            redis.acl.set_requirepass(&value).await;
+            Ok(())
+        }
+        "acllog-max-len" => match value.parse::<usize>() {
+            Ok(len) => {
+                redis.acl.set_log_max_len(len).await;
+                Ok(())
+            }
+            Err(_) => Err("ERR CONFIG SET failed (possibly related to argument 'acllog-max-len') - argument couldn't be parsed into an integer".to_string()),
+        },
+        "busy-reply-threshold" | "lua-time-limit" => match value.parse::<u64>() {
+            Ok(ms) => {
+                redis.running_script.set_busy_reply_threshold(ms);
+                Ok(())
+            }
+            Err(_) => Err(format!("ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer", name)),
+        },
+        "loglevel" => match log::Level::parse(&value) {
+            Some(level) => {
+                log::set_level(level);
+                Ok(())
+            }
+            None => Err("ERR CONFIG SET failed (possibly related to argument 'loglevel') - argument(s) must be one of the following: debug, verbose, notice, warning".to_string()),
+        },
+        "client-output-buffer-limit" => match parse_output_buffer_limit(&value) {
+            Some(Some(limit)) => {
+                redis.pubsub.set_output_buffer_limit(limit).await;
+                Ok(())
+            }
+            Some(None) => Ok(()),
+            None => Err("ERR CONFIG SET failed (possibly related to argument 'client-output-buffer-limit') - Invalid arguments".to_string()),
+        },
+        _ => Err(format!(
+            "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+            name
+        )),
+    };
+    match res {
+        Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+        Err(e) => RedisValueRef::Error(Bytes::from(e)),
+    }
+}
+
+// "<class> <hard> <soft> <soft seconds> ...". Returns the pubsub class's
+// limit if it is given. The other classes are unlimited, so only 0s are
+// accepted for them.
+fn parse_output_buffer_limit(value: &str) -> Option<Option<OutputBufferLimit>> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if words.is_empty() || !words.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut pubsub = None;
+    for group in words.chunks(4) {
+        let limit = OutputBufferLimit {
+            hard: parse_memory(group[1])?,
+            soft: parse_memory(group[2])?,
+            soft_seconds: group[3].parse().ok()?,
+        };
+        match group[0].to_lowercase().as_str() {
+            "pubsub" => pubsub = Some(limit),
+            "normal" | "replica" | "slave" if limit.hard == 0 && limit.soft == 0 => {}
+            _ => return None,
+        }
+    }
+    Some(pubsub)
+}
+
+/// Reads a size such as "32mb": k, m and g are powers of 1000 and kb, mb
+/// and gb powers of 1024, as in redis.conf.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let units: [(&str, u64); 7] = [
+        ("kb", 1 << 10),
+        ("mb", 1 << 20),
+        ("gb", 1 << 30),
+        ("k", 1_000),
+        ("m", 1_000_000),
+        ("g", 1_000_000_000),
+        ("b", 1),
+    ];
+    let (digits, unit) = units
+        .iter()
+        .find_map(|(suffix, unit)| value.strip_suffix(suffix).map(|d| (d, *unit)))
+        .unwrap_or((value.as_str(), 1));
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn yes_no(on: bool) -> String {
+    if on { "yes" } else { "no" }.to_string()
+}
+
+/// Reads a boolean parameter, which is spelled "yes" or "no".
+pub fn parse_yes_no(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!(
+            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be 'yes' or 'no'",
+            name
+        )),
+    }
+}
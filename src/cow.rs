@@ -0,0 +1,76 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Copies writers have made of data a snapshot was still reading, and how
+// many elements those copies held
+static COPIES: AtomicU64 = AtomicU64::new(0);
+static COPIED_ITEMS: AtomicU64 = AtomicU64::new(0);
+
+/// A value that a snapshot can share without a copy. The stores keep one per
+/// key in an `im::HashMap`, so a write during a snapshot copies that key's
+/// value and the few map nodes above it, not the whole keyspace.
+#[derive(Debug, Default)]
+pub struct Cow<T>(Arc<T>);
+
+// Cloning shares the data, it's the write that copies
+impl<T> Clone for Cow<T> {
+    fn clone(&self) -> Self {
+        Cow(self.0.clone())
+    }
+}
+
+impl<T> Deref for Cow<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone + Items> Cow<T> {
+    pub fn new(value: T) -> Self {
+        Cow(Arc::new(value))
+    }
+
+    /// Mutable access, copying the data first if a snapshot shares it.
+    pub fn make_mut(&mut self) -> &mut T {
+        if Arc::strong_count(&self.0) > 1 {
+            COPIES.fetch_add(1, Ordering::Relaxed);
+            COPIED_ITEMS.fetch_add(self.0.items() as u64, Ordering::Relaxed);
+        }
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+/// The number of elements a copy duplicates, to report its cost.
+pub trait Items {
+    fn items(&self) -> usize;
+}
+
+impl<K, V> Items for HashMap<K, V> {
+    fn items(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> Items for HashSet<T> {
+    fn items(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> Items for VecDeque<T> {
+    fn items(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Copies made so far and the elements they held.
+pub fn copy_stats() -> (u64, u64) {
+    (
+        COPIES.load(Ordering::Relaxed),
+        COPIED_ITEMS.load(Ordering::Relaxed),
+    )
+}
@@ -0,0 +1,35 @@
+/// CRC-64/Jones, the checksum at the end of RDB files: reflected polynomial
+/// 0x95AC9329AC4BC9B5, zero initial value, no final xor.
+const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+
+const TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Extends `crc` with `data`. Start from 0.
+pub fn update(mut crc: u64, data: &[u8]) -> u64 {
+    for &b in data {
+        crc = TABLE[((crc ^ b as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn crc64(data: &[u8]) -> u64 {
+    update(0, data)
+}
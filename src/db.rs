@@ -0,0 +1,149 @@
+use crate::hashes::{Hash, HashSnapshot};
+use crate::lists::{List, ListSnapshot};
+use crate::notify::{DbNotifier, Notifier, NOTIFY_GENERIC};
+use crate::rdb::{KeyValue, KeyValueSnapshot, RdbWriter};
+use crate::sets::{Set, SetSnapshot};
+use crate::streams::{Stream, StreamSnapshot};
+use crate::zsets::{SortedSet, SortedSetSnapshot};
+use bytes::Bytes;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// One logical database: a keyspace per value type. SELECT picks which one a
+/// connection works on, and SWAPDB exchanges two of them.
+pub struct Db {
+    // Where the database currently sits, shared with its stores' notifiers
+    id: Arc<AtomicUsize>,
+    notifier: Arc<Notifier>,
+    pub kv: KeyValue,
+    pub lists: List,
+    pub sets: Set,
+    pub zsets: SortedSet,
+    pub hashes: Hash,
+    pub stream: Stream,
+}
+
+impl Db {
+    pub fn new(id: usize, notifier: Arc<Notifier>) -> Self {
+        let id = Arc::new(AtomicUsize::new(id));
+        let db_notifier = || DbNotifier::new(notifier.clone(), id.clone());
+        Db {
+            kv: KeyValue::new(db_notifier()),
+            lists: List::new(db_notifier()),
+            sets: Set::new(),
+            zsets: SortedSet::new(),
+            hashes: Hash::new(),
+            stream: Stream::new(db_notifier()),
+            id,
+            notifier,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_id(&self, id: usize) {
+        self.id.store(id, Ordering::SeqCst);
+    }
+
+    /// Whether `key` holds a value of any type.
+    pub async fn contains(&self, key: &Bytes) -> bool {
+        self.kv.exists(key).await
+            || self.lists.contains(key).await
+            || self.sets.contains(key).await
+            || self.zsets.contains(key).await
+            || self.hashes.contains(key).await
+            || self.stream.contains(key).await
+    }
+
+    /// Keys in the database and how many of them have a TTL.
+    pub async fn counts(&self) -> (usize, usize) {
+        let (strings, expires) = self.kv.counts().await;
+        let keys = strings
+            + self.lists.key_count().await
+            + self.sets.key_count().await
+            + self.zsets.key_count().await
+            + self.hashes.key_count().await
+            + self.stream.key_count().await;
+        (keys, expires)
+    }
+
+    /// MOVE: hands `key` over to `to` unless it is missing here or already
+    /// exists there.
+    pub async fn move_key(&self, key: &Bytes, to: &Db) -> bool {
+        if !self.contains(key).await || to.contains(key).await {
+            return false;
+        }
+        let moved = self.kv.move_key(key, &to.kv).await
+            || self.lists.move_key(key, &to.lists).await
+            || self.sets.move_key(key, &to.sets).await
+            || self.zsets.move_key(key, &to.zsets).await
+            || self.hashes.move_key(key, &to.hashes).await
+            || self.stream.move_key(key, &to.stream).await;
+        if moved {
+            self.notifier
+                .notify(self.id(), NOTIFY_GENERIC, "move_from", key)
+                .await;
+            self.notifier
+                .notify(to.id(), NOTIFY_GENERIC, "move_to", key)
+                .await;
+        }
+        moved
+    }
+
+    /// The database as it is now; later writes don't change it.
+    pub async fn snapshot(&self) -> DbSnapshot {
+        DbSnapshot {
+            id: self.id(),
+            kv: self.kv.snapshot().await,
+            lists: self.lists.snapshot().await,
+            sets: self.sets.snapshot().await,
+            zsets: self.zsets.snapshot().await,
+            hashes: self.hashes.snapshot().await,
+            streams: self.stream.snapshot().await,
+        }
+    }
+}
+
+/// A database captured by `Db::snapshot`.
+pub struct DbSnapshot {
+    id: usize,
+    kv: KeyValueSnapshot,
+    lists: ListSnapshot,
+    sets: SetSnapshot,
+    zsets: SortedSetSnapshot,
+    hashes: HashSnapshot,
+    streams: StreamSnapshot,
+}
+
+impl DbSnapshot {
+    pub fn len(&self) -> usize {
+        self.kv.len()
+            + self.lists.len()
+            + self.sets.len()
+            + self.zsets.len()
+            + self.hashes.len()
+            + self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the database under its own SELECTDB, with a RESIZEDB hint.
+    /// Empty databases are left out, as Redis does.
+    pub fn save(&self, writer: &mut RdbWriter) {
+        if self.is_empty() {
+            return;
+        }
+        writer.select_db(self.id as u64);
+        writer.resize_db(self.len() as u64, self.kv.expires() as u64);
+        self.kv.save(writer);
+        self.lists.save(writer);
+        self.sets.save(writer);
+        self.zsets.save(writer);
+        self.hashes.save(writer);
+        self.streams.save(writer);
+    }
+}
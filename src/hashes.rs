@@ -0,0 +1,105 @@
+use crate::cow::Cow;
+use crate::rdb::RdbWriter;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+type HashesMap = im::HashMap<Bytes, Cow<HashMap<Bytes, Bytes>>>;
+
+pub struct Hash {
+    hashes: RwLock<HashesMap>,
+}
+
+impl Default for Hash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hash {
+    pub fn new() -> Self {
+        Self {
+            hashes: RwLock::new(im::HashMap::new()),
+        }
+    }
+
+    pub async fn hget(&self, key: &Bytes, field: &Bytes) -> Option<Bytes> {
+        let hashes = self.hashes.read().await;
+        hashes.get(key)?.get(field).cloned()
+    }
+
+    /// Fields and values, alternating.
+    pub async fn hgetall(&self, key: &Bytes) -> Vec<RedisValueRef> {
+        let hashes = self.hashes.read().await;
+        let Some(hash) = hashes.get(key) else {
+            return Vec::new();
+        };
+        hash.iter()
+            .flat_map(|(field, value)| {
+                [
+                    RedisValueRef::BulkString(field.clone()),
+                    RedisValueRef::BulkString(value.clone()),
+                ]
+            })
+            .collect()
+    }
+
+    pub async fn hlen(&self, key: &Bytes) -> i64 {
+        let hashes = self.hashes.read().await;
+        hashes.get(key).map_or(0, |hash| hash.len() as i64)
+    }
+
+    pub async fn contains(&self, key: &Bytes) -> bool {
+        let hashes = self.hashes.read().await;
+        hashes.contains_key(key)
+    }
+
+    /// How many keys hold a value of this type
+    pub async fn key_count(&self) -> usize {
+        self.hashes.read().await.len()
+    }
+
+    /// Moves `key`'s hash to `to`. Returns false if there is none here.
+    pub async fn move_key(&self, key: &Bytes, to: &Hash) -> bool {
+        let mut hashes = self.hashes.write().await;
+        if !hashes.contains_key(key) {
+            return false;
+        }
+        let Some(value) = hashes.remove(key) else {
+            return false;
+        };
+        to.hashes.write().await.insert(key.clone(), value);
+        true
+    }
+
+    /// Load a hash read from an RDB file
+    pub async fn load(&self, key: Bytes, fields: Vec<(Bytes, Bytes)>) {
+        let mut hashes = self.hashes.write().await;
+        hashes.insert(key, Cow::new(fields.into_iter().collect()));
+    }
+
+    /// The hashes as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> HashSnapshot {
+        HashSnapshot(self.hashes.read().await.clone())
+    }
+}
+
+/// Hashes captured by `Hash::snapshot`.
+pub struct HashSnapshot(im::HashMap<Bytes, Cow<HashMap<Bytes, Bytes>>>);
+
+impl HashSnapshot {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, hash) in self.0.iter() {
+            writer.hash(key, hash.iter());
+        }
+    }
+}
@@ -1,7 +1,30 @@
+pub mod acl;
+pub mod aof;
+pub mod cjson;
+pub mod client;
 pub mod commands;
-pub mod rdb;
+pub mod config;
+pub mod cow;
+pub mod crc64;
+pub mod db;
+pub mod hashes;
+pub mod listpack;
 pub mod lists;
+pub mod loading;
+pub mod log;
+pub mod lzf;
+pub mod module;
+pub mod notify;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
 pub mod redis;
 pub mod resp;
+pub mod scripting;
+pub mod sets;
+pub mod stream_node;
 pub mod streams;
+pub mod tracking;
 pub mod transactions;
+pub mod ziplist;
+pub mod zsets;
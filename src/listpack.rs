@@ -0,0 +1,223 @@
+use bytes::Bytes;
+
+/// Builds a listpack, the compact encoding RDB files use for small lists and
+/// stream nodes:
+///
+/// ```text
+/// total-bytes (u32) | num-elements (u16) | entry... | 0xFF
+/// entry: encoding | data | backlen
+/// ```
+///
+/// Strings that are canonical integers are stored as integers, like Redis
+/// does, and read back as the same text.
+pub struct Listpack {
+    buf: Vec<u8>,
+    count: usize,
+}
+
+impl Default for Listpack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Listpack {
+    pub fn new() -> Self {
+        Listpack {
+            buf: vec![0; 6],
+            count: 0,
+        }
+    }
+
+    /// Bytes used so far, without the terminator.
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn push_str(&mut self, s: &[u8]) {
+        if let Some(i) = canonical_int(s) {
+            return self.push_int(i);
+        }
+        let start = self.buf.len();
+        let len = s.len();
+        if len < 64 {
+            self.buf.push(0x80 | len as u8);
+        } else if len < 4096 {
+            self.buf.push(0xE0 | (len >> 8) as u8);
+            self.buf.push(len as u8);
+        } else {
+            self.buf.push(0xF0);
+            self.buf.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+        self.buf.extend_from_slice(s);
+        self.finish_entry(start);
+    }
+
+    pub fn push_int(&mut self, i: i64) {
+        let start = self.buf.len();
+        if (0..=127).contains(&i) {
+            self.buf.push(i as u8);
+        } else if (-4096..=4095).contains(&i) {
+            let u = (i as u16) & 0x1FFF;
+            self.buf.push(0xC0 | (u >> 8) as u8);
+            self.buf.push(u as u8);
+        } else if i16::try_from(i).is_ok() {
+            self.buf.push(0xF1);
+            self.buf.extend_from_slice(&(i as i16).to_le_bytes());
+        } else if (-(1 << 23)..(1 << 23)).contains(&i) {
+            self.buf.push(0xF2);
+            self.buf.extend_from_slice(&(i as i32).to_le_bytes()[..3]);
+        } else if i32::try_from(i).is_ok() {
+            self.buf.push(0xF3);
+            self.buf.extend_from_slice(&(i as i32).to_le_bytes());
+        } else {
+            self.buf.push(0xF4);
+            self.buf.extend_from_slice(&i.to_le_bytes());
+        }
+        self.finish_entry(start);
+    }
+
+    // Appends the entry's length, readable backwards from its end
+    fn finish_entry(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u64;
+        let size = backlen_size(len);
+        for i in (0..size).rev() {
+            let part = ((len >> (7 * i)) & 127) as u8;
+            self.buf.push(if i == size - 1 { part } else { part | 128 });
+        }
+        self.count += 1;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.push(0xFF);
+        let total = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&total.to_le_bytes());
+        let count = self.count.min(u16::MAX as usize) as u16;
+        self.buf[4..6].copy_from_slice(&count.to_le_bytes());
+        self.buf
+    }
+}
+
+/// An element read back from a listpack.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    Int(i64),
+    Str(Bytes),
+}
+
+impl Entry {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Entry::Int(i) => Some(*i),
+            Entry::Str(s) => std::str::from_utf8(s).ok()?.parse().ok(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        match self {
+            Entry::Int(i) => Bytes::from(i.to_string()),
+            Entry::Str(s) => s,
+        }
+    }
+}
+
+/// Every element of the listpack in `data`.
+pub fn decode(data: &[u8]) -> Result<Vec<Entry>, String> {
+    let truncated = || "listpack is truncated".to_string();
+    if data.len() < 7 {
+        return Err(truncated());
+    }
+    let total = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err("listpack size doesn't match its header".to_string());
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = 6;
+    loop {
+        let start = pos;
+        let byte = *data.get(pos).ok_or_else(truncated)?;
+        if byte == 0xFF {
+            break;
+        }
+        let take = |from: usize, n: usize| data.get(from..from + n).ok_or_else(truncated);
+        let entry = if byte & 0x80 == 0 {
+            pos += 1;
+            Entry::Int(byte as i64)
+        } else if byte & 0xC0 == 0x80 {
+            let len = (byte & 0x3F) as usize;
+            let s = take(pos + 1, len)?;
+            pos += 1 + len;
+            Entry::Str(Bytes::copy_from_slice(s))
+        } else if byte & 0xE0 == 0xC0 {
+            let low = *take(pos + 1, 1)?.first().unwrap();
+            let u = (((byte & 0x1F) as i64) << 8) | low as i64;
+            pos += 2;
+            Entry::Int(if u >= 1 << 12 { u - (1 << 13) } else { u })
+        } else if byte & 0xF0 == 0xE0 {
+            let low = *take(pos + 1, 1)?.first().unwrap();
+            let len = (((byte & 0x0F) as usize) << 8) | low as usize;
+            let s = take(pos + 2, len)?;
+            pos += 2 + len;
+            Entry::Str(Bytes::copy_from_slice(s))
+        } else {
+            match byte {
+                0xF0 => {
+                    let len = u32::from_le_bytes(take(pos + 1, 4)?.try_into().unwrap()) as usize;
+                    let s = take(pos + 5, len)?;
+                    pos += 5 + len;
+                    Entry::Str(Bytes::copy_from_slice(s))
+                }
+                0xF1 => {
+                    let v = i16::from_le_bytes(take(pos + 1, 2)?.try_into().unwrap());
+                    pos += 3;
+                    Entry::Int(v as i64)
+                }
+                0xF2 => {
+                    let b = take(pos + 1, 3)?;
+                    // Sign-extend from the top byte
+                    let v = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+                    pos += 4;
+                    Entry::Int(v as i64)
+                }
+                0xF3 => {
+                    let v = i32::from_le_bytes(take(pos + 1, 4)?.try_into().unwrap());
+                    pos += 5;
+                    Entry::Int(v as i64)
+                }
+                0xF4 => {
+                    let v = i64::from_le_bytes(take(pos + 1, 8)?.try_into().unwrap());
+                    pos += 9;
+                    Entry::Int(v)
+                }
+                _ => return Err(format!("invalid listpack encoding 0x{:02X}", byte)),
+            }
+        };
+        pos += backlen_size((pos - start) as u64);
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn backlen_size(len: u64) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+/// The integer `s` spells, if it's written the way Redis would print it.
+pub(crate) fn canonical_int(s: &[u8]) -> Option<i64> {
+    if s.is_empty() || s.len() > 20 {
+        return None;
+    }
+    let i: i64 = std::str::from_utf8(s).ok()?.parse().ok()?;
+    (i.to_string().as_bytes() == s).then_some(i)
+}
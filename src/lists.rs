@@ -1,66 +1,87 @@
+use crate::cow::Cow;
+use crate::notify::{DbNotifier, NOTIFY_GENERIC, NOTIFY_LIST, NOTIFY_NEW};
+use crate::rdb::RdbWriter;
 use crate::resp::RedisValueRef;
 use bytes::Bytes;
 use std::collections::{HashMap, VecDeque};
 use tokio::sync::{oneshot, RwLock};
 use tokio::time::{timeout, Duration};
 type BlockedClientsMap = HashMap<Bytes, VecDeque<oneshot::Sender<bool>>>;
+type ListMap = im::HashMap<Bytes, Cow<VecDeque<Bytes>>>;
 
 pub struct List {
     blocked: RwLock<BlockedClientsMap>,
-    lists: RwLock<HashMap<Bytes, VecDeque<Bytes>>>,
+    lists: RwLock<ListMap>,
+    notifier: DbNotifier,
 }
 
 impl List {
-    pub fn new() -> Self {
+    pub fn new(notifier: DbNotifier) -> Self {
         Self {
-            lists: RwLock::new(HashMap::new()),
+            lists: RwLock::new(im::HashMap::new()),
             blocked: RwLock::new(HashMap::new()),
+            notifier,
         }
     }
 
-    pub async fn rpush(&self, key: &Bytes, value: Bytes) -> i64 {
+    pub async fn rpush(&self, key: &Bytes, values: Vec<Bytes>) -> i64 {
+        self.push(key, values, false).await
+    }
+
+    pub async fn lpush(&self, key: &Bytes, values: Vec<Bytes>) -> i64 {
+        self.push(key, values, true).await
+    }
+
+    // Pushes all of `values` under one lock, with one event for the command
+    async fn push(&self, key: &Bytes, values: Vec<Bytes>, left: bool) -> i64 {
+        if values.is_empty() {
+            return self.llen(key).await;
+        }
         let mut lists = self.lists.write().await;
 
-        let list = lists.entry(key.clone()).or_insert_with(VecDeque::new);
-        list.push_back(value);
+        let pushed = values.len();
+        let list = lists.entry(key.clone()).or_default().make_mut();
+        let created = list.is_empty();
+        for value in values {
+            if left {
+                list.push_front(value);
+            } else {
+                list.push_back(value);
+            }
+        }
         let new_len = list.len() as i64;
 
         drop(lists);
 
-        let mut blocked_clients = self.blocked.write().await;
-        if let Some(notifiers) = blocked_clients.get_mut(key) {
-            if let Some(notifier) = notifiers.pop_front() {
-                let _ = notifier.send(true);
-
-                if notifiers.is_empty() {
-                    blocked_clients.remove(key);
-                }
-            }
+        if created {
+            self.notifier.notify(NOTIFY_NEW, "new", key).await;
         }
+        let event = if left { "lpush" } else { "rpush" };
+        self.notifier.notify(NOTIFY_LIST, event, key).await;
+        self.wake(key, pushed).await;
 
         new_len
     }
 
-    pub async fn lpush(&self, key: &Bytes, value: Bytes) -> i64 {
-        let mut lists = self.lists.write().await;
-
-        let list = lists.entry(key.clone()).or_insert_with(VecDeque::new);
-        list.push_front(value);
-        let new_len = list.len() as i64;
-
-        drop(lists);
-
+    // Hands `key` to the `count` clients that have been blocked on it the
+    // longest, one for each element there is to pop
+    async fn wake(&self, key: &Bytes, count: usize) {
         let mut blocked_clients = self.blocked.write().await;
         if let Some(notifiers) = blocked_clients.get_mut(key) {
-            if let Some(notifier) = notifiers.pop_front() {
-                let _ = notifier.send(true);
-                if notifiers.is_empty() {
-                    blocked_clients.remove(key);
+            let mut woken = 0;
+            // Skipping clients that have timed out since
+            while woken < count {
+                let Some(notifier) = notifiers.pop_front() else {
+                    break;
+                };
+                if notifier.send(true).is_ok() {
+                    woken += 1;
                 }
             }
+            if notifiers.is_empty() {
+                blocked_clients.remove(key);
+            }
         }
-
-        new_len
     }
 
     pub async fn llen(&self, key: &Bytes) -> i64 {
@@ -108,13 +129,12 @@ impl List {
     }
 
     pub async fn lpop(&self, key: &Bytes, count: usize) -> Option<Vec<RedisValueRef>> {
-        let mut lists = self.lists.write().await;
-
-        let list = lists.get_mut(key)?;
-
-        if list.is_empty() {
+        let mut guard = self.lists.write().await;
+        if guard.get(key).is_none_or(|list| list.is_empty()) {
             return None;
         }
+        let lists = &mut *guard;
+        let list = lists.get_mut(key)?.make_mut();
 
         let mut res = Vec::new();
         for _ in 0..count {
@@ -125,70 +145,108 @@ impl List {
             }
         }
 
-        if list.is_empty() {
+        let emptied = list.is_empty();
+        if emptied {
             lists.remove(key);
         }
+        drop(guard);
 
+        self.notify_pop(key, emptied).await;
         Some(res)
     }
 
-    pub async fn blpop(&self, key: &Bytes, duration: Duration) -> RedisValueRef {
-        {
-            let mut lists = self.lists.write().await;
-            if let Some(list) = lists.get_mut(key) {
-                if let Some(value) = list.pop_front() {
-                    // Clean up empty list
-                    if list.is_empty() {
-                        lists.remove(key);
-                    }
-                    return RedisValueRef::Array(vec![
-                        RedisValueRef::BulkString(key.clone()),
-                        RedisValueRef::BulkString(value),
-                    ]);
-                }
-            }
+    async fn notify_pop(&self, key: &Bytes, emptied: bool) {
+        self.notifier.notify(NOTIFY_LIST, "lpop", key).await;
+        if emptied {
+            self.notifier.notify(NOTIFY_GENERIC, "del", key).await;
         }
+    }
 
-        let (tx, rx) = oneshot::channel::<bool>();
-
-        {
-            let mut blocked_clients = self.blocked.write().await;
-            blocked_clients
+    /// Waits up to `duration` for `key` to hold a list, as BLPOP does
+    /// between pop attempts. False if it timed out.
+    pub async fn wait_for_push(&self, key: &Bytes, duration: Duration) -> bool {
+        let rx = {
+            // Registered with the lists locked, so a push can't land between
+            // the check and the registration unnoticed
+            let lists = self.lists.read().await;
+            if lists.get(key).is_some_and(|list| !list.is_empty()) {
+                return true;
+            }
+            let (tx, rx) = oneshot::channel::<bool>();
+            self.blocked
+                .write()
+                .await
                 .entry(key.clone())
                 .or_default()
                 .push_back(tx);
+            rx
+        };
+        if let Ok(Ok(_)) = timeout(duration, rx).await {
+            return true;
         }
-
-        match timeout(duration, rx).await {
-            Ok(Ok(_)) => {
-                let mut lists = self.lists.write().await;
-                if let Some(list) = lists.get_mut(key) {
-                    if let Some(value) = list.pop_front() {
-                        if list.is_empty() {
-                            lists.remove(key);
-                        }
-                        return RedisValueRef::Array(vec![
-                            RedisValueRef::BulkString(key.clone()),
-                            RedisValueRef::BulkString(value),
-                        ]);
-                    }
-                }
-                RedisValueRef::NullArray
-            }
-            Ok(Err(_)) | Err(_) => {
-                let mut blocked_clients = self.blocked.write().await;
-                if let Some(notifiers) = blocked_clients.get_mut(key) {
-                    if notifiers.is_empty() {
-                        blocked_clients.remove(key);
-                    }
-                }
-                RedisValueRef::NullArray
+        let mut blocked_clients = self.blocked.write().await;
+        if let Some(notifiers) = blocked_clients.get_mut(key) {
+            notifiers.retain(|notifier| !notifier.is_closed());
+            if notifiers.is_empty() {
+                blocked_clients.remove(key);
             }
         }
+        false
     }
 
     pub async fn contains(&self, key: &Bytes) -> bool {
         let lists = self.lists.read().await;
         lists.contains_key(key)
     }
+
+    /// How many keys hold a value of this type
+    pub async fn key_count(&self) -> usize {
+        self.lists.read().await.len()
+    }
+
+    /// Moves `key`'s list to `to`. Returns false if there is none here.
+    pub async fn move_key(&self, key: &Bytes, to: &List) -> bool {
+        let mut lists = self.lists.write().await;
+        if !lists.contains_key(key) {
+            return false;
+        }
+        let Some(list) = lists.remove(key) else {
+            return false;
+        };
+        drop(lists);
+        let len = list.len();
+        to.lists.write().await.insert(key.clone(), list);
+        to.wake(key, len).await;
+        true
+    }
+
+    /// Load a list read from an RDB file
+    pub async fn load(&self, key: Bytes, items: Vec<Bytes>) {
+        let mut lists = self.lists.write().await;
+        lists.insert(key, Cow::new(VecDeque::from(items)));
+    }
+
+    /// The lists as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> ListSnapshot {
+        ListSnapshot(self.lists.read().await.clone())
+    }
+}
+
+/// Lists captured by `List::snapshot`.
+pub struct ListSnapshot(im::HashMap<Bytes, Cow<VecDeque<Bytes>>>);
+
+impl ListSnapshot {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, list) in self.0.iter() {
+            writer.list(key, list.iter());
+        }
+    }
 }
@@ -0,0 +1,125 @@
+use crate::persistence::unix_time;
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+/// Progress of loading the dataset, from disk at startup or from the master
+/// after a full resync. Clients get -LOADING until it is done.
+pub struct Loading {
+    loading: AtomicBool,
+    // Unix time in seconds loading started, for INFO
+    start_time: AtomicU64,
+    // When loading started, for the ETA
+    started: RwLock<Option<Instant>>,
+    total_bytes: AtomicU64,
+    loaded_bytes: AtomicU64,
+}
+
+impl Default for Loading {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Loading {
+    pub fn new() -> Self {
+        Loading {
+            loading: AtomicBool::new(false),
+            start_time: AtomicU64::new(0),
+            started: RwLock::new(None),
+            total_bytes: AtomicU64::new(0),
+            loaded_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::SeqCst)
+    }
+
+    /// Starts turning clients away, before the size of what is loaded is
+    /// known.
+    pub async fn start(&self) {
+        *self.started.write().await = Some(Instant::now());
+        self.start_time.store(unix_time(), Ordering::SeqCst);
+        self.total_bytes.store(0, Ordering::SeqCst);
+        self.loaded_bytes.store(0, Ordering::SeqCst);
+        self.loading.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_total(&self, total: u64) {
+        self.total_bytes.store(total, Ordering::SeqCst);
+        self.loaded_bytes.store(0, Ordering::SeqCst);
+    }
+
+    pub fn set_loaded(&self, loaded: u64) {
+        self.loaded_bytes.store(loaded, Ordering::SeqCst);
+    }
+
+    pub async fn finish(&self) {
+        self.loading.store(false, Ordering::SeqCst);
+        *self.started.write().await = None;
+    }
+
+    /// Wraps `reader` so the bytes read through it count as loaded.
+    pub fn reader<R: Read>(&self, reader: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner: reader,
+            loading: self,
+        }
+    }
+
+    pub fn loaded_bytes(&self) -> u64 {
+        self.loaded_bytes.load(Ordering::SeqCst)
+    }
+
+    pub async fn info(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "loading:{}", self.is_loading() as u8).unwrap();
+        let Some(started) = *self.started.read().await else {
+            return s;
+        };
+        let total = self.total_bytes.load(Ordering::SeqCst);
+        let loaded = self.loaded_bytes().min(total);
+        let perc = if total == 0 {
+            0.0
+        } else {
+            loaded as f64 * 100.0 / total as f64
+        };
+        // Assumes the rest loads as fast as what came before it
+        let elapsed = started.elapsed().as_secs_f64();
+        let eta = if loaded == 0 || elapsed == 0.0 {
+            1
+        } else {
+            ((total - loaded) as f64 * elapsed / loaded as f64).ceil() as u64
+        };
+        writeln!(
+            s,
+            "loading_start_time:{}",
+            self.start_time.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(s, "loading_total_bytes:{}", total).unwrap();
+        writeln!(s, "loading_loaded_bytes:{}", loaded).unwrap();
+        writeln!(s, "loading_loaded_perc:{:.2}", perc).unwrap();
+        writeln!(s, "loading_eta_seconds:{}", eta).unwrap();
+        s
+    }
+}
+
+/// Passes reads through, adding them to the loaded byte count.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    loading: &'a Loading,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.loading
+            .loaded_bytes
+            .fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+}
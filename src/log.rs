@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the server logs, from the most verbose. Set with loglevel.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Notice as u8);
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Debug, Level::Verbose, Level::Notice, Level::Warning];
+
+    pub fn parse(s: &str) -> Option<Level> {
+        Level::ALL
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(s))
+    }
+
+    /// The level redis.log is called with: redis.LOG_DEBUG is 0.
+    pub fn from_index(i: i64) -> Option<Level> {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| Level::ALL.get(i).copied())
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Verbose => "verbose",
+            Level::Notice => "notice",
+            Level::Warning => "warning",
+        }
+    }
+}
+
+pub fn level() -> Level {
+    Level::ALL[LEVEL.load(Ordering::SeqCst) as usize]
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+/// Writes `msg` if loglevel lets `level` through. Warnings go to stderr,
+/// like the server's other errors.
+pub fn log(level: Level, msg: &str) {
+    if level < self::level() {
+        return;
+    }
+    if level == Level::Warning {
+        eprintln!("{}", msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+/// Logs a `format!` message at `Warning`, for errors worth the operator's
+/// attention.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warning, &format!($($arg)*))
+    };
+}
+
+/// Logs a `format!` message at `Notice`, for normal but significant events.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_notice {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Notice, &format!($($arg)*))
+    };
+}
+
+pub use __log_notice as notice;
+pub use __log_warn as warn;
@@ -0,0 +1,136 @@
+/// LZF, the compression RDB files use for strings when `rdbcompression` is
+/// on. The stream is a series of chunks, each starting with a control byte:
+///
+/// ```text
+/// 000LLLLL                     literal run of L + 1 bytes
+/// LLLooooo oooooooo            back reference, length L + 2 (L < 7)
+/// 111ooooo LLLLLLLL oooooooo   back reference, length L + 9
+/// ```
+///
+/// A back reference copies from `offset + 1` bytes behind the output end.
+const HASH_LOG: u32 = 14;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_MATCH: usize = (1 << 8) + (1 << 3);
+// The most output one input byte can stand for: a 3-byte back reference
+// of MAX_MATCH bytes
+const MAX_EXPANSION: usize = MAX_MATCH / 3;
+
+/// Decompresses `input`, which must expand to exactly `len` bytes.
+pub fn decompress(input: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let corrupt = || "Invalid LZF compressed string".to_string();
+    // Checked before allocating, as `len` comes from the file too
+    if len > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(corrupt());
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < MAX_LITERAL {
+            if out.len() + ctrl + 1 > len {
+                return Err(corrupt());
+            }
+            let run = input.get(ip..ip + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            ip += ctrl + 1;
+            continue;
+        }
+
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += *input.get(ip).ok_or_else(corrupt)? as usize;
+            ip += 1;
+        }
+        let offset = ((ctrl & 0x1F) << 8) | *input.get(ip).ok_or_else(corrupt)? as usize;
+        ip += 1;
+        let start = out.len().checked_sub(offset + 1).ok_or_else(corrupt)?;
+        if out.len() + run + 2 > len {
+            return Err(corrupt());
+        }
+        // The reference may overlap what it produces
+        for i in 0..run + 2 {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+/// Compresses `input`, or None if that wouldn't make it smaller.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let n = input.len();
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut out = Vec::with_capacity(n);
+    // Position of the current literal run's control byte
+    let mut lit_pos = 0;
+    let mut lit = 0;
+    out.push(0);
+
+    let mut ip = 0;
+    while ip < n {
+        if ip + 2 < n {
+            let key = (input[ip] as u32) << 16 | (input[ip + 1] as u32) << 8 | input[ip + 2] as u32;
+            let h = (key.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize;
+            // Positions are stored plus one so zero means empty
+            let candidate = table[h];
+            table[h] = ip + 1;
+            if candidate != 0 {
+                let r = candidate - 1;
+                let offset = ip - r - 1;
+                if offset < MAX_OFFSET && input[r..r + 3] == input[ip..ip + 3] {
+                    let max = (n - ip).min(MAX_MATCH);
+                    let mut len = 3;
+                    while len < max && input[r + len] == input[ip + len] {
+                        len += 1;
+                    }
+
+                    if lit == 0 {
+                        out.pop();
+                    } else {
+                        out[lit_pos] = (lit - 1) as u8;
+                    }
+                    let l = len - 2;
+                    if l < 7 {
+                        out.push(((l << 5) | (offset >> 8)) as u8);
+                    } else {
+                        out.push(((7 << 5) | (offset >> 8)) as u8);
+                        out.push((l - 7) as u8);
+                    }
+                    out.push(offset as u8);
+                    lit_pos = out.len();
+                    out.push(0);
+                    lit = 0;
+                    ip += len;
+                    if out.len() >= n {
+                        return None;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        out.push(input[ip]);
+        lit += 1;
+        ip += 1;
+        if lit == MAX_LITERAL {
+            out[lit_pos] = (lit - 1) as u8;
+            lit_pos = out.len();
+            out.push(0);
+            lit = 0;
+        }
+        if out.len() >= n {
+            return None;
+        }
+    }
+
+    if lit == 0 {
+        out.pop();
+    } else {
+        out[lit_pos] = (lit - 1) as u8;
+    }
+    (out.len() < n).then_some(out)
+}
@@ -1,12 +1,20 @@
+use bytes::{Buf, Bytes, BytesMut};
 use clap::Parser;
 use futures::{SinkExt, StreamExt};
+use redis::acl;
+use redis::aof::{self, AppendFsync};
+use redis::client::Client;
 use redis::commands::handle_command;
-use redis::redis::Redis;
-use redis::resp::RespParser;
+use redis::config;
+use redis::log;
+use redis::persistence;
+use redis::rdb::RdbError;
+use redis::redis::{Redis, DEFAULT_DATABASES};
+use redis::resp::{RedisValueRef, RespParser};
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_util::codec::Framed;
+use tokio_util::codec::{Framed, FramedParts};
 
 #[derive(Parser, Debug)]
 #[command(name = "myapp", version = "1.0", about = "port")]
@@ -20,6 +28,34 @@ struct Args {
     dbfilename: Option<String>,
     #[arg(short, long)]
     replicaof: Option<String>,
+    #[arg(long)]
+    requirepass: Option<String>,
+    #[arg(long)]
+    aclfile: Option<String>,
+    /// Save points as "<seconds> <changes> ..."
+    #[arg(long)]
+    save: Option<String>,
+    /// "yes" or "no": LZF-compress strings in the RDB file
+    #[arg(long)]
+    rdbcompression: Option<String>,
+    /// "yes" or "no": write and verify the RDB file's CRC64
+    #[arg(long)]
+    rdbchecksum: Option<String>,
+    /// Number of logical databases, selected with SELECT
+    #[arg(long)]
+    databases: Option<usize>,
+    /// "yes" or "no": log every write to the append-only file
+    #[arg(long)]
+    appendonly: Option<String>,
+    /// Name of the append-only file, in --dir
+    #[arg(long)]
+    appendfilename: Option<String>,
+    /// "always", "everysec" or "no": when the append-only file is fsynced
+    #[arg(long)]
+    appendfsync: Option<String>,
+    /// "debug", "verbose", "notice" or "warning": what redis.log writes
+    #[arg(long)]
+    loglevel: Option<String>,
 }
 
 #[tokio::main]
@@ -33,27 +69,142 @@ async fn main() {
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
         .await
         .unwrap();
-    let redis = Arc::new(Redis::new());
+    let databases = args.databases.unwrap_or(DEFAULT_DATABASES);
+    if databases == 0 {
+        eprintln!("databases must be at least 1");
+        std::process::exit(1);
+    }
+    let redis = Arc::new(Redis::with_databases(databases));
 
-    // Load RDB
-    let _ = match (&args.dir, &args.dbfilename) {
-        (Some(dir), Some(filename)) => {
-            redis
-                .kv
-                .load_from_rdb_file(dir.clone(), filename.clone())
-                .await
+    if let Some(save) = &args.save {
+        if let Err(e) = redis.persistence.set_save_params(save).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-        _ => Ok(()),
-    };
+    }
+
+    if let Some(value) = &args.rdbcompression {
+        match config::parse_yes_no("rdbcompression", value) {
+            Ok(on) => redis.persistence.set_rdbcompression(on),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(value) = &args.rdbchecksum {
+        match config::parse_yes_no("rdbchecksum", value) {
+            Ok(on) => redis.persistence.set_rdbchecksum(on),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Connect to master
-    if let Some(replicaof) = &args.replicaof {
-        let addr = replicaof.clone();
+    if let Some(value) = &args.appendonly {
+        match config::parse_yes_no("appendonly", value) {
+            Ok(on) => redis.aof.set_enabled(on),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(filename) = &args.appendfilename {
+        if let Err(e) = redis.aof.set_filename(filename.clone()).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(value) = &args.appendfsync {
+        match AppendFsync::parse(value) {
+            Some(fsync) => redis.aof.set_fsync(fsync).await,
+            None => {
+                eprintln!("appendfsync must be one of always, everysec or no");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(value) = &args.loglevel {
+        match log::Level::parse(value) {
+            Some(level) => log::set_level(level),
+            None => {
+                eprintln!("loglevel must be one of debug, verbose, notice or warning");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(dir) = &args.dir {
+        redis.persistence.set_dir(dir.clone()).await;
+    }
+
+    // Users from the ACL file replace the default user's requirepass
+    if let Some(password) = &args.requirepass {
+        redis.acl.set_requirepass(password).await;
+    }
+    if let Some(path) = &args.aclfile {
+        if let Err(e) = redis.acl.load_file(path.clone()).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // Load the dataset in the background, once the options that affect
+    // reading it are set. Connections are accepted meanwhile and get
+    // -LOADING; a replica only syncs with its master afterwards.
+    redis.loading.start().await;
+    if args.replicaof.is_some() {
         redis.info.set_role("slave").await;
-        let redis_clone = redis.clone();
-        let port_clone = port.clone();
-        connect_to_master(redis_clone, &addr, &port_clone).await;
     }
+    let load_redis = redis.clone();
+    let (dir, dbfilename, replicaof) = (args.dir, args.dbfilename, args.replicaof);
+    let master_port = port.clone();
+    tokio::spawn(async move {
+        load_dataset(&load_redis, dir, dbfilename).await;
+        load_redis.loading.finish().await;
+        if let Some(addr) = replicaof {
+            connect_to_master(load_redis, &addr, &master_port).await;
+        }
+    });
+
+    // Actively expire keys nobody reads
+    let expire_redis = redis.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            // Keys must not change under a CLIENT PAUSE, nor while loading
+            if expire_redis.clients.is_paused().await || expire_redis.loading.is_loading() {
+                continue;
+            }
+            let _guard = expire_redis.exec_lock.read().await;
+            for db in expire_redis.dbs().await {
+                db.kv.expire_cycle().await;
+            }
+        }
+    });
+
+    // Start a background save when a save point is due
+    let save_redis = redis.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            persistence::save_cron(&save_redis).await;
+        }
+    });
+
+    // Flush the append-only file when appendfsync is everysec
+    let fsync_redis = redis.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            fsync_redis.aof.fsync_cron().await;
+        }
+    });
 
     //accept connections in a loop
     loop {
@@ -61,38 +212,109 @@ async fn main() {
             Ok((stream, addr)) => {
                 println!("accepted new connection from: {addr}");
                 let redis = redis.clone();
+
+                // Pub/sub messages, subscription replies and invalidations for this client
+                let (push_tx, mut push_rx) = mpsc::unbounded_channel();
+                let client = Arc::new(Client::new(addr, stream.local_addr().unwrap(), push_tx));
+
                 tokio::spawn(async move {
                     let mut framed = Framed::new(stream, RespParser);
+                    redis.add_client(client.clone()).await;
+
+                    loop {
+                        let result = tokio::select! {
+                            result = framed.next() => match result {
+                                Some(result) => result,
+                                None => break,
+                            },
+                            Some(message) = push_rx.recv() => {
+                                let len = message.encoded_len();
+                                // A subscriber killed for not reading may never
+                                // let this write finish
+                                let sent = tokio::select! {
+                                    sent = framed.send(message) => sent,
+                                    _ = client.killed() => break,
+                                };
+                                if let Err(e) = sent {
+                                    eprintln!("Failed to send pushed message: {:?}", e);
+                                    break;
+                                }
+                                client.push_sent(len);
+                                continue;
+                            }
+                            _ = client.killed() => break,
+                        };
+                        client.set_query_buf(framed.read_buffer().len());
 
-                    while let Some(result) = framed.next().await {
                         match result {
                             Ok(value) => {
                                 // Check if this is a PSYNC command
-                                if is_psync_command(&value) {
+                                if is_psync_command(&value) && client.is_authenticated().await {
+                                    if let Err(e) = acl::enforce_sync(&redis, &client).await {
+                                        if let Err(e) = framed.send(e).await {
+                                            eprintln!("Failed to send response: {:?}", e);
+                                            break;
+                                        }
+                                        continue;
+                                    }
+                                    // There is no dataset to send until it has loaded
+                                    if redis.loading.is_loading() {
+                                        let loading = RedisValueRef::Error(Bytes::from(
+                                            "LOADING Redis is loading the dataset in memory",
+                                        ));
+                                        if let Err(e) = framed.send(loading).await {
+                                            eprintln!("Failed to send response: {:?}", e);
+                                            break;
+                                        }
+                                        continue;
+                                    }
+
                                     let mut stream = framed.into_inner();
 
-                                    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(100);
-                                    redis.add_slave(tx).await;
+                                    // Captured with writers held off, and the replica added before
+                                    // they resume, so each write is either in the snapshot or
+                                    // queued for the replica after it
+                                    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                                    let (capture, replid, offset) = {
+                                        let _guard = redis.exec_lock.write().await;
+                                        let capture = persistence::capture(&redis).await;
+                                        redis.add_slave(tx).await;
+                                        (
+                                            capture,
+                                            redis.info.master_replid().await,
+                                            redis.info.master_repl_offset().await,
+                                        )
+                                    };
 
                                     // Send FULLRESYNC response
-                                    let fullresync = format!(
-                                        "+FULLRESYNC {} {}\r\n",
-                                        redis.info.master_replid().await,
-                                        redis.info.master_repl_offset().await
-                                    );
+                                    let fullresync =
+                                        format!("+FULLRESYNC {} {}\r\n", replid, offset);
                                     if stream.write_all(fullresync.as_bytes()).await.is_err() {
                                         eprintln!("Failed to send FULLRESYNC to slave");
                                         break;
                                     }
 
-                                    // Send RDB file
-                                    let empty_rdb = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2").unwrap();
-                                    let rdb_response = format!("${}\r\n", empty_rdb.len());
+                                    // Send the RDB file; writes made meanwhile wait in the channel
+                                    let rdb = match tokio::task::spawn_blocking(move || {
+                                        capture.serialize()
+                                    })
+                                    .await
+                                    {
+                                        Ok(snapshot) => snapshot.data,
+                                        Err(e) => {
+                                            eprintln!(
+                                                "Failed to serialize the RDB for the slave: {}",
+                                                e
+                                            );
+                                            break;
+                                        }
+                                    };
+                                    let rdb_response = format!("${}\r\n", rdb.len());
                                     if stream.write_all(rdb_response.as_bytes()).await.is_err() {
                                         eprintln!("Failed to send RDB response to slave");
                                         break;
                                     }
-                                    if stream.write_all(&empty_rdb).await.is_err() {
+                                    if stream.write_all(&rdb).await.is_err() {
                                         eprintln!("Failed to send RDB file to slave");
                                         break;
                                     }
@@ -122,12 +344,35 @@ async fn main() {
                                 }
 
                                 // Normal command handling
-                                if let Some(response) = handle_command(value, addr, &redis).await {
+                                let response = handle_command(value, &client, &redis).await;
+
+                                // Flush anything the command queued (e.g. SUBSCRIBE
+                                // replies) so pushes stay ordered with responses
+                                let mut failed = false;
+                                while let Ok(message) = push_rx.try_recv() {
+                                    let len = message.encoded_len();
+                                    if let Err(e) = framed.send(message).await {
+                                        eprintln!("Failed to send pushed message: {:?}", e);
+                                        failed = true;
+                                        break;
+                                    }
+                                    client.push_sent(len);
+                                }
+                                if failed {
+                                    break;
+                                }
+
+                                if let Some(response) = response {
                                     if let Err(e) = framed.send(response).await {
                                         eprintln!("Failed to send response: {:?}", e);
                                         break;
                                     }
                                 }
+
+                                // CLIENT KILL on ourselves still gets its reply first
+                                if client.is_killed() {
+                                    break;
+                                }
                             }
                             Err(e) => {
                                 eprintln!("Parse error: {:?}", e);
@@ -136,6 +381,7 @@ async fn main() {
                         }
                     }
 
+                    redis.remove_client(client.id).await;
                     println!("Connection closed: {addr}");
                 });
             }
@@ -146,9 +392,52 @@ async fn main() {
     }
 }
 
+// Loads the dataset at startup: from the append-only file when there is one,
+// as it is more recent, or else from the RDB file. A missing file just means
+// an empty dataset. Any other failure is fatal, rather than serving, and
+// later saving, whatever part of the dataset was loaded.
+async fn load_dataset(redis: &Arc<Redis>, dir: Option<String>, dbfilename: Option<String>) {
+    let started = std::time::Instant::now();
+    let aof_path = redis.aof.path(&redis.persistence.dir().await).await;
+    if redis.aof.enabled() && aof_path.exists() {
+        if let Err(e) = aof::load(redis).await {
+            log::warn!("Fatal error loading the append only file: {}. Exiting.", e);
+            std::process::exit(1);
+        }
+        log::notice!(
+            "DB loaded from append only file: {:.3} seconds",
+            started.elapsed().as_secs_f64()
+        );
+    } else if let (Some(dir), Some(dbfilename)) = (dir, dbfilename) {
+        let path = format!("{}/{}", dir, dbfilename);
+        match redis.load_rdb_file(dir, dbfilename).await {
+            Err(RdbError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                log::warn!(
+                    "Fatal error loading the RDB file {} after {} bytes: {}. Exiting.",
+                    path,
+                    redis.loading.loaded_bytes(),
+                    e
+                );
+                std::process::exit(1);
+            }
+            Ok(()) => log::notice!(
+                "DB loaded from disk: {:.3} seconds",
+                started.elapsed().as_secs_f64()
+            ),
+        }
+    }
+    if redis.aof.enabled() {
+        if let Err(e) = aof::start(redis).await {
+            log::warn!("Can't open the append only file: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn is_psync_command(value: &redis::resp::RedisValueRef) -> bool {
     if let redis::resp::RedisValueRef::Array(arr) = value {
-        if let Some(redis::resp::RedisValueRef::String(cmd)) = arr.get(0) {
+        if let Some(redis::resp::RedisValueRef::String(cmd)) = arr.first() {
             return cmd.as_ref().eq_ignore_ascii_case(b"PSYNC");
         }
     }
@@ -160,50 +449,148 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 async fn connect_to_master(redis: Arc<Redis>, master_addr: &str, port: &str) {
     let (host, mport) = master_addr.split_once(' ').unwrap();
     let addr = format!("{host}:{mport}");
-    println!("Connecting to master at {}", addr);
-
-    match tokio::net::TcpStream::connect(&addr).await {
-        Ok(mut stream) => {
-            println!("Connected to master");
-
-            //Send PING
-            stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
-            let mut buf = vec![0u8; 1024];
-            let n = stream.read(&mut buf).await.unwrap();
-            println!("Master replied: {}", String::from_utf8_lossy(&buf[..n]));
-
-            //Send REPLCONF listening-port
-            let cmd = format!(
-                "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n${}\r\n{}\r\n",
-                port.len(),
-                port
-            );
-            stream.write_all(cmd.as_bytes()).await.unwrap();
-            let n = stream.read(&mut buf).await.unwrap();
-            println!("Master replied: {}", String::from_utf8_lossy(&buf[..n]));
-
-            //Send REPLCONF capa psync2
-            stream
-                .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n")
-                .await
-                .unwrap();
-            let n = stream.read(&mut buf).await.unwrap();
-            println!("Master replied: {}", String::from_utf8_lossy(&buf[..n]));
-
-            //Send PSYNC ? -1
-            stream
-                .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
-                .await
-                .unwrap();
-            let n = stream.read(&mut buf).await.unwrap();
-            println!("Master replied: {}", String::from_utf8_lossy(&buf[..n]));
-
-            //TODO Parse and load the RDB file sent after FULLRESYNC
-            let n = stream.read(&mut buf).await.unwrap();
-           // redis.kv.load_from_rdb(&buf[..n]).await.unwrap();
-        }
-        Err(e) => {
-            eprintln!("Failed to connect to master: {}", e);
+
+    // Until a full sync succeeds, try again every second like Redis's
+    // replication cron, rather than serving data the master doesn't have
+    let mut framed = loop {
+        match sync_with_master(&redis, &addr, port).await {
+            Ok(framed) => break framed,
+            Err(e) => {
+                log::warn!("Sync with master at {} failed: {}. Retrying", addr, e);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    };
+
+    // Apply the propagated command stream in the background
+    // The master link gets its own client context; nothing reads its pushes
+    let (push_tx, _) = mpsc::unbounded_channel();
+    let master = Arc::new(Client::new_master(
+        framed.get_ref().peer_addr().unwrap(),
+        framed.get_ref().local_addr().unwrap(),
+        push_tx,
+    ));
+    redis.add_client(master.clone()).await;
+    tokio::spawn(async move {
+        loop {
+            let value = tokio::select! {
+                value = framed.next() => match value {
+                    Some(Ok(value)) => value,
+                    _ => break,
+                },
+                _ = master.killed() => break,
+            };
+            handle_command(value, &master, &redis).await;
+        }
+        redis.remove_client(master.id).await;
+        log::notice!("Master connection closed");
+    });
+}
+
+// Runs the handshake and a full resync, replacing our dataset with the
+// master's. Returns the link, with any commands already read after the RDB
+// left buffered in it.
+async fn sync_with_master(
+    redis: &Arc<Redis>,
+    addr: &str,
+    port: &str,
+) -> Result<Framed<tokio::net::TcpStream, RespParser>, String> {
+    log::notice!("Connecting to master at {}", addr);
+    let mut stream = tokio::net::TcpStream::connect(addr)
+        .await
+        .map_err(|e| format!("can't connect: {}", e))?;
+    log::notice!("Connected to master");
+
+    let listening_port = format!(
+        "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n${}\r\n{}\r\n",
+        port.len(),
+        port
+    );
+    let handshake: [&[u8]; 3] = [
+        b"*1\r\n$4\r\nPING\r\n",
+        listening_port.as_bytes(),
+        b"*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n",
+    ];
+    let mut buf = vec![0u8; 1024];
+    for cmd in handshake {
+        stream.write_all(cmd).await.map_err(|e| e.to_string())?;
+        let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("master closed the connection during the handshake".to_string());
+        }
+        log::notice!(
+            "Master replied: {}",
+            String::from_utf8_lossy(&buf[..n]).trim_end()
+        );
+    }
+
+    stream
+        .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // FULLRESYNC, the RDB payload and the first propagated commands
+    // may all arrive in a single read, so buffer explicitly
+    let mut pending = BytesMut::new();
+    let reply = read_line(&mut stream, &mut pending)
+        .await
+        .ok_or("master closed the connection during PSYNC")?;
+    log::notice!("Master replied: {}", String::from_utf8_lossy(&reply));
+    if !reply.starts_with(b"+FULLRESYNC") {
+        return Err(format!(
+            "unexpected reply to PSYNC: {}",
+            String::from_utf8_lossy(&reply)
+        ));
+    }
+
+    //Load the RDB file sent after FULLRESYNC ($<len>\r\n<payload>, no trailing CRLF)
+    let header = read_line(&mut stream, &mut pending)
+        .await
+        .ok_or("master closed the connection before sending the RDB")?;
+    let len = header
+        .strip_prefix(b"$")
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse::<usize>().ok())
+        .ok_or("invalid RDB payload header from master")?;
+    while pending.len() < len {
+        if stream.read_buf(&mut pending).await.unwrap_or(0) == 0 {
+            return Err("master closed the connection while sending the RDB".to_string());
+        }
+    }
+    let rdb = pending.split_to(len);
+    redis.loading.start().await;
+    // The master's data replaces ours rather than merging into it
+    redis.flush_all().await;
+    if let Err(e) = redis.load_rdb(&rdb).await {
+        // Whatever part of it loaded is dropped along with the link
+        redis.flush_all().await;
+        redis.loading.finish().await;
+        return Err(format!("can't load the RDB from master: {}", e));
+    }
+    // The master's data replaces ours, so the log starts over from it
+    if redis.aof.enabled() {
+        let _guard = redis.exec_lock.write().await;
+        if let Err(e) = aof::rewrite(redis).await {
+            log::warn!("Can't rewrite the append only file: {}", e);
+        }
+    }
+    redis.loading.finish().await;
+
+    let mut parts = FramedParts::new::<RedisValueRef>(stream, RespParser);
+    parts.read_buf = pending;
+    Ok(Framed::from_parts(parts))
+}
+
+// Reads one CRLF-terminated line, buffering any bytes that follow it
+async fn read_line(stream: &mut tokio::net::TcpStream, pending: &mut BytesMut) -> Option<BytesMut> {
+    loop {
+        if let Some(pos) = pending.windows(2).position(|w| w == b"\r\n") {
+            let line = pending.split_to(pos);
+            pending.advance(2);
+            return Some(line);
+        }
+        if stream.read_buf(pending).await.ok()? == 0 {
+            return None;
         }
     }
 }
@@ -0,0 +1,397 @@
+use crate::acl::map_reply;
+use crate::client::Client;
+use crate::redis::Redis;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// Flags a module command may declare
+const COMMAND_FLAGS: &[&str] = &["write", "readonly", "admin", "fast", "no-script"];
+
+/// A set of commands and data types registered together, loaded with
+/// `Redis::load_module` before the server starts accepting connections.
+pub trait Module: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn version(&self) -> i64 {
+        1
+    }
+
+    /// Registers the module's commands and data types.
+    fn load(&self, registry: &mut Registry) -> Result<(), String>;
+}
+
+/// Runs a module command. `args` excludes the command name.
+pub trait CommandHandler: Send + Sync {
+    fn call<'a>(&'a self, ctx: Context<'a>, args: Vec<Bytes>) -> BoxFuture<'a, RedisValueRef>;
+}
+
+impl<F> CommandHandler for F
+where
+    F: for<'a> Fn(Context<'a>, Vec<Bytes>) -> BoxFuture<'a, RedisValueRef> + Send + Sync,
+{
+    fn call<'a>(&'a self, ctx: Context<'a>, args: Vec<Bytes>) -> BoxFuture<'a, RedisValueRef> {
+        self(ctx, args)
+    }
+}
+
+/// Wraps a closure as a handler, e.g.
+/// `command_handler(|ctx, args| async move { ... }.boxed())`.
+pub fn command_handler<F>(f: F) -> Arc<dyn CommandHandler>
+where
+    F: for<'a> Fn(Context<'a>, Vec<Bytes>) -> BoxFuture<'a, RedisValueRef> + Send + Sync + 'static,
+{
+    Arc::new(f)
+}
+
+/// What a command handler can reach: the keyspace and the calling client,
+/// whose `db()` is the database the command runs in.
+pub struct Context<'a> {
+    pub redis: &'a Arc<Redis>,
+    pub client: &'a Client,
+}
+
+/// A value type owned by a module. Its data is saved in the RDB aux section
+/// and handed back on load.
+///
+/// The module keeps its keys itself, per database index, and follows what
+/// the server does to the databases through the methods below.
+pub trait DataType: Send + Sync {
+    /// Up to 9 letters, digits, '-' or '_', as reported by TYPE.
+    fn name(&self) -> &str;
+
+    fn contains(&self, db: usize, key: &[u8]) -> bool;
+
+    /// MOVE: hands `key` from database `from` over to `to`, which doesn't
+    /// hold it. Returns false if `from` doesn't hold it either.
+    fn move_key(&self, from: usize, to: usize, key: &[u8]) -> bool;
+
+    /// SWAPDB: databases `a` and `b` trade their keys.
+    fn swap_db(&self, a: usize, b: usize);
+
+    /// Drops every key, before a full resync loads the master's data.
+    fn flush(&self);
+
+    /// The data to save, or None when there's nothing to persist.
+    fn aux_save(&self) -> Option<Vec<u8>>;
+
+    fn aux_load(&self, data: &[u8]) -> Result<(), String>;
+}
+
+/// A command as a module describes it. Arity counts the command name and is
+/// negative for "at least"; keys sit at first_key..=last_key every key_step
+/// arguments, with a negative last_key counting from the end.
+pub struct CommandSpec {
+    pub name: String,
+    pub arity: i64,
+    pub first_key: usize,
+    pub last_key: i64,
+    pub key_step: usize,
+    pub flags: Vec<String>,
+    pub handler: Arc<dyn CommandHandler>,
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    fn arity_ok(&self, argc: usize) -> bool {
+        if self.arity < 0 {
+            argc as i64 >= -self.arity
+        } else {
+            argc as i64 == self.arity
+        }
+    }
+
+    // Positions are counted with the command name at 0
+    fn keys(&self, args: &[Bytes]) -> Vec<Bytes> {
+        if self.first_key == 0 {
+            return Vec::new();
+        }
+        let argc = args.len() as i64 + 1;
+        let last = if self.last_key < 0 {
+            argc + self.last_key
+        } else {
+            self.last_key
+        };
+        (self.first_key as i64..=last)
+            .step_by(self.key_step.max(1))
+            .filter_map(|i| args.get(i as usize - 1).cloned())
+            .collect()
+    }
+}
+
+/// Collects what a module registers while it loads.
+pub struct Registry {
+    commands: Vec<CommandSpec>,
+    data_types: Vec<Arc<dyn DataType>>,
+}
+
+impl Registry {
+    pub fn create_command(&mut self, spec: CommandSpec) -> Result<(), String> {
+        if let Some(flag) = spec
+            .flags
+            .iter()
+            .find(|f| !COMMAND_FLAGS.contains(&f.as_str()))
+        {
+            return Err(format!("Invalid command flag '{}'", flag));
+        }
+        if spec.arity == 0 {
+            return Err(format!("Invalid arity for command '{}'", spec.name));
+        }
+        self.commands.push(spec);
+        Ok(())
+    }
+
+    pub fn create_data_type(&mut self, data_type: Arc<dyn DataType>) -> Result<(), String> {
+        let name = data_type.name();
+        if name.is_empty()
+            || name.len() > 9
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("Invalid data type name '{}'", name));
+        }
+        self.data_types.push(data_type);
+        Ok(())
+    }
+}
+
+/// A parsed call to a module command.
+pub struct ModuleCall {
+    spec: Arc<CommandSpec>,
+    // The data types registered by the same module
+    types: Arc<Vec<String>>,
+    args: Vec<Bytes>,
+}
+
+impl ModuleCall {
+    pub fn is_write(&self) -> bool {
+        self.spec.has_flag("write")
+    }
+
+    pub fn allowed_in_script(&self) -> bool {
+        !self.spec.has_flag("no-script")
+    }
+
+    pub fn keys(&self) -> Vec<Bytes> {
+        self.spec.keys(&self.args)
+    }
+
+    /// Runs the handler, unless one of the keys is taken by a builtin type
+    /// or another module's.
+    pub async fn run(self, client: &Client, redis: &Arc<Redis>) -> RedisValueRef {
+        let db = redis.db(client.db()).await;
+        for key in self.keys() {
+            let taken = db.contains(&key).await
+                || matches!(
+                    redis.modules.type_of(client.db(), &key).await,
+                    Some(name) if !self.types.contains(&name)
+                );
+            if taken {
+                return RedisValueRef::Error(Bytes::from(
+                    "WRONGTYPE Operation against a key holding the wrong kind of value",
+                ));
+            }
+        }
+        let ctx = Context { redis, client };
+        self.spec.handler.call(ctx, self.args).await
+    }
+}
+
+// A command, with the data types of the module that registered it
+#[derive(Clone)]
+struct Registered {
+    spec: Arc<CommandSpec>,
+    types: Arc<Vec<String>>,
+}
+
+struct Loaded {
+    name: String,
+    version: i64,
+}
+
+/// Loaded modules and everything they registered.
+pub struct Modules {
+    modules: RwLock<Vec<Loaded>>,
+    commands: RwLock<HashMap<String, Registered>>,
+    data_types: RwLock<Vec<Arc<dyn DataType>>>,
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Modules {
+    pub fn new() -> Self {
+        Modules {
+            modules: RwLock::new(Vec::new()),
+            commands: RwLock::new(HashMap::new()),
+            data_types: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Registers everything `module` provides. Nothing is kept if any of it
+    /// clashes with a builtin or an already loaded module.
+    pub async fn load(&self, module: &dyn Module, builtins: &[&str]) -> Result<(), String> {
+        let mut modules = self.modules.write().await;
+        if modules.iter().any(|m| m.name == module.name()) {
+            return Err(format!("Module '{}' is already loaded", module.name()));
+        }
+        let mut registry = Registry {
+            commands: Vec::new(),
+            data_types: Vec::new(),
+        };
+        module.load(&mut registry)?;
+
+        let mut commands = self.commands.write().await;
+        let mut data_types = self.data_types.write().await;
+        let mut names: Vec<String> = Vec::new();
+        for spec in &registry.commands {
+            let name = spec.name.to_uppercase();
+            if builtins.contains(&name.as_str())
+                || commands.contains_key(&name)
+                || names.contains(&name)
+            {
+                return Err(format!("Command '{}' already exists", spec.name));
+            }
+            names.push(name);
+        }
+        let mut type_names: Vec<&str> = data_types.iter().map(|t| t.name()).collect();
+        for data_type in &registry.data_types {
+            if type_names.contains(&data_type.name()) {
+                return Err(format!("Data type '{}' already exists", data_type.name()));
+            }
+            type_names.push(data_type.name());
+        }
+
+        let types: Arc<Vec<String>> = Arc::new(
+            registry
+                .data_types
+                .iter()
+                .map(|t| t.name().to_string())
+                .collect(),
+        );
+        for (name, spec) in names.into_iter().zip(registry.commands) {
+            let registered = Registered {
+                spec: Arc::new(spec),
+                types: types.clone(),
+            };
+            commands.insert(name, registered);
+        }
+        data_types.extend(registry.data_types);
+        modules.push(Loaded {
+            name: module.name().to_string(),
+            version: module.version(),
+        });
+        Ok(())
+    }
+
+    pub async fn has_command(&self, name: &str) -> bool {
+        self.commands
+            .read()
+            .await
+            .contains_key(&name.to_uppercase())
+    }
+
+    /// Parses a call to a module command. None if there's no such command
+    /// or the arity doesn't match.
+    pub async fn parse(&self, arr: &[RedisValueRef]) -> Option<ModuleCall> {
+        let mut args = Vec::new();
+        for item in arr {
+            match item {
+                RedisValueRef::String(s) => args.push(s.clone()),
+                _ => return None,
+            }
+        }
+        let name = String::from_utf8_lossy(args.first()?).to_uppercase();
+        let Registered { spec, types } = self.commands.read().await.get(&name)?.clone();
+        if !spec.arity_ok(args.len()) {
+            return None;
+        }
+        args.remove(0);
+        Some(ModuleCall { spec, types, args })
+    }
+
+    /// The name of the module type holding `key` in database `db`.
+    pub async fn type_of(&self, db: usize, key: &[u8]) -> Option<String> {
+        let data_types = self.data_types.read().await;
+        data_types
+            .iter()
+            .find(|t| t.contains(db, key))
+            .map(|t| t.name().to_string())
+    }
+
+    /// Moves a module key between databases. False if none holds it in
+    /// `from`.
+    pub async fn move_key(&self, from: usize, to: usize, key: &[u8]) -> bool {
+        let data_types = self.data_types.read().await;
+        data_types
+            .iter()
+            .find(|t| t.contains(from, key))
+            .is_some_and(|t| t.move_key(from, to, key))
+    }
+
+    pub async fn swap_db(&self, a: usize, b: usize) {
+        for data_type in self.data_types.read().await.iter() {
+            data_type.swap_db(a, b);
+        }
+    }
+
+    pub async fn flush(&self) {
+        for data_type in self.data_types.read().await.iter() {
+            data_type.flush();
+        }
+    }
+
+    /// Aux data of every module type, by type name.
+    pub async fn aux_save(&self) -> Vec<(String, Vec<u8>)> {
+        let data_types = self.data_types.read().await;
+        data_types
+            .iter()
+            .filter_map(|t| Some((t.name().to_string(), t.aux_save()?)))
+            .collect()
+    }
+
+    pub async fn aux_load(&self, aux: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+        let data_types = self.data_types.read().await;
+        for (name, data) in aux {
+            let Some(data_type) = data_types.iter().find(|t| t.name() == name) else {
+                return Err(format!(
+                    "The RDB file contains module data for the module type '{}', that the responsible module is not able to load",
+                    name
+                ));
+            };
+            data_type.aux_load(&data)?;
+        }
+        Ok(())
+    }
+
+    pub async fn list(&self, protocol: u8) -> RedisValueRef {
+        let modules = self.modules.read().await;
+        RedisValueRef::Array(
+            modules
+                .iter()
+                .map(|m| {
+                    map_reply(
+                        protocol,
+                        vec![
+                            (
+                                "name",
+                                RedisValueRef::BulkString(Bytes::from(m.name.clone())),
+                            ),
+                            ("ver", RedisValueRef::Int(m.version)),
+                        ],
+                    )
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,180 @@
+use crate::pubsub::PubSub;
+use crate::tracking::Tracking;
+use crate::transactions::Transaction;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// notify-keyspace-events classes, same letters as Redis
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_MODULE: u32 = 1 << 12; // d
+pub const NOTIFY_NEW: u32 = 1 << 13; // n
+
+// Everything "A" stands for
+const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+const CLASS_LETTERS: [(u32, char); 12] = [
+    (NOTIFY_GENERIC, 'g'),
+    (NOTIFY_STRING, '$'),
+    (NOTIFY_LIST, 'l'),
+    (NOTIFY_SET, 's'),
+    (NOTIFY_HASH, 'h'),
+    (NOTIFY_ZSET, 'z'),
+    (NOTIFY_EXPIRED, 'x'),
+    (NOTIFY_EVICTED, 'e'),
+    (NOTIFY_STREAM, 't'),
+    (NOTIFY_MODULE, 'd'),
+    (NOTIFY_KEY_MISS, 'm'),
+    (NOTIFY_NEW, 'n'),
+];
+
+/// Receives an event from every path that touches a key and turns it into
+/// keyspace/keyevent pub/sub messages according to notify-keyspace-events.
+/// Events for writes also invalidate tracked copies of the key, mark
+/// transactions watching it as dirty and count towards the save points.
+pub struct Notifier {
+    flags: RwLock<u32>,
+    // Changes made since startup
+    dirty: AtomicU64,
+    pubsub: Arc<PubSub>,
+    tracking: Arc<Tracking>,
+    tr: Arc<Transaction>,
+}
+
+impl Notifier {
+    pub fn new(pubsub: Arc<PubSub>, tracking: Arc<Tracking>, tr: Arc<Transaction>) -> Self {
+        Notifier {
+            flags: RwLock::new(0),
+            dirty: AtomicU64::new(0),
+            pubsub,
+            tracking,
+            tr,
+        }
+    }
+
+    pub async fn set_flags(&self, value: &str) -> Result<(), String> {
+        let flags = parse_flags(value).ok_or_else(|| {
+            format!(
+                "ERR Invalid argument '{}' for CONFIG SET 'notify-keyspace-events'",
+                value
+            )
+        })?;
+        *self.flags.write().await = flags;
+        Ok(())
+    }
+
+    pub async fn flags_string(&self) -> String {
+        flags_to_string(*self.flags.read().await)
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    /// Counts a change that doesn't go through a key, like loading a function.
+    pub fn add_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::SeqCst);
+    }
+
+    /// Publishes `event` for `key` in database `db` if its class is enabled.
+    pub async fn notify(&self, db: usize, class: u32, event: &str, key: &Bytes) {
+        // "new" always comes with the event for the write that created the key
+        if class & (NOTIFY_KEY_MISS | NOTIFY_NEW) == 0 {
+            self.add_dirty(1);
+            self.tr.touch(db, key).await;
+            self.tracking.invalidate(key).await;
+        }
+
+        let flags = *self.flags.read().await;
+        if flags & class == 0 {
+            return;
+        }
+
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+            channel.extend_from_slice(key);
+            self.pubsub
+                .publish(&Bytes::from(channel), &Bytes::from(event.to_string()))
+                .await;
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.pubsub.publish(&Bytes::from(channel), key).await;
+        }
+    }
+}
+
+/// The `Notifier` as seen from one database's stores. The index is shared
+/// with the database, so it follows the data through SWAPDB.
+pub struct DbNotifier {
+    notifier: Arc<Notifier>,
+    db: Arc<AtomicUsize>,
+}
+
+impl DbNotifier {
+    pub fn new(notifier: Arc<Notifier>, db: Arc<AtomicUsize>) -> Self {
+        DbNotifier { notifier, db }
+    }
+
+    pub async fn notify(&self, class: u32, event: &str, key: &Bytes) {
+        let db = self.db.load(Ordering::SeqCst);
+        self.notifier.notify(db, class, event, key).await;
+    }
+}
+
+fn parse_flags(value: &str) -> Option<u32> {
+    let mut flags = 0;
+    for c in value.chars() {
+        flags |= match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            c => CLASS_LETTERS.iter().find(|(_, l)| *l == c)?.0,
+        };
+    }
+    // Without K or E nothing would ever be delivered
+    if flags & (NOTIFY_KEYSPACE | NOTIFY_KEYEVENT) == 0 {
+        flags = 0;
+    }
+    Some(flags)
+}
+
+fn flags_to_string(flags: u32) -> String {
+    let mut s = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        s.push('A');
+    }
+    for (class, letter) in CLASS_LETTERS {
+        if flags & class != 0 && (NOTIFY_ALL & class == 0 || !s.starts_with('A')) {
+            s.push(letter);
+        }
+    }
+    if flags & NOTIFY_KEYSPACE != 0 {
+        s.push('K');
+    }
+    if flags & NOTIFY_KEYEVENT != 0 {
+        s.push('E');
+    }
+    s
+}
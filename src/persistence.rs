@@ -0,0 +1,453 @@
+use crate::cow;
+use crate::db::DbSnapshot;
+use crate::log;
+use crate::rdb::{RdbError, RdbWriter};
+use crate::redis::Redis;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+// How long a failed automatic save waits before trying again, in seconds
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// State of SAVE, BGSAVE and the automatic save points.
+pub struct Persistence {
+    // Where the RDB file is read from at startup and saved to
+    dir: RwLock<String>,
+    dbfilename: RwLock<String>,
+    // (seconds, changes): save once `changes` writes are `seconds` old
+    save_params: RwLock<Vec<(u64, u64)>>,
+    // Unix time in seconds of the last successful save
+    last_save: AtomicU64,
+    // Notifier's dirty counter as of the last successful save
+    saved_dirty: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    // A BGSAVE SCHEDULE arrived while another was running
+    bgsave_scheduled: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+    // Unix time in seconds the last BGSAVE started
+    last_bgsave_try: AtomicU64,
+    bgsave_started: RwLock<Option<Instant>>,
+    // Seconds the last BGSAVE took, -1 before the first one
+    last_bgsave_secs: AtomicI64,
+    // How long the last BGSAVE held commands back to capture the dataset,
+    // and how long it took from there until the file was written
+    last_pause_usec: AtomicU64,
+    last_snapshot_ms: AtomicU64,
+    // Copies writers made of data the last BGSAVE was still serialising
+    last_cow_copies: AtomicU64,
+    last_cow_items: AtomicU64,
+    // LZF-compress strings in the RDB file
+    rdbcompression: AtomicBool,
+    // Write a CRC64 at the end of RDB files and check it when loading
+    rdbchecksum: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Persistence {
+    pub fn new() -> Self {
+        Persistence {
+            dir: RwLock::new(String::new()),
+            dbfilename: RwLock::new(String::new()),
+            save_params: RwLock::new(Vec::new()),
+            last_save: AtomicU64::new(unix_time()),
+            saved_dirty: AtomicU64::new(0),
+            bgsave_in_progress: AtomicBool::new(false),
+            bgsave_scheduled: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+            last_bgsave_try: AtomicU64::new(0),
+            bgsave_started: RwLock::new(None),
+            last_bgsave_secs: AtomicI64::new(-1),
+            last_pause_usec: AtomicU64::new(0),
+            last_snapshot_ms: AtomicU64::new(0),
+            last_cow_copies: AtomicU64::new(0),
+            last_cow_items: AtomicU64::new(0),
+            rdbcompression: AtomicBool::new(true),
+            rdbchecksum: AtomicBool::new(true),
+        }
+    }
+
+    pub async fn dir(&self) -> String {
+        self.dir.read().await.clone()
+    }
+
+    pub async fn set_dir(&self, dir: String) {
+        *self.dir.write().await = dir;
+    }
+
+    pub async fn dbfilename(&self) -> String {
+        self.dbfilename.read().await.clone()
+    }
+
+    /// Set the RDB file path and open the file there for reading, with its
+    /// size
+    pub async fn open_rdb_file(
+        &self,
+        dir: String,
+        dbfilename: String,
+    ) -> Result<(BufReader<File>, u64), RdbError> {
+        let path = format!("{}/{}", dir, dbfilename);
+        *self.dir.write().await = dir;
+        *self.dbfilename.write().await = dbfilename;
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok((BufReader::new(file), len))
+    }
+
+    /// Sets the save points from "<seconds> <changes> ...", or clears them
+    /// for an empty string.
+    pub async fn set_save_params(&self, value: &str) -> Result<(), String> {
+        let err = || {
+            "ERR CONFIG SET failed (possibly related to argument 'save') - Invalid save parameters"
+                .to_string()
+        };
+        let numbers = value
+            .split_whitespace()
+            .map(|n| n.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err())?;
+        if numbers.len() % 2 != 0 {
+            return Err(err());
+        }
+        *self.save_params.write().await = numbers.chunks(2).map(|p| (p[0], p[1])).collect();
+        Ok(())
+    }
+
+    pub async fn save_params_string(&self) -> String {
+        let params = self.save_params.read().await;
+        params
+            .iter()
+            .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn rdbcompression(&self) -> bool {
+        self.rdbcompression.load(Ordering::SeqCst)
+    }
+
+    pub fn set_rdbcompression(&self, on: bool) {
+        self.rdbcompression.store(on, Ordering::SeqCst);
+    }
+
+    pub fn rdbchecksum(&self) -> bool {
+        self.rdbchecksum.load(Ordering::SeqCst)
+    }
+
+    pub fn set_rdbchecksum(&self, on: bool) {
+        self.rdbchecksum.store(on, Ordering::SeqCst);
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save.load(Ordering::SeqCst)
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::SeqCst)
+    }
+
+    pub async fn info(&self, dirty: u64) -> String {
+        let current_secs = match *self.bgsave_started.read().await {
+            Some(started) => started.elapsed().as_secs() as i64,
+            None => -1,
+        };
+        let mut s = String::new();
+        writeln!(s, "# Persistence").unwrap();
+        writeln!(
+            s,
+            "rdb_changes_since_last_save:{}",
+            dirty.saturating_sub(self.saved_dirty.load(Ordering::SeqCst))
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "rdb_bgsave_in_progress:{}",
+            self.bgsave_in_progress() as u8
+        )
+        .unwrap();
+        writeln!(s, "rdb_last_save_time:{}", self.last_save()).unwrap();
+        writeln!(
+            s,
+            "rdb_last_bgsave_status:{}",
+            if self.last_bgsave_ok() { "ok" } else { "err" }
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "rdb_last_bgsave_time_sec:{}",
+            self.last_bgsave_secs.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(s, "rdb_current_bgsave_time_sec:{}", current_secs).unwrap();
+        writeln!(
+            s,
+            "rdb_last_snapshot_pause_usec:{}",
+            self.last_pause_usec.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "rdb_last_snapshot_duration_ms:{}",
+            self.last_snapshot_ms.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "rdb_last_cow_copies:{}",
+            self.last_cow_copies.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(
+            s,
+            "rdb_last_cow_copied_items:{}",
+            self.last_cow_items.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        s
+    }
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The dataset at one point in time. Taking it only clones each store's
+/// persistent key map, which shares its nodes; writers that come after copy
+/// just the map nodes and values they change.
+pub struct Capture {
+    functions: Vec<Bytes>,
+    module_aux: Vec<(String, Vec<u8>)>,
+    dbs: Vec<DbSnapshot>,
+    dirty: u64,
+    compression: bool,
+    checksum: bool,
+}
+
+/// Captures the dataset. The caller must keep writers out while this runs,
+/// e.g. by holding `exec_lock` exclusively.
+pub async fn capture(redis: &Redis) -> Capture {
+    let mut dbs = Vec::new();
+    for db in redis.dbs().await {
+        dbs.push(db.snapshot().await);
+    }
+    Capture {
+        functions: redis.functions.codes().await,
+        module_aux: redis.modules.aux_save().await,
+        dbs,
+        dirty: redis.notifier.dirty(),
+        compression: redis.persistence.rdbcompression(),
+        checksum: redis.persistence.rdbchecksum(),
+    }
+}
+
+impl Capture {
+    pub fn serialize(self) -> Snapshot {
+        let mut writer = RdbWriter::new(self.compression);
+        for code in &self.functions {
+            writer.function(code);
+        }
+        for (name, data) in &self.module_aux {
+            writer.module_aux(name, data);
+        }
+        for db in &self.dbs {
+            db.save(&mut writer);
+        }
+        Snapshot {
+            data: writer.finish(self.checksum),
+            dirty: self.dirty,
+        }
+    }
+}
+
+/// Serialises the whole dataset, under the same locking rule as `capture`.
+pub async fn snapshot(redis: &Redis) -> Snapshot {
+    capture(redis).await.serialize()
+}
+
+/// An RDB image and the number of changes it includes.
+pub struct Snapshot {
+    pub data: Vec<u8>,
+    dirty: u64,
+}
+
+// Writes a temp file next to the target and renames it over, so the old file
+// stays intact until the new one is complete
+fn write_atomically(dir: &str, dbfilename: &str, data: &[u8]) -> std::io::Result<()> {
+    let dir = Path::new(if dir.is_empty() { "." } else { dir });
+    let dbfilename = if dbfilename.is_empty() {
+        "dump.rdb"
+    } else {
+        dbfilename
+    };
+    let temp = dir.join(format!("temp-{}.rdb", std::process::id()));
+    let res = (|| {
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, dir.join(dbfilename))
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    res
+}
+
+async fn write_snapshot(redis: &Redis, snapshot: Snapshot) -> std::io::Result<()> {
+    let dir = redis.persistence.dir().await;
+    let dbfilename = redis.persistence.dbfilename().await;
+    let data = snapshot.data;
+    tokio::task::spawn_blocking(move || write_atomically(&dir, &dbfilename, &data))
+        .await
+        .map_err(std::io::Error::other)??;
+    let persistence = &redis.persistence;
+    persistence.last_save.store(unix_time(), Ordering::SeqCst);
+    persistence
+        .saved_dirty
+        .store(snapshot.dirty, Ordering::SeqCst);
+    Ok(())
+}
+
+/// SAVE: writes the dataset in the foreground. Runs with `exec_lock` held
+/// exclusively, so no command sees it half done.
+pub async fn save(redis: &Redis) -> RedisValueRef {
+    if redis.persistence.bgsave_in_progress() {
+        return RedisValueRef::Error(Bytes::from("ERR Background save already in progress"));
+    }
+    let snapshot = snapshot(redis).await;
+    match write_snapshot(redis, snapshot).await {
+        Ok(()) => RedisValueRef::String(Bytes::from("OK")),
+        Err(e) => {
+            log::warn!("Failed saving the DB: {}", e);
+            RedisValueRef::Error(Bytes::from("ERR"))
+        }
+    }
+}
+
+/// BGSAVE: takes the snapshot and writes it from a background task.
+pub fn bgsave(redis: &Arc<Redis>, schedule: bool) -> RedisValueRef {
+    let persistence = &redis.persistence;
+    if persistence.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+        if schedule {
+            persistence.bgsave_scheduled.store(true, Ordering::SeqCst);
+            return RedisValueRef::String(Bytes::from("Background saving scheduled"));
+        }
+        return RedisValueRef::Error(Bytes::from("ERR Background save already in progress"));
+    }
+
+    let redis = redis.clone();
+    tokio::spawn(async move {
+        loop {
+            let started = Instant::now();
+            *redis.persistence.bgsave_started.write().await = Some(started);
+            redis
+                .persistence
+                .last_bgsave_try
+                .store(unix_time(), Ordering::SeqCst);
+            let (copies, items) = cow::copy_stats();
+            let capture = {
+                let _guard = redis.exec_lock.write().await;
+                let paused = Instant::now();
+                let capture = capture(&redis).await;
+                redis
+                    .persistence
+                    .last_pause_usec
+                    .store(paused.elapsed().as_micros() as u64, Ordering::SeqCst);
+                capture
+            };
+            // Writers keep going while this runs, copying what they touch
+            let snapshot = tokio::task::spawn_blocking(move || capture.serialize()).await;
+            let (copies_after, items_after) = cow::copy_stats();
+            redis
+                .persistence
+                .last_cow_copies
+                .store(copies_after - copies, Ordering::SeqCst);
+            redis
+                .persistence
+                .last_cow_items
+                .store(items_after - items, Ordering::SeqCst);
+
+            let res = match snapshot {
+                Ok(snapshot) => write_snapshot(&redis, snapshot).await,
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+            redis
+                .persistence
+                .last_snapshot_ms
+                .store(started.elapsed().as_millis() as u64, Ordering::SeqCst);
+            let ok = match res {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Background saving error: {}", e);
+                    false
+                }
+            };
+            redis.persistence.last_bgsave_ok.store(ok, Ordering::SeqCst);
+            redis
+                .persistence
+                .last_bgsave_secs
+                .store(started.elapsed().as_secs() as i64, Ordering::SeqCst);
+            *redis.persistence.bgsave_started.write().await = None;
+            if !redis
+                .persistence
+                .bgsave_scheduled
+                .swap(false, Ordering::SeqCst)
+            {
+                break;
+            }
+        }
+        redis
+            .persistence
+            .bgsave_in_progress
+            .store(false, Ordering::SeqCst);
+    });
+    RedisValueRef::String(Bytes::from("Background saving started"))
+}
+
+/// Starts a BGSAVE when a save point is due: enough changes, and the last
+/// save older than its seconds. A failed save is retried after a delay.
+pub async fn save_cron(redis: &Arc<Redis>) {
+    let persistence = &redis.persistence;
+    // A dataset still loading isn't worth saving
+    if persistence.bgsave_in_progress() || redis.loading.is_loading() {
+        return;
+    }
+    let now = unix_time();
+    let changes = redis
+        .notifier
+        .dirty()
+        .saturating_sub(persistence.saved_dirty.load(Ordering::SeqCst));
+    let since_save = now.saturating_sub(persistence.last_save());
+    let may_retry = persistence.last_bgsave_ok()
+        || now.saturating_sub(persistence.last_bgsave_try.load(Ordering::SeqCst))
+            > BGSAVE_RETRY_DELAY;
+    let due = persistence
+        .save_params
+        .read()
+        .await
+        .iter()
+        .find(|(seconds, min_changes)| changes >= *min_changes && since_save > *seconds)
+        .copied();
+    if let (Some((seconds, min_changes)), true) = (due, may_retry) {
+        log::notice!("{} changes in {} seconds. Saving...", min_changes, seconds);
+        bgsave(redis, false);
+    }
+}
@@ -0,0 +1,446 @@
+use crate::client::{Client, OutputBufferLimit};
+use crate::log;
+use crate::rdb::KeyValue;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// Per-connection subscription state
+struct Subscriber {
+    // Messages go out through the client's push queue, so PUBLISH never
+    // waits on a slow subscriber
+    client: Arc<Client>,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    shard_channels: HashSet<Bytes>,
+}
+
+impl Subscriber {
+    fn count(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+
+    // Sharded subscriptions are counted separately, as in Redis
+    fn shard_count(&self) -> i64 {
+        self.shard_channels.len() as i64
+    }
+
+    fn send(&self, items: Vec<RedisValueRef>) -> bool {
+        self.client.push(items)
+    }
+
+    // A published message, after which a subscriber that has fallen too far
+    // behind is disconnected rather than queueing without bound
+    fn deliver(&self, items: Vec<RedisValueRef>, limit: &OutputBufferLimit) -> bool {
+        let sent = self.send(items);
+        if sent && self.client.over_output_limit(limit) {
+            log::log(
+                log::Level::Warning,
+                &format!(
+                    "Client id={} addr={} scheduled to be closed ASAP for overcoming of output buffer limits.",
+                    self.client.id, self.client.addr
+                ),
+            );
+            self.client.kill();
+        }
+        sent
+    }
+}
+
+// Subscribers are keyed by client ID
+struct Subscriptions {
+    clients: HashMap<u64, Subscriber>,
+    // channel -> subscribed clients
+    channels: HashMap<Bytes, HashSet<u64>>,
+    // pattern -> subscribed clients
+    patterns: HashMap<Bytes, HashSet<u64>>,
+    // shard channel -> subscribed clients, a namespace separate from `channels`
+    shard_channels: HashMap<Bytes, HashSet<u64>>,
+    // client-output-buffer-limit for the pubsub class
+    limit: OutputBufferLimit,
+}
+
+pub struct PubSub {
+    subs: RwLock<Subscriptions>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            subs: RwLock::new(Subscriptions {
+                clients: HashMap::new(),
+                channels: HashMap::new(),
+                patterns: HashMap::new(),
+                shard_channels: HashMap::new(),
+                limit: OutputBufferLimit::PUBSUB,
+            }),
+        }
+    }
+
+    pub async fn output_buffer_limit(&self) -> OutputBufferLimit {
+        self.subs.read().await.limit
+    }
+
+    pub async fn set_output_buffer_limit(&self, limit: OutputBufferLimit) {
+        self.subs.write().await.limit = limit;
+    }
+
+    pub async fn register(&self, client: Arc<Client>) {
+        let mut subs = self.subs.write().await;
+        subs.clients.insert(
+            client.id,
+            Subscriber {
+                client,
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+                shard_channels: HashSet::new(),
+            },
+        );
+    }
+
+    pub async fn unregister(&self, id: u64) {
+        let mut subs = self.subs.write().await;
+        if let Some(client) = subs.clients.remove(&id) {
+            for channel in client.channels {
+                remove_from(&mut subs.channels, &channel, id);
+            }
+            for pattern in client.patterns {
+                remove_from(&mut subs.patterns, &pattern, id);
+            }
+            for channel in client.shard_channels {
+                remove_from(&mut subs.shard_channels, &channel, id);
+            }
+        }
+    }
+
+    pub async fn is_subscribed_to(&self, id: u64, channel: &[u8]) -> bool {
+        let subs = self.subs.read().await;
+        subs.clients
+            .get(&id)
+            .map(|client| client.channels.contains(channel))
+            .unwrap_or(false)
+    }
+
+    /// Channel, pattern and shard channel subscription counts.
+    pub async fn counts(&self, id: u64) -> (usize, usize, usize) {
+        let subs = self.subs.read().await;
+        subs.clients.get(&id).map_or((0, 0, 0), |client| {
+            (
+                client.channels.len(),
+                client.patterns.len(),
+                client.shard_channels.len(),
+            )
+        })
+    }
+
+    pub async fn is_subscribed(&self, id: u64) -> bool {
+        let subs = self.subs.read().await;
+        subs.clients
+            .get(&id)
+            .map(|client| client.count() + client.shard_count() > 0)
+            .unwrap_or(false)
+    }
+
+    pub async fn subscribe(&self, id: u64, channels: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        for channel in channels {
+            if client.channels.insert(channel.clone()) {
+                subs.channels.entry(channel.clone()).or_default().insert(id);
+            }
+            client.send(reply("subscribe", Some(channel), client.count()));
+        }
+    }
+
+    pub async fn psubscribe(&self, id: u64, patterns: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        for pattern in patterns {
+            if client.patterns.insert(pattern.clone()) {
+                subs.patterns.entry(pattern.clone()).or_default().insert(id);
+            }
+            client.send(reply("psubscribe", Some(pattern), client.count()));
+        }
+    }
+
+    /// Unsubscribes from `channels`, or from every channel when empty.
+    pub async fn unsubscribe(&self, id: u64, channels: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        let channels = if channels.is_empty() {
+            client.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            client.send(reply("unsubscribe", None, client.count()));
+        }
+        for channel in channels {
+            if client.channels.remove(&channel) {
+                remove_from(&mut subs.channels, &channel, id);
+            }
+            client.send(reply("unsubscribe", Some(channel), client.count()));
+        }
+    }
+
+    /// Unsubscribes from `patterns`, or from every pattern when empty.
+    pub async fn punsubscribe(&self, id: u64, patterns: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        let patterns = if patterns.is_empty() {
+            client.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            client.send(reply("punsubscribe", None, client.count()));
+        }
+        for pattern in patterns {
+            if client.patterns.remove(&pattern) {
+                remove_from(&mut subs.patterns, &pattern, id);
+            }
+            client.send(reply("punsubscribe", Some(pattern), client.count()));
+        }
+    }
+
+    pub async fn ssubscribe(&self, id: u64, channels: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        for channel in channels {
+            if client.shard_channels.insert(channel.clone()) {
+                subs.shard_channels
+                    .entry(channel.clone())
+                    .or_default()
+                    .insert(id);
+            }
+            client.send(reply("ssubscribe", Some(channel), client.shard_count()));
+        }
+    }
+
+    /// Unsubscribes from shard `channels`, or from every shard channel when empty.
+    pub async fn sunsubscribe(&self, id: u64, channels: Vec<Bytes>) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        let channels = if channels.is_empty() {
+            client.shard_channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            client.send(reply("sunsubscribe", None, client.shard_count()));
+        }
+        for channel in channels {
+            if client.shard_channels.remove(&channel) {
+                remove_from(&mut subs.shard_channels, &channel, id);
+            }
+            client.send(reply("sunsubscribe", Some(channel), client.shard_count()));
+        }
+    }
+
+    /// Drops every subscription without sending replies, as RESET does.
+    pub async fn reset(&self, id: u64) {
+        let mut subs = self.subs.write().await;
+        let subs = &mut *subs;
+        let Some(client) = subs.clients.get_mut(&id) else {
+            return;
+        };
+        for channel in client.channels.drain() {
+            remove_from(&mut subs.channels, &channel, id);
+        }
+        for pattern in client.patterns.drain() {
+            remove_from(&mut subs.patterns, &pattern, id);
+        }
+        for channel in client.shard_channels.drain() {
+            remove_from(&mut subs.shard_channels, &channel, id);
+        }
+    }
+
+    /// Queues `message` for every subscriber and returns how many received it.
+    pub async fn publish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        let subs = self.subs.read().await;
+        let mut receivers = 0;
+
+        if let Some(ids) = subs.channels.get(channel) {
+            for id in ids {
+                if let Some(client) = subs.clients.get(id) {
+                    let msg = vec![
+                        RedisValueRef::BulkString(Bytes::from("message")),
+                        RedisValueRef::BulkString(channel.clone()),
+                        RedisValueRef::BulkString(message.clone()),
+                    ];
+                    if client.deliver(msg, &subs.limit) {
+                        receivers += 1;
+                    }
+                }
+            }
+        }
+
+        let channel_str = String::from_utf8_lossy(channel);
+        for (pattern, ids) in subs.patterns.iter() {
+            if !KeyValue::match_pattern(&String::from_utf8_lossy(pattern), &channel_str) {
+                continue;
+            }
+            for id in ids {
+                if let Some(client) = subs.clients.get(id) {
+                    let msg = vec![
+                        RedisValueRef::BulkString(Bytes::from("pmessage")),
+                        RedisValueRef::BulkString(pattern.clone()),
+                        RedisValueRef::BulkString(channel.clone()),
+                        RedisValueRef::BulkString(message.clone()),
+                    ];
+                    if client.deliver(msg, &subs.limit) {
+                        receivers += 1;
+                    }
+                }
+            }
+        }
+
+        receivers
+    }
+
+    /// Queues `message` for the shard channel's subscribers. Patterns never
+    /// match shard channels.
+    pub async fn spublish(&self, channel: &Bytes, message: &Bytes) -> i64 {
+        let subs = self.subs.read().await;
+        let mut receivers = 0;
+
+        if let Some(ids) = subs.shard_channels.get(channel) {
+            for id in ids {
+                if let Some(client) = subs.clients.get(id) {
+                    let msg = vec![
+                        RedisValueRef::BulkString(Bytes::from("smessage")),
+                        RedisValueRef::BulkString(channel.clone()),
+                        RedisValueRef::BulkString(message.clone()),
+                    ];
+                    if client.deliver(msg, &subs.limit) {
+                        receivers += 1;
+                    }
+                }
+            }
+        }
+
+        receivers
+    }
+
+    pub async fn shard_channels(&self, pattern: Option<Bytes>) -> RedisValueRef {
+        let subs = self.subs.read().await;
+        list_channels(&subs.shard_channels, pattern)
+    }
+
+    pub async fn shard_numsub(&self, channels: Vec<Bytes>) -> RedisValueRef {
+        let subs = self.subs.read().await;
+        count_subscribers(&subs.shard_channels, channels)
+    }
+
+    pub async fn channels(&self, pattern: Option<Bytes>) -> RedisValueRef {
+        let subs = self.subs.read().await;
+        list_channels(&subs.channels, pattern)
+    }
+
+    pub async fn numsub(&self, channels: Vec<Bytes>) -> RedisValueRef {
+        let subs = self.subs.read().await;
+        count_subscribers(&subs.channels, channels)
+    }
+
+    pub async fn numpat(&self) -> i64 {
+        let subs = self.subs.read().await;
+        subs.patterns.len() as i64
+    }
+}
+
+fn reply(kind: &'static str, name: Option<Bytes>, count: i64) -> Vec<RedisValueRef> {
+    vec![
+        RedisValueRef::BulkString(Bytes::from(kind)),
+        match name {
+            Some(name) => RedisValueRef::BulkString(name),
+            None => RedisValueRef::NullBulkString,
+        },
+        RedisValueRef::Int(count),
+    ]
+}
+
+fn list_channels(map: &HashMap<Bytes, HashSet<u64>>, pattern: Option<Bytes>) -> RedisValueRef {
+    let pattern = pattern.map(|p| String::from_utf8_lossy(&p).to_string());
+    RedisValueRef::Array(
+        map.keys()
+            .filter(|channel| match &pattern {
+                Some(p) => KeyValue::match_pattern(p, &String::from_utf8_lossy(channel)),
+                None => true,
+            })
+            .map(|channel| RedisValueRef::BulkString(channel.clone()))
+            .collect(),
+    )
+}
+
+fn count_subscribers(map: &HashMap<Bytes, HashSet<u64>>, channels: Vec<Bytes>) -> RedisValueRef {
+    let mut res = Vec::new();
+    for channel in channels {
+        let count = map.get(&channel).map_or(0, |c| c.len());
+        res.push(RedisValueRef::BulkString(channel));
+        res.push(RedisValueRef::Int(count as i64));
+    }
+    RedisValueRef::Array(res)
+}
+
+/// Cluster hash slot of `key`: CRC16 (XMODEM) mod 16384, honouring `{hash tags}`.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let key = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            // Only a non-empty tag counts
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(key) % 16384
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn remove_from(map: &mut HashMap<Bytes, HashSet<u64>>, name: &Bytes, id: u64) {
+    if let Some(ids) = map.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            map.remove(name);
+        }
+    }
+}
@@ -1,11 +1,67 @@
+use crate::crc64;
+use crate::listpack::{self, Listpack};
+use crate::lzf;
+use crate::stream_node::{StreamEntry, StreamId};
+use crate::ziplist;
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::io::{self, Cursor, Read};
+use std::sync::Mutex;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub(crate) const RDB_VERSION: u16 = 11;
+// Library code saved by FUNCTION LOAD
+pub(crate) const RDB_OPCODE_FUNCTION: u8 = 0xF5;
+// Module data type aux data: the type name, then its payload
+pub(crate) const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+// Scores as length-prefixed text
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+// Scores as binary doubles
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+// A list of ziplist nodes
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+// A list of listpack nodes
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+// Adds the first ID, max deleted ID, entries added and entries read
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+// Adds consumers' active time
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+// Same as Redis's list-max-listpack-size -2
+const LIST_NODE_MAX_BYTES: usize = 8192;
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+// Same as Redis's default set/zset/hash-max-listpack-entries and -value:
+// bigger collections are saved one element at a time
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+
+// Stream listpack entry flags
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
 #[derive(Debug, Clone)]
 pub struct RdbFile {
     pub version: String,
     pub metadata: HashMap<String, String>,
+    pub functions: Vec<Bytes>,
+    pub module_aux: Vec<(String, Vec<u8>)>,
     pub databases: Vec<Database>,
 }
 
@@ -19,12 +75,12 @@ pub struct Database {
 
 #[derive(Debug, Clone)]
 pub struct KeyValuePair {
-    pub key: String,
+    pub key: Bytes,
     pub value: Value,
     pub expire: Option<Expiry>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expiry {
     Seconds(u32),
     Milliseconds(u64),
@@ -56,9 +112,48 @@ impl Expiry {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
-    String(String),
+    String(Bytes),
+    List(Vec<Bytes>),
+    Set(Vec<Bytes>),
+    SortedSet(Vec<(Bytes, f64)>),
+    Hash(Vec<(Bytes, Bytes)>),
+    Stream(StreamData),
+}
+
+/// A stream as it is saved: its entries, ID bookkeeping and consumer groups.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StreamData {
+    pub entries: Vec<(StreamId, StreamEntry)>,
+    pub last_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<GroupData>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupData {
+    pub name: Bytes,
+    pub last_delivered_id: StreamId,
+    pub entries_read: Option<u64>,
+    pub pending: Vec<PendingData>,
+    pub consumers: Vec<ConsumerData>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingData {
+    pub id: StreamId,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerData {
+    pub name: Bytes,
+    pub seen_time: u64,
+    pub active_time: Option<u64>,
+    pub pending: Vec<StreamId>,
 }
 
 #[derive(Debug)]
@@ -90,20 +185,71 @@ impl std::fmt::Display for RdbError {
 
 impl std::error::Error for RdbError {}
 
+// Aux fields, function libraries and module aux data, all found before the
+// first database
+type Preamble = (HashMap<String, String>, Vec<Bytes>, Vec<(String, Vec<u8>)>);
+
+/// What `RdbParser::next_item` reads from the database section, in file order.
+#[derive(Debug, Clone)]
+pub enum RdbItem {
+    /// The keys that follow belong to database `index`, sized by its RESIZEDB
+    /// hint, or zeros without one
+    Db {
+        index: u64,
+        key_value_hash_size: u64,
+        expire_hash_size: u64,
+    },
+    Entry(KeyValuePair),
+}
+
+// Passes reads through, keeping the CRC64 of every byte read so far
+struct ChecksumReader<R: Read> {
+    inner: R,
+    crc: u64,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc64::update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
 pub struct RdbParser<R: Read> {
-    reader: R,
+    reader: ChecksumReader<R>,
     peeked_byte: Option<u8>,
+    verify_checksum: bool,
+    // From the header; only version 5 and later end with a checksum
+    version: u32,
+    // Database the keys being read belong to, None before the first
+    db: Option<u64>,
+    // Set once the end of the file is reached
+    finished: bool,
 }
 
 impl<R: Read> RdbParser<R> {
     pub fn new(reader: R) -> Self {
         RdbParser {
-            reader,
+            reader: ChecksumReader {
+                inner: reader,
+                crc: 0,
+            },
             peeked_byte: None,
+            verify_checksum: true,
+            version: RDB_VERSION as u32,
+            db: None,
+            finished: false,
         }
     }
 
-    fn read_byte(&mut self) -> Result<u8, RdbError> {
+    /// Whether to check the trailing checksum, like `rdbchecksum`.
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
+    }
+
+    pub(crate) fn read_byte(&mut self) -> Result<u8, RdbError> {
         if let Some(byte) = self.peeked_byte.take() {
             return Ok(byte);
         }
@@ -114,19 +260,42 @@ impl<R: Read> RdbParser<R> {
     }
 
     pub fn parse(&mut self) -> Result<RdbFile, RdbError> {
-        // Parse header
-        let version = self.parse_header()?;
-
-        // Parse metadata
-        let metadata = self.parse_metadata()?;
-
-        // Parse databases
-        let databases = self.parse_databases()?;
+        let mut rdb_file = self.parse_preamble()?;
+        while let Some(item) = self.next_item()? {
+            match item {
+                RdbItem::Db {
+                    index,
+                    key_value_hash_size,
+                    expire_hash_size,
+                } => rdb_file.databases.push(Database {
+                    index,
+                    key_value_hash_size,
+                    expire_hash_size,
+                    entries: Vec::new(),
+                }),
+                RdbItem::Entry(entry) => {
+                    // next_item only returns keys inside a database
+                    if let Some(db) = rdb_file.databases.last_mut() {
+                        db.entries.push(entry);
+                    }
+                }
+            }
+        }
+        Ok(rdb_file)
+    }
 
+    /// Reads everything before the first database: the header, aux fields,
+    /// function libraries and module aux data. The databases are left for
+    /// `next_item`, so the returned file has none.
+    pub fn parse_preamble(&mut self) -> Result<RdbFile, RdbError> {
+        let version = self.parse_header()?;
+        let (metadata, functions, module_aux) = self.parse_metadata()?;
         Ok(RdbFile {
             version,
             metadata,
-            databases,
+            functions,
+            module_aux,
+            databases: Vec::new(),
         })
     }
 
@@ -141,11 +310,14 @@ impl<R: Read> RdbParser<R> {
 
         // Extract version
         let version = String::from_utf8_lossy(&header[5..9]).to_string();
+        self.version = version.parse().map_err(|_| RdbError::InvalidHeader)?;
         Ok(version)
     }
 
-    fn parse_metadata(&mut self) -> Result<HashMap<String, String>, RdbError> {
+    fn parse_metadata(&mut self) -> Result<Preamble, RdbError> {
         let mut metadata = HashMap::new();
+        let mut functions = Vec::new();
+        let mut module_aux = Vec::new();
 
         loop {
             let byte = self.read_byte()?;
@@ -157,15 +329,22 @@ impl<R: Read> RdbParser<R> {
                     let value = self.parse_string()?;
                     metadata.insert(key, value);
                 }
+                RDB_OPCODE_FUNCTION => {
+                    functions.push(Bytes::from(self.parse_bytes()?));
+                }
+                RDB_OPCODE_MODULE_AUX => {
+                    let name = self.parse_string()?;
+                    module_aux.push((name, self.parse_bytes()?));
+                }
                 0xFE => {
-                    // Start of database section - preserve byte for parse_databases
+                    // Start of database section - preserve byte for next_item
                     self.peeked_byte = Some(0xFE);
-                    return Ok(metadata);
+                    return Ok((metadata, functions, module_aux));
                 }
                 0xFF => {
-                    // End of file - preserve byte for parse_databases
+                    // End of file - preserve byte for next_item
                     self.peeked_byte = Some(0xFF);
-                    return Ok(metadata);
+                    return Ok((metadata, functions, module_aux));
                 }
                 _ => {
                     return Err(RdbError::InvalidFormat(format!(
@@ -177,105 +356,79 @@ impl<R: Read> RdbParser<R> {
         }
     }
 
-    fn parse_databases(&mut self) -> Result<Vec<Database>, RdbError> {
-        let mut databases = Vec::new();
-
-        loop {
-            let byte = match self.read_byte() {
-                Ok(b) => b,
-                Err(RdbError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            };
+    /// Reads the next database header or key after the preamble, so a file
+    /// can be loaded without holding all of it in memory. None at the end of
+    /// the file, once its checksum has been checked.
+    pub fn next_item(&mut self) -> Result<Option<RdbItem>, RdbError> {
+        if self.finished {
+            return Ok(None);
+        }
+        // Every file ends with 0xFF, so running out before it means the
+        // file was cut short
+        let byte = match self.read_byte() {
+            Ok(b) => b,
+            Err(RdbError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(RdbError::UnexpectedEof);
+            }
+            Err(e) => return Err(e),
+        };
 
-            match byte {
-                0xFE => {
-                    // Database subsection
-                    let db = self.parse_database()?;
-                    databases.push(db);
+        match byte {
+            0xFE => {
+                // Database subsection, maybe with its hash table sizes
+                let index = self.parse_length()?;
+                let mut key_value_hash_size = 0;
+                let mut expire_hash_size = 0;
+                let byte = self.read_byte()?;
+                if byte == 0xFB {
+                    key_value_hash_size = self.parse_length()?;
+                    expire_hash_size = self.parse_length()?;
+                } else {
+                    // Not a hash table size marker - this is the start of key-value pairs
+                    self.peeked_byte = Some(byte);
                 }
-                0xFF => {
-                    // End of file - read and validate checksum
-                    let mut checksum = [0u8; 8];
-                    match self.reader.read_exact(&mut checksum) {
-                        Ok(_) => {
-                            // TODO: Implement actual CRC64 validation
+                self.db = Some(index);
+                Ok(Some(RdbItem::Db {
+                    index,
+                    key_value_hash_size,
+                    expire_hash_size,
+                }))
+            }
+            0xFF => {
+                // End of file: the checksum covers everything up to here
+                self.finished = true;
+                let expected = self.reader.crc;
+                // Files before version 5 have no checksum at all
+                if self.version < 5 {
+                    return Ok(None);
+                }
+                let mut checksum = [0u8; 8];
+                match self.reader.read_exact(&mut checksum) {
+                    Ok(_) => {
+                        // Zero means the writer didn't compute one
+                        let checksum = u64::from_le_bytes(checksum);
+                        if self.verify_checksum && checksum != 0 && checksum != expected {
+                            return Err(RdbError::ChecksumMismatch);
                         }
-                        Err(_) => {
-                            // Some RDB files might not have checksum
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        if self.verify_checksum {
+                            return Err(RdbError::UnexpectedEof);
                         }
                     }
-                    break;
-                }
-                _ => {
-                    return Err(RdbError::InvalidFormat(format!(
-                        "Unexpected byte in database section: 0x{:02X}",
-                        byte
-                    )));
+                    Err(e) => return Err(e.into()),
                 }
+                Ok(None)
             }
-        }
-
-        Ok(databases)
-    }
-
-    fn parse_database(&mut self) -> Result<Database, RdbError> {
-        // Parse database index
-        let index = self.parse_length()?;
-
-        let mut key_value_hash_size = 0;
-        let mut expire_hash_size = 0;
-        let mut entries = Vec::new();
-
-        // Check for hash table size info (0xFB)
-        let byte = self.read_byte()?;
-
-        if byte == 0xFB {
-            key_value_hash_size = self.parse_length()?;
-            expire_hash_size = self.parse_length()?;
-        } else {
-            // Not a hash table size marker - this is the start of key-value pairs
-            self.peeked_byte = Some(byte);
-        }
-
-        // Parse key-value pairs
-        loop {
-            let byte = match self.read_byte() {
-                Ok(b) => b,
-                Err(RdbError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            };
-
-            match byte {
-                // Expiry markers
-                0xFC | 0xFD => {
-                    let entry = self.parse_key_value_pair(byte)?;
-                    entries.push(entry);
-                }
-                // Value type bytes (0x00 = string, 0x01-0x0E = other types)
-                0x00..=0x0E => {
-                    let entry = self.parse_key_value_pair(byte)?;
-                    entries.push(entry);
-                }
-                // End of database or file
-                0xFE | 0xFF => {
-                    self.peeked_byte = Some(byte);
-                    break;
-                }
-                _ => {
-                    return Err(RdbError::InvalidFormat(format!(
-                        "Unexpected byte in database {}: 0x{:02X}",
-                        index, byte
-                    )));
-                }
+            // Expiry markers and value type bytes (0x00 = string, 0x01-0x15 = other types)
+            0xFC | 0xFD | 0x00..=0x15 if self.db.is_some() => {
+                Ok(Some(RdbItem::Entry(self.parse_key_value_pair(byte)?)))
             }
+            _ => Err(RdbError::InvalidFormat(match self.db {
+                Some(index) => format!("Unexpected byte in database {}: 0x{:02X}", index, byte),
+                None => format!("Unexpected byte in database section: 0x{:02X}", byte),
+            })),
         }
-
-        Ok(Database {
-            index,
-            key_value_hash_size,
-            expire_hash_size,
-            entries,
-        })
     }
 
     fn parse_key_value_pair(&mut self, first_byte: u8) -> Result<KeyValuePair, RdbError> {
@@ -307,19 +460,108 @@ impl<R: Read> RdbParser<R> {
         };
 
         // Parse key
-        let key = self.parse_string()?;
+        let key = Bytes::from(self.parse_bytes()?);
 
         // Parse value based on type
         let value = match value_type {
-            0x00 => {
-                // String type
-                let val_str = self.parse_string()?;
-                Value::String(val_str)
+            RDB_TYPE_STRING => Value::String(Bytes::from(self.parse_bytes()?)),
+            RDB_TYPE_LIST | RDB_TYPE_SET => {
+                let mut items = Vec::new();
+                for _ in 0..self.parse_length()? {
+                    items.push(Bytes::from(self.parse_bytes()?));
+                }
+                if value_type == RDB_TYPE_LIST {
+                    Value::List(items)
+                } else {
+                    Value::Set(items)
+                }
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let mut members = Vec::new();
+                for _ in 0..self.parse_length()? {
+                    let member = Bytes::from(self.parse_bytes()?);
+                    let score = if value_type == RDB_TYPE_ZSET {
+                        self.parse_text_double()?
+                    } else {
+                        let mut raw = [0u8; 8];
+                        self.reader.read_exact(&mut raw)?;
+                        f64::from_le_bytes(raw)
+                    };
+                    members.push((member, score));
+                }
+                Value::SortedSet(members)
+            }
+            RDB_TYPE_HASH => {
+                let mut fields = Vec::new();
+                for _ in 0..self.parse_length()? {
+                    let field = Bytes::from(self.parse_bytes()?);
+                    let value = Bytes::from(self.parse_bytes()?);
+                    fields.push((field, value));
+                }
+                Value::Hash(fields)
+            }
+            RDB_TYPE_HASH_ZIPMAP => {
+                let data = self.parse_bytes()?;
+                Value::Hash(decode_zipmap(&data).map_err(RdbError::InvalidFormat)?)
+            }
+            RDB_TYPE_LIST_ZIPLIST => Value::List(
+                self.parse_packed(true)?
+                    .into_iter()
+                    .map(listpack::Entry::into_bytes)
+                    .collect(),
+            ),
+            RDB_TYPE_SET_INTSET => {
+                let data = self.parse_bytes()?;
+                Value::Set(decode_intset(&data).map_err(RdbError::InvalidFormat)?)
+            }
+            RDB_TYPE_SET_LISTPACK => Value::Set(
+                self.parse_packed(false)?
+                    .into_iter()
+                    .map(listpack::Entry::into_bytes)
+                    .collect(),
+            ),
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let entries = self.parse_packed(value_type == RDB_TYPE_ZSET_ZIPLIST)?;
+                let members = pairs(entries)
+                    .map_err(RdbError::InvalidFormat)?
+                    .into_iter()
+                    .map(|(member, score)| Ok((member.into_bytes(), score_of(score)?)))
+                    .collect::<Result<_, String>>()
+                    .map_err(RdbError::InvalidFormat)?;
+                Value::SortedSet(members)
+            }
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let entries = self.parse_packed(value_type == RDB_TYPE_HASH_ZIPLIST)?;
+                let fields = pairs(entries)
+                    .map_err(RdbError::InvalidFormat)?
+                    .into_iter()
+                    .map(|(field, value)| (field.into_bytes(), value.into_bytes()))
+                    .collect();
+                Value::Hash(fields)
+            }
+            RDB_TYPE_LIST_QUICKLIST => {
+                let mut items = Vec::new();
+                for _ in 0..self.parse_length()? {
+                    let entries = self.parse_packed(true)?;
+                    items.extend(entries.into_iter().map(listpack::Entry::into_bytes));
+                }
+                Value::List(items)
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => Value::List(self.parse_quicklist()?),
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => Value::Stream(self.parse_stream(value_type)?),
+            RDB_TYPE_MODULE | RDB_TYPE_MODULE_2 => {
+                return Err(RdbError::InvalidFormat(format!(
+                    "Key '{}' holds a module value, which no loaded module can read",
+                    String::from_utf8_lossy(&key)
+                )));
             }
             _ => {
                 return Err(RdbError::InvalidFormat(format!(
                     "Unsupported value type: 0x{:02X} for key '{}'",
-                    value_type, key
+                    value_type,
+                    String::from_utf8_lossy(&key)
                 )));
             }
         };
@@ -328,6 +570,18 @@ impl<R: Read> RdbParser<R> {
     }
 
     fn parse_length(&mut self) -> Result<u64, RdbError> {
+        match self.parse_length_or_encoding()? {
+            (len, false) => Ok(len),
+            (encoding, true) => Err(RdbError::InvalidFormat(format!(
+                "Unexpected string encoding {} where a length was expected",
+                encoding
+            ))),
+        }
+    }
+
+    // A length, or with `true` the special string encoding (integer or LZF)
+    // that follows in its place
+    fn parse_length_or_encoding(&mut self) -> Result<(u64, bool), RdbError> {
         let byte = self.read_byte()?;
 
         let first_two_bits = (byte & 0xC0) >> 6;
@@ -335,191 +589,818 @@ impl<R: Read> RdbParser<R> {
         match first_two_bits {
             0b00 => {
                 // Length is in the remaining 6 bits
-                Ok((byte & 0x3F) as u64)
+                Ok(((byte & 0x3F) as u64, false))
             }
             0b01 => {
                 // Length is in next 14 bits (6 bits + 8 bits)
                 let next_byte = self.read_byte()?;
                 let len = (((byte & 0x3F) as u64) << 8) | (next_byte as u64);
-                Ok(len)
+                Ok((len, false))
+            }
+            0b10 if byte == 0x81 => {
+                // Length is in next 8 bytes (big-endian)
+                let mut len_bytes = [0u8; 8];
+                self.reader.read_exact(&mut len_bytes)?;
+                Ok((u64::from_be_bytes(len_bytes), false))
             }
             0b10 => {
                 // Length is in next 4 bytes (big-endian)
                 let mut len_bytes = [0u8; 4];
                 self.reader.read_exact(&mut len_bytes)?;
-                Ok(u32::from_be_bytes(len_bytes) as u64)
+                Ok((u32::from_be_bytes(len_bytes) as u64, false))
             }
             0b11 => {
-                // Special encoding format indicator
-                Ok(byte as u64)
+                // Special encoding format in the remaining 6 bits
+                Ok(((byte & 0x3F) as u64, true))
             }
             _ => unreachable!(),
         }
     }
 
+    fn parse_quicklist(&mut self) -> Result<Vec<Bytes>, RdbError> {
+        let nodes = self.parse_length()?;
+        let mut items = Vec::new();
+        for _ in 0..nodes {
+            let container = self.parse_length()?;
+            let data = self.parse_bytes()?;
+            if container == QUICKLIST_NODE_PLAIN {
+                items.push(Bytes::from(data));
+                continue;
+            }
+            let entries = listpack::decode(&data).map_err(RdbError::InvalidFormat)?;
+            items.extend(entries.into_iter().map(listpack::Entry::into_bytes));
+        }
+        Ok(items)
+    }
+
+    // A ziplist or listpack saved as a string
+    fn parse_packed(&mut self, ziplist: bool) -> Result<Vec<listpack::Entry>, RdbError> {
+        let data = self.parse_bytes()?;
+        let entries = if ziplist {
+            ziplist::decode(&data)
+        } else {
+            listpack::decode(&data)
+        };
+        entries.map_err(RdbError::InvalidFormat)
+    }
+
+    // A score saved as text: a length byte, or 253 to 255 for NaN, +inf and
+    // -inf
+    fn parse_text_double(&mut self) -> Result<f64, RdbError> {
+        let len = self.read_byte()?;
+        match len {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            _ => {
+                let mut text = vec![0u8; len as usize];
+                self.reader.read_exact(&mut text)?;
+                std::str::from_utf8(&text)
+                    .ok()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or_else(|| RdbError::InvalidFormat("Invalid sorted set score".to_string()))
+            }
+        }
+    }
+
+    // Older versions leave out the fields later ones added, see the
+    // RDB_TYPE_STREAM_LISTPACKS constants
+    fn parse_stream(&mut self, value_type: u8) -> Result<StreamData, RdbError> {
+        let mut stream = StreamData::default();
+        let nodes = self.parse_length()?;
+        for _ in 0..nodes {
+            let key = self.parse_bytes()?;
+            let master_id = key
+                .get(..16)
+                .map(|k| read_stream_id(k.try_into().unwrap()))
+                .ok_or_else(|| {
+                    RdbError::InvalidFormat("Stream node key is not an ID".to_string())
+                })?;
+            let data = self.parse_bytes()?;
+            let entries = listpack::decode(&data).map_err(RdbError::InvalidFormat)?;
+            stream
+                .entries
+                .extend(parse_stream_node(master_id, entries).map_err(RdbError::InvalidFormat)?);
+        }
+
+        let length = self.parse_length()?;
+        stream.last_id = (self.parse_length()?, self.parse_length()?);
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            let _first_id = (self.parse_length()?, self.parse_length()?);
+            stream.max_deleted_id = (self.parse_length()?, self.parse_length()?);
+            stream.entries_added = self.parse_length()?;
+        } else {
+            stream.entries_added = length;
+        }
+
+        let groups = self.parse_length()?;
+        for _ in 0..groups {
+            let name = Bytes::from(self.parse_bytes()?);
+            let last_delivered_id = (self.parse_length()?, self.parse_length()?);
+            let entries_read = match value_type {
+                RDB_TYPE_STREAM_LISTPACKS => None,
+                _ => match self.parse_length()? {
+                    u64::MAX => None,
+                    n => Some(n),
+                },
+            };
+            let mut pending = Vec::new();
+            for _ in 0..self.parse_length()? {
+                let id = self.read_raw_id()?;
+                let delivery_time = self.read_millis()?;
+                let delivery_count = self.parse_length()?;
+                pending.push(PendingData {
+                    id,
+                    delivery_time: delivery_time.max(0) as u64,
+                    delivery_count,
+                });
+            }
+            let mut consumers = Vec::new();
+            for _ in 0..self.parse_length()? {
+                let name = Bytes::from(self.parse_bytes()?);
+                let seen_time = self.read_millis()?.max(0) as u64;
+                let active_time = match value_type {
+                    RDB_TYPE_STREAM_LISTPACKS_3 => match self.read_millis()? {
+                        -1 => None,
+                        t => Some(t.max(0) as u64),
+                    },
+                    _ => None,
+                };
+                let mut ids = Vec::new();
+                for _ in 0..self.parse_length()? {
+                    ids.push(self.read_raw_id()?);
+                }
+                consumers.push(ConsumerData {
+                    name,
+                    seen_time,
+                    active_time,
+                    pending: ids,
+                });
+            }
+            stream.groups.push(GroupData {
+                name,
+                last_delivered_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+        Ok(stream)
+    }
+
+    fn read_raw_id(&mut self) -> Result<StreamId, RdbError> {
+        let mut raw = [0u8; 16];
+        self.reader.read_exact(&mut raw)?;
+        Ok(read_stream_id(&raw))
+    }
+
+    // `len` bytes, allocated as they arrive: the length comes from the file,
+    // and a corrupt one mustn't ask for more memory than the file holds
+    fn read_vec(&mut self, len: u64) -> Result<Vec<u8>, RdbError> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if (buf.len() as u64) < len {
+            return Err(RdbError::UnexpectedEof);
+        }
+        Ok(buf)
+    }
+
+    fn read_millis(&mut self) -> Result<i64, RdbError> {
+        let mut raw = [0u8; 8];
+        self.reader.read_exact(&mut raw)?;
+        Ok(i64::from_le_bytes(raw))
+    }
+
     fn parse_string(&mut self) -> Result<String, RdbError> {
-        let size = self.parse_length()?;
+        let bytes = self.parse_bytes()?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    pub(crate) fn parse_bytes(&mut self) -> Result<Vec<u8>, RdbError> {
+        let (size, encoded) = self.parse_length_or_encoding()?;
+        if !encoded {
+            return self.read_vec(size);
+        }
 
         match size {
-            192 => {
-                // 0xC0 as u64
-                // 8-bit integer
-                let byte = self.read_byte()?;
-                Ok(byte.to_string())
+            // Integers are signed and little-endian, and stand for their
+            // decimal text
+            0 => {
+                // 0xC0: 8-bit integer
+                let val = self.read_byte()? as i8;
+                Ok(val.to_string().into_bytes())
             }
-            193 => {
-                // 0xC1 as u64
-                // 16-bit integer (little-endian)
+            1 => {
+                // 0xC1: 16-bit integer
                 let mut bytes = [0u8; 2];
                 self.reader.read_exact(&mut bytes)?;
-                let val = u16::from_le_bytes(bytes);
-                Ok(val.to_string())
+                let val = i16::from_le_bytes(bytes);
+                Ok(val.to_string().into_bytes())
             }
-            194 => {
-                // 0xC2 as u64
-                // 32-bit integer (little-endian)
+            2 => {
+                // 0xC2: 32-bit integer
                 let mut bytes = [0u8; 4];
                 self.reader.read_exact(&mut bytes)?;
-                let val = u32::from_le_bytes(bytes);
-                Ok(val.to_string())
+                let val = i32::from_le_bytes(bytes);
+                Ok(val.to_string().into_bytes())
+            }
+            3 => {
+                // 0xC3: compressed length, original length, LZF data
+                let compressed_len = self.parse_length()?;
+                let len = self.parse_length()?;
+                let compressed = self.read_vec(compressed_len)?;
+                let len = usize::try_from(len)
+                    .map_err(|_| RdbError::InvalidFormat("String too long".to_string()))?;
+                lzf::decompress(&compressed, len).map_err(RdbError::InvalidFormat)
+            }
+            encoding => Err(RdbError::InvalidFormat(format!(
+                "Unknown string encoding {}",
+                encoding
+            ))),
+        }
+    }
+}
+
+// Stream IDs in node keys and PELs are big-endian so they sort as bytes
+fn read_stream_id(raw: &[u8; 16]) -> StreamId {
+    (
+        u64::from_be_bytes(raw[..8].try_into().unwrap()),
+        u64::from_be_bytes(raw[8..].try_into().unwrap()),
+    )
+}
+
+fn write_stream_id(out: &mut Vec<u8>, id: StreamId) {
+    out.extend_from_slice(&id.0.to_be_bytes());
+    out.extend_from_slice(&id.1.to_be_bytes());
+}
+
+// Splits the entries of a listpack or ziplist holding field/value or
+// member/score pairs
+fn pairs(entries: Vec<listpack::Entry>) -> Result<Vec<(listpack::Entry, listpack::Entry)>, String> {
+    if !entries.len().is_multiple_of(2) {
+        return Err("Odd number of elements in a paired listpack".to_string());
+    }
+    let mut items = entries.into_iter();
+    let mut out = Vec::new();
+    while let (Some(a), Some(b)) = (items.next(), items.next()) {
+        out.push((a, b));
+    }
+    Ok(out)
+}
+
+fn score_of(entry: listpack::Entry) -> Result<f64, String> {
+    match entry {
+        listpack::Entry::Int(i) => Ok(i as f64),
+        listpack::Entry::Str(s) => std::str::from_utf8(&s)
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .ok_or_else(|| "Invalid sorted set score".to_string()),
+    }
+}
+
+// An intset: the integer width (2, 4 or 8 bytes), the count, then the
+// integers in ascending order, all little-endian
+fn decode_intset(data: &[u8]) -> Result<Vec<Bytes>, String> {
+    let invalid = || "Invalid intset".to_string();
+    let header = data.get(..8).ok_or_else(invalid)?;
+    let width = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let count = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) || data.len() != 8 + width * count {
+        return Err(invalid());
+    }
+    Ok(data[8..]
+        .chunks(width)
+        .map(|raw| {
+            let value = match width {
+                2 => i16::from_le_bytes(raw.try_into().unwrap()) as i64,
+                4 => i32::from_le_bytes(raw.try_into().unwrap()) as i64,
+                _ => i64::from_le_bytes(raw.try_into().unwrap()),
+            };
+            Bytes::from(value.to_string())
+        })
+        .collect())
+}
+
+// A zipmap, the hash encoding before ziplists: a count byte, then each field
+// and value with its length, the value followed by unused padding
+fn decode_zipmap(data: &[u8]) -> Result<Vec<(Bytes, Bytes)>, String> {
+    let invalid = || "Invalid zipmap".to_string();
+    let mut pos = 1;
+    // A length byte, or 254 and a 4-byte length
+    let read_len = |pos: &mut usize| -> Result<Option<usize>, String> {
+        let byte = *data.get(*pos).ok_or_else(invalid)?;
+        *pos += 1;
+        match byte {
+            255 => Ok(None),
+            254 => {
+                let raw = data.get(*pos..*pos + 4).ok_or_else(invalid)?;
+                *pos += 4;
+                Ok(Some(u32::from_le_bytes(raw.try_into().unwrap()) as usize))
             }
-            195 => {
-                // 0xC3 as u64
-                return Err(RdbError::InvalidFormat(
-                    "LZF-compressed strings not supported".to_string(),
+            len => Ok(Some(len as usize)),
+        }
+    };
+    let mut fields = Vec::new();
+    while let Some(len) = read_len(&mut pos)? {
+        let field = data.get(pos..pos + len).ok_or_else(invalid)?;
+        pos += len;
+        let len = read_len(&mut pos)?.ok_or_else(invalid)?;
+        let free = *data.get(pos).ok_or_else(invalid)? as usize;
+        let value = data.get(pos + 1..pos + 1 + len).ok_or_else(invalid)?;
+        pos += 1 + len + free;
+        fields.push((Bytes::copy_from_slice(field), Bytes::copy_from_slice(value)));
+    }
+    Ok(fields)
+}
+
+// A stream listpack node: a master entry with the field names the node's
+// entries may share, then each entry as flags, ID deltas against the node
+// key, fields and the number of elements it took.
+fn parse_stream_node(
+    master_id: StreamId,
+    entries: Vec<listpack::Entry>,
+) -> Result<Vec<(StreamId, StreamEntry)>, String> {
+    let invalid = || "Invalid stream listpack node".to_string();
+    let mut items = entries.into_iter();
+    let next_int = |items: &mut std::vec::IntoIter<listpack::Entry>| {
+        items.next().and_then(|e| e.as_int()).ok_or_else(invalid)
+    };
+    // A count of things still to come, each taking at least one element
+    let next_count = |items: &mut std::vec::IntoIter<listpack::Entry>| {
+        let count = next_int(items)?;
+        usize::try_from(count)
+            .ok()
+            .filter(|&count| count <= items.len())
+            .ok_or_else(invalid)
+    };
+    let count = next_count(&mut items)?;
+    let deleted = next_count(&mut items)?;
+    let entries = count
+        .checked_add(deleted)
+        .filter(|&entries| entries <= items.len())
+        .ok_or_else(invalid)?;
+    let num_fields = next_count(&mut items)?;
+    let mut master_fields = Vec::with_capacity(num_fields);
+    for _ in 0..num_fields {
+        master_fields.push(items.next().ok_or_else(invalid)?.into_bytes());
+    }
+    // Master entry terminator
+    next_int(&mut items)?;
+
+    let mut out = Vec::new();
+    for _ in 0..entries {
+        let flags = next_int(&mut items)?;
+        let ms = master_id.0.wrapping_add(next_int(&mut items)? as u64);
+        let seq = master_id.1.wrapping_add(next_int(&mut items)? as u64);
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push((
+                    field.clone(),
+                    items.next().ok_or_else(invalid)?.into_bytes(),
                 ));
             }
-            len => {
-                // Regular string
-                let mut buf = vec![0u8; len as usize];
-                self.reader.read_exact(&mut buf)?;
-                Ok(String::from_utf8_lossy(&buf).to_string())
+        } else {
+            for _ in 0..next_count(&mut items)? {
+                let field = items.next().ok_or_else(invalid)?.into_bytes();
+                let value = items.next().ok_or_else(invalid)?.into_bytes();
+                fields.push((field, value));
+            }
+        }
+        next_int(&mut items)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            out.push(((ms, seq), fields));
+        }
+    }
+    Ok(out)
+}
+
+// Whether a collection is small enough for Redis to keep it as a listpack
+fn fits_listpack(mut sizes: impl Iterator<Item = usize>, count: usize) -> bool {
+    count <= MAX_LISTPACK_ENTRIES && sizes.all(|size| size <= MAX_LISTPACK_VALUE)
+}
+
+/// Appends `len` in the RDB length encoding.
+pub(crate) fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Appends `s` as a length-prefixed RDB string.
+pub(crate) fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_length(out, s.len() as u64);
+    out.extend_from_slice(s);
+}
+
+/// Serialises a dataset into an RDB file that redis-server can load as well.
+pub struct RdbWriter {
+    buf: Vec<u8>,
+    // LZF-compress strings longer than 20 bytes, like rdbcompression
+    compression: bool,
+}
+
+impl Default for RdbWriter {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl RdbWriter {
+    /// Starts a file with the header and the aux fields Redis writes.
+    pub fn new(compression: bool) -> Self {
+        let mut writer = RdbWriter {
+            buf: format!("REDIS{:04}", RDB_VERSION).into_bytes(),
+            compression,
+        };
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        writer.aux("redis-ver", b"7.2.0");
+        writer.aux("redis-bits", b"64");
+        writer.aux("ctime", ctime.to_string().as_bytes());
+        writer.aux("used-mem", b"0");
+        writer.aux("aof-base", b"0");
+        writer
+    }
+
+    fn write_string(&mut self, s: &[u8]) {
+        // Like Redis, numbers that read back as the same text are saved as
+        // integers
+        if let Some(i) = listpack::canonical_int(s).and_then(|i| i32::try_from(i).ok()) {
+            if let Ok(i) = i8::try_from(i) {
+                self.buf.push(0xC0);
+                self.buf.extend_from_slice(&i.to_le_bytes());
+            } else if let Ok(i) = i16::try_from(i) {
+                self.buf.push(0xC1);
+                self.buf.extend_from_slice(&i.to_le_bytes());
+            } else {
+                self.buf.push(0xC2);
+                self.buf.extend_from_slice(&i.to_le_bytes());
+            }
+            return;
+        }
+        if self.compression && s.len() > 20 {
+            if let Some(compressed) = lzf::compress(s) {
+                self.buf.push(0xC3);
+                write_length(&mut self.buf, compressed.len() as u64);
+                write_length(&mut self.buf, s.len() as u64);
+                self.buf.extend_from_slice(&compressed);
+                return;
+            }
+        }
+        write_string(&mut self.buf, s);
+    }
+
+    pub fn aux(&mut self, key: &str, value: &[u8]) {
+        self.buf.push(0xFA);
+        self.write_string(key.as_bytes());
+        self.write_string(value);
+    }
+
+    pub fn function(&mut self, code: &[u8]) {
+        self.buf.push(RDB_OPCODE_FUNCTION);
+        self.write_string(code);
+    }
+
+    pub fn module_aux(&mut self, name: &str, data: &[u8]) {
+        self.buf.push(RDB_OPCODE_MODULE_AUX);
+        self.write_string(name.as_bytes());
+        self.write_string(data);
+    }
+
+    pub fn select_db(&mut self, index: u64) {
+        self.buf.push(0xFE);
+        write_length(&mut self.buf, index);
+    }
+
+    /// Key and expire counts for the database just selected, so a loader can
+    /// size its tables up front.
+    pub fn resize_db(&mut self, db_size: u64, expires_size: u64) {
+        self.buf.push(0xFB);
+        write_length(&mut self.buf, db_size);
+        write_length(&mut self.buf, expires_size);
+    }
+
+    // Expiry and type byte ahead of every key
+    fn key(&mut self, value_type: u8, key: &[u8], expire_ms: Option<u64>) {
+        if let Some(ms) = expire_ms {
+            self.buf.push(0xFC);
+            self.buf.extend_from_slice(&ms.to_le_bytes());
+        }
+        self.buf.push(value_type);
+        self.write_string(key);
+    }
+
+    /// A string key; `expire_ms` is an absolute Unix time in milliseconds.
+    pub fn string(&mut self, key: &[u8], value: &[u8], expire_ms: Option<u64>) {
+        self.key(RDB_TYPE_STRING, key, expire_ms);
+        self.write_string(value);
+    }
+
+    pub fn list<'a>(&mut self, key: &[u8], items: impl IntoIterator<Item = &'a Bytes>) {
+        let mut nodes = Vec::new();
+        let mut node = Listpack::new();
+        for item in items {
+            if node.count() > 0 && node.size() + item.len() > LIST_NODE_MAX_BYTES {
+                nodes.push(std::mem::take(&mut node).finish());
+            }
+            node.push_str(item);
+        }
+        if node.count() > 0 {
+            nodes.push(node.finish());
+        }
+
+        self.key(RDB_TYPE_LIST_QUICKLIST_2, key, None);
+        write_length(&mut self.buf, nodes.len() as u64);
+        for node in nodes {
+            write_length(&mut self.buf, 2);
+            self.write_string(&node);
+        }
+    }
+
+    pub fn set<'a>(&mut self, key: &[u8], members: impl IntoIterator<Item = &'a Bytes>) {
+        let members: Vec<_> = members.into_iter().collect();
+        if fits_listpack(members.iter().map(|m| m.len()), members.len()) {
+            let mut lp = Listpack::new();
+            for member in members {
+                lp.push_str(member);
+            }
+            self.key(RDB_TYPE_SET_LISTPACK, key, None);
+            self.write_string(&lp.finish());
+            return;
+        }
+        self.key(RDB_TYPE_SET, key, None);
+        write_length(&mut self.buf, members.len() as u64);
+        for member in members {
+            self.write_string(member);
+        }
+    }
+
+    pub fn zset<'a>(&mut self, key: &[u8], members: impl IntoIterator<Item = (&'a Bytes, f64)>) {
+        let members: Vec<_> = members.into_iter().collect();
+        if fits_listpack(members.iter().map(|(m, _)| m.len()), members.len()) {
+            let mut lp = Listpack::new();
+            for (member, score) in members {
+                lp.push_str(member);
+                lp.push_str(score.to_string().as_bytes());
+            }
+            self.key(RDB_TYPE_ZSET_LISTPACK, key, None);
+            self.write_string(&lp.finish());
+            return;
+        }
+        self.key(RDB_TYPE_ZSET_2, key, None);
+        write_length(&mut self.buf, members.len() as u64);
+        // Highest score first, the order Redis saves them in
+        for (member, score) in members.into_iter().rev() {
+            self.write_string(member);
+            self.buf.extend_from_slice(&score.to_le_bytes());
+        }
+    }
+
+    pub fn hash<'a>(
+        &mut self,
+        key: &[u8],
+        fields: impl IntoIterator<Item = (&'a Bytes, &'a Bytes)>,
+    ) {
+        let fields: Vec<_> = fields.into_iter().collect();
+        let sizes = fields.iter().flat_map(|(f, v)| [f.len(), v.len()]);
+        if fits_listpack(sizes, fields.len()) {
+            let mut lp = Listpack::new();
+            for (field, value) in fields {
+                lp.push_str(field);
+                lp.push_str(value);
+            }
+            self.key(RDB_TYPE_HASH_LISTPACK, key, None);
+            self.write_string(&lp.finish());
+            return;
+        }
+        self.key(RDB_TYPE_HASH, key, None);
+        write_length(&mut self.buf, fields.len() as u64);
+        for (field, value) in fields {
+            self.write_string(field);
+            self.write_string(value);
+        }
+    }
+
+    pub fn stream(&mut self, key: &[u8], stream: &StreamData) {
+        self.key(RDB_TYPE_STREAM_LISTPACKS_3, key, None);
+
+        let nodes: Vec<_> = stream.entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+        write_length(&mut self.buf, nodes.len() as u64);
+        for node in nodes {
+            let (master_id, master) = &node[0];
+            let master_fields: Vec<&Bytes> = master.iter().map(|(f, _)| f).collect();
+            let mut lp = Listpack::new();
+            lp.push_int(node.len() as i64);
+            lp.push_int(0);
+            lp.push_int(master_fields.len() as i64);
+            for field in &master_fields {
+                lp.push_str(field);
+            }
+            lp.push_int(0);
+            for (id, fields) in node {
+                let same_fields = fields.len() == master_fields.len()
+                    && fields.iter().zip(&master_fields).all(|((f, _), m)| f == *m);
+                lp.push_int(if same_fields {
+                    STREAM_ITEM_FLAG_SAMEFIELDS
+                } else {
+                    0
+                });
+                lp.push_int(id.0.wrapping_sub(master_id.0) as i64);
+                lp.push_int(id.1.wrapping_sub(master_id.1) as i64);
+                if same_fields {
+                    for (_, value) in fields {
+                        lp.push_str(value);
+                    }
+                    lp.push_int(fields.len() as i64 + 3);
+                } else {
+                    lp.push_int(fields.len() as i64);
+                    for (field, value) in fields {
+                        lp.push_str(field);
+                        lp.push_str(value);
+                    }
+                    lp.push_int(fields.len() as i64 * 2 + 4);
+                }
             }
+            let mut node_key = Vec::with_capacity(16);
+            write_stream_id(&mut node_key, *master_id);
+            self.write_string(&node_key);
+            self.write_string(&lp.finish());
         }
+
+        let first_id = stream.entries.first().map(|(id, _)| *id).unwrap_or((0, 0));
+        for n in [
+            stream.entries.len() as u64,
+            stream.last_id.0,
+            stream.last_id.1,
+            first_id.0,
+            first_id.1,
+            stream.max_deleted_id.0,
+            stream.max_deleted_id.1,
+            stream.entries_added,
+        ] {
+            write_length(&mut self.buf, n);
+        }
+
+        write_length(&mut self.buf, stream.groups.len() as u64);
+        for group in &stream.groups {
+            self.write_string(&group.name);
+            write_length(&mut self.buf, group.last_delivered_id.0);
+            write_length(&mut self.buf, group.last_delivered_id.1);
+            write_length(&mut self.buf, group.entries_read.unwrap_or(u64::MAX));
+            write_length(&mut self.buf, group.pending.len() as u64);
+            for pending in &group.pending {
+                write_stream_id(&mut self.buf, pending.id);
+                self.buf
+                    .extend_from_slice(&(pending.delivery_time as i64).to_le_bytes());
+                write_length(&mut self.buf, pending.delivery_count);
+            }
+            write_length(&mut self.buf, group.consumers.len() as u64);
+            for consumer in &group.consumers {
+                self.write_string(&consumer.name);
+                self.buf
+                    .extend_from_slice(&(consumer.seen_time as i64).to_le_bytes());
+                let active = consumer.active_time.map_or(-1, |t| t as i64);
+                self.buf.extend_from_slice(&active.to_le_bytes());
+                write_length(&mut self.buf, consumer.pending.len() as u64);
+                for id in &consumer.pending {
+                    write_stream_id(&mut self.buf, *id);
+                }
+            }
+        }
+    }
+
+    /// Ends the file with its CRC64, or with zero, which loaders take as
+    /// "not computed", when `checksum` is off.
+    pub fn finish(mut self, checksum: bool) -> Vec<u8> {
+        self.buf.push(0xFF);
+        let crc = if checksum { crc64::crc64(&self.buf) } else { 0 };
+        self.buf.extend_from_slice(&crc.to_le_bytes());
+        self.buf
     }
 }
 
 // Helper function to parse from bytes
-pub fn parse_rdb(data: &[u8]) -> Result<RdbFile, RdbError> {
+pub fn parse_rdb(data: &[u8], verify_checksum: bool) -> Result<RdbFile, RdbError> {
     let cursor = Cursor::new(data);
-    let mut parser = RdbParser::new(cursor);
+    let mut parser = RdbParser::new(cursor).verify_checksum(verify_checksum);
     parser.parse()
 }
 
+/// Parses the RDB file at the start of `data`, like the preamble of an
+/// append-only file, and returns how many bytes it took up.
+pub fn parse_rdb_prefix(data: &[u8], verify_checksum: bool) -> Result<(RdbFile, usize), RdbError> {
+    let mut parser = RdbParser::new(Cursor::new(data)).verify_checksum(verify_checksum);
+    let rdb_file = parser.parse()?;
+    Ok((rdb_file, parser.reader.inner.position() as usize))
+}
+
+use crate::notify::{DbNotifier, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_NEW, NOTIFY_STRING};
 use crate::resp::RedisValueRef;
 use tokio::sync::RwLock;
+#[derive(Clone)]
 struct Set {
     value: Bytes,
     expiry: Option<Instant>,
 }
 
-pub struct RdbPath {
-    dir: String,
-    dbfilename: String,
-}
-
-impl RdbPath {
-    pub fn new() -> Self {
-        RdbPath {
-            dir: String::new(),
-            dbfilename: String::new(),
-        }
-    }
-}
-
 pub struct KeyValue {
-    entries: RwLock<HashMap<Bytes, Set>>,
-    path: RwLock<RdbPath>,
+    // Persistent, so a snapshot is a cheap clone that later writes don't touch
+    entries: RwLock<im::HashMap<Bytes, Set>>,
+    // Every TTL given to a key, soonest first, so the expire cycle only
+    // looks at keys that are due. Entries go stale when a key is replaced;
+    // the cycle checks each against the key before removing it.
+    expiries: Mutex<BTreeSet<(Instant, Bytes)>>,
+    notifier: DbNotifier,
 }
 
 impl KeyValue {
-    pub fn new() -> Self {
+    pub fn new(notifier: DbNotifier) -> Self {
         KeyValue {
-            entries: RwLock::new(HashMap::new()),
-            path: RwLock::new(RdbPath::new()),
+            entries: RwLock::new(im::HashMap::new()),
+            expiries: Mutex::new(BTreeSet::new()),
+            notifier,
         }
     }
 
-    pub async fn get_dir(&self) -> String {
-        let path = self.path.read().await;
-        path.dir.clone()
-    }
-
-    pub async fn get_filename(&self) -> String {
-        let path = self.path.read().await;
-        path.dbfilename.clone()
+    // Called with the entries locked for writing, so the cycle can't look
+    // for the key before it is there
+    fn index_expiry(&self, key: &Bytes, expiry: Option<Instant>) {
+        if let Some(expiry) = expiry {
+            self.expiries.lock().unwrap().insert((expiry, key.clone()));
+        }
     }
 
-    /// Load data from an RDB file
-    pub async fn load_from_rdb(&self, rdb_data: &[u8]) -> Result<(), RdbError> {
-        let rdb_file = parse_rdb(rdb_data)?;
+    /// Load a string key read from an RDB file
+    pub async fn load(&self, key: Bytes, value: Bytes, expiry: Option<Instant>) {
         let mut entries = self.entries.write().await;
+        self.index_expiry(&key, expiry);
+        entries.insert(key, Set { value, expiry });
+    }
 
-        for database in rdb_file.databases {
-            for entry in database.entries {
-                // Skip expired entries
-                if let Some(ref exp) = entry.expire {
-                    if exp.is_expired() {
-                        continue;
-                    }
-                }
-
-                let key = Bytes::from(entry.key);
-                let value = match entry.value {
-                    Value::String(s) => Bytes::from(s),
-                };
-                let expiry = entry.expire.and_then(|exp| exp.to_instant());
-
-                entries.insert(key, Set { value, expiry });
-            }
+    /// The string keys as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> KeyValueSnapshot {
+        KeyValueSnapshot {
+            entries: self.entries.read().await.clone(),
+            taken: Instant::now(),
+            taken_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
         }
-        Ok(())
     }
 
-    /// Load data from an RDB file path
-    pub async fn load_from_rdb_file(
-        &self,
-        dir: String,
-        dbfilename: String,
-    ) -> Result<(), RdbError> {
-        let mut path = self.path.write().await;
-        path.dbfilename = dbfilename;
-        path.dir = dir;
-        let data = std::fs::read(format!("{}/{}", path.dir, path.dbfilename))
-            .map_err(RdbError::IoError)?;
-        self.load_from_rdb(&data).await
+    /// String keys and how many of them have a TTL, for INFO keyspace
+    pub async fn counts(&self) -> (usize, usize) {
+        let entries = self.entries.read().await;
+        let expires = entries.values().filter(|set| set.expiry.is_some()).count();
+        (entries.len(), expires)
+    }
+
+    /// Moves a live key to `to`, with its TTL. Returns false if it doesn't
+    /// exist here.
+    pub async fn move_key(&self, key: &Bytes, to: &KeyValue) -> bool {
+        let mut entries = self.entries.write().await;
+        match entries.get(key) {
+            Some(set) if set.expiry.is_none_or(|expiry| Instant::now() < expiry) => {}
+            _ => return false,
+        }
+        let Some(set) = entries.remove(key) else {
+            return false;
+        };
+        let mut target = to.entries.write().await;
+        to.index_expiry(key, set.expiry);
+        target.insert(key.clone(), set);
+        true
     }
 
     pub async fn insert_entry(&self, key: Bytes, value: Bytes, expiry: Option<(Bytes, i64)>) {
         let mut entries = self.entries.write().await;
 
         let set = if let Some((ty, time)) = expiry {
-            let ty: &[u8] = &ty;
-            let duration = match ty {
-                b"EX" => Duration::from_secs(time as u64),
-                b"PX" => Duration::from_millis(time as u64),
-                b"EXAT" => {
-                    let target = Instant::now() + Duration::from_secs(time as u64);
-                    entries.insert(
-                        key,
-                        Set {
-                            value,
-                            expiry: Some(target),
-                        },
-                    );
-                    return;
-                }
-                b"PXAT" => {
-                    let target = Instant::now() + Duration::from_millis(time as u64);
-                    entries.insert(
-                        key,
-                        Set {
-                            value,
-                            expiry: Some(target),
-                        },
-                    );
-                    return;
-                }
+            let time = time.max(0) as u64;
+            let now = SystemTime::now();
+            let duration = match ty.to_ascii_uppercase().as_slice() {
+                b"EX" => Duration::from_secs(time),
+                b"PX" => Duration::from_millis(time),
+                // Unix times; one already past expires the key right away
+                b"EXAT" => (UNIX_EPOCH + Duration::from_secs(time))
+                    .duration_since(now)
+                    .unwrap_or_default(),
+                b"PXAT" => (UNIX_EPOCH + Duration::from_millis(time))
+                    .duration_since(now)
+                    .unwrap_or_default(),
                 _ => Duration::from_secs(0),
             };
             Set {
@@ -533,15 +1414,28 @@ impl KeyValue {
             }
         };
 
-        entries.insert(key, set);
+        let has_expiry = set.expiry.is_some();
+        self.index_expiry(&key, set.expiry);
+        let is_new = entries.insert(key.clone(), set).is_none();
+        drop(entries);
+
+        if is_new {
+            self.notifier.notify(NOTIFY_NEW, "new", &key).await;
+        }
+        self.notifier.notify(NOTIFY_STRING, "set", &key).await;
+        if has_expiry {
+            self.notifier.notify(NOTIFY_GENERIC, "expire", &key).await;
+        }
     }
 
     pub async fn get_entry(&self, key: &Bytes) -> Option<Bytes> {
         let mut entries = self.entries.write().await;
-        if let Some(entry) = entries.get_mut(key) {
+        if let Some(entry) = entries.get(key) {
             if let Some(expiry) = entry.expiry {
                 if Instant::now() >= expiry {
                     entries.remove(key);
+                    drop(entries);
+                    self.notifier.notify(NOTIFY_EXPIRED, "expired", key).await;
                     return None;
                 }
             }
@@ -550,6 +1444,41 @@ impl KeyValue {
         None
     }
 
+    /// Removes every key whose TTL has passed, so keys nobody reads still
+    /// expire (and notify) on time. Only the keys that are due are visited.
+    pub async fn expire_cycle(&self) {
+        let now = Instant::now();
+        let due = {
+            let mut expiries = self.expiries.lock().unwrap();
+            let mut due = Vec::new();
+            while expiries.first().is_some_and(|(expiry, _)| *expiry <= now) {
+                due.extend(expiries.pop_first());
+            }
+            due
+        };
+        if due.is_empty() {
+            return;
+        }
+
+        let mut expired = Vec::new();
+        {
+            let mut entries = self.entries.write().await;
+            for (expiry, key) in due {
+                // Skip keys replaced or given another TTL since
+                if entries
+                    .get(&key)
+                    .is_some_and(|set| set.expiry == Some(expiry))
+                {
+                    entries.remove(&key);
+                    expired.push(key);
+                }
+            }
+        }
+        for key in expired {
+            self.notifier.notify(NOTIFY_EXPIRED, "expired", &key).await;
+        }
+    }
+
     pub async fn exists(&self, key: &Bytes) -> bool {
         let entries = self.entries.read().await;
 
@@ -571,7 +1500,8 @@ impl KeyValue {
     }
 
     pub async fn incr(&self, key: &Bytes) -> Result<i64, String> {
-        let mut entries = self.entries.write().await;
+        let mut guard = self.entries.write().await;
+        let entries = &mut *guard;
 
         let set = Set {
             value: Bytes::from((1).to_string()),
@@ -583,6 +1513,10 @@ impl KeyValue {
                 if Instant::now() >= expiry {
                     entries.remove(key);
                     entries.insert(key.clone(), set);
+                    drop(guard);
+                    self.notifier.notify(NOTIFY_EXPIRED, "expired", key).await;
+                    self.notifier.notify(NOTIFY_NEW, "new", key).await;
+                    self.notifier.notify(NOTIFY_STRING, "incrby", key).await;
                     return Ok(1);
                 }
             }
@@ -597,10 +1531,15 @@ impl KeyValue {
                 }
             };
             entry.value = Bytes::from(value.to_string());
+            drop(guard);
+            self.notifier.notify(NOTIFY_STRING, "incrby", key).await;
             return Ok(value);
         } else {
             entries.insert(key.clone(), set);
         }
+        drop(guard);
+        self.notifier.notify(NOTIFY_NEW, "new", key).await;
+        self.notifier.notify(NOTIFY_STRING, "incrby", key).await;
         Ok(1)
     }
 
@@ -631,7 +1570,7 @@ impl KeyValue {
         RedisValueRef::Array(res)
     }
 
-    fn match_pattern(pattern: &str, key: &str) -> bool {
+    pub(crate) fn match_pattern(pattern: &str, key: &str) -> bool {
         // Convert pattern to regex-like matching
         let mut pattern_chars = pattern.chars().peekable();
         let mut key_chars = key.chars().peekable();
@@ -744,3 +1683,44 @@ impl KeyValue {
         }
     }
 }
+
+/// String keys captured by `KeyValue::snapshot`. Keys that had expired
+/// when it was taken are left out, though the expire cycle may not have
+/// removed them yet.
+pub struct KeyValueSnapshot {
+    entries: im::HashMap<Bytes, Set>,
+    taken: Instant,
+    // The same moment as a Unix time
+    taken_ms: u64,
+}
+
+impl KeyValueSnapshot {
+    fn live(&self) -> impl Iterator<Item = (&Bytes, &Set)> {
+        self.entries
+            .iter()
+            .filter(|(_, set)| set.expiry.is_none_or(|expiry| expiry > self.taken))
+    }
+
+    pub fn len(&self) -> usize {
+        self.live().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live().next().is_none()
+    }
+
+    /// How many keys have a TTL
+    pub fn expires(&self) -> usize {
+        self.live().filter(|(_, set)| set.expiry.is_some()).count()
+    }
+
+    /// Write every live string key, with its expiry as a Unix timestamp
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, set) in self.live() {
+            let expire_ms = set
+                .expiry
+                .map(|expiry| self.taken_ms + (expiry - self.taken).as_millis() as u64);
+            writer.string(key, &set.value, expire_ms);
+        }
+    }
+}
@@ -1,10 +1,21 @@
-use crate::lists::List;
-use crate::rdb::KeyValue;
-use crate::resp::RedisValueRef;
-use crate::streams::Stream;
+use crate::acl::Acl;
+use crate::aof::Aof;
+use crate::client::{Client, Clients};
+use crate::commands::COMMAND_NAMES;
+use crate::db::Db;
+use crate::loading::Loading;
+use crate::module::{Module, Modules};
+use crate::notify::Notifier;
+use crate::persistence::Persistence;
+use crate::pubsub::PubSub;
+use crate::rdb::{KeyValuePair, RdbError, RdbFile, RdbItem, RdbParser, Value};
+use crate::scripting::{Functions, RunningScript, Scripts};
+use crate::tracking::Tracking;
 use crate::transactions::Transaction;
 use bytes::Bytes;
 use std::fmt::Write;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::{Mutex, RwLock};
@@ -20,6 +31,12 @@ pub struct Info {
     repl_backlog_histlen: RwLock<u64>,
 }
 
+impl Default for Info {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Info {
     pub fn new() -> Self {
         Info {
@@ -42,6 +59,11 @@ impl Info {
 
     pub async fn master_repl_offset(&self) -> u64 {
         let r = self.master_repl_offset.read().await;
+        *r
+    }
+
+    pub async fn role(&self) -> String {
+        let r = self.role.read().await;
         (*r).clone()
     }
 
@@ -72,7 +94,7 @@ impl Info {
         *off = offset;
     }
 
-    pub async fn serialize(&self) -> RedisValueRef {
+    pub async fn serialize(&self) -> String {
         let role = self.role.read().await.clone();
         let connected_slaves = *self.connected_slaves.read().await;
         let master_replid = self.master_replid.read().await.clone();
@@ -99,35 +121,264 @@ impl Info {
         )
         .unwrap();
         writeln!(s, "repl_backlog_histlen:{}", backlog_histlen).unwrap();
-
-        RedisValueRef::BulkString(Bytes::from(s))
+        s
     }
 }
 
+// Logical databases unless `databases` says otherwise
+pub const DEFAULT_DATABASES: usize = 16;
+
+// Keys parsed from an RDB file between inserts into the dataset
+const LOAD_BATCH: usize = 1024;
+
 pub struct Redis {
-    pub kv: KeyValue,
-    pub lists: List,
-    pub stream: Stream,
-    pub tr: Transaction,
+    // Indexed by database number; SWAPDB exchanges two slots
+    dbs: RwLock<Vec<Arc<Db>>>,
+    pub tr: Arc<Transaction>,
+    pub clients: Arc<Clients>,
+    pub acl: Acl,
+    pub pubsub: Arc<PubSub>,
+    pub tracking: Arc<Tracking>,
+    pub notifier: Arc<Notifier>,
+    pub scripts: Scripts,
+    pub functions: Functions,
+    pub running_script: Arc<RunningScript>,
+    pub modules: Modules,
+    pub persistence: Persistence,
+    pub aof: Aof,
+    pub loading: Loading,
     pub info: Info,
-    pub connected_slaves: Arc<Mutex<Vec<mpsc::Sender<Vec<u8>>>>>,
+    pub connected_slaves: Arc<Mutex<Vec<mpsc::UnboundedSender<Vec<u8>>>>>,
+    // Database the replication stream last selected, usize::MAX when a
+    // replica has yet to be told. Only changed with connected_slaves locked.
+    pub repl_db: AtomicUsize,
+    // Held shared while a command runs and exclusively by EXEC, so nothing
+    // interleaves with a transaction
+    pub exec_lock: RwLock<()>,
+    // Held by a write from when it runs until it is propagated, so replicas
+    // and the AOF get writes made under the shared exec lock in order
+    pub write_order: Mutex<()>,
+}
+
+impl Default for Redis {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Redis {
     pub fn new() -> Self {
+        Self::with_databases(DEFAULT_DATABASES)
+    }
+
+    /// A server with `databases` logical databases, numbered from 0.
+    pub fn with_databases(databases: usize) -> Self {
+        let pubsub = Arc::new(PubSub::new());
+        let clients = Arc::new(Clients::new());
+        let tracking = Arc::new(Tracking::new(clients.clone(), pubsub.clone()));
+        let tr = Arc::new(Transaction::new());
+        let notifier = Arc::new(Notifier::new(pubsub.clone(), tracking.clone(), tr.clone()));
+        let dbs = (0..databases)
+            .map(|id| Arc::new(Db::new(id, notifier.clone())))
+            .collect();
         Self {
-            kv: KeyValue::new(),
-            lists: List::new(),
-            stream: Stream::new(),
-            tr: Transaction::new(),
+            dbs: RwLock::new(dbs),
+            tr,
+            clients,
+            acl: Acl::new(),
+            pubsub,
+            tracking,
+            notifier,
+            scripts: Scripts::new(),
+            functions: Functions::new(),
+            running_script: Arc::new(RunningScript::new()),
+            modules: Modules::new(),
+            persistence: Persistence::new(),
+            aof: Aof::new(),
+            loading: Loading::new(),
             info: Info::new(),
             connected_slaves: Arc::new(Mutex::new(Vec::new())),
+            repl_db: AtomicUsize::new(usize::MAX),
+            exec_lock: RwLock::new(()),
+            write_order: Mutex::new(()),
+        }
+    }
+
+    pub async fn databases(&self) -> usize {
+        self.dbs.read().await.len()
+    }
+
+    /// Database `index`, which the caller has checked is in range.
+    pub async fn db(&self, index: usize) -> Arc<Db> {
+        self.dbs.read().await[index].clone()
+    }
+
+    /// Every database, in index order.
+    pub async fn dbs(&self) -> Vec<Arc<Db>> {
+        self.dbs.read().await.clone()
+    }
+
+    /// SWAPDB: clients of either database see the other one's data from now
+    /// on, and transactions watching keys in them abort.
+    pub async fn swap_dbs(&self, a: usize, b: usize) {
+        {
+            let mut dbs = self.dbs.write().await;
+            dbs.swap(a, b);
+            dbs[a].set_id(a);
+            dbs[b].set_id(b);
+        }
+        self.modules.swap_db(a, b).await;
+        self.tr.touch_db(a).await;
+        self.tr.touch_db(b).await;
+    }
+
+    /// Empties every database, before a full resync replaces them with the
+    /// master's. Transactions watching keys in them abort.
+    pub async fn flush_all(&self) {
+        let count = {
+            let mut dbs = self.dbs.write().await;
+            for (id, db) in dbs.iter_mut().enumerate() {
+                *db = Arc::new(Db::new(id, self.notifier.clone()));
+            }
+            dbs.len()
+        };
+        self.modules.flush().await;
+        for db in 0..count {
+            self.tr.touch_db(db).await;
         }
     }
 
-    pub async fn add_slave(&self, tx: mpsc::Sender<Vec<u8>>) {
+    pub async fn load_module(&self, module: &dyn Module) -> Result<(), String> {
+        self.modules.load(module, COMMAND_NAMES).await
+    }
+
+    /// Loads an RDB snapshot: function libraries and module data first, then
+    /// the keys.
+    pub async fn load_rdb(&self, data: &[u8]) -> Result<(), RdbError> {
+        self.load_rdb_from(data, data.len() as u64).await
+    }
+
+    /// Loads an RDB file `total` bytes long while parsing it from `reader`,
+    /// so it never has to be in memory whole. Progress goes to `loading`.
+    pub async fn load_rdb_from(
+        &self,
+        reader: impl Read + Send,
+        total: u64,
+    ) -> Result<(), RdbError> {
+        self.loading.set_total(total);
+        let mut parser = RdbParser::new(self.loading.reader(reader))
+            .verify_checksum(self.persistence.rdbchecksum());
+        let preamble = tokio::task::block_in_place(|| parser.parse_preamble())?;
+        self.restore_rdb(preamble).await?;
+
+        let databases = self.databases().await;
+        let mut db = None;
+        loop {
+            // Parsing blocks on the reader, so it's kept off the async workers
+            let batch = tokio::task::block_in_place(|| {
+                let mut batch = Vec::with_capacity(LOAD_BATCH);
+                while batch.len() < LOAD_BATCH {
+                    match parser.next_item()? {
+                        Some(item) => batch.push(item),
+                        None => break,
+                    }
+                }
+                Ok::<_, RdbError>(batch)
+            })?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            for item in batch {
+                match item {
+                    RdbItem::Db { index, .. } => {
+                        db = Some(self.db_to_load(index, databases).await?);
+                    }
+                    RdbItem::Entry(entry) => {
+                        if let Some(db) = &db {
+                            self.load_entry(db, entry).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads an RDB file that has already been parsed.
+    pub async fn restore_rdb(&self, rdb_file: RdbFile) -> Result<(), RdbError> {
+        self.functions
+            .restore_snapshot(rdb_file.functions)
+            .await
+            .map_err(RdbError::InvalidFormat)?;
+        self.modules
+            .aux_load(rdb_file.module_aux)
+            .await
+            .map_err(RdbError::InvalidFormat)?;
+        let databases = self.databases().await;
+        for database in rdb_file.databases {
+            let db = self.db_to_load(database.index, databases).await?;
+            for entry in database.entries {
+                self.load_entry(&db, entry).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn db_to_load(&self, index: u64, databases: usize) -> Result<Arc<Db>, RdbError> {
+        if index as usize >= databases {
+            return Err(RdbError::InvalidFormat(format!(
+                "RDB file has database {}, but only {} are configured",
+                index, databases
+            )));
+        }
+        Ok(self.db(index as usize).await)
+    }
+
+    async fn load_entry(&self, db: &Db, entry: KeyValuePair) {
+        let expiry = match entry.expire {
+            Some(expire) => match expire.to_instant() {
+                Some(instant) => Some(instant),
+                // Already expired
+                None => return,
+            },
+            None => None,
+        };
+        let key = entry.key;
+        match entry.value {
+            Value::String(value) => db.kv.load(key, value, expiry).await,
+            Value::List(items) => db.lists.load(key, items).await,
+            Value::Set(members) => db.sets.load(key, members).await,
+            Value::SortedSet(members) => db.zsets.load(key, members).await,
+            Value::Hash(fields) => db.hashes.load(key, fields).await,
+            Value::Stream(stream) => db.stream.load(key, stream).await,
+        }
+    }
+
+    pub async fn load_rdb_file(&self, dir: String, dbfilename: String) -> Result<(), RdbError> {
+        let (reader, len) = self.persistence.open_rdb_file(dir, dbfilename).await?;
+        self.load_rdb_from(reader, len).await
+    }
+
+    pub async fn add_client(&self, client: Arc<Client>) {
+        if self.acl.default_nopass().await {
+            client.set_user(Bytes::from("default"), true).await;
+        }
+        self.clients.register(client.clone()).await;
+        self.pubsub.register(client).await;
+    }
+
+    /// Drops every piece of state held for a closed connection.
+    pub async fn remove_client(&self, id: u64) {
+        self.tracking.disable(id).await;
+        self.tr.remove(id).await;
+        self.pubsub.unregister(id).await;
+        self.clients.unregister(id).await;
+    }
+
+    pub async fn add_slave(&self, tx: mpsc::UnboundedSender<Vec<u8>>) {
         let mut slaves = self.connected_slaves.lock().await;
         slaves.push(tx);
+        // The new replica starts out in database 0, whatever the others are in
+        self.repl_db.store(usize::MAX, Ordering::SeqCst);
         self.info.add_slave().await;
     }
 
@@ -16,6 +16,36 @@ pub enum RedisValueRef {
     NullArray,
     NullBulkString,
     ErrorMsg(Vec<u8>),
+    // RESP3 only, sent to clients that switched protocol with HELLO 3
+    Push(Vec<RedisValueRef>),
+    Map(Vec<(RedisValueRef, RedisValueRef)>),
+}
+
+impl RedisValueRef {
+    /// How many bytes the encoder writes for this value.
+    pub fn encoded_len(&self) -> usize {
+        // Type byte, decimal header and CRLF
+        fn header(n: usize) -> usize {
+            n.to_string().len() + 3
+        }
+        match self {
+            RedisValueRef::String(s) | RedisValueRef::Error(s) => s.len() + 3,
+            RedisValueRef::Int(i) => i.to_string().len() + 3,
+            RedisValueRef::BulkString(s) => header(s.len()) + s.len() + 2,
+            RedisValueRef::Array(items) | RedisValueRef::Push(items) => {
+                header(items.len()) + items.iter().map(Self::encoded_len).sum::<usize>()
+            }
+            RedisValueRef::Map(pairs) => {
+                header(pairs.len())
+                    + pairs
+                        .iter()
+                        .map(|(k, v)| k.encoded_len() + v.encoded_len())
+                        .sum::<usize>()
+            }
+            RedisValueRef::NullArray | RedisValueRef::NullBulkString => 5,
+            RedisValueRef::ErrorMsg(_) => 0,
+        }
+    }
 }
 
 struct BufSplit(usize, usize);
@@ -117,7 +147,8 @@ fn bulk_string(buf: &BytesMut, pos: usize) -> RedisResult {
 }
 
 fn parse(buf: &BytesMut, pos: usize) -> RedisResult {
-    if buf.is_empty() {
+    // The rest of an array may not have arrived yet
+    if buf.len() <= pos {
         return Ok(None);
     }
 
@@ -239,6 +270,23 @@ impl Encoder<RedisValueRef> for RespParser {
                     self.encode(val, dst)?;
                 }
             }
+            RedisValueRef::Push(s) => {
+                dst.extend_from_slice(b">");
+                dst.extend_from_slice(s.len().to_string().as_bytes());
+                dst.extend_from_slice(b"\r\n");
+                for val in s {
+                    self.encode(val, dst)?;
+                }
+            }
+            RedisValueRef::Map(pairs) => {
+                dst.extend_from_slice(b"%");
+                dst.extend_from_slice(pairs.len().to_string().as_bytes());
+                dst.extend_from_slice(b"\r\n");
+                for (key, val) in pairs {
+                    self.encode(key, dst)?;
+                    self.encode(val, dst)?;
+                }
+            }
             RedisValueRef::NullBulkString => {
                 dst.extend_from_slice(b"$-1\r\n");
             }
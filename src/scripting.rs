@@ -0,0 +1,994 @@
+use crate::acl::map_reply;
+use crate::cjson;
+use crate::crc64;
+use crate::log;
+use crate::rdb::{write_string, KeyValue, RdbParser, RDB_OPCODE_FUNCTION, RDB_VERSION};
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, MultiValue, RegistryKey, StdLib, Table, Value,
+};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
+
+// Lua instructions run between checks of the time and of SCRIPT KILL
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+// How long a library's top-level chunk may run when it is loaded
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+// busy-reply-threshold unless configured, in milliseconds
+const DEFAULT_BUSY_REPLY_THRESHOLD: u64 = 5000;
+
+// redis.call is redis.pcall that raises the error reply instead of returning it
+const PRELUDE: &str = r#"
+redis.call = function(...)
+    local reply = redis.pcall(...)
+    if type(reply) == "table" and reply.err ~= nil then
+        error(reply, 0)
+    end
+    return reply
+end
+"#;
+
+// Set up last, once everything scripts may use is in place: like Redis,
+// scripts can't create globals or read ones that don't exist
+const PROTECT_GLOBALS: &str = r#"
+setmetatable(_G, {
+    __newindex = function(_, name)
+        error("Script attempted to create global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+})
+"#;
+
+pub fn sha1_hex(script: &[u8]) -> String {
+    hex::encode(Sha1::digest(script))
+}
+
+/// Scripts loaded by EVAL or SCRIPT LOAD, by SHA1.
+pub struct Scripts {
+    cache: RwLock<HashMap<String, Bytes>>,
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        Scripts {
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Caches `script` and returns its SHA1.
+    pub async fn load(&self, script: Bytes) -> String {
+        let sha = sha1_hex(&script);
+        self.cache.write().await.insert(sha.clone(), script);
+        sha
+    }
+
+    pub async fn get(&self, sha: &[u8]) -> Option<Bytes> {
+        let sha = String::from_utf8_lossy(sha).to_lowercase();
+        self.cache.read().await.get(&sha).cloned()
+    }
+
+    pub async fn exists(&self, sha: &[u8]) -> bool {
+        self.get(sha).await.is_some()
+    }
+
+    pub async fn flush(&self) {
+        self.cache.write().await.clear();
+    }
+}
+
+/// Whether a run is an EVAL script or an FCALL function, which decides
+/// whether SCRIPT KILL or FUNCTION KILL stops it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RunKind {
+    Script,
+    Function,
+}
+
+impl RunKind {
+    fn name(self) -> &'static str {
+        match self {
+            RunKind::Script => "SCRIPT",
+            RunKind::Function => "FUNCTION",
+        }
+    }
+}
+
+// The script or function running now
+struct Run {
+    kind: RunKind,
+    started: Instant,
+    // It ran a write, so killing it would leave the write half done
+    wrote: bool,
+    killed: bool,
+    // It ran past busy-reply-threshold and other clients get -BUSY
+    busy: bool,
+}
+
+/// Tracks the script or function running now. Once it has run longer than
+/// busy-reply-threshold, clients waiting for it get -BUSY and it can be
+/// stopped with SCRIPT KILL or FUNCTION KILL, which a hook in the Lua VM
+/// checks every few instructions.
+pub struct RunningScript {
+    // Locked from the Lua hook, which can't await
+    run: Mutex<Option<Run>>,
+    became_busy: Notify,
+    busy_reply_threshold: AtomicU64,
+}
+
+impl Default for RunningScript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunningScript {
+    pub fn new() -> Self {
+        RunningScript {
+            run: Mutex::new(None),
+            became_busy: Notify::new(),
+            busy_reply_threshold: AtomicU64::new(DEFAULT_BUSY_REPLY_THRESHOLD),
+        }
+    }
+
+    pub fn busy_reply_threshold(&self) -> u64 {
+        self.busy_reply_threshold.load(Ordering::SeqCst)
+    }
+
+    pub fn set_busy_reply_threshold(&self, ms: u64) {
+        self.busy_reply_threshold.store(ms, Ordering::SeqCst);
+    }
+
+    pub fn start(&self, kind: RunKind) {
+        *self.run.lock().unwrap() = Some(Run {
+            kind,
+            started: Instant::now(),
+            wrote: false,
+            killed: false,
+            busy: false,
+        });
+    }
+
+    /// Ends the run. Returns the error its caller gets instead of the reply
+    /// if it was killed.
+    pub fn finish(&self) -> Option<RedisValueRef> {
+        let run = self.run.lock().unwrap().take()?;
+        run.killed.then(|| {
+            RedisValueRef::Error(Bytes::from(format!(
+                "ERR Script killed by user with {} KILL...",
+                run.kind.name()
+            )))
+        })
+    }
+
+    pub fn mark_write(&self) {
+        if let Some(run) = self.run.lock().unwrap().as_mut() {
+            run.wrote = true;
+        }
+    }
+
+    /// SCRIPT KILL or FUNCTION KILL, stopping a running run of `kind`.
+    pub fn kill(&self, kind: RunKind) -> RedisValueRef {
+        let mut run = self.run.lock().unwrap();
+        let Some(run) = run.as_mut().filter(|run| run.kind == kind) else {
+            return RedisValueRef::Error(Bytes::from(format!(
+                "NOTBUSY No {} in execution right now.",
+                match kind {
+                    RunKind::Script => "scripts",
+                    RunKind::Function => "function",
+                }
+            )));
+        };
+        if run.wrote {
+            return RedisValueRef::Error(Bytes::from(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. You can only wait for it to finish.",
+            ));
+        }
+        run.killed = true;
+        RedisValueRef::String(Bytes::from("OK"))
+    }
+
+    /// The -BUSY reply, if a run has passed busy-reply-threshold.
+    pub fn busy_error(&self) -> Option<RedisValueRef> {
+        let run = self.run.lock().unwrap();
+        let run = run.as_ref().filter(|run| run.busy)?;
+        Some(RedisValueRef::Error(Bytes::from(format!(
+            "BUSY Redis is busy running a {}. You can only call {} KILL.",
+            match run.kind {
+                RunKind::Script => "script",
+                RunKind::Function => "function",
+            },
+            run.kind.name()
+        ))))
+    }
+
+    /// Resolves with the -BUSY reply once a run passes busy-reply-threshold.
+    pub async fn busy(&self) -> RedisValueRef {
+        loop {
+            // Registered before the check, so a run turning busy in between
+            // still wakes it
+            let became_busy = self.became_busy.notified();
+            if let Some(err) = self.busy_error() {
+                return err;
+            }
+            became_busy.await;
+        }
+    }
+
+    // Called from the hook: marks the run busy once it passes the threshold
+    // and stops it once killed. A kill keeps raising, so pcall in the script
+    // can't swallow it.
+    fn check(&self) -> mlua::Result<()> {
+        let mut run = self.run.lock().unwrap();
+        let Some(run) = run.as_mut() else {
+            return Ok(());
+        };
+        if !run.busy && run.started.elapsed() >= Duration::from_millis(self.busy_reply_threshold())
+        {
+            run.busy = true;
+            self.became_busy.notify_waiters();
+        }
+        if run.killed {
+            return Err(mlua::Error::RuntimeError(format!(
+                "Script killed by user with {} KILL...",
+                run.kind.name()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// A check the hook runs; an error stops the Lua code
+type Check = Arc<dyn Fn() -> mlua::Result<()> + Send + Sync>;
+
+// Runs `check` every `every` instructions. Once it fails it runs on every
+// instruction, so a pcall loop in the script gets no further than the
+// instruction after the pcall that caught the error.
+fn watch(lua: &Lua, check: Check, every: u32) {
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(every),
+        move |lua, _| {
+            let res = check();
+            if res.is_err() && every > 1 {
+                watch(lua, check.clone(), 1);
+            }
+            res
+        },
+    );
+}
+
+// Collects what the library registers; checked once the chunk has run
+const LIBRARY_PRELUDE: &str = r##"
+__functions = {}
+redis.register_function = function(...)
+    local args = {...}
+    local entry
+    if select("#", ...) == 1 and type(args[1]) == "table" then
+        local t = args[1]
+        entry = {name = t.function_name, callback = t.callback, flags = t.flags, description = t.description}
+    elseif select("#", ...) == 2 then
+        entry = {name = args[1], callback = args[2]}
+    else
+        error("wrong number of arguments to redis.register_function", 0)
+    end
+    table.insert(__functions, entry)
+end
+"##;
+
+const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+#[derive(Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+/// A library loaded by FUNCTION LOAD, kept with the code that defines it
+/// and the VM its chunk ran in, where its functions are called.
+#[derive(Clone)]
+pub struct Library {
+    pub name: String,
+    pub code: Bytes,
+    pub functions: Vec<FunctionInfo>,
+    vm: Arc<LibraryVm>,
+}
+
+/// A library's Lua state, with the callbacks it registered by name.
+pub struct LibraryVm {
+    lua: Mutex<Lua>,
+    callbacks: HashMap<String, RegistryKey>,
+}
+
+/// Function libraries by name.
+pub struct Functions {
+    libraries: RwLock<BTreeMap<String, Library>>,
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Functions {
+            libraries: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Loads the library in `code` and returns its name.
+    pub async fn load(&self, code: Bytes, replace: bool) -> Result<String, String> {
+        let library = load_library(code)?;
+        let name = library.name.clone();
+        add_library(&mut *self.libraries.write().await, library, replace)?;
+        Ok(name)
+    }
+
+    pub async fn delete(&self, name: &[u8]) -> Result<(), String> {
+        let name = String::from_utf8_lossy(name);
+        match self.libraries.write().await.remove(name.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err("ERR Library not found".to_string()),
+        }
+    }
+
+    pub async fn flush(&self) {
+        self.libraries.write().await.clear();
+    }
+
+    /// The VM and description of function `name`.
+    pub async fn get(&self, name: &[u8]) -> Option<(Arc<LibraryVm>, FunctionInfo)> {
+        let libraries = self.libraries.read().await;
+        libraries.values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|f| f.name.as_bytes() == name)
+                .map(|f| (library.vm.clone(), f.clone()))
+        })
+    }
+
+    /// The code of every library, for snapshots.
+    pub async fn codes(&self) -> Vec<Bytes> {
+        let libraries = self.libraries.read().await;
+        libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    pub async fn list(
+        &self,
+        pattern: Option<Bytes>,
+        withcode: bool,
+        protocol: u8,
+    ) -> RedisValueRef {
+        let pattern = pattern.map(|p| String::from_utf8_lossy(&p).to_string());
+        let libraries = self.libraries.read().await;
+        let mut reply = Vec::new();
+        for library in libraries.values() {
+            if let Some(pattern) = &pattern {
+                if !KeyValue::match_pattern(pattern, &library.name) {
+                    continue;
+                }
+            }
+            let functions = library
+                .functions
+                .iter()
+                .map(|f| {
+                    map_reply(
+                        protocol,
+                        vec![
+                            (
+                                "name",
+                                RedisValueRef::BulkString(Bytes::from(f.name.clone())),
+                            ),
+                            (
+                                "description",
+                                match &f.description {
+                                    Some(d) => RedisValueRef::BulkString(Bytes::from(d.clone())),
+                                    None => RedisValueRef::NullBulkString,
+                                },
+                            ),
+                            (
+                                "flags",
+                                RedisValueRef::Array(
+                                    f.flags
+                                        .iter()
+                                        .map(|flag| {
+                                            RedisValueRef::String(Bytes::from(flag.clone()))
+                                        })
+                                        .collect(),
+                                ),
+                            ),
+                        ],
+                    )
+                })
+                .collect();
+            let mut fields = vec![
+                (
+                    "library_name",
+                    RedisValueRef::BulkString(Bytes::from(library.name.clone())),
+                ),
+                ("engine", RedisValueRef::BulkString(Bytes::from("LUA"))),
+                ("functions", RedisValueRef::Array(functions)),
+            ];
+            if withcode {
+                fields.push((
+                    "library_code",
+                    RedisValueRef::BulkString(library.code.clone()),
+                ));
+            }
+            reply.push(map_reply(protocol, fields));
+        }
+        RedisValueRef::Array(reply)
+    }
+
+    /// Every library as a FUNCTION RESTORE payload.
+    pub async fn dump(&self) -> Bytes {
+        let mut payload = Vec::new();
+        for code in self.codes().await {
+            payload.push(RDB_OPCODE_FUNCTION);
+            write_string(&mut payload, &code);
+        }
+        payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+        let crc = crc64::crc64(&payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+        Bytes::from(payload)
+    }
+
+    pub async fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<(), String> {
+        let codes = parse_dump(payload)?;
+        let mut loaded = Vec::new();
+        for code in codes {
+            loaded.push(load_library(code)?);
+        }
+        let mut libraries = self.libraries.write().await;
+        let mut restored = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            RestorePolicy::Append | RestorePolicy::Replace => libraries.clone(),
+        };
+        for library in loaded {
+            add_library(&mut restored, library, policy == RestorePolicy::Replace)?;
+        }
+        *libraries = restored;
+        Ok(())
+    }
+
+    /// Replaces every library with the ones saved in a snapshot.
+    pub async fn restore_snapshot(&self, codes: Vec<Bytes>) -> Result<(), String> {
+        let mut restored = BTreeMap::new();
+        for code in codes {
+            add_library(&mut restored, load_library(code)?, false)?;
+        }
+        *self.libraries.write().await = restored;
+        Ok(())
+    }
+}
+
+/// What FUNCTION RESTORE does with libraries that already exist.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+fn add_library(
+    libraries: &mut BTreeMap<String, Library>,
+    library: Library,
+    replace: bool,
+) -> Result<(), String> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(format!("ERR Library '{}' already exists", library.name));
+    }
+    for other in libraries.values() {
+        if other.name == library.name {
+            continue;
+        }
+        for f in &library.functions {
+            if other.functions.iter().any(|o| o.name == f.name) {
+                return Err(format!("ERR Function {} already exists", f.name));
+            }
+        }
+    }
+    libraries.insert(library.name.clone(), library);
+    Ok(())
+}
+
+fn parse_dump(payload: &[u8]) -> Result<Vec<Bytes>, String> {
+    let invalid = || "ERR payload version or checksum are wrong".to_string();
+    if payload.len() < 10 {
+        return Err(invalid());
+    }
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    if version > RDB_VERSION {
+        return Err(invalid());
+    }
+    // The checksum covers the body and version; zero means none was computed
+    let (covered, checksum) = payload.split_at(payload.len() - 8);
+    let checksum = u64::from_le_bytes(checksum.try_into().unwrap());
+    if checksum != 0 && checksum != crc64::crc64(covered) {
+        return Err(invalid());
+    }
+    let mut parser = RdbParser::new(Cursor::new(body));
+    let mut codes = Vec::new();
+    loop {
+        match parser.read_byte() {
+            Ok(RDB_OPCODE_FUNCTION) => match parser.parse_bytes() {
+                Ok(code) => codes.push(Bytes::from(code)),
+                Err(_) => return Err("ERR given payload is not a valid function dump".to_string()),
+            },
+            Ok(_) => return Err("ERR given type is not a function".to_string()),
+            Err(_) => return Ok(codes),
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Splits "#!lua name=<library>" off the code. The line is left blank so
+// errors still point at the right line.
+fn library_body(code: &[u8]) -> Result<(String, Vec<u8>), String> {
+    let (first, rest) = match code.iter().position(|&b| b == b'\n') {
+        Some(i) => code.split_at(i),
+        None => (code, &b""[..]),
+    };
+    let Some(metadata) = first.strip_prefix(b"#!") else {
+        return Err("ERR Missing library metadata".to_string());
+    };
+    let metadata = String::from_utf8_lossy(metadata);
+    let mut parts = metadata.split_whitespace();
+    let engine = parts.next().unwrap_or("");
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            _ => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let Some(name) = name else {
+        return Err("ERR Library name was not given".to_string());
+    };
+    if !valid_name(&name) {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    Ok((name, rest.to_vec()))
+}
+
+/// Runs the library in `code` to find the functions it registers, in the
+/// VM they are later called in. The chunk gets no redis.call, so loading
+/// can't touch the keyspace, and it is stopped if it runs longer than
+/// LOAD_TIMEOUT.
+pub fn load_library(code: Bytes) -> Result<Library, String> {
+    let (name, body) = library_body(&code)?;
+    let lua = new_lua().map_err(|e| format!("ERR {}", e))?;
+    let started = Instant::now();
+    let timeout: Check = Arc::new(move || {
+        if started.elapsed() > LOAD_TIMEOUT {
+            return Err(mlua::Error::RuntimeError(
+                "FUNCTION LOAD timeout".to_string(),
+            ));
+        }
+        Ok(())
+    });
+    watch(&lua, timeout, HOOK_INSTRUCTIONS);
+    let mut functions = Vec::new();
+    let mut callbacks = HashMap::new();
+    {
+        let chunk = load_script(&lua, &body, "@user_function")?;
+        let (ok, value) = (|| -> mlua::Result<(bool, Value)> {
+            lua.globals().set("redis", redis_table(&lua)?)?;
+            lua.load(LIBRARY_PRELUDE).exec()?;
+            lua.load(PROTECT_GLOBALS).exec()?;
+            let pcall: Function = lua.globals().get("pcall")?;
+            pcall.call(chunk)
+        })()
+        .map_err(|e| format!("ERR {}", e))?;
+        if !ok {
+            return Err(format!(
+                "ERR Error registering functions: {}",
+                error_message(value)
+            ));
+        }
+        for (info, callback) in registered_functions(&lua)? {
+            let key = lua
+                .create_registry_value(callback)
+                .map_err(|e| format!("ERR {}", e))?;
+            callbacks.insert(info.name.clone(), key);
+            functions.push(info);
+        }
+    }
+    if functions.is_empty() {
+        return Err("ERR No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code,
+        functions,
+        vm: Arc::new(LibraryVm {
+            lua: Mutex::new(lua),
+            callbacks,
+        }),
+    })
+}
+
+fn registered_functions(lua: &Lua) -> Result<Vec<(FunctionInfo, Function<'_>)>, String> {
+    let lua_err = |e: mlua::Error| format!("ERR {}", e);
+    let registered: Table = lua.globals().get("__functions").map_err(lua_err)?;
+    let mut functions: Vec<(FunctionInfo, Function)> = Vec::new();
+    for entry in registered.sequence_values::<Table>() {
+        let entry = entry.map_err(lua_err)?;
+        let name =
+            match entry.raw_get::<_, Value>("name").map_err(lua_err)? {
+                Value::String(s) => s.to_string_lossy().to_string(),
+                _ => return Err(
+                    "ERR function_name argument given to redis.register_function must be a string"
+                        .to_string(),
+                ),
+            };
+        if !valid_name(&name) {
+            return Err("ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+        }
+        let Value::Function(callback) = entry.raw_get::<_, Value>("callback").map_err(lua_err)?
+        else {
+            return Err(
+                "ERR callback argument given to redis.register_function must be a function"
+                    .to_string(),
+            );
+        };
+        let description =
+            match entry.raw_get::<_, Value>("description").map_err(lua_err)? {
+                Value::Nil => None,
+                Value::String(s) => Some(s.to_string_lossy().to_string()),
+                _ => return Err(
+                    "ERR description argument given to redis.register_function must be a string"
+                        .to_string(),
+                ),
+            };
+        let mut flags = Vec::new();
+        match entry.raw_get::<_, Value>("flags").map_err(lua_err)? {
+            Value::Nil => {}
+            Value::Table(t) => {
+                for flag in t.sequence_values::<mlua::String>() {
+                    let flag = flag.map_err(|_| "ERR unknown flag given".to_string())?;
+                    let flag = flag.to_string_lossy().to_string();
+                    if !FUNCTION_FLAGS.contains(&flag.as_str()) {
+                        return Err("ERR unknown flag given".to_string());
+                    }
+                    flags.push(flag);
+                }
+            }
+            _ => return Err("ERR flags argument to redis.register_function must be a table representing function flags".to_string()),
+        }
+        if functions.iter().any(|(f, _)| f.name == name) {
+            return Err("ERR Function already exists in the library".to_string());
+        }
+        functions.push((
+            FunctionInfo {
+                name,
+                description,
+                flags,
+            },
+            callback,
+        ));
+    }
+    Ok(functions)
+}
+
+// Scripts only get the libraries that can't touch the host, on Lua 5.1 as
+// in Redis, plus cjson
+fn new_lua() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    // The base library would otherwise let a script read files
+    globals.raw_remove("dofile")?;
+    globals.raw_remove("loadfile")?;
+    globals.set("cjson", cjson::table(&lua)?)?;
+    drop(globals);
+    Ok(lua)
+}
+
+fn load_script<'lua>(lua: &'lua Lua, script: &[u8], name: &str) -> Result<Function<'lua>, String> {
+    lua.load(script)
+        .set_name(name)
+        .into_function()
+        .map_err(|e| format!("ERR Error compiling script (new function): {}", e))
+}
+
+/// Checks that `script` compiles, for SCRIPT LOAD and EVAL.
+pub fn compile(script: &[u8]) -> Result<(), String> {
+    let lua = new_lua().map_err(|e| format!("ERR {}", e))?;
+    load_script(&lua, script, "@user_script").map(|_| ())
+}
+
+/// Runs `script` with KEYS and ARGV set. Every redis.call and redis.pcall is
+/// handed to `call`, which runs the command and returns its reply.
+pub fn run(
+    script: &[u8],
+    sha: &str,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    running: &Arc<RunningScript>,
+    call: impl FnMut(Vec<Bytes>) -> RedisValueRef,
+) -> RedisValueRef {
+    let res = (|| -> mlua::Result<RedisValueRef> {
+        let lua = new_lua()?;
+        let running = running.clone();
+        watch(&lua, Arc::new(move || running.check()), HOOK_INSTRUCTIONS);
+        let chunk = match load_script(&lua, script, "@user_script") {
+            Ok(chunk) => chunk,
+            Err(e) => return Ok(RedisValueRef::Error(Bytes::from(e))),
+        };
+        let globals = lua.globals();
+        globals.set("redis", redis_table(&lua)?)?;
+        globals.set("KEYS", string_array(&lua, keys)?)?;
+        globals.set("ARGV", string_array(&lua, args)?)?;
+        lua.load(PROTECT_GLOBALS).exec()?;
+        let pcall: Function = globals.get("pcall")?;
+        with_calls(&lua, sha, call, || pcall.call(chunk))
+    })();
+    res.unwrap_or_else(|e| RedisValueRef::Error(Bytes::from(format!("ERR {}", e))))
+}
+
+/// Calls function `name` of the library loaded in `vm`, passing it the keys
+/// and arguments tables.
+pub fn call_function(
+    vm: &LibraryVm,
+    name: &str,
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    running: &Arc<RunningScript>,
+    call: impl FnMut(Vec<Bytes>) -> RedisValueRef,
+) -> RedisValueRef {
+    let Some(callback) = vm.callbacks.get(name) else {
+        return RedisValueRef::Error(Bytes::from("ERR Function not found"));
+    };
+    let lua = vm.lua.lock().unwrap_or_else(|e| e.into_inner());
+    let res = (|| -> mlua::Result<RedisValueRef> {
+        let running = running.clone();
+        watch(&lua, Arc::new(move || running.check()), HOOK_INSTRUCTIONS);
+        let callback: Function = lua.registry_value(callback)?;
+        let pcall: Function = lua.globals().get("pcall")?;
+        let keys = string_array(&lua, keys)?;
+        let args = string_array(&lua, args)?;
+        with_calls(&lua, name, call, || pcall.call((callback, keys, args)))
+    })();
+    res.unwrap_or_else(|e| RedisValueRef::Error(Bytes::from(format!("ERR {}", e))))
+}
+
+// Runs `body`, a pcall, with redis.pcall and redis.call handing commands to
+// `call`. They only work while it runs.
+fn with_calls<'lua>(
+    lua: &'lua Lua,
+    tag: &str,
+    mut call: impl FnMut(Vec<Bytes>) -> RedisValueRef,
+    body: impl FnOnce() -> mlua::Result<(bool, Value<'lua>)>,
+) -> mlua::Result<RedisValueRef> {
+    lua.scope(|scope| {
+        let redis: Table = lua.globals().get("redis")?;
+        redis.set(
+            "pcall",
+            scope.create_function_mut(|lua, argv: MultiValue| {
+                let mut cmd = Vec::new();
+                for arg in argv {
+                    match arg {
+                        Value::String(s) => cmd.push(Bytes::copy_from_slice(s.as_bytes())),
+                        Value::Integer(i) => cmd.push(Bytes::from(i.to_string())),
+                        Value::Number(n) => cmd.push(Bytes::from(n.to_string())),
+                        _ => {
+                            return reply_table(
+                                lua,
+                                "err",
+                                b"ERR Lua redis lib command arguments must be strings or integers",
+                            )
+                        }
+                    }
+                }
+                if cmd.is_empty() {
+                    return reply_table(
+                        lua,
+                        "err",
+                        b"ERR Please specify at least one argument for this redis lib call",
+                    );
+                }
+                to_lua(lua, call(cmd))
+            })?,
+        )?;
+        lua.load(PRELUDE).exec()?;
+
+        let (ok, value) = body()?;
+        Ok(if ok {
+            from_lua(value)
+        } else {
+            script_error(value, tag)
+        })
+    })
+}
+
+// The parts of the redis table that don't run commands
+fn redis_table(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let redis = lua.create_table()?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, msg: mlua::String| reply_table(lua, "ok", msg.as_bytes()))?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, msg: mlua::String| reply_table(lua, "err", msg.as_bytes()))?,
+    )?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, s: mlua::String| Ok(sha1_hex(s.as_bytes())))?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_, args: MultiValue| {
+            if args.len() < 2 {
+                return Err(mlua::Error::RuntimeError(
+                    "redis.log() requires two arguments or more.".to_string(),
+                ));
+            }
+            let mut args = args.into_iter();
+            let level = match args.next() {
+                Some(Value::Integer(i)) => log::Level::from_index(i),
+                Some(Value::Number(n)) => log::Level::from_index(n as i64),
+                _ => None,
+            }
+            .ok_or_else(|| mlua::Error::RuntimeError("Invalid debug level.".to_string()))?;
+            let mut msg = String::new();
+            for arg in args {
+                let part = match arg {
+                    Value::String(s) => s.to_string_lossy().into_owned(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Number(n) => n.to_string(),
+                    _ => continue,
+                };
+                if !msg.is_empty() {
+                    msg.push(' ');
+                }
+                msg.push_str(&part);
+            }
+            log::log(level, &msg);
+            Ok(())
+        })?,
+    )?;
+    for (i, level) in ["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"]
+        .iter()
+        .enumerate()
+    {
+        redis.set(*level, i)?;
+    }
+    Ok(redis)
+}
+
+// Error replies raised by redis.call are passed on as they are
+fn script_error(value: Value, tag: &str) -> RedisValueRef {
+    if let Value::Table(t) = &value {
+        if let Ok(Value::String(err)) = t.raw_get::<_, Value>("err") {
+            return RedisValueRef::Error(Bytes::copy_from_slice(err.as_bytes()));
+        }
+    }
+    RedisValueRef::Error(Bytes::from(format!(
+        "ERR {} script: {}",
+        error_message(value),
+        tag
+    )))
+}
+
+fn error_message(value: Value) -> String {
+    match value {
+        Value::Table(t) => match t.raw_get::<_, Value>("err") {
+            Ok(Value::String(err)) => err.to_string_lossy().to_string(),
+            _ => "unknown error".to_string(),
+        },
+        Value::String(s) => s.to_string_lossy().to_string(),
+        Value::Error(e) => e.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn string_array(lua: &Lua, items: Vec<Bytes>) -> mlua::Result<Table<'_>> {
+    let table = lua.create_table()?;
+    for (i, item) in items.iter().enumerate() {
+        table.raw_set(i + 1, lua.create_string(item)?)?;
+    }
+    Ok(table)
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, msg: &[u8]) -> mlua::Result<Value<'lua>> {
+    let table = lua.create_table()?;
+    table.raw_set(field, lua.create_string(msg)?)?;
+    Ok(Value::Table(table))
+}
+
+// Replies as scripts see them, following the RESP2 conversion rules
+fn to_lua(lua: &Lua, value: RedisValueRef) -> mlua::Result<Value<'_>> {
+    Ok(match value {
+        RedisValueRef::String(s) => reply_table(lua, "ok", &s)?,
+        RedisValueRef::Error(e) => reply_table(lua, "err", &e)?,
+        RedisValueRef::ErrorMsg(e) => reply_table(lua, "err", &e)?,
+        RedisValueRef::Int(i) => Value::Integer(i),
+        RedisValueRef::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RedisValueRef::NullBulkString | RedisValueRef::NullArray => Value::Boolean(false),
+        RedisValueRef::Array(items) | RedisValueRef::Push(items) => {
+            let table = lua.create_table()?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RedisValueRef::Map(pairs) => {
+            let table = lua.create_table()?;
+            for (i, item) in pairs.into_iter().flat_map(|(k, v)| [k, v]).enumerate() {
+                table.raw_set(i + 1, to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+// Script return values as replies. Arrays stop at the first nil.
+fn from_lua(value: Value) -> RedisValueRef {
+    match value {
+        Value::Boolean(true) => RedisValueRef::Int(1),
+        Value::Integer(i) => RedisValueRef::Int(i),
+        Value::Number(n) => RedisValueRef::Int(n as i64),
+        Value::String(s) => RedisValueRef::BulkString(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(t) => {
+            if let Ok(Value::String(err)) = t.raw_get::<_, Value>("err") {
+                return RedisValueRef::Error(Bytes::copy_from_slice(err.as_bytes()));
+            }
+            if let Ok(Value::String(ok)) = t.raw_get::<_, Value>("ok") {
+                return RedisValueRef::String(Bytes::copy_from_slice(ok.as_bytes()));
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match t.raw_get::<_, Value>(i) {
+                    Ok(Value::Nil) | Err(_) => break,
+                    Ok(item) => items.push(from_lua(item)),
+                }
+            }
+            RedisValueRef::Array(items)
+        }
+        _ => RedisValueRef::NullBulkString,
+    }
+}
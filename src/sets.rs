@@ -0,0 +1,99 @@
+use crate::cow::Cow;
+use crate::rdb::RdbWriter;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::HashSet;
+use tokio::sync::RwLock;
+
+type SetMap = im::HashMap<Bytes, Cow<HashSet<Bytes>>>;
+
+pub struct Set {
+    sets: RwLock<SetMap>,
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Set {
+    pub fn new() -> Self {
+        Self {
+            sets: RwLock::new(im::HashMap::new()),
+        }
+    }
+
+    pub async fn smembers(&self, key: &Bytes) -> Vec<RedisValueRef> {
+        let sets = self.sets.read().await;
+        let Some(set) = sets.get(key) else {
+            return Vec::new();
+        };
+        set.iter()
+            .map(|member| RedisValueRef::BulkString(member.clone()))
+            .collect()
+    }
+
+    pub async fn scard(&self, key: &Bytes) -> i64 {
+        let sets = self.sets.read().await;
+        sets.get(key).map_or(0, |set| set.len() as i64)
+    }
+
+    pub async fn sismember(&self, key: &Bytes, member: &Bytes) -> bool {
+        let sets = self.sets.read().await;
+        sets.get(key).is_some_and(|set| set.contains(member))
+    }
+
+    pub async fn contains(&self, key: &Bytes) -> bool {
+        let sets = self.sets.read().await;
+        sets.contains_key(key)
+    }
+
+    /// How many keys hold a value of this type
+    pub async fn key_count(&self) -> usize {
+        self.sets.read().await.len()
+    }
+
+    /// Moves `key`'s set to `to`. Returns false if there is none here.
+    pub async fn move_key(&self, key: &Bytes, to: &Set) -> bool {
+        let mut sets = self.sets.write().await;
+        if !sets.contains_key(key) {
+            return false;
+        }
+        let Some(value) = sets.remove(key) else {
+            return false;
+        };
+        to.sets.write().await.insert(key.clone(), value);
+        true
+    }
+
+    /// Load a set read from an RDB file
+    pub async fn load(&self, key: Bytes, members: Vec<Bytes>) {
+        let mut sets = self.sets.write().await;
+        sets.insert(key, Cow::new(members.into_iter().collect()));
+    }
+
+    /// The sets as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> SetSnapshot {
+        SetSnapshot(self.sets.read().await.clone())
+    }
+}
+
+/// Sets captured by `Set::snapshot`.
+pub struct SetSnapshot(im::HashMap<Bytes, Cow<HashSet<Bytes>>>);
+
+impl SetSnapshot {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, set) in self.0.iter() {
+            writer.set(key, set.iter());
+        }
+    }
+}
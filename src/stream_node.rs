@@ -0,0 +1,277 @@
+use crate::cow::{Cow, Items};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+pub type StreamId = (u64, u64);
+
+// Field/value pairs in the order they were given to XADD, duplicates included
+pub type StreamEntry = Vec<(Bytes, Bytes)>;
+
+// Same defaults as Redis's stream-node-max-entries / stream-node-max-bytes
+pub const NODE_MAX_ENTRIES: usize = 100;
+pub const NODE_MAX_BYTES: usize = 4096;
+
+// Entry flag: the entry uses exactly the master entry's field names, so only
+// the values are stored
+const FLAG_SAME_FIELDS: u8 = 1;
+
+/// A macro node holding a run of consecutive entries, like a listpack in
+/// Redis's stream rax.
+///
+/// The first entry added becomes the master entry. Every entry's ID is stored
+/// as a delta against the master ID, and entries whose field names match the
+/// master's only store their values. Entries are packed into a single buffer:
+///
+/// ```text
+/// ms-delta (varint) | seq (varint) | flags (u8) |
+///     SAME_FIELDS: value...                     (varint len + bytes each)
+///     otherwise:   count (varint) | field, value...
+/// ```
+///
+/// `seq` is a delta against the master sequence when `ms-delta` is 0 and the
+/// raw sequence number otherwise.
+#[derive(Clone)]
+pub struct StreamNode {
+    master_id: StreamId,
+    master_fields: Vec<Bytes>,
+    count: usize,
+    data: Vec<u8>,
+}
+
+impl StreamNode {
+    fn new(master_id: StreamId, fields: &[(Bytes, Bytes)]) -> Self {
+        StreamNode {
+            master_id,
+            master_fields: fields.iter().map(|(f, _)| f.clone()).collect(),
+            count: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.count >= NODE_MAX_ENTRIES || self.data.len() >= NODE_MAX_BYTES
+    }
+
+    fn push(&mut self, id: StreamId, fields: &[(Bytes, Bytes)]) {
+        let ms_delta = id.0 - self.master_id.0;
+        write_varint(&mut self.data, ms_delta);
+        if ms_delta == 0 {
+            write_varint(&mut self.data, id.1 - self.master_id.1);
+        } else {
+            write_varint(&mut self.data, id.1);
+        }
+
+        let same_fields = fields.len() == self.master_fields.len()
+            && fields
+                .iter()
+                .zip(self.master_fields.iter())
+                .all(|((f, _), m)| f == m);
+
+        if same_fields {
+            self.data.push(FLAG_SAME_FIELDS);
+            for (_, value) in fields {
+                write_bytes(&mut self.data, value);
+            }
+        } else {
+            self.data.push(0);
+            write_varint(&mut self.data, fields.len() as u64);
+            for (field, value) in fields {
+                write_bytes(&mut self.data, field);
+                write_bytes(&mut self.data, value);
+            }
+        }
+        self.count += 1;
+    }
+
+    // Releases spare capacity once no more entries will be appended
+    fn seal(&mut self) {
+        self.data.shrink_to_fit();
+    }
+
+    fn iter(&self) -> NodeIter<'_> {
+        NodeIter { node: self, pos: 0 }
+    }
+}
+
+struct NodeIter<'a> {
+    node: &'a StreamNode,
+    pos: usize,
+}
+
+impl Iterator for NodeIter<'_> {
+    type Item = (StreamId, StreamEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = &self.node.data;
+        if self.pos >= data.len() {
+            return None;
+        }
+
+        let ms_delta = read_varint(data, &mut self.pos);
+        let seq = read_varint(data, &mut self.pos);
+        let id = if ms_delta == 0 {
+            (self.node.master_id.0, self.node.master_id.1 + seq)
+        } else {
+            (self.node.master_id.0 + ms_delta, seq)
+        };
+
+        let flags = data[self.pos];
+        self.pos += 1;
+
+        let mut fields = Vec::new();
+        if flags & FLAG_SAME_FIELDS != 0 {
+            for field in self.node.master_fields.iter() {
+                let value = read_bytes(data, &mut self.pos);
+                fields.push((field.clone(), value));
+            }
+        } else {
+            let count = read_varint(data, &mut self.pos);
+            for _ in 0..count {
+                let field = read_bytes(data, &mut self.pos);
+                let value = read_bytes(data, &mut self.pos);
+                fields.push((field, value));
+            }
+        }
+
+        Some((id, fields))
+    }
+}
+
+impl Items for StreamNode {
+    fn items(&self) -> usize {
+        self.count
+    }
+}
+
+/// Stream entries stored as macro nodes keyed by their master entry ID.
+/// Nodes are shared with snapshots, so a write copies only the last node.
+#[derive(Clone)]
+pub struct StreamEntries {
+    nodes: BTreeMap<StreamId, Cow<StreamNode>>,
+    len: usize,
+}
+
+impl Default for StreamEntries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamEntries {
+    pub fn new() -> Self {
+        StreamEntries {
+            nodes: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Appends an entry. `id` must be greater than every ID already stored.
+    pub fn push(&mut self, id: StreamId, fields: &[(Bytes, Bytes)]) {
+        let needs_node = match self.nodes.values_mut().next_back() {
+            Some(node) if node.is_full() => {
+                node.make_mut().seal();
+                true
+            }
+            Some(_) => false,
+            None => true,
+        };
+        if needs_node {
+            self.nodes.insert(id, Cow::new(StreamNode::new(id, fields)));
+        }
+
+        self.nodes
+            .values_mut()
+            .next_back()
+            .unwrap()
+            .make_mut()
+            .push(id, fields);
+        self.len += 1;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (StreamId, StreamEntry)> + '_ {
+        self.nodes.values().flat_map(|node| node.iter())
+    }
+
+    /// Entries with `start <= id <= end`.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (StreamId, StreamEntry)> + '_ {
+        // The node holding `start` may begin before it
+        let first_node = self
+            .nodes
+            .range(..=start)
+            .next_back()
+            .map(|(id, _)| *id)
+            .unwrap_or(start);
+
+        self.nodes
+            .range((Bound::Included(first_node), Bound::Unbounded))
+            .take_while(move |(master, _)| **master <= end)
+            .flat_map(|(_, node)| node.iter())
+            .skip_while(move |(id, _)| *id < start)
+            .take_while(move |(id, _)| *id <= end)
+    }
+
+    pub fn first(&self) -> Option<(StreamId, StreamEntry)> {
+        self.nodes
+            .values()
+            .next()
+            .and_then(|node| node.iter().next())
+    }
+
+    pub fn last(&self) -> Option<(StreamId, StreamEntry)> {
+        self.nodes
+            .values()
+            .next_back()
+            .and_then(|node| node.iter().last())
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        n |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes(buf: &[u8], pos: &mut usize) -> Bytes {
+    let len = read_varint(buf, pos) as usize;
+    let bytes = Bytes::copy_from_slice(&buf[*pos..*pos + len]);
+    *pos += len;
+    bytes
+}
@@ -1,111 +1,244 @@
+use crate::cow::{Cow, Items};
+use crate::notify::{DbNotifier, NOTIFY_NEW, NOTIFY_STREAM};
+use crate::rdb::{ConsumerData, GroupData, PendingData, RdbWriter, StreamData};
 use crate::resp::RedisValueRef;
+use crate::stream_node::{StreamEntries, StreamId};
 use bytes::Bytes;
 use memchr::memchr;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use tokio::sync::{oneshot, RwLock};
 use tokio::time::{timeout, Duration};
 type BlockedClientsMap = HashMap<Bytes, VecDeque<oneshot::Sender<bool>>>;
 
+#[derive(Clone)]
 pub struct StreamKV {
-    // (sequence num - time) -> map (key , value)
-    map: BTreeMap<(Bytes, Bytes), BTreeMap<Bytes, Bytes>>,
+    entries: StreamEntries,
+    last_id: StreamId,
+    max_deleted_id: StreamId,
+    entries_added: u64,
+    // group name -> consumer group, ordered like Redis's rax
+    groups: BTreeMap<Bytes, ConsumerGroup>,
+}
+
+#[derive(Clone)]
+pub struct ConsumerGroup {
+    last_delivered_id: StreamId,
+    entries_read: Option<u64>,
+    // entry id -> pending delivery
+    pel: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Bytes, Consumer>,
+}
+
+#[derive(Clone)]
+pub struct PendingEntry {
+    consumer: Bytes,
+    delivery_time: u64,
+    delivery_count: u64,
+}
+
+#[derive(Clone)]
+pub struct Consumer {
+    seen_time: u64,
+    active_time: Option<u64>,
+    pel: BTreeSet<StreamId>,
+}
+
+pub enum XInfoSub {
+    // Options after the key, checked once the stream is found as in Redis
+    Stream(Vec<Bytes>),
+    Groups,
+    Consumers(Bytes),
+}
+
+impl Default for StreamKV {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl StreamKV {
     pub fn new() -> Self {
         StreamKV {
-            map: BTreeMap::new(),
+            entries: StreamEntries::new(),
+            last_id: (0, 0),
+            max_deleted_id: (0, 0),
+            entries_added: 0,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    fn append(&mut self, id: StreamId, kv: &[Bytes]) {
+        let fields: Vec<(Bytes, Bytes)> = kv
+            .chunks_exact(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        self.entries.push(id, &fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    fn first_id(&self) -> StreamId {
+        self.entries.first().map(|(id, _)| id).unwrap_or((0, 0))
+    }
+
+    fn to_rdb(&self) -> StreamData {
+        let groups = self
+            .groups
+            .iter()
+            .map(|(name, group)| GroupData {
+                name: name.clone(),
+                last_delivered_id: group.last_delivered_id,
+                entries_read: group.entries_read,
+                pending: group
+                    .pel
+                    .iter()
+                    .map(|(id, pending)| PendingData {
+                        id: *id,
+                        delivery_time: pending.delivery_time,
+                        delivery_count: pending.delivery_count,
+                    })
+                    .collect(),
+                consumers: group
+                    .consumers
+                    .iter()
+                    .map(|(name, consumer)| ConsumerData {
+                        name: name.clone(),
+                        seen_time: consumer.seen_time,
+                        active_time: consumer.active_time,
+                        pending: consumer.pel.iter().copied().collect(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        StreamData {
+            entries: self.entries.iter().collect(),
+            last_id: self.last_id,
+            max_deleted_id: self.max_deleted_id,
+            entries_added: self.entries_added,
+            groups,
+        }
+    }
+
+    fn from_rdb(data: StreamData) -> Self {
+        let mut stream = StreamKV::new();
+        for (id, fields) in &data.entries {
+            stream.entries.push(*id, fields);
+        }
+        stream.last_id = data.last_id;
+        stream.max_deleted_id = data.max_deleted_id;
+        stream.entries_added = data.entries_added;
+
+        for group in data.groups {
+            let mut cg = ConsumerGroup::new(group.last_delivered_id, group.entries_read);
+            // Pending entries name their consumer through the consumer's PEL
+            let mut owners = HashMap::new();
+            for consumer in group.consumers {
+                for id in &consumer.pending {
+                    owners.insert(*id, consumer.name.clone());
+                }
+                cg.consumers.insert(
+                    consumer.name,
+                    Consumer {
+                        seen_time: consumer.seen_time,
+                        active_time: consumer.active_time,
+                        pel: consumer.pending.into_iter().collect(),
+                    },
+                );
+            }
+            for pending in group.pending {
+                let Some(consumer) = owners.remove(&pending.id) else {
+                    continue;
+                };
+                cg.pel.insert(
+                    pending.id,
+                    PendingEntry {
+                        consumer,
+                        delivery_time: pending.delivery_time,
+                        delivery_count: pending.delivery_count,
+                    },
+                );
+            }
+            stream.groups.insert(group.name, cg);
+        }
+        stream
+    }
+
+    fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 || group.last_delivered_id >= self.last_id {
+            return Some(0);
+        }
+        group
+            .entries_read
+            .map(|read| self.entries_added.saturating_sub(read))
+    }
+}
+
+// Copying a stream duplicates its node pointers, not the entries
+impl Items for StreamKV {
+    fn items(&self) -> usize {
+        self.entries.node_count() + self.groups.len()
+    }
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered_id: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup {
+            last_delivered_id,
+            entries_read,
+            pel: BTreeMap::new(),
+            consumers: BTreeMap::new(),
         }
     }
 }
 
 pub struct Stream {
     // streamid -> StreamKV
-    streams: RwLock<HashMap<Bytes, StreamKV>>,
+    streams: RwLock<im::HashMap<Bytes, Cow<StreamKV>>>,
     blocked: RwLock<BlockedClientsMap>,
+    notifier: DbNotifier,
 }
 
 impl Stream {
-    pub fn new() -> Self {
+    pub fn new(notifier: DbNotifier) -> Self {
         Stream {
-            streams: RwLock::new(HashMap::new()),
+            streams: RwLock::new(im::HashMap::new()),
             blocked: RwLock::new(HashMap::new()),
+            notifier,
         }
     }
 
     pub async fn xadd(&self, stream_key: Bytes, stream_id: Bytes, kv: Vec<Bytes>) -> RedisValueRef {
-        let mut res = RedisValueRef::Error(Bytes::from(
-            "ERR Invalid stream ID specified as stream command argument",
-        ));
-        if let Some(pos) = memchr(b'-', &stream_id) {
-            let ts = Bytes::copy_from_slice(&stream_id[..pos]);
-            let seq = Bytes::copy_from_slice(&stream_id[pos + 1..]);
-
-            let ts_str = std::str::from_utf8(&ts).ok().unwrap();
-            let seq_str = std::str::from_utf8(&seq).ok().unwrap();
-
-            // Determine final timestamp and sequence
-            let (final_ts, final_seq) = if ts_str == "*" {
-                // Full auto-generation: *
-                self.generate_id(&stream_key, None).await
-            } else if seq_str == "*" {
-                // Partial auto-generation: <timestamp>-*
-                self.generate_id(&stream_key, Some(&ts)).await
-            } else {
-                // Fully specified ID, validate it
-                match self.validate_key(&stream_key, ts_str, seq_str).await {
-                    Some(s) => return RedisValueRef::Error(Bytes::from(s)),
-                    None => (ts, seq),
-                }
-            };
-
-            let mut streams = self.streams.write().await;
-            streams.entry(stream_key.clone()).or_insert(StreamKV::new());
-            if let Some(stream) = streams.get_mut(&stream_key) {
-                stream
-                    .map
-                    .entry((final_ts.clone(), final_seq.clone()))
-                    .or_insert(BTreeMap::new());
-                if let Some(map) = stream.map.get_mut(&(final_ts.clone(), final_seq.clone())) {
-                    for i in (0..kv.len()).step_by(2) {
-                        map.insert(kv[i].clone(), kv[i + 1].clone());
-                    }
-                }
-            }
-
-            let result_id = format!(
-                "{}-{}",
-                std::str::from_utf8(&final_ts).unwrap(),
-                std::str::from_utf8(&final_seq).unwrap()
-            );
-            res = RedisValueRef::BulkString(Bytes::from(result_id));
-        } else {
-            // Handle special case: just "*" without a dash
-            if stream_id.as_ref() == b"*" {
-                let (final_ts, final_seq) = self.generate_id(&stream_key, None).await;
-
-                let mut streams = self.streams.write().await;
-                streams.entry(stream_key.clone()).or_insert(StreamKV::new());
-                if let Some(stream) = streams.get_mut(&stream_key) {
-                    stream
-                        .map
-                        .entry((final_ts.clone(), final_seq.clone()))
-                        .or_insert(BTreeMap::new());
-                    if let Some(map) = stream.map.get_mut(&(final_ts.clone(), final_seq.clone())) {
-                        for i in (0..kv.len()).step_by(2) {
-                            map.insert(kv[i].clone(), kv[i + 1].clone());
-                        }
-                    }
-                }
+        let Some(requested) = XAddId::parse(&stream_id) else {
+            return RedisValueRef::Error(Bytes::from(
+                "ERR Invalid stream ID specified as stream command argument",
+            ));
+        };
 
-                let result_id = format!(
-                    "{}-{}",
-                    std::str::from_utf8(&final_ts).unwrap(),
-                    std::str::from_utf8(&final_seq).unwrap()
-                );
-                res = RedisValueRef::BulkString(Bytes::from(result_id));
-            }
+        // The ID is picked and the entry added under one guard, so concurrent
+        // XADDs can't both build on the same last ID
+        let mut streams = self.streams.write().await;
+        let is_new = !streams.contains_key(&stream_key);
+        let last_id = streams
+            .get(&stream_key)
+            .map(|stream| stream.last_id)
+            .unwrap_or((0, 0));
+        let id = match requested.resolve(last_id) {
+            Ok(id) => id,
+            Err(e) => return RedisValueRef::Error(Bytes::from(e)),
+        };
+        streams
+            .entry(stream_key.clone())
+            .or_default()
+            .make_mut()
+            .append(id, &kv);
+        drop(streams);
+
+        if is_new {
+            self.notifier.notify(NOTIFY_NEW, "new", &stream_key).await;
         }
+        self.notifier
+            .notify(NOTIFY_STREAM, "xadd", &stream_key)
+            .await;
 
         let mut blocked_clients = self.blocked.write().await;
         if let Some(notifiers) = blocked_clients.get_mut(&stream_key) {
@@ -117,7 +250,7 @@ impl Stream {
             }
         }
 
-        res
+        id_to_resp(id)
     }
 
     pub async fn contains(&self, stream_key: &Bytes) -> bool {
@@ -125,46 +258,34 @@ impl Stream {
         streams.contains_key(stream_key)
     }
 
-    async fn validate_key(
-        &self,
-        stream_key: &Bytes,
-        ts_str: &str,
-        seq_str: &str,
-    ) -> Option<String> {
-        let streams = self.streams.read().await;
-
-        let ts_num = ts_str.parse::<u64>().ok();
-        let seq_num = seq_str.parse::<u64>().ok();
-
-        if ts_num.is_none() || seq_num.is_none() {
-            return Some("ERR Invalid stream ID specified as stream command argument".to_string());
-        }
-
-        let ts_num = ts_num.unwrap();
-        let seq_num = seq_num.unwrap();
+    /// How many keys hold a value of this type
+    pub async fn key_count(&self) -> usize {
+        self.streams.read().await.len()
+    }
 
-        if ts_num == 0 && seq_num == 0 {
-            return Some("ERR The ID specified in XADD must be greater than 0-0".to_string());
+    /// Moves `stream_key`'s stream to `to`. Returns false if there is none
+    /// here.
+    pub async fn move_key(&self, stream_key: &Bytes, to: &Stream) -> bool {
+        let mut streams = self.streams.write().await;
+        if !streams.contains_key(stream_key) {
+            return false;
         }
+        let Some(stream) = streams.remove(stream_key) else {
+            return false;
+        };
+        to.streams.write().await.insert(stream_key.clone(), stream);
+        true
+    }
 
-        if let Some(stream) = streams.get(stream_key) {
-            if let Some(((last_ts, last_seq), _)) = stream.map.last_key_value() {
-                let last_ts_str = std::str::from_utf8(last_ts).ok()?;
-                let last_seq_str = std::str::from_utf8(last_seq).ok()?;
-
-                if let (Ok(last_ts_num), Ok(last_seq_num)) =
-                    (last_ts_str.parse::<u64>(), last_seq_str.parse::<u64>())
-                {
-                    if ts_num < last_ts_num || (ts_num == last_ts_num && seq_num <= last_seq_num) {
-                        return Some(format!(
-                        "ERR The ID specified in XADD is equal or smaller than the target stream top item"
-                    ));
-                    }
-                }
-            }
-        }
+    /// Load a stream read from an RDB file
+    pub async fn load(&self, stream_key: Bytes, data: StreamData) {
+        let mut streams = self.streams.write().await;
+        streams.insert(stream_key, Cow::new(StreamKV::from_rdb(data)));
+    }
 
-        None
+    /// The streams as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> StreamSnapshot {
+        StreamSnapshot(self.streams.read().await.clone())
     }
 
     pub async fn xrange(
@@ -175,173 +296,38 @@ impl Stream {
     ) -> Vec<RedisValueRef> {
         let mut res: Vec<RedisValueRef> = Vec::new();
 
-        let (start_ts, start_seq) = if start.as_ref() == b"-" {
+        let start = if start.as_ref() == b"-" {
             // "-" means start from the beginning
-            (Bytes::from("0"), Bytes::from("0"))
-        } else if let Some(pos) = memchr(b'-', start) {
-            let ts = Bytes::copy_from_slice(&start[..pos]);
-            let seq = Bytes::copy_from_slice(&start[pos + 1..]);
-            (ts, seq)
+            (0, 0)
         } else {
-            // Invalid format
-            return res;
+            match parse_range_id(start, 0) {
+                Some(id) => id,
+                // Invalid format
+                None => return res,
+            }
         };
 
-        let (end_ts, end_seq) = if end.as_ref() == b"+" {
+        let end = if end.as_ref() == b"+" {
             // "+" means go to the end (use max values)
-            (
-                Bytes::from(u64::MAX.to_string()),
-                Bytes::from(u64::MAX.to_string()),
-            )
-        } else if let Some(pos) = memchr(b'-', end) {
-            let ts = Bytes::copy_from_slice(&end[..pos]);
-            let seq = Bytes::copy_from_slice(&end[pos + 1..]);
-            (ts, seq)
+            (u64::MAX, u64::MAX)
         } else {
-            // Invalid format
-            return res;
+            match parse_range_id(end, u64::MAX) {
+                Some(id) => id,
+                // Invalid format
+                None => return res,
+            }
         };
 
         let streams = self.streams.read().await;
         if let Some(stream) = streams.get(stream_id) {
-            for ((ts, seq), map) in stream.map.iter() {
-                // Parse current entry's timestamp and sequence
-                let ts_str = std::str::from_utf8(ts).ok().unwrap();
-                let seq_str = std::str::from_utf8(seq).ok().unwrap();
-                let ts_num = ts_str.parse::<u64>().ok().unwrap();
-                let seq_num = seq_str.parse::<u64>().ok().unwrap();
-
-                // Parse start and end bounds
-                let start_ts_num = std::str::from_utf8(&start_ts)
-                    .ok()
-                    .unwrap()
-                    .parse::<u64>()
-                    .ok()
-                    .unwrap();
-                let start_seq_num = std::str::from_utf8(&start_seq)
-                    .ok()
-                    .unwrap()
-                    .parse::<u64>()
-                    .ok()
-                    .unwrap();
-                let end_ts_num = std::str::from_utf8(&end_ts)
-                    .ok()
-                    .unwrap()
-                    .parse::<u64>()
-                    .ok()
-                    .unwrap();
-                let end_seq_num = std::str::from_utf8(&end_seq)
-                    .ok()
-                    .unwrap()
-                    .parse::<u64>()
-                    .ok()
-                    .unwrap();
-
-                let after_start =
-                    ts_num > start_ts_num || (ts_num == start_ts_num && seq_num >= start_seq_num);
-                let before_end =
-                    ts_num < end_ts_num || (ts_num == end_ts_num && seq_num <= end_seq_num);
-
-                if after_start && before_end {
-                    let result_id = format!("{}-{}", ts_str, seq_str);
-                    let mut map_vec: Vec<RedisValueRef> = Vec::new();
-                    map_vec.push(RedisValueRef::BulkString(Bytes::from(result_id)));
-
-                    let mut kv_array: Vec<RedisValueRef> = Vec::new();
-                    for (key, val) in map.iter() {
-                        kv_array.push(RedisValueRef::BulkString(key.clone()));
-                        kv_array.push(RedisValueRef::BulkString(val.clone()));
-                    }
-                    map_vec.push(RedisValueRef::Array(kv_array));
-
-                    res.push(RedisValueRef::Array(map_vec));
-                } else if ts_num > end_ts_num || (ts_num == end_ts_num && seq_num > end_seq_num) {
-                    break;
-                }
+            for (id, fields) in stream.entries.range(start, end) {
+                res.push(entry_to_resp(id, &fields));
             }
         }
         res
     }
 
-    async fn generate_id(&self, stream_key: &Bytes, cur_ts: Option<&Bytes>) -> (Bytes, Bytes) {
-        let streams = self.streams.read().await;
-
-        if let Some(stream) = streams.get(stream_key) {
-            if let Some(((last_ts, last_seq), _)) = stream.map.last_key_value() {
-                match cur_ts {
-                    Some(cur_ts) => {
-                        // User provided timestamp, auto-generate sequence number
-                        if last_ts == cur_ts {
-                            // Same timestamp as last entry, increment sequence
-                            let seq_str = std::str::from_utf8(last_seq).ok().unwrap();
-                            let seq_num = seq_str.parse::<u64>().ok().unwrap();
-                            (cur_ts.clone(), Bytes::from((seq_num + 1).to_string()))
-                        } else {
-                            // Different timestamp, start sequence at 0
-                            (cur_ts.clone(), Bytes::from("0"))
-                        }
-                    }
-                    None => {
-                        // Auto-generate both timestamp and sequence
-                        let current_timestamp = current_unix_timestamp_ms();
-                        let last_ts_str = std::str::from_utf8(last_ts).ok().unwrap();
-                        let last_ts_num = last_ts_str.parse::<u64>().ok().unwrap();
-
-                        if current_timestamp > last_ts_num {
-                            // Current time is ahead, use it with sequence 0
-                            (Bytes::from(current_timestamp.to_string()), Bytes::from("0"))
-                        } else {
-                            // Current time is same or behind, use last timestamp and increment sequence
-                            let last_seq_str = std::str::from_utf8(last_seq).ok().unwrap();
-                            let last_seq_num = last_seq_str.parse::<u64>().ok().unwrap();
-                            (last_ts.clone(), Bytes::from((last_seq_num + 1).to_string()))
-                        }
-                    }
-                }
-            } else {
-                // Stream is empty, this is the first entry
-                match cur_ts {
-                    Some(cur_ts) => {
-                        // User provided timestamp, use sequence 0 or 1
-                        let ts_str = std::str::from_utf8(cur_ts).ok().unwrap();
-                        let ts_num = ts_str.parse::<u64>().ok().unwrap();
-
-                        if ts_num == 0 {
-                            // Special case: if timestamp is 0, start with 0-1
-                            (cur_ts.clone(), Bytes::from("1"))
-                        } else {
-                            (cur_ts.clone(), Bytes::from("0"))
-                        }
-                    }
-                    None => {
-                        // Auto-generate both for first entry
-                        let current_timestamp = current_unix_timestamp_ms();
-                        (Bytes::from(current_timestamp.to_string()), Bytes::from("0"))
-                    }
-                }
-            }
-        } else {
-            // Stream doesn't exist yet, this will be the first entry
-            match cur_ts {
-                Some(cur_ts) => {
-                    let ts_str = std::str::from_utf8(cur_ts).ok().unwrap();
-                    let ts_num = ts_str.parse::<u64>().ok().unwrap();
-
-                    if ts_num == 0 {
-                        (cur_ts.clone(), Bytes::from("1"))
-                    } else {
-                        (cur_ts.clone(), Bytes::from("0"))
-                    }
-                }
-                None => {
-                    let current_timestamp = current_unix_timestamp_ms();
-                    (Bytes::from(current_timestamp.to_string()), Bytes::from("0"))
-                }
-            }
-        }
-    }
-
-    pub async fn xread(&self, kv: &Vec<Bytes>) -> Vec<RedisValueRef> {
+    pub async fn xread(&self, kv: &[Bytes]) -> Vec<RedisValueRef> {
         let mut res: Vec<RedisValueRef> = Vec::new();
         let streams = self.streams.read().await;
 
@@ -352,38 +338,12 @@ impl Stream {
 
             let mut stream_entries: Vec<RedisValueRef> = Vec::new();
 
-            if let Some(pos) = memchr(b'-', stream_id) {
-                let cur_ts = Bytes::copy_from_slice(&stream_id[..pos]);
-                let cur_seq = Bytes::copy_from_slice(&stream_id[pos + 1..]);
-
-                let cur_ts_str = std::str::from_utf8(&cur_ts).ok().unwrap();
-                let cur_seq_str = std::str::from_utf8(&cur_seq).ok().unwrap();
-
-                let cur_ts_int = cur_ts_str.parse::<u64>().unwrap();
-                let cur_seq_int = cur_seq_str.parse::<u64>().unwrap();
-
+            if let Some(cur_id) = parse_id_arg(stream_id) {
                 if let Some(stream) = streams.get(stream_key) {
-                    for ((ts, seq), map) in stream.map.iter() {
-                        let ts_str = std::str::from_utf8(ts).ok().unwrap();
-                        let seq_str = std::str::from_utf8(seq).ok().unwrap();
-
-                        let ts_int = ts_str.parse::<u64>().unwrap();
-                        let seq_int = seq_str.parse::<u64>().unwrap();
-
-                        // Check if this entry is AFTER the provided ID (exclusive)
-                        if ts_int > cur_ts_int || (ts_int == cur_ts_int && seq_int > cur_seq_int) {
-                            let result_id = format!("{}-{}", ts_str, seq_str);
-                            let mut entry_array: Vec<RedisValueRef> = Vec::new();
-                            entry_array.push(RedisValueRef::BulkString(Bytes::from(result_id)));
-
-                            let mut kv_array: Vec<RedisValueRef> = Vec::new();
-                            for (key, val) in map.iter() {
-                                kv_array.push(RedisValueRef::BulkString(key.clone()));
-                                kv_array.push(RedisValueRef::BulkString(val.clone()));
-                            }
-                            entry_array.push(RedisValueRef::Array(kv_array));
-
-                            stream_entries.push(RedisValueRef::Array(entry_array));
+                    // Entries strictly AFTER the provided ID
+                    for (id, fields) in stream.entries.range(cur_id, (u64::MAX, u64::MAX)) {
+                        if id > cur_id {
+                            stream_entries.push(entry_to_resp(id, &fields));
                         }
                     }
                 }
@@ -401,7 +361,16 @@ impl Stream {
         res
     }
 
-    pub async fn blocking_xread(&self, kv: &Vec<Bytes>, duration: Duration) -> RedisValueRef {
+    /// `gate` is held around each read, but not while waiting, so a wake-up
+    /// can't observe a transaction halfway through.
+    pub async fn blocking_xread(
+        &self,
+        kv: &[Bytes],
+        duration: Duration,
+        gate: &RwLock<()>,
+    ) -> RedisValueRef {
+        let gate_guard = gate.read().await;
+
         // Resolve any "$" IDs to actual IDs BEFORE checking for data
         // This ensures we use the same reference point throughout the blocking operation
         let mut resolved_kv = Vec::new();
@@ -412,18 +381,11 @@ impl Stream {
                 let stream_id = match kv[i + 1].as_ref() {
                     b"$" => {
                         // Resolve "$" to the last entry ID at the time of the call
-                        if let Some(stream) = streams.get(stream_key) {
-                            match stream.map.last_key_value() {
-                                Some(((ts, seq), _)) => {
-                                    let ts_str = std::str::from_utf8(&ts).ok().unwrap();
-                                    let seq_str = std::str::from_utf8(&seq).ok().unwrap();
-                                    Bytes::from(format!("{}-{}", ts_str, seq_str))
-                                }
-                                None => Bytes::from("0-0"),
-                            }
-                        } else {
-                            Bytes::from("0-0")
-                        }
+                        let (ts, seq) = streams
+                            .get(stream_key)
+                            .map(|stream| stream.last_id)
+                            .unwrap_or((0, 0));
+                        Bytes::from(format!("{}-{}", ts, seq))
                     }
                     _ => kv[i + 1].clone(),
                 };
@@ -437,6 +399,7 @@ impl Stream {
         if !res.is_empty() {
             return RedisValueRef::Array(res);
         }
+        drop(gate_guard);
 
         // No data yet, block and wait
         let (tx, rx) = oneshot::channel::<bool>();
@@ -456,6 +419,7 @@ impl Stream {
         match timeout(duration, rx).await {
             Ok(Ok(_)) => {
                 // Got notified - check for new data using the same resolved IDs
+                let _gate = gate.read().await;
                 let res = self.xread(&resolved_kv).await;
                 if res.is_empty() {
                     println!("None");
@@ -485,6 +449,432 @@ impl Stream {
             }
         }
     }
+
+    pub async fn xgroup_create(
+        &self,
+        stream_key: &Bytes,
+        group: Bytes,
+        id: &Bytes,
+        mkstream: bool,
+    ) -> RedisValueRef {
+        let mut streams = self.streams.write().await;
+        let exists = streams.contains_key(stream_key);
+        if !exists && !mkstream {
+            return RedisValueRef::Error(Bytes::from(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+            ));
+        }
+        let (last_id, entries_added, group_exists) = match streams.get(stream_key) {
+            Some(stream) => (
+                stream.last_id,
+                stream.entries_added,
+                stream.groups.contains_key(&group),
+            ),
+            None => ((0, 0), 0, false),
+        };
+
+        let (last_delivered_id, entries_read) = if id.as_ref() == b"$" {
+            (last_id, Some(entries_added))
+        } else {
+            match parse_id_arg(id) {
+                Some((0, 0)) => ((0, 0), Some(0)),
+                Some(id) => (id, None),
+                None => {
+                    return RedisValueRef::Error(Bytes::from(
+                        "ERR Invalid stream ID specified as stream command argument",
+                    ))
+                }
+            }
+        };
+
+        if group_exists {
+            return RedisValueRef::Error(Bytes::from(
+                "BUSYGROUP Consumer Group name already exists",
+            ));
+        }
+        // MKSTREAM creates the stream here if it's missing
+        streams
+            .entry(stream_key.clone())
+            .or_default()
+            .make_mut()
+            .groups
+            .insert(group, ConsumerGroup::new(last_delivered_id, entries_read));
+        drop(streams);
+
+        self.notifier
+            .notify(NOTIFY_STREAM, "xgroup-create", stream_key)
+            .await;
+        RedisValueRef::String(Bytes::from("OK"))
+    }
+
+    pub async fn xgroup_destroy(&self, stream_key: &Bytes, group: &Bytes) -> RedisValueRef {
+        let mut streams = self.streams.write().await;
+        match streams.get(stream_key) {
+            Some(stream) => {
+                let removed = stream.groups.contains_key(group);
+                if removed {
+                    let stream = streams.get_mut(stream_key).unwrap();
+                    stream.make_mut().groups.remove(group);
+                }
+                drop(streams);
+                if removed {
+                    self.notifier
+                        .notify(NOTIFY_STREAM, "xgroup-destroy", stream_key)
+                        .await;
+                }
+                RedisValueRef::Int(removed as i64)
+            }
+            None => RedisValueRef::Error(Bytes::from(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.",
+            )),
+        }
+    }
+
+    pub async fn xinfo(&self, stream_key: &Bytes, sub: XInfoSub) -> RedisValueRef {
+        let streams = self.streams.read().await;
+        let Some(stream) = streams.get(stream_key) else {
+            return RedisValueRef::Error(Bytes::from("ERR no such key"));
+        };
+
+        match sub {
+            XInfoSub::Stream(options) => match xinfo_stream_count(&options) {
+                Ok(None) => xinfo_stream(stream),
+                Ok(Some(count)) => xinfo_stream_full(stream, count),
+                Err(e) => RedisValueRef::Error(Bytes::from(e)),
+            },
+            XInfoSub::Groups => RedisValueRef::Array(
+                stream
+                    .groups
+                    .iter()
+                    .map(|(name, group)| xinfo_group(stream, name, group))
+                    .collect(),
+            ),
+            XInfoSub::Consumers(group_name) => match stream.groups.get(&group_name) {
+                Some(group) => {
+                    let now = current_unix_timestamp_ms();
+                    RedisValueRef::Array(
+                        group
+                            .consumers
+                            .iter()
+                            .map(|(name, consumer)| {
+                                let inactive = match consumer.active_time {
+                                    Some(t) => now.saturating_sub(t) as i64,
+                                    None => -1,
+                                };
+                                RedisValueRef::Array(vec![
+                                    bulk("name"),
+                                    RedisValueRef::BulkString(name.clone()),
+                                    bulk("pending"),
+                                    RedisValueRef::Int(consumer.pel.len() as i64),
+                                    bulk("idle"),
+                                    RedisValueRef::Int(
+                                        now.saturating_sub(consumer.seen_time) as i64
+                                    ),
+                                    bulk("inactive"),
+                                    RedisValueRef::Int(inactive),
+                                ])
+                            })
+                            .collect(),
+                    )
+                }
+                None => RedisValueRef::Error(Bytes::from(format!(
+                    "NOGROUP No such consumer group '{}' for key name '{}'",
+                    String::from_utf8_lossy(&group_name),
+                    String::from_utf8_lossy(stream_key)
+                ))),
+            },
+        }
+    }
+}
+
+fn bulk(s: &'static str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s))
+}
+
+// The ID argument of XADD
+enum XAddId {
+    // *
+    Auto,
+    // <ms>-*
+    AutoSeq(u64),
+    // <ms>-<seq>
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    fn parse(id: &[u8]) -> Option<XAddId> {
+        if id == b"*" {
+            return Some(XAddId::Auto);
+        }
+        let pos = memchr(b'-', id)?;
+        let (ms, seq) = (&id[..pos], &id[pos + 1..]);
+        if seq == b"*" {
+            let ms = std::str::from_utf8(ms).ok()?.parse::<u64>().ok()?;
+            return Some(XAddId::AutoSeq(ms));
+        }
+        parse_stream_id(ms, seq).map(XAddId::Explicit)
+    }
+
+    // The ID the new entry gets in a stream whose last ID is `last`, which
+    // is 0-0 for a missing stream
+    fn resolve(self, last: StreamId) -> Result<StreamId, &'static str> {
+        const SMALLER: &str =
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+        const EXHAUSTED: &str =
+            "ERR The stream has exhausted the last possible ID, unable to add more items";
+
+        match self {
+            XAddId::Auto => {
+                let now = current_unix_timestamp_ms();
+                if now > last.0 {
+                    Ok((now, 0))
+                } else {
+                    next_id(last).ok_or(EXHAUSTED)
+                }
+            }
+            XAddId::AutoSeq(ms) if ms == last.0 => match last.1.checked_add(1) {
+                Some(seq) => Ok((ms, seq)),
+                None => Err(SMALLER),
+            },
+            XAddId::AutoSeq(ms) if ms < last.0 => Err(SMALLER),
+            XAddId::AutoSeq(ms) => Ok((ms, 0)),
+            XAddId::Explicit((0, 0)) => {
+                Err("ERR The ID specified in XADD must be greater than 0-0")
+            }
+            XAddId::Explicit(id) if id <= last => Err(SMALLER),
+            XAddId::Explicit(id) => Ok(id),
+        }
+    }
+}
+
+// The smallest ID after `id`
+fn next_id(id: StreamId) -> Option<StreamId> {
+    match id.1.checked_add(1) {
+        Some(seq) => Some((id.0, seq)),
+        None => Some((id.0.checked_add(1)?, 0)),
+    }
+}
+
+fn id_to_resp(id: StreamId) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(format!("{}-{}", id.0, id.1)))
+}
+
+fn entry_to_resp(id: StreamId, fields: &[(Bytes, Bytes)]) -> RedisValueRef {
+    let mut kv_array: Vec<RedisValueRef> = Vec::new();
+    for (key, val) in fields {
+        kv_array.push(RedisValueRef::BulkString(key.clone()));
+        kv_array.push(RedisValueRef::BulkString(val.clone()));
+    }
+    RedisValueRef::Array(vec![id_to_resp(id), RedisValueRef::Array(kv_array)])
+}
+
+fn lag_to_resp(lag: Option<u64>) -> RedisValueRef {
+    match lag {
+        Some(lag) => RedisValueRef::Int(lag as i64),
+        None => RedisValueRef::NullBulkString,
+    }
+}
+
+// Fields shared by the plain and FULL forms of XINFO STREAM
+fn xinfo_stream_header(stream: &StreamKV) -> Vec<RedisValueRef> {
+    vec![
+        bulk("length"),
+        RedisValueRef::Int(stream.entries.len() as i64),
+        bulk("radix-tree-keys"),
+        RedisValueRef::Int(stream.entries.node_count() as i64),
+        bulk("radix-tree-nodes"),
+        RedisValueRef::Int(stream.entries.node_count() as i64 + 1),
+        bulk("last-generated-id"),
+        id_to_resp(stream.last_id),
+        bulk("max-deleted-entry-id"),
+        id_to_resp(stream.max_deleted_id),
+        bulk("entries-added"),
+        RedisValueRef::Int(stream.entries_added as i64),
+        bulk("recorded-first-entry-id"),
+        id_to_resp(stream.first_id()),
+    ]
+}
+
+fn xinfo_stream(stream: &StreamKV) -> RedisValueRef {
+    let mut res = xinfo_stream_header(stream);
+    res.push(bulk("groups"));
+    res.push(RedisValueRef::Int(stream.groups.len() as i64));
+    res.push(bulk("first-entry"));
+    res.push(match stream.entries.first() {
+        Some((id, fields)) => entry_to_resp(id, &fields),
+        None => RedisValueRef::NullBulkString,
+    });
+    res.push(bulk("last-entry"));
+    res.push(match stream.entries.last() {
+        Some((id, fields)) => entry_to_resp(id, &fields),
+        None => RedisValueRef::NullBulkString,
+    });
+    RedisValueRef::Array(res)
+}
+
+// XINFO STREAM's [FULL [COUNT count]]: None for the summary, or how many
+// entries FULL lists. A negative count is the default.
+fn xinfo_stream_count(options: &[Bytes]) -> Result<Option<usize>, String> {
+    match options {
+        [] => Ok(None),
+        [full] if full.eq_ignore_ascii_case(b"FULL") => Ok(Some(10)),
+        [full, opt, count]
+            if full.eq_ignore_ascii_case(b"FULL") && opt.eq_ignore_ascii_case(b"COUNT") =>
+        {
+            let count = std::str::from_utf8(count)
+                .ok()
+                .and_then(|c| c.parse::<i64>().ok())
+                .ok_or_else(|| "ERR value is not an integer or out of range".to_string())?;
+            Ok(Some(usize::try_from(count).unwrap_or(10)))
+        }
+        _ => Err(
+            "ERR unknown subcommand or wrong number of arguments for 'STREAM'. Try XINFO HELP."
+                .to_string(),
+        ),
+    }
+}
+
+fn xinfo_stream_full(stream: &StreamKV, count: usize) -> RedisValueRef {
+    // COUNT 0 means "everything", matching Redis
+    let limit = if count == 0 { usize::MAX } else { count };
+
+    let mut res = xinfo_stream_header(stream);
+    res.push(bulk("entries"));
+    res.push(RedisValueRef::Array(
+        stream
+            .entries
+            .iter()
+            .take(limit)
+            .map(|(id, fields)| entry_to_resp(id, &fields))
+            .collect(),
+    ));
+
+    let mut groups = Vec::new();
+    for (name, group) in stream.groups.iter() {
+        let pending = group
+            .pel
+            .iter()
+            .take(limit)
+            .map(|(id, pe)| {
+                RedisValueRef::Array(vec![
+                    id_to_resp(*id),
+                    RedisValueRef::BulkString(pe.consumer.clone()),
+                    RedisValueRef::Int(pe.delivery_time as i64),
+                    RedisValueRef::Int(pe.delivery_count as i64),
+                ])
+            })
+            .collect();
+
+        let consumers = group
+            .consumers
+            .iter()
+            .map(|(cname, consumer)| {
+                let pending = consumer
+                    .pel
+                    .iter()
+                    .take(limit)
+                    .filter_map(|id| group.pel.get(id).map(|pe| (id, pe)))
+                    .map(|(id, pe)| {
+                        RedisValueRef::Array(vec![
+                            id_to_resp(*id),
+                            RedisValueRef::Int(pe.delivery_time as i64),
+                            RedisValueRef::Int(pe.delivery_count as i64),
+                        ])
+                    })
+                    .collect();
+                RedisValueRef::Array(vec![
+                    bulk("name"),
+                    RedisValueRef::BulkString(cname.clone()),
+                    bulk("seen-time"),
+                    RedisValueRef::Int(consumer.seen_time as i64),
+                    bulk("active-time"),
+                    RedisValueRef::Int(consumer.active_time.map_or(-1, |t| t as i64)),
+                    bulk("pel-count"),
+                    RedisValueRef::Int(consumer.pel.len() as i64),
+                    bulk("pending"),
+                    RedisValueRef::Array(pending),
+                ])
+            })
+            .collect();
+
+        groups.push(RedisValueRef::Array(vec![
+            bulk("name"),
+            RedisValueRef::BulkString(name.clone()),
+            bulk("last-delivered-id"),
+            id_to_resp(group.last_delivered_id),
+            bulk("entries-read"),
+            lag_to_resp(group.entries_read),
+            bulk("lag"),
+            lag_to_resp(stream.lag(group)),
+            bulk("pel-count"),
+            RedisValueRef::Int(group.pel.len() as i64),
+            bulk("pending"),
+            RedisValueRef::Array(pending),
+            bulk("consumers"),
+            RedisValueRef::Array(consumers),
+        ]));
+    }
+    res.push(bulk("groups"));
+    res.push(RedisValueRef::Array(groups));
+    RedisValueRef::Array(res)
+}
+
+fn xinfo_group(stream: &StreamKV, name: &Bytes, group: &ConsumerGroup) -> RedisValueRef {
+    RedisValueRef::Array(vec![
+        bulk("name"),
+        RedisValueRef::BulkString(name.clone()),
+        bulk("consumers"),
+        RedisValueRef::Int(group.consumers.len() as i64),
+        bulk("pending"),
+        RedisValueRef::Int(group.pel.len() as i64),
+        bulk("last-delivered-id"),
+        id_to_resp(group.last_delivered_id),
+        bulk("entries-read"),
+        lag_to_resp(group.entries_read),
+        bulk("lag"),
+        lag_to_resp(stream.lag(group)),
+    ])
+}
+
+/// Streams captured by `Stream::snapshot`.
+pub struct StreamSnapshot(im::HashMap<Bytes, Cow<StreamKV>>);
+
+impl StreamSnapshot {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, stream) in self.0.iter() {
+            writer.stream(key, &stream.to_rdb());
+        }
+    }
+}
+
+fn parse_stream_id(ts: &[u8], seq: &[u8]) -> Option<StreamId> {
+    let ts = std::str::from_utf8(ts).ok()?.parse::<u64>().ok()?;
+    let seq = std::str::from_utf8(seq).ok()?.parse::<u64>().ok()?;
+    Some((ts, seq))
+}
+
+// Parses "<ms>-<seq>" or a bare "<ms>" (sequence 0)
+fn parse_id_arg(id: &[u8]) -> Option<StreamId> {
+    parse_range_id(id, 0)
+}
+
+// Parses "<ms>-<seq>", filling in `default_seq` for a bare "<ms>"
+fn parse_range_id(id: &[u8], default_seq: u64) -> Option<StreamId> {
+    match memchr(b'-', id) {
+        Some(pos) => parse_stream_id(&id[..pos], &id[pos + 1..]),
+        None => Some((
+            std::str::from_utf8(id).ok()?.parse::<u64>().ok()?,
+            default_seq,
+        )),
+    }
 }
 
 pub fn current_unix_timestamp_ms() -> u64 {
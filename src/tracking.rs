@@ -0,0 +1,294 @@
+use crate::client::Clients;
+use crate::pubsub::PubSub;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// Channel RESP2 clients subscribe to when they are the target of a REDIRECT
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+tokio::task_local! {
+    // ID of the client whose command is running, so writes can honour NOLOOP
+    pub static CURRENT_CLIENT: u64;
+}
+
+/// Options given to CLIENT TRACKING ON.
+#[derive(Default)]
+pub struct TrackingOptions {
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    pub prefixes: Vec<Bytes>,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
+}
+
+// Per-connection tracking state
+struct Tracker {
+    redirect: Option<u64>,
+    bcast: bool,
+    prefixes: HashSet<Bytes>,
+    optin: bool,
+    optout: bool,
+    noloop: bool,
+    // CLIENT CACHING yes/no, only valid for the next command
+    caching: Option<bool>,
+    // Keys this client may have cached, default mode only
+    keys: HashSet<Bytes>,
+}
+
+struct TrackingTable {
+    clients: HashMap<u64, Tracker>,
+    // key -> clients that read it since it last changed
+    keys: HashMap<Bytes, HashSet<u64>>,
+    // BCAST prefix -> clients, the empty prefix matches every key
+    prefixes: HashMap<Bytes, HashSet<u64>>,
+}
+
+/// Server-assisted client side caching. Reads remember which clients saw a
+/// key, and any change to the key sends those clients an invalidation.
+pub struct Tracking {
+    table: RwLock<TrackingTable>,
+    clients: Arc<Clients>,
+    pubsub: Arc<PubSub>,
+}
+
+impl Tracking {
+    pub fn new(clients: Arc<Clients>, pubsub: Arc<PubSub>) -> Self {
+        Tracking {
+            table: RwLock::new(TrackingTable {
+                clients: HashMap::new(),
+                keys: HashMap::new(),
+                prefixes: HashMap::new(),
+            }),
+            clients,
+            pubsub,
+        }
+    }
+
+    pub async fn enable(&self, id: u64, opts: TrackingOptions) -> Result<(), String> {
+        if !opts.bcast && !opts.prefixes.is_empty() {
+            return Err("ERR PREFIX option requires BCAST mode to be enabled".to_string());
+        }
+        if opts.optin && opts.optout {
+            return Err("ERR You can't use both OPTIN and OPTOUT".to_string());
+        }
+        if opts.bcast && (opts.optin || opts.optout) {
+            return Err("ERR OPTIN and OPTOUT are not compatible with BCAST".to_string());
+        }
+        if let Some(id) = opts.redirect {
+            if self.clients.get(id).await.is_none() {
+                return Err("ERR The client ID you want redirect to does not exist".to_string());
+            }
+        }
+
+        let mut table = self.table.write().await;
+        let table = &mut *table;
+        if let Some(tracker) = table.clients.get(&id) {
+            if tracker.bcast != opts.bcast {
+                return Err("ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string());
+            }
+        }
+
+        let existing = table.clients.get(&id).map(|t| &t.prefixes);
+        let mut prefixes: Vec<&Bytes> = existing.into_iter().flatten().collect();
+        for prefix in opts.prefixes.iter() {
+            if let Some(other) = prefixes
+                .iter()
+                .find(|p| **p != prefix && (p.starts_with(prefix) || prefix.starts_with(p)))
+            {
+                return Err(format!(
+                    "ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.",
+                    String::from_utf8_lossy(prefix),
+                    String::from_utf8_lossy(other)
+                ));
+            }
+            prefixes.push(prefix);
+        }
+
+        let tracker = table.clients.entry(id).or_insert_with(|| Tracker {
+            redirect: None,
+            bcast: opts.bcast,
+            prefixes: HashSet::new(),
+            optin: false,
+            optout: false,
+            noloop: false,
+            caching: None,
+            keys: HashSet::new(),
+        });
+        tracker.redirect = opts.redirect;
+        tracker.optin = opts.optin;
+        tracker.optout = opts.optout;
+        tracker.noloop = opts.noloop;
+
+        if opts.bcast {
+            let mut prefixes = opts.prefixes;
+            if prefixes.is_empty() && tracker.prefixes.is_empty() {
+                prefixes.push(Bytes::new());
+            }
+            for prefix in prefixes {
+                table.prefixes.entry(prefix.clone()).or_default().insert(id);
+                tracker.prefixes.insert(prefix);
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns tracking off and forgets every key the client was tracking.
+    pub async fn disable(&self, id: u64) {
+        let mut table = self.table.write().await;
+        let table = &mut *table;
+        if let Some(tracker) = table.clients.remove(&id) {
+            for key in tracker.keys {
+                remove_from(&mut table.keys, &key, id);
+            }
+            for prefix in tracker.prefixes {
+                remove_from(&mut table.prefixes, &prefix, id);
+            }
+        }
+    }
+
+    pub async fn set_caching(&self, id: u64, yes: bool) -> Result<(), String> {
+        let mut table = self.table.write().await;
+        match table.clients.get_mut(&id) {
+            Some(tracker) if (yes && tracker.optin) || (!yes && tracker.optout) => {
+                tracker.caching = Some(yes);
+                Ok(())
+            }
+            _ => Err("ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".to_string()),
+        }
+    }
+
+    /// Ends the scope of a CLIENT CACHING call.
+    pub async fn command_done(&self, id: u64) {
+        let mut table = self.table.write().await;
+        if let Some(tracker) = table.clients.get_mut(&id) {
+            tracker.caching = None;
+        }
+    }
+
+    /// -1 when tracking is off, 0 without redirection, else the target ID.
+    pub async fn redirect(&self, id: u64) -> i64 {
+        let table = self.table.read().await;
+        match table.clients.get(&id) {
+            Some(tracker) => tracker.redirect.map_or(0, |id| id as i64),
+            None => -1,
+        }
+    }
+
+    /// Remembers that client `id` read `keys`, unless its mode says otherwise.
+    pub async fn record_reads(&self, id: u64, keys: Vec<Bytes>) {
+        if keys.is_empty() {
+            return;
+        }
+        let mut table = self.table.write().await;
+        let table = &mut *table;
+        let Some(tracker) = table.clients.get_mut(&id) else {
+            return;
+        };
+        let track = if tracker.bcast {
+            false
+        } else if tracker.optin {
+            tracker.caching == Some(true)
+        } else if tracker.optout {
+            tracker.caching != Some(false)
+        } else {
+            true
+        };
+        if !track {
+            return;
+        }
+        for key in keys {
+            table.keys.entry(key.clone()).or_default().insert(id);
+            tracker.keys.insert(key);
+        }
+    }
+
+    /// Sends an invalidation for `key` to every client that may cache it.
+    pub async fn invalidate(&self, key: &Bytes) {
+        let writer = CURRENT_CLIENT.try_with(|id| *id).ok();
+
+        let mut targets = Vec::new();
+        {
+            let mut table = self.table.write().await;
+            let table = &mut *table;
+            if let Some(ids) = table.keys.remove(key) {
+                for id in ids {
+                    if let Some(tracker) = table.clients.get_mut(&id) {
+                        tracker.keys.remove(key);
+                        targets.push((id, tracker.redirect, tracker.noloop));
+                    }
+                }
+            }
+            for (prefix, ids) in table.prefixes.iter() {
+                if !key.starts_with(prefix) {
+                    continue;
+                }
+                for id in ids {
+                    if let Some(tracker) = table.clients.get(id) {
+                        targets.push((*id, tracker.redirect, tracker.noloop));
+                    }
+                }
+            }
+        }
+
+        for (id, redirect, noloop) in targets {
+            if noloop && writer == Some(id) {
+                continue;
+            }
+            self.send_invalidation(id, redirect, key).await;
+        }
+    }
+
+    async fn send_invalidation(&self, id: u64, redirect: Option<u64>, key: &Bytes) {
+        let Some(client) = self.clients.get(id).await else {
+            return;
+        };
+        let target = match redirect {
+            Some(target_id) => match self.clients.get(target_id).await {
+                Some(target) => target,
+                None => {
+                    if client.protocol() == 3 {
+                        client.push(vec![
+                            RedisValueRef::BulkString(Bytes::from("tracking-redir-broken")),
+                            RedisValueRef::Int(target_id as i64),
+                        ]);
+                    }
+                    return;
+                }
+            },
+            None => client,
+        };
+
+        let keys = RedisValueRef::Array(vec![RedisValueRef::BulkString(key.clone())]);
+        if target.protocol() == 3 {
+            target.push(vec![
+                RedisValueRef::BulkString(Bytes::from("invalidate")),
+                keys,
+            ]);
+        } else if redirect.is_some()
+            && self
+                .pubsub
+                .is_subscribed_to(target.id, INVALIDATE_CHANNEL.as_bytes())
+                .await
+        {
+            // RESP2 has no push type, so the redirect target gets a pub/sub message
+            target.push(vec![
+                RedisValueRef::BulkString(Bytes::from("message")),
+                RedisValueRef::BulkString(Bytes::from(INVALIDATE_CHANNEL)),
+                keys,
+            ]);
+        }
+    }
+}
+
+fn remove_from(map: &mut HashMap<Bytes, HashSet<u64>>, name: &Bytes, id: u64) {
+    if let Some(ids) = map.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            map.remove(name);
+        }
+    }
+}
@@ -1,62 +1,134 @@
 use crate::commands::Command;
 use crate::resp::RedisValueRef;
 use bytes::Bytes;
-use std::collections::{HashMap, VecDeque};
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
 use tokio::sync::RwLock;
 
 // Per-client transaction state
 pub struct TransactionState {
     // None = not in transaction
-    // Some(queue) = in transaction, commands are queued
-    transaction_queue: Option<VecDeque<Command>>,
+    // Some(queue) = in transaction, commands are queued with the arguments
+    // they were sent with
+    transaction_queue: Option<VecDeque<(Command, Vec<RedisValueRef>)>>,
+    // Keys passed to WATCH since the last EXEC, DISCARD or UNWATCH, with the
+    // database each was watched in
+    watched: HashSet<(usize, Bytes)>,
+    // Set once a watched key changes, so EXEC must abort
+    dirty: bool,
+    // Set when a command was rejected while queueing
+    errored: bool,
+}
+
+impl Default for TransactionState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionState {
     pub fn new() -> Self {
         TransactionState {
             transaction_queue: None,
+            watched: HashSet::new(),
+            dirty: false,
+            errored: false,
         }
     }
 }
 
+/// What EXEC should do with a client's transaction.
+pub enum ExecOutcome {
+    NotInTransaction,
+    // A command was rejected while queueing
+    QueueError,
+    // A watched key changed after WATCH
+    WatchedKeyChanged,
+    Run(VecDeque<(Command, Vec<RedisValueRef>)>),
+}
+
+// Keyed by client ID
+struct Clients {
+    states: HashMap<u64, TransactionState>,
+    // (db, key) -> clients watching it
+    watchers: HashMap<(usize, Bytes), HashSet<u64>>,
+}
+
 pub struct Transaction {
-    tr: RwLock<HashMap<SocketAddr, TransactionState>>,
+    tr: RwLock<Clients>,
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Transaction {
     pub fn new() -> Self {
         Transaction {
-            tr: RwLock::new(HashMap::new()),
+            tr: RwLock::new(Clients {
+                states: HashMap::new(),
+                watchers: HashMap::new(),
+            }),
         }
     }
 
-    pub async fn start_transaction(&self, addr: SocketAddr) -> RedisValueRef {
+    pub async fn start_transaction(&self, id: u64) -> RedisValueRef {
         let mut clients = self.tr.write().await;
-        let state = clients.entry(addr).or_insert_with(TransactionState::new);
+        let state = clients
+            .states
+            .entry(id)
+            .or_insert_with(TransactionState::new);
 
+        // An error, but one that leaves the transaction as it was
         if state.transaction_queue.is_some() {
             return RedisValueRef::Error(Bytes::from("ERR MULTI calls can not be nested"));
         }
 
         state.transaction_queue = Some(VecDeque::new());
+        state.errored = false;
         RedisValueRef::String(Bytes::from("OK"))
     }
 
-    pub async fn in_transaction(&self, addr: SocketAddr) -> bool {
+    /// Queued command count (-1 outside MULTI), watched key count and
+    /// whether a watched key has changed.
+    pub async fn info(&self, id: u64) -> (i64, usize, bool) {
+        let clients = self.tr.read().await;
+        clients.states.get(&id).map_or((-1, 0, false), |state| {
+            (
+                state
+                    .transaction_queue
+                    .as_ref()
+                    .map_or(-1, |queue| queue.len() as i64),
+                state.watched.len(),
+                state.dirty,
+            )
+        })
+    }
+
+    pub async fn in_transaction(&self, id: u64) -> bool {
         let clients = self.tr.read().await;
         clients
-            .get(&addr)
+            .states
+            .get(&id)
             .map(|state| state.transaction_queue.is_some())
             .unwrap_or(false)
     }
 
-    pub async fn queue_command(&self, addr: SocketAddr, command: Command) -> RedisValueRef {
+    pub async fn queue_command(
+        &self,
+        id: u64,
+        command: Command,
+        args: Vec<RedisValueRef>,
+    ) -> RedisValueRef {
         let mut clients = self.tr.write().await;
-        let state = clients.entry(addr).or_insert_with(TransactionState::new);
+        let state = clients
+            .states
+            .entry(id)
+            .or_insert_with(TransactionState::new);
 
         if let Some(queue) = &mut state.transaction_queue {
-            queue.push_back(command);
+            queue.push_back((command, args));
             RedisValueRef::String(Bytes::from("QUEUED"))
         } else {
             // Not in transaction - this shouldn't happen
@@ -64,24 +136,126 @@ impl Transaction {
         }
     }
 
-    pub async fn discard_transaction(&self, addr: SocketAddr) -> RedisValueRef {
+    /// Records a queue-time error so EXEC discards the whole transaction.
+    pub async fn flag_error(&self, id: u64) {
+        let mut clients = self.tr.write().await;
+        if let Some(state) = clients.states.get_mut(&id) {
+            state.errored = true;
+        }
+    }
+
+    pub async fn discard_transaction(&self, id: u64) -> RedisValueRef {
         let mut clients = self.tr.write().await;
 
-        if let Some(state) = clients.get_mut(&addr) {
+        if let Some(state) = clients.states.get_mut(&id) {
             if state.transaction_queue.is_none() {
                 return RedisValueRef::Error(Bytes::from("ERR DISCARD without MULTI"));
             }
             state.transaction_queue = None;
+            clients.unwatch(id);
             RedisValueRef::String(Bytes::from("OK"))
         } else {
             RedisValueRef::Error(Bytes::from("ERR DISCARD without MULTI"))
         }
     }
 
-    pub async fn exec_transaction(&self, addr: SocketAddr) -> Option<VecDeque<Command>> {
+    pub async fn exec_transaction(&self, id: u64) -> ExecOutcome {
         let mut clients = self.tr.write().await;
-        clients
-            .get_mut(&addr)
-            .and_then(|state| state.transaction_queue.take())
+        let Some(state) = clients.states.get_mut(&id) else {
+            return ExecOutcome::NotInTransaction;
+        };
+        let Some(queue) = state.transaction_queue.take() else {
+            return ExecOutcome::NotInTransaction;
+        };
+        let dirty = state.dirty;
+        let errored = state.errored;
+        clients.unwatch(id);
+        if errored {
+            ExecOutcome::QueueError
+        } else if dirty {
+            ExecOutcome::WatchedKeyChanged
+        } else {
+            ExecOutcome::Run(queue)
+        }
+    }
+
+    pub async fn watch(&self, id: u64, db: usize, keys: Vec<Bytes>) -> RedisValueRef {
+        let mut clients = self.tr.write().await;
+        let clients = &mut *clients;
+        let state = clients
+            .states
+            .entry(id)
+            .or_insert_with(TransactionState::new);
+        if state.transaction_queue.is_some() {
+            return RedisValueRef::Error(Bytes::from("ERR WATCH inside MULTI is not allowed"));
+        }
+        for key in keys {
+            clients
+                .watchers
+                .entry((db, key.clone()))
+                .or_default()
+                .insert(id);
+            state.watched.insert((db, key));
+        }
+        RedisValueRef::String(Bytes::from("OK"))
+    }
+
+    pub async fn unwatch(&self, id: u64) {
+        self.tr.write().await.unwatch(id);
+    }
+
+    /// Forgets a disconnected client's transaction and watched keys.
+    pub async fn remove(&self, id: u64) {
+        let mut clients = self.tr.write().await;
+        clients.unwatch(id);
+        clients.states.remove(&id);
+    }
+
+    /// Called whenever `key` in database `db` is modified, so transactions
+    /// watching it abort.
+    pub async fn touch(&self, db: usize, key: &Bytes) {
+        let mut clients = self.tr.write().await;
+        let clients = &mut *clients;
+        if let Some(ids) = clients.watchers.get(&(db, key.clone())) {
+            for id in ids {
+                if let Some(state) = clients.states.get_mut(id) {
+                    state.dirty = true;
+                }
+            }
+        }
+    }
+
+    /// Called when a whole database changes under its clients (SWAPDB), so
+    /// every transaction watching a key in it aborts.
+    pub async fn touch_db(&self, db: usize) {
+        let mut clients = self.tr.write().await;
+        let clients = &mut *clients;
+        for ((d, _), ids) in &clients.watchers {
+            if *d != db {
+                continue;
+            }
+            for id in ids {
+                if let Some(state) = clients.states.get_mut(id) {
+                    state.dirty = true;
+                }
+            }
+        }
+    }
+}
+
+impl Clients {
+    fn unwatch(&mut self, id: u64) {
+        let Some(state) = self.states.get_mut(&id) else {
+            return;
+        };
+        for key in state.watched.drain() {
+            if let Some(ids) = self.watchers.get_mut(&key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.watchers.remove(&key);
+                }
+            }
+        }
+        state.dirty = false;
     }
 }
@@ -0,0 +1,81 @@
+use crate::listpack::Entry;
+use bytes::Bytes;
+
+/// Reads a ziplist, the compact encoding older RDB files use where newer
+/// ones use listpacks:
+///
+/// ```text
+/// total-bytes (u32) | tail-offset (u32) | num-elements (u16) | entry... | 0xFF
+/// entry: previous-entry-length | encoding | data
+/// ```
+pub fn decode(data: &[u8]) -> Result<Vec<Entry>, String> {
+    let truncated = || "ziplist is truncated".to_string();
+    if data.len() < 11 {
+        return Err(truncated());
+    }
+    let total = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    if total != data.len() {
+        return Err("ziplist size doesn't match its header".to_string());
+    }
+
+    let take = |from: usize, n: usize| data.get(from..from + n).ok_or_else(truncated);
+    let mut entries = Vec::new();
+    let mut pos = 10;
+    loop {
+        let byte = *data.get(pos).ok_or_else(truncated)?;
+        if byte == 0xFF {
+            break;
+        }
+        // The previous entry's length takes 1 byte, or 0xFE and 4 more
+        pos += if byte == 0xFE { 5 } else { 1 };
+
+        let enc = *data.get(pos).ok_or_else(truncated)?;
+        let entry = match enc >> 6 {
+            0b00 => {
+                let len = (enc & 0x3F) as usize;
+                let s = take(pos + 1, len)?;
+                pos += 1 + len;
+                Entry::Str(Bytes::copy_from_slice(s))
+            }
+            0b01 => {
+                let low = take(pos + 1, 1)?[0];
+                let len = (((enc & 0x3F) as usize) << 8) | low as usize;
+                let s = take(pos + 2, len)?;
+                pos += 2 + len;
+                Entry::Str(Bytes::copy_from_slice(s))
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(take(pos + 1, 4)?.try_into().unwrap()) as usize;
+                let s = take(pos + 5, len)?;
+                pos += 5 + len;
+                Entry::Str(Bytes::copy_from_slice(s))
+            }
+            _ => {
+                let (value, size) = match enc {
+                    0xC0 => (
+                        i16::from_le_bytes(take(pos + 1, 2)?.try_into().unwrap()) as i64,
+                        2,
+                    ),
+                    0xD0 => (
+                        i32::from_le_bytes(take(pos + 1, 4)?.try_into().unwrap()) as i64,
+                        4,
+                    ),
+                    0xE0 => (i64::from_le_bytes(take(pos + 1, 8)?.try_into().unwrap()), 8),
+                    0xF0 => {
+                        let b = take(pos + 1, 3)?;
+                        // Sign-extend from the top byte
+                        ((i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64, 3)
+                    }
+                    0xFE => (take(pos + 1, 1)?[0] as i8 as i64, 1),
+                    // 1111xxxx holds 0 to 12 as xxxx - 1
+                    0xF1..=0xFD => (((enc & 0x0F) - 1) as i64, 0),
+                    _ => return Err(format!("invalid ziplist encoding 0x{:02X}", enc)),
+                };
+                pos += 1 + size;
+                Entry::Int(value)
+            }
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,153 @@
+use crate::cow::{Cow, Items};
+use crate::rdb::RdbWriter;
+use crate::resp::RedisValueRef;
+use bytes::Bytes;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+type ZSetMap = im::HashMap<Bytes, Cow<ZSet>>;
+
+// Members by name for lookups, and by (score, member) for ranges
+#[derive(Clone, Default)]
+struct ZSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: Vec<(f64, Bytes)>,
+}
+
+impl Items for ZSet {
+    fn items(&self) -> usize {
+        self.scores.len()
+    }
+}
+
+impl ZSet {
+    fn from_members(members: Vec<(Bytes, f64)>) -> Self {
+        let scores: HashMap<_, _> = members.into_iter().collect();
+        let mut ordered: Vec<_> = scores
+            .iter()
+            .map(|(member, score)| (*score, member.clone()))
+            .collect();
+        ordered.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        ZSet { scores, ordered }
+    }
+}
+
+pub struct SortedSet {
+    zsets: RwLock<ZSetMap>,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self {
+            zsets: RwLock::new(im::HashMap::new()),
+        }
+    }
+
+    /// Members ranked `start` to `end` by score, counting from the end for
+    /// negative indices, each followed by its score with `withscores`.
+    pub async fn zrange(
+        &self,
+        key: &Bytes,
+        start: isize,
+        end: isize,
+        withscores: bool,
+    ) -> Vec<RedisValueRef> {
+        let zsets = self.zsets.read().await;
+        let Some(zset) = zsets.get(key) else {
+            return Vec::new();
+        };
+
+        let len = zset.ordered.len() as isize;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let end = if end < 0 { len + end } else { end.min(len - 1) };
+        if start > end || start >= len {
+            return Vec::new();
+        }
+
+        let mut res = Vec::new();
+        for (score, member) in &zset.ordered[start as usize..=end as usize] {
+            res.push(RedisValueRef::BulkString(member.clone()));
+            if withscores {
+                res.push(RedisValueRef::BulkString(Bytes::from(score.to_string())));
+            }
+        }
+        res
+    }
+
+    pub async fn zscore(&self, key: &Bytes, member: &Bytes) -> Option<Bytes> {
+        let zsets = self.zsets.read().await;
+        let score = zsets.get(key)?.scores.get(member)?;
+        Some(Bytes::from(score.to_string()))
+    }
+
+    pub async fn zcard(&self, key: &Bytes) -> i64 {
+        let zsets = self.zsets.read().await;
+        zsets.get(key).map_or(0, |zset| zset.scores.len() as i64)
+    }
+
+    pub async fn contains(&self, key: &Bytes) -> bool {
+        let zsets = self.zsets.read().await;
+        zsets.contains_key(key)
+    }
+
+    /// How many keys hold a value of this type
+    pub async fn key_count(&self) -> usize {
+        self.zsets.read().await.len()
+    }
+
+    /// Moves `key`'s sorted set to `to`. Returns false if there is none here.
+    pub async fn move_key(&self, key: &Bytes, to: &SortedSet) -> bool {
+        let mut zsets = self.zsets.write().await;
+        if !zsets.contains_key(key) {
+            return false;
+        }
+        let Some(value) = zsets.remove(key) else {
+            return false;
+        };
+        to.zsets.write().await.insert(key.clone(), value);
+        true
+    }
+
+    /// Load a sorted set read from an RDB file
+    pub async fn load(&self, key: Bytes, members: Vec<(Bytes, f64)>) {
+        let mut zsets = self.zsets.write().await;
+        zsets.insert(key, Cow::new(ZSet::from_members(members)));
+    }
+
+    /// The sorted sets as they are now; later writes don't change it.
+    pub async fn snapshot(&self) -> SortedSetSnapshot {
+        SortedSetSnapshot(self.zsets.read().await.clone())
+    }
+}
+
+/// Sorted sets captured by `SortedSet::snapshot`.
+pub struct SortedSetSnapshot(im::HashMap<Bytes, Cow<ZSet>>);
+
+impl SortedSetSnapshot {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn save(&self, writer: &mut RdbWriter) {
+        for (key, zset) in self.0.iter() {
+            writer.zset(
+                key,
+                zset.ordered.iter().map(|(score, member)| (member, *score)),
+            );
+        }
+    }
+}
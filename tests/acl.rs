@@ -0,0 +1,350 @@
+use bytes::Bytes;
+use redis::acl;
+use redis::client::Client;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn error(s: &str) -> RedisValueRef {
+    RedisValueRef::Error(Bytes::from(s.to_string()))
+}
+
+// A connection logged in as `user`, given `rules` first
+async fn login_as(redis: &Redis, user: &str, rules: &[&str]) -> Client {
+    let rules: Vec<Bytes> = rules.iter().map(|r| Bytes::from(r.to_string())).collect();
+    redis
+        .acl
+        .setuser(Bytes::from(user.to_string()), &rules)
+        .await;
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from(user.to_string()), true).await;
+    client
+}
+
+async fn setuser(redis: &Redis, user: &str, rules: &[&str]) -> RedisValueRef {
+    let rules: Vec<Bytes> = rules.iter().map(|r| Bytes::from(r.to_string())).collect();
+    redis
+        .acl
+        .setuser(Bytes::from(user.to_string()), &rules)
+        .await
+}
+
+// The user's ACL LIST line
+async fn describe(redis: &Redis, user: &str) -> String {
+    let RedisValueRef::Array(lines) = redis.acl.list().await else {
+        panic!("ACL LIST didn't return an array");
+    };
+    let prefix = format!("user {} ", user);
+    lines
+        .into_iter()
+        .find_map(|line| match line {
+            RedisValueRef::BulkString(line) => {
+                let line = String::from_utf8_lossy(&line).to_string();
+                line.strip_prefix(&prefix).map(str::to_string)
+            }
+            _ => None,
+        })
+        .unwrap_or_else(|| panic!("no user {}", user))
+}
+
+fn request<'a>(name: &'a str, keys: &[&str], write: bool) -> acl::Request<'a> {
+    acl::Request {
+        name,
+        keys: keys.iter().map(|k| Bytes::from(k.to_string())).collect(),
+        write,
+        channels: Vec::new(),
+        patterns: Vec::new(),
+    }
+}
+
+fn channels<'a>(name: &'a str, channels: &[&str], patterns: &[&str]) -> acl::Request<'a> {
+    let bytes = |items: &[&str]| items.iter().map(|c| Bytes::from(c.to_string())).collect();
+    acl::Request {
+        name,
+        keys: Vec::new(),
+        write: false,
+        channels: bytes(channels),
+        patterns: bytes(patterns),
+    }
+}
+
+async fn login(redis: &Redis, client: &Client, user: Option<&str>, password: &str) -> bool {
+    let user = user.map(|u| Bytes::from(u.to_string()));
+    acl::login(redis, client, user, Bytes::from(password.to_string()))
+        .await
+        .is_ok()
+}
+
+#[tokio::test]
+async fn new_users_start_off_with_no_permissions() {
+    let redis = Redis::new();
+    assert_eq!(setuser(&redis, "alice", &[]).await, ok());
+    assert_eq!(describe(&redis, "alice").await, "off resetchannels -@all");
+}
+
+#[tokio::test]
+async fn on_off_and_passwords_control_login() {
+    let redis = Redis::new();
+    let client = login_as(&redis, "default", &[]).await;
+    setuser(&redis, "alice", &["on", ">secret", ">other"]).await;
+    assert!(login(&redis, &client, Some("alice"), "secret").await);
+    assert!(login(&redis, &client, Some("alice"), "other").await);
+    assert!(!login(&redis, &client, Some("alice"), "wrong").await);
+    assert_eq!(client.user().await, Bytes::from("alice"));
+
+    // <pass removes one password; removing one that isn't there fails
+    setuser(&redis, "alice", &["<other"]).await;
+    assert!(!login(&redis, &client, Some("alice"), "other").await);
+    assert_eq!(
+        setuser(&redis, "alice", &["<other"]).await,
+        error("ERR Error in ACL SETUSER modifier '<other': The password you are trying to remove from the user does not exist")
+    );
+
+    setuser(&redis, "alice", &["off"]).await;
+    assert!(!login(&redis, &client, Some("alice"), "secret").await);
+    setuser(&redis, "alice", &["on", "nopass"]).await;
+    assert!(login(&redis, &client, Some("alice"), "anything").await);
+    assert!(!login(&redis, &client, Some("nobody"), "secret").await);
+}
+
+#[tokio::test]
+async fn requirepass_sets_the_default_users_password() {
+    let redis = Redis::new();
+    let client = login_as(&redis, "default", &[]).await;
+    assert!(redis.acl.default_nopass().await);
+    assert_eq!(
+        acl::login(&redis, &client, None, Bytes::from("pw")).await,
+        Err(error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"))
+    );
+
+    redis.acl.set_requirepass("pw").await;
+    assert!(!redis.acl.default_nopass().await);
+    assert!(login(&redis, &client, None, "pw").await);
+    assert_eq!(
+        acl::login(&redis, &client, None, Bytes::from("bad")).await,
+        Err(error(
+            "WRONGPASS invalid username-password pair or user is disabled."
+        ))
+    );
+
+    redis.acl.set_requirepass("").await;
+    assert!(redis.acl.default_nopass().await);
+}
+
+#[tokio::test]
+async fn command_rules_apply_in_order() {
+    let redis = Redis::new();
+    let client = login_as(
+        &redis,
+        "bob",
+        &["on", "nopass", "~*", "+@string", "-set", "+lpush"],
+    )
+    .await;
+    assert_eq!(
+        describe(&redis, "bob").await,
+        "on nopass ~* resetchannels -@all +@string -set +lpush"
+    );
+
+    assert!(
+        acl::enforce(&redis, &client, request("get", &["k"], false), "toplevel")
+            .await
+            .is_ok()
+    );
+    assert!(
+        acl::enforce(&redis, &client, request("lpush", &["k"], true), "toplevel")
+            .await
+            .is_ok()
+    );
+    assert_eq!(
+        acl::enforce(&redis, &client, request("set", &["k"], true), "toplevel").await,
+        Err(error(
+            "NOPERM User bob has no permissions to run the 'set' command"
+        ))
+    );
+    assert_eq!(
+        acl::enforce(&redis, &client, request("rpush", &["k"], true), "toplevel").await,
+        Err(error(
+            "NOPERM User bob has no permissions to run the 'rpush' command"
+        ))
+    );
+
+    // Unknown names and bad rules are rejected, leaving the user as it was
+    assert_eq!(
+        setuser(&redis, "bob", &["+nosuchcommand"]).await,
+        error("ERR Error in ACL SETUSER modifier '+nosuchcommand': Unknown command or category name in ACL")
+    );
+    assert_eq!(
+        setuser(&redis, "bob", &["-@all", "bogus"]).await,
+        error("ERR Error in ACL SETUSER modifier 'bogus': Syntax error")
+    );
+    assert!(
+        acl::enforce(&redis, &client, request("get", &["k"], false), "toplevel")
+            .await
+            .is_ok()
+    );
+}
+
+#[tokio::test]
+async fn key_patterns_limit_reads_and_writes() {
+    let redis = Redis::new();
+    let client = login_as(
+        &redis,
+        "carol",
+        &["on", "nopass", "+@all", "~pub:*", "%R~ro:*", "%W~wo:*"],
+    )
+    .await;
+    assert_eq!(
+        describe(&redis, "carol").await,
+        "on nopass ~pub:* %R~ro:* %W~wo:* resetchannels +@all"
+    );
+
+    let check = |name, key, write| {
+        let redis = &redis;
+        let client = &client;
+        async move { acl::enforce(redis, client, request(name, &[key], write), "toplevel").await }
+    };
+    assert!(check("set", "pub:1", true).await.is_ok());
+    assert!(check("get", "ro:1", false).await.is_ok());
+    assert!(check("set", "wo:1", true).await.is_ok());
+    let denied = Err(error("NOPERM No permissions to access a key"));
+    assert_eq!(check("get", "secret", false).await, denied);
+    assert_eq!(check("set", "ro:1", true).await, denied);
+    assert_eq!(check("get", "wo:1", false).await, denied);
+
+    // resetkeys drops them all, allkeys grants everything
+    setuser(&redis, "carol", &["resetkeys"]).await;
+    assert_eq!(check("get", "pub:1", false).await, denied);
+    setuser(&redis, "carol", &["allkeys"]).await;
+    assert!(check("set", "secret", true).await.is_ok());
+}
+
+#[tokio::test]
+async fn channel_patterns_limit_pubsub() {
+    let redis = Redis::new();
+    let client = login_as(&redis, "dave", &["on", "nopass", "+@all", "&news.*"]).await;
+    assert_eq!(describe(&redis, "dave").await, "on nopass &news.* +@all");
+
+    let denied = Err(error("NOPERM No permissions to access a channel"));
+    assert!(acl::enforce(
+        &redis,
+        &client,
+        channels("subscribe", &["news.sport"], &[]),
+        "toplevel"
+    )
+    .await
+    .is_ok());
+    assert_eq!(
+        acl::enforce(
+            &redis,
+            &client,
+            channels("publish", &["weather"], &[]),
+            "toplevel"
+        )
+        .await,
+        denied
+    );
+    // Patterns must be granted as written
+    assert!(acl::enforce(
+        &redis,
+        &client,
+        channels("psubscribe", &[], &["news.*"]),
+        "toplevel"
+    )
+    .await
+    .is_ok());
+    assert_eq!(
+        acl::enforce(
+            &redis,
+            &client,
+            channels("psubscribe", &[], &["news.s*"]),
+            "toplevel"
+        )
+        .await,
+        denied
+    );
+}
+
+#[tokio::test]
+async fn reset_returns_a_user_to_nothing() {
+    let redis = Redis::new();
+    let client = login_as(&redis, "erin", &["on", ">pw", "~*", "&*", "+@all"]).await;
+    setuser(&redis, "erin", &["reset"]).await;
+    assert_eq!(describe(&redis, "erin").await, "off resetchannels -@all");
+    assert!(!login(&redis, &client, Some("erin"), "pw").await);
+    assert!(
+        acl::enforce(&redis, &client, request("get", &["k"], false), "toplevel")
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn denials_are_logged() {
+    let redis = Redis::new();
+    let client = login_as(&redis, "frank", &["on", "nopass", "+get", "~pub:*"]).await;
+    for _ in 0..2 {
+        let _ = acl::enforce(
+            &redis,
+            &client,
+            request("get", &["secret"], false),
+            "toplevel",
+        )
+        .await;
+    }
+    let _ = acl::enforce(&redis, &client, request("set", &["pub:1"], true), "multi").await;
+
+    let RedisValueRef::Array(entries) = redis.acl.log(None, 2).await else {
+        panic!("ACL LOG didn't return an array");
+    };
+    assert_eq!(entries.len(), 2);
+    let field = |entry: &RedisValueRef, name: &str| match entry {
+        RedisValueRef::Array(items) => items
+            .chunks(2)
+            .find(|pair| pair[0] == RedisValueRef::BulkString(Bytes::from(name.to_string())))
+            .map(|pair| pair[1].clone())
+            .unwrap(),
+        other => panic!("unexpected entry {:?}", other),
+    };
+    let bulk = |s: &str| RedisValueRef::BulkString(Bytes::from(s.to_string()));
+    // Newest first; repeats of the same denial are counted in one entry
+    assert_eq!(field(&entries[0], "reason"), bulk("command"));
+    assert_eq!(field(&entries[0], "context"), bulk("multi"));
+    assert_eq!(field(&entries[0], "object"), bulk("set"));
+    assert_eq!(field(&entries[1], "reason"), bulk("key"));
+    assert_eq!(field(&entries[1], "object"), bulk("secret"));
+    assert_eq!(field(&entries[1], "count"), RedisValueRef::Int(2));
+}
+
+#[tokio::test]
+async fn psync_needs_the_command_and_every_key_and_channel() {
+    let redis = Redis::new();
+
+    let reader = login_as(&redis, "reader", &["on", "nopass", "+get", "~pub:*"]).await;
+    assert_eq!(
+        acl::enforce_sync(&redis, &reader).await,
+        Err(error(
+            "NOPERM User reader has no permissions to run the 'psync' command"
+        ))
+    );
+
+    let some_keys = login_as(&redis, "some", &["on", "nopass", "+psync", "~pub:*", "&*"]).await;
+    assert_eq!(
+        acl::enforce_sync(&redis, &some_keys).await,
+        Err(error("NOPERM No permissions to access a key"))
+    );
+
+    let no_channels = login_as(&redis, "nochan", &["on", "nopass", "+psync", "~*"]).await;
+    assert_eq!(
+        acl::enforce_sync(&redis, &no_channels).await,
+        Err(error("NOPERM No permissions to access a channel"))
+    );
+
+    let replica = login_as(&redis, "replica", &["on", "nopass", "+psync", "~*", "&*"]).await;
+    assert_eq!(acl::enforce_sync(&redis, &replica).await, Ok(()));
+}
@@ -0,0 +1,173 @@
+use bytes::Bytes;
+use redis::aof;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+// A directory of its own under the system temp dir
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("redisrs-aof-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().to_string()
+}
+
+async fn connect() -> Client {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> RedisValueRef {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis)
+        .await
+        .expect("no reply")
+}
+
+// A server logging to an empty AOF in `dir`
+async fn logging(dir: &str) -> Arc<Redis> {
+    let redis = Arc::new(Redis::new());
+    redis.persistence.set_dir(dir.to_string()).await;
+    redis.aof.set_enabled(true);
+    aof::start(&redis).await.unwrap();
+    redis
+}
+
+// A new server that replayed the AOF in `dir`
+async fn reload(dir: &str) -> Arc<Redis> {
+    let redis = Arc::new(Redis::new());
+    redis.persistence.set_dir(dir.to_string()).await;
+    aof::load(&redis).await.unwrap();
+    redis
+}
+
+// What was logged after the RDB preamble `start` wrote, from the first SELECT
+async fn logged(redis: &Redis, dir: &str) -> String {
+    let data = std::fs::read(redis.aof.path(dir).await).unwrap();
+    let select = b"*2\r\n$6\r\nSELECT";
+    let commands = data
+        .windows(select.len())
+        .position(|w| w == select)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[commands..]).to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishes_are_not_logged() {
+    let dir = temp_dir("publish");
+    let redis = logging(&dir).await;
+    let client = connect().await;
+
+    send(&redis, &client, &["PUBLISH", "news", "hi"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    // Nor inside a transaction, which then has a single write left
+    let script = "redis.call('publish', 'news', 'hi') redis.call('set', 'k', 'w') return 1";
+    send(&redis, &client, &["EVAL", script, "0"]).await;
+
+    assert_eq!(
+        logged(&redis, &dir).await,
+        "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n\
+         *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n\
+         *3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nw\r\n"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn writes_are_replayed_into_their_databases() {
+    let dir = temp_dir("replay");
+    let redis = logging(&dir).await;
+    let client = connect().await;
+    send(&redis, &client, &["SET", "k", "zero"]).await;
+    send(&redis, &client, &["SELECT", "1"]).await;
+    send(&redis, &client, &["SET", "k", "one"]).await;
+    send(&redis, &client, &["RPUSH", "l", "a", "b"]).await;
+    // Reads aren't logged
+    send(&redis, &client, &["GET", "k"]).await;
+
+    assert_eq!(
+        logged(&redis, &dir).await,
+        "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n\
+         *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\nzero\r\n\
+         *2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n\
+         *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\none\r\n\
+         *4\r\n$5\r\nRPUSH\r\n$1\r\nl\r\n$1\r\na\r\n$1\r\nb\r\n"
+    );
+
+    let replayed = reload(&dir).await;
+    let client = connect().await;
+    assert_eq!(send(&replayed, &client, &["GET", "k"]).await, bulk("zero"));
+    send(&replayed, &client, &["SELECT", "1"]).await;
+    assert_eq!(send(&replayed, &client, &["GET", "k"]).await, bulk("one"));
+    assert_eq!(
+        send(&replayed, &client, &["LRANGE", "l", "0", "-1"]).await,
+        RedisValueRef::Array(vec![bulk("a"), bulk("b")])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_rdb_preamble_is_loaded_before_the_commands_after_it() {
+    let dir = temp_dir("preamble");
+    let redis = Arc::new(Redis::new());
+    redis.persistence.set_dir(dir.clone()).await;
+    let client = connect().await;
+    // Already there when logging starts, so it goes in the preamble
+    send(&redis, &client, &["SET", "before", "v"]).await;
+    redis.aof.set_enabled(true);
+    aof::start(&redis).await.unwrap();
+    send(&redis, &client, &["SET", "after", "w"]).await;
+
+    let data = std::fs::read(redis.aof.path(&dir).await).unwrap();
+    assert!(data.starts_with(b"REDIS"));
+
+    let replayed = reload(&dir).await;
+    assert_eq!(
+        send(&replayed, &client, &["GET", "before"]).await,
+        bulk("v")
+    );
+    assert_eq!(send(&replayed, &client, &["GET", "after"]).await, bulk("w"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_unfinished_tail_is_dropped_and_truncated() {
+    let complete = "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n";
+    for (name, tail) in [
+        ("command", "*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1"),
+        (
+            "multi",
+            "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n",
+        ),
+    ] {
+        let dir = temp_dir(&format!("tail-{}", name));
+        let path = std::path::Path::new(&dir).join("appendonly.aof");
+        std::fs::write(&path, format!("{}{}", complete, tail)).unwrap();
+
+        let replayed = reload(&dir).await;
+        let client = connect().await;
+        assert_eq!(send(&replayed, &client, &["GET", "a"]).await, bulk("1"));
+        assert_eq!(
+            send(&replayed, &client, &["GET", "b"]).await,
+            RedisValueRef::NullBulkString,
+            "{}",
+            name
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            complete,
+            "{}",
+            name
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+fn ok() -> Option<RedisValueRef> {
+    Some(RedisValueRef::String(Bytes::from("OK")))
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+async fn connect(redis: &Redis, port: u16) -> Arc<Client> {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+    let laddr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, laddr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+#[tokio::test]
+async fn client_kill_matches_every_filter_given() {
+    let redis = Arc::new(Redis::new());
+    let me = connect(&redis, 50001).await;
+    let a = connect(&redis, 50002).await;
+    let b = connect(&redis, 50003).await;
+    let c = connect(&redis, 50004).await;
+    send(&redis, &c, &["SUBSCRIBE", "news"]).await;
+
+    let id = a.id.to_string();
+    assert_eq!(
+        send(
+            &redis,
+            &me,
+            &["CLIENT", "KILL", "ID", &id, "ADDR", "127.0.0.1:50003"]
+        )
+        .await,
+        Some(RedisValueRef::Int(0))
+    );
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "ID", &id]).await,
+        Some(RedisValueRef::Int(1))
+    );
+    assert!(a.is_killed());
+
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "TYPE", "pubsub"]).await,
+        Some(RedisValueRef::Int(1))
+    );
+    assert!(c.is_killed());
+    assert!(!b.is_killed());
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "TYPE", "bogus"]).await,
+        Some(RedisValueRef::Error(Bytes::from(
+            "ERR Unknown client type 'bogus'"
+        )))
+    );
+
+    // The caller is skipped unless it asks otherwise
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "LADDR", "127.0.0.1:6379"]).await,
+        Some(RedisValueRef::Int(3))
+    );
+    assert!(!me.is_killed());
+    assert_eq!(
+        send(
+            &redis,
+            &me,
+            &["CLIENT", "KILL", "USER", "default", "SKIPME", "no"]
+        )
+        .await,
+        Some(RedisValueRef::Int(4))
+    );
+    assert!(me.is_killed());
+}
+
+#[tokio::test]
+async fn client_kill_by_address_alone() {
+    let redis = Arc::new(Redis::new());
+    let me = connect(&redis, 50001).await;
+    let other = connect(&redis, 50002).await;
+
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "127.0.0.1:50002"]).await,
+        ok()
+    );
+    assert!(other.is_killed());
+    assert_eq!(
+        send(&redis, &me, &["CLIENT", "KILL", "127.0.0.1:1"]).await,
+        Some(RedisValueRef::Error(Bytes::from("ERR No such client")))
+    );
+}
+
+#[tokio::test]
+async fn client_pause_write_holds_only_writes() {
+    let redis = Arc::new(Redis::new());
+    let admin = connect(&redis, 50001).await;
+    let writer = connect(&redis, 50002).await;
+
+    assert_eq!(
+        send(&redis, &admin, &["CLIENT", "PAUSE", "10000", "WRITE"]).await,
+        ok()
+    );
+    assert_eq!(
+        send(&redis, &writer, &["GET", "k"]).await,
+        Some(RedisValueRef::NullBulkString)
+    );
+    let set = {
+        let redis = redis.clone();
+        let writer = writer.clone();
+        tokio::spawn(async move { send(&redis, &writer, &["SET", "k", "v"]).await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!set.is_finished());
+
+    assert_eq!(send(&redis, &admin, &["CLIENT", "UNPAUSE"]).await, ok());
+    assert_eq!(set.await.unwrap(), ok());
+    assert_eq!(send(&redis, &writer, &["GET", "k"]).await, Some(bulk("v")));
+}
+
+#[tokio::test]
+async fn client_pause_all_holds_reads_until_it_ends() {
+    let redis = Arc::new(Redis::new());
+    let admin = connect(&redis, 50001).await;
+    let reader = connect(&redis, 50002).await;
+
+    assert_eq!(
+        send(&redis, &admin, &["CLIENT", "PAUSE", "100"]).await,
+        ok()
+    );
+    let get = {
+        let redis = redis.clone();
+        let reader = reader.clone();
+        tokio::spawn(async move { send(&redis, &reader, &["GET", "k"]).await })
+    };
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert!(!get.is_finished());
+
+    // CLIENT commands still run while paused
+    assert!(matches!(
+        send(&redis, &admin, &["CLIENT", "LIST"]).await,
+        Some(RedisValueRef::BulkString(_))
+    ));
+
+    // Released by the timeout
+    assert_eq!(
+        tokio::time::timeout(Duration::from_secs(5), get)
+            .await
+            .unwrap()
+            .unwrap(),
+        Some(RedisValueRef::NullBulkString)
+    );
+}
+
+#[tokio::test]
+async fn client_reply_off_and_skip_drop_replies() {
+    let redis = Arc::new(Redis::new());
+    let client = connect(&redis, 50001).await;
+
+    assert_eq!(
+        send(&redis, &client, &["CLIENT", "REPLY", "OFF"]).await,
+        None
+    );
+    assert_eq!(send(&redis, &client, &["SET", "k", "v"]).await, None);
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, None);
+    assert_eq!(
+        send(&redis, &client, &["CLIENT", "REPLY", "ON"]).await,
+        ok()
+    );
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, Some(bulk("v")));
+
+    // SKIP drops the next reply only
+    assert_eq!(
+        send(&redis, &client, &["CLIENT", "REPLY", "SKIP"]).await,
+        None
+    );
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, None);
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, Some(bulk("v")));
+}
+
+fn fields(line: &str) -> Vec<(&str, &str)> {
+    line.trim_end()
+        .split(' ')
+        .map(|field| field.split_once('=').unwrap())
+        .collect()
+}
+
+async fn list(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> String {
+    let mut command = vec!["CLIENT", "LIST"];
+    command.extend(args);
+    match send(redis, client, &command).await {
+        Some(RedisValueRef::BulkString(list)) => String::from_utf8(list.to_vec()).unwrap(),
+        other => panic!("CLIENT LIST replied {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn client_list_lines_follow_the_redis_format() {
+    let redis = Arc::new(Redis::new());
+    let client = connect(&redis, 50001).await;
+    let other = connect(&redis, 50002).await;
+    send(&redis, &client, &["CLIENT", "SETNAME", "worker"]).await;
+    send(&redis, &client, &["SELECT", "2"]).await;
+
+    let all = list(&redis, &client, &[]).await;
+    assert_eq!(all.lines().count(), 2);
+    assert!(all.ends_with('\n'));
+
+    let mine = list(&redis, &client, &["ID", &client.id.to_string()]).await;
+    let names: Vec<_> = fields(&mine).iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "id", "addr", "laddr", "fd", "name", "age", "idle", "flags", "db", "sub", "psub",
+            "ssub", "multi", "watch", "qbuf", "oll", "omem", "cmd", "user", "redir", "resp",
+            "lib-name", "lib-ver"
+        ]
+    );
+    let mine = fields(&mine);
+    let value = |name: &str| mine.iter().find(|(n, _)| *n == name).unwrap().1;
+    assert_eq!(value("id"), client.id.to_string());
+    assert_eq!(value("addr"), "127.0.0.1:50001");
+    assert_eq!(value("laddr"), "127.0.0.1:6379");
+    assert_eq!(value("name"), "worker");
+    assert_eq!(value("flags"), "N");
+    assert_eq!(value("db"), "2");
+    assert_eq!(value("multi"), "-1");
+    assert_eq!(value("cmd"), "client|list");
+    assert_eq!(value("user"), "default");
+    assert_eq!(value("redir"), "-1");
+    assert_eq!(value("resp"), "2");
+
+    // CLIENT INFO is the caller's own line
+    let info = match send(&redis, &client, &["CLIENT", "INFO"]).await {
+        Some(RedisValueRef::BulkString(info)) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("CLIENT INFO replied {:?}", other),
+    };
+    assert!(info.starts_with(&format!("id={} ", client.id)), "{}", info);
+    assert!(info.contains(" cmd=client|info "), "{}", info);
+    assert!(info.ends_with('\n'));
+
+    send(&redis, &other, &["MULTI"]).await;
+    let line = list(&redis, &client, &["ID", &other.id.to_string()]).await;
+    assert!(line.contains(" flags=x "), "{}", line);
+    assert!(line.contains(" multi=0 "), "{}", line);
+    send(&redis, &other, &["DISCARD"]).await;
+
+    send(&redis, &other, &["SUBSCRIBE", "news"]).await;
+    let pubsub = list(&redis, &client, &["TYPE", "pubsub"]).await;
+    assert_eq!(pubsub.lines().count(), 1);
+    assert!(pubsub.contains(" flags=P "), "{}", pubsub);
+    assert!(pubsub.contains(" sub=1 "), "{}", pubsub);
+    let normal = list(&redis, &client, &["TYPE", "normal"]).await;
+    assert!(
+        normal.starts_with(&format!("id={} ", client.id)),
+        "{}",
+        normal
+    );
+    assert_eq!(normal.lines().count(), 1);
+}
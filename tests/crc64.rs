@@ -0,0 +1,15 @@
+use redis::crc64;
+
+#[test]
+fn matches_the_jones_check_value() {
+    assert_eq!(crc64::update(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    assert_eq!(crc64::crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+}
+
+#[test]
+fn can_be_computed_in_pieces() {
+    let data = b"REDIS0011\xfa\x09redis-ver\x057.2.0\xff";
+    let (a, b) = data.split_at(7);
+    assert_eq!(crc64::update(crc64::update(0, a), b), crc64::crc64(data));
+    assert_eq!(crc64::crc64(b""), 0);
+}
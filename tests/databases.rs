@@ -0,0 +1,152 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::persistence;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn ok() -> Option<RedisValueRef> {
+    Some(RedisValueRef::String(Bytes::from("OK")))
+}
+
+fn bulk(s: &str) -> Option<RedisValueRef> {
+    Some(RedisValueRef::BulkString(Bytes::from(s.to_string())))
+}
+
+fn error(s: &str) -> Option<RedisValueRef> {
+    Some(RedisValueRef::Error(Bytes::from(s.to_string())))
+}
+
+fn int(i: i64) -> Option<RedisValueRef> {
+    Some(RedisValueRef::Int(i))
+}
+
+async fn connect() -> Client {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+#[tokio::test]
+async fn select_switches_between_separate_keyspaces() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    send(&redis, &client, &["SET", "k", "zero"]).await;
+    assert_eq!(send(&redis, &client, &["SELECT", "1"]).await, ok());
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::NullBulkString)
+    );
+    send(&redis, &client, &["SET", "k", "one"]).await;
+    send(&redis, &client, &["SELECT", "0"]).await;
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, bulk("zero"));
+
+    // Each connection has its own
+    let other = connect().await;
+    send(&redis, &client, &["SELECT", "1"]).await;
+    assert_eq!(send(&redis, &other, &["GET", "k"]).await, bulk("zero"));
+
+    assert_eq!(
+        send(&redis, &client, &["SELECT", "16"]).await,
+        error("ERR DB index is out of range")
+    );
+    assert_eq!(
+        send(&redis, &client, &["SELECT", "-1"]).await,
+        error("ERR DB index is out of range")
+    );
+    assert_eq!(
+        send(&redis, &client, &["SELECT", "one"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+}
+
+#[tokio::test]
+async fn move_hands_a_key_to_another_database() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    send(&redis, &client, &["RPUSH", "l", "a", "b"]).await;
+    assert_eq!(send(&redis, &client, &["MOVE", "l", "1"]).await, int(1));
+    assert_eq!(send(&redis, &client, &["LLEN", "l"]).await, int(0));
+    assert_eq!(
+        send(&redis, &client, &["MOVE", "missing", "1"]).await,
+        int(0)
+    );
+    assert_eq!(
+        send(&redis, &client, &["MOVE", "l", "0"]).await,
+        error("ERR source and destination objects are the same")
+    );
+
+    // Not onto a key the target already has
+    send(&redis, &client, &["SET", "k", "zero"]).await;
+    send(&redis, &client, &["SELECT", "1"]).await;
+    assert_eq!(send(&redis, &client, &["LLEN", "l"]).await, int(2));
+    send(&redis, &client, &["SET", "k", "one"]).await;
+    assert_eq!(send(&redis, &client, &["MOVE", "k", "0"]).await, int(0));
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, bulk("one"));
+    send(&redis, &client, &["SELECT", "0"]).await;
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, bulk("zero"));
+}
+
+#[tokio::test]
+async fn swapdb_swaps_the_data_under_connected_clients() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    send(&redis, &client, &["SET", "k", "zero"]).await;
+    send(&redis, &client, &["SELECT", "1"]).await;
+    send(&redis, &client, &["SET", "k", "one"]).await;
+
+    assert_eq!(send(&redis, &client, &["SWAPDB", "0", "1"]).await, ok());
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, bulk("zero"));
+    let other = connect().await;
+    assert_eq!(send(&redis, &other, &["GET", "k"]).await, bulk("one"));
+
+    assert_eq!(
+        send(&redis, &client, &["SWAPDB", "0", "16"]).await,
+        error("ERR DB index is out of range")
+    );
+    assert_eq!(
+        send(&redis, &client, &["SWAPDB", "x", "1"]).await,
+        error("ERR invalid first DB index")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keys_keep_their_database_through_an_rdb_round_trip() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    send(&redis, &client, &["SET", "k", "zero"]).await;
+    send(&redis, &client, &["SELECT", "5"]).await;
+    send(&redis, &client, &["SET", "k", "five"]).await;
+    send(&redis, &client, &["RPUSH", "l", "a"]).await;
+
+    let data = persistence::snapshot(&redis).await.data;
+    let loaded = Arc::new(Redis::new());
+    loaded.load_rdb(&data).await.unwrap();
+
+    let client = connect().await;
+    assert_eq!(send(&loaded, &client, &["GET", "k"]).await, bulk("zero"));
+    send(&loaded, &client, &["SELECT", "5"]).await;
+    assert_eq!(send(&loaded, &client, &["GET", "k"]).await, bulk("five"));
+    assert_eq!(send(&loaded, &client, &["LLEN", "l"]).await, int(1));
+    for db in [1, 4, 6] {
+        send(&loaded, &client, &["SELECT", &db.to_string()]).await;
+        assert_eq!(
+            send(&loaded, &client, &["GET", "k"]).await,
+            Some(RedisValueRef::NullBulkString)
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use bytes::Bytes;
+use redis::redis::Redis;
+use std::time::{Duration, Instant};
+
+fn px(ms: i64) -> Option<(Bytes, i64)> {
+    Some((Bytes::from("PX"), ms))
+}
+
+#[tokio::test]
+async fn the_expire_cycle_removes_only_keys_that_are_due() {
+    let redis = Redis::new();
+    let db = redis.db(0).await;
+    let key = |k: &str| Bytes::from(k.to_string());
+
+    db.kv.insert_entry(key("due"), key("v"), px(10)).await;
+    db.kv.insert_entry(key("later"), key("v"), px(60_000)).await;
+    db.kv.insert_entry(key("forever"), key("v"), None).await;
+    // Given a TTL, then set again without one
+    db.kv.insert_entry(key("replaced"), key("v"), px(10)).await;
+    db.kv.insert_entry(key("replaced"), key("v"), None).await;
+    // Given a longer TTL after a short one
+    db.kv.insert_entry(key("extended"), key("v"), px(10)).await;
+    db.kv
+        .insert_entry(key("extended"), key("v"), px(60_000))
+        .await;
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    db.kv.expire_cycle().await;
+
+    assert!(!db.kv.contains(&key("due")).await);
+    for k in ["later", "forever", "replaced", "extended"] {
+        assert!(db.kv.contains(&key(k)).await, "{} was removed", k);
+    }
+    assert_eq!(db.kv.counts().await, (4, 2));
+}
+
+#[tokio::test]
+async fn loaded_and_moved_keys_expire_too() {
+    let redis = Redis::new();
+    let from = redis.db(0).await;
+    let to = redis.db(1).await;
+    let key = |k: &str| Bytes::from(k.to_string());
+
+    let soon = Instant::now() + Duration::from_millis(10);
+    to.kv.load(key("loaded"), key("v"), Some(soon)).await;
+    from.kv.insert_entry(key("moved"), key("v"), px(10)).await;
+    assert!(from.kv.move_key(&key("moved"), &to.kv).await);
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    to.kv.expire_cycle().await;
+    assert_eq!(to.kv.counts().await, (0, 0));
+}
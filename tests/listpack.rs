@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use redis::listpack::{self, Entry, Listpack};
+
+// Wraps encoded entries in a listpack header and terminator
+fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+    let body: Vec<u8> = entries.concat();
+    let mut out = ((6 + body.len() + 1) as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    out.extend_from_slice(&body);
+    out.push(0xFF);
+    out
+}
+
+fn str_entry(s: &str) -> Entry {
+    Entry::Str(Bytes::from(s.to_string()))
+}
+
+#[test]
+fn decodes_every_integer_width() {
+    let data = listpack(&[
+        // 7-bit unsigned
+        &[0x05, 0x01],
+        // 13-bit signed
+        &[0xC3, 0xE8, 0x02],
+        &[0xDF, 0xFF, 0x02],
+        // 16-bit
+        &[0xF1, 0xE0, 0xB1, 0x03],
+        // 24-bit
+        &[0xF2, 0xC0, 0xB4, 0xB3, 0x04],
+        // 32-bit
+        &[0xF3, 0x00, 0xE1, 0xF5, 0x05, 0x05],
+        // 64-bit
+        &[0xF4, 0, 0, 0, 0, 0, 0, 0, 0x80, 0x09],
+    ]);
+    assert_eq!(
+        listpack::decode(&data).unwrap(),
+        vec![
+            Entry::Int(5),
+            Entry::Int(1000),
+            Entry::Int(-1),
+            Entry::Int(-20000),
+            Entry::Int(-5_000_000),
+            Entry::Int(100_000_000),
+            Entry::Int(i64::MIN),
+        ]
+    );
+}
+
+#[test]
+fn encodes_integers_in_the_smallest_width() {
+    let mut lp = Listpack::new();
+    for i in [5, 1000, -1, -20000, -5_000_000, 100_000_000, i64::MIN] {
+        lp.push_int(i);
+    }
+    let expected = listpack(&[
+        &[0x05, 0x01],
+        &[0xC3, 0xE8, 0x02],
+        &[0xDF, 0xFF, 0x02],
+        &[0xF1, 0xE0, 0xB1, 0x03],
+        &[0xF2, 0xC0, 0xB4, 0xB3, 0x04],
+        &[0xF3, 0x00, 0xE1, 0xF5, 0x05, 0x05],
+        &[0xF4, 0, 0, 0, 0, 0, 0, 0, 0x80, 0x09],
+    ]);
+    assert_eq!(lp.finish(), expected);
+}
+
+#[test]
+fn decodes_every_string_length_width() {
+    let medium = "m".repeat(100);
+    let long = "l".repeat(5000);
+
+    let mut medium_entry = vec![0xE0, 100];
+    medium_entry.extend_from_slice(medium.as_bytes());
+    medium_entry.push(102);
+
+    let mut long_entry = vec![0xF0];
+    long_entry.extend_from_slice(&5000u32.to_le_bytes());
+    long_entry.extend_from_slice(long.as_bytes());
+    // 5005 bytes, as a 2-byte backlen
+    long_entry.extend_from_slice(&[39, 141]);
+
+    let data = listpack(&[&[0x82, b'a', b'b', 0x03], &medium_entry, &long_entry]);
+    assert_eq!(
+        listpack::decode(&data).unwrap(),
+        vec![str_entry("ab"), str_entry(&medium), str_entry(&long)]
+    );
+
+    let mut lp = Listpack::new();
+    for s in ["ab", &medium, &long] {
+        lp.push_str(s.as_bytes());
+    }
+    assert_eq!(lp.finish(), data);
+}
+
+#[test]
+fn stores_canonical_numbers_as_integers() {
+    let mut lp = Listpack::new();
+    for s in ["12", "-7", "007", "1.5", "+3"] {
+        lp.push_str(s.as_bytes());
+    }
+    let entries = listpack::decode(&lp.finish()).unwrap();
+    assert_eq!(
+        entries,
+        vec![
+            Entry::Int(12),
+            Entry::Int(-7),
+            str_entry("007"),
+            str_entry("1.5"),
+            str_entry("+3"),
+        ]
+    );
+}
+
+#[test]
+fn rejects_malformed_listpacks() {
+    // Size in the header doesn't match
+    let mut data = listpack(&[&[0x05, 0x01]]);
+    data[0] += 1;
+    assert!(listpack::decode(&data).is_err());
+    // A string running past the end
+    assert!(listpack::decode(&listpack(&[&[0x85, b'a']])).is_err());
+    // No terminator
+    let data = listpack(&[&[0x05, 0x01]]);
+    let mut cut = data[..data.len() - 1].to_vec();
+    cut[0] -= 1;
+    assert!(listpack::decode(&cut).is_err());
+}
@@ -0,0 +1,127 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+// A registered connection, and what is pushed to it
+async fn connect(redis: &Redis) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, addr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    (client, rx)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+#[tokio::test]
+async fn a_push_notifies_once_per_command() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    let (subscriber, mut pushes) = connect(&redis).await;
+    send(
+        &redis,
+        &client,
+        &["CONFIG", "SET", "notify-keyspace-events", "El"],
+    )
+    .await;
+    send(&redis, &subscriber, &["SUBSCRIBE", "__keyevent@0__:rpush"]).await;
+    pushes.try_recv().unwrap();
+
+    assert_eq!(
+        send(&redis, &client, &["RPUSH", "l", "a", "b", "c"]).await,
+        Some(RedisValueRef::Int(3))
+    );
+    assert_eq!(
+        pushes.try_recv().unwrap(),
+        RedisValueRef::Array(vec![
+            bulk("message"),
+            bulk("__keyevent@0__:rpush"),
+            bulk("l")
+        ])
+    );
+    assert!(pushes.try_recv().is_err());
+
+    assert_eq!(
+        send(&redis, &client, &["LPUSH", "l", "x", "y"]).await,
+        Some(RedisValueRef::Int(5))
+    );
+    assert_eq!(
+        send(&redis, &client, &["LRANGE", "l", "0", "-1"]).await,
+        Some(RedisValueRef::Array(
+            ["y", "x", "a", "b", "c"].into_iter().map(bulk).collect()
+        ))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_push_of_several_elements_serves_as_many_blocked_clients() {
+    let redis = Arc::new(Redis::new());
+    let waiters: Vec<_> = (0..2)
+        .map(|_| {
+            let redis = redis.clone();
+            tokio::spawn(async move {
+                let (client, _) = connect(&redis).await;
+                send(&redis, &client, &["BLPOP", "l", "5"]).await
+            })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (client, _) = connect(&redis).await;
+    send(&redis, &client, &["RPUSH", "l", "a", "b"]).await;
+    let mut popped = Vec::new();
+    for waiter in waiters {
+        let reply = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("still blocked")
+            .unwrap();
+        popped.push(reply);
+    }
+    popped.sort_by_key(|reply| format!("{:?}", reply));
+    assert_eq!(
+        popped,
+        vec![
+            Some(RedisValueRef::Array(vec![bulk("l"), bulk("a")])),
+            Some(RedisValueRef::Array(vec![bulk("l"), bulk("b")])),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn bad_numbers_are_errors() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    for args in [
+        &["LRANGE", "l", "a", "-1"][..],
+        &["LPOP", "l", "-1"],
+        &["BLPOP", "l", "soon"],
+        &["BLPOP", "l", "-1"],
+        &["SET", "k", "v", "PX", "x"],
+        &["XREAD", "block", "x", "streams", "s", "0"],
+        &["XREAD", "block", "5"],
+    ] {
+        let reply = send(&redis, &client, args).await;
+        assert!(
+            matches!(reply, Some(RedisValueRef::Error(_))),
+            "{:?} replied {:?}",
+            args,
+            reply
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn loading_error() -> Option<RedisValueRef> {
+    Some(RedisValueRef::Error(Bytes::from(
+        "LOADING Redis is loading the dataset in memory",
+    )))
+}
+
+async fn connect() -> Client {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+async fn info_persistence(redis: &Arc<Redis>, client: &Client) -> String {
+    match send(redis, client, &["INFO", "persistence"]).await {
+        Some(RedisValueRef::BulkString(info)) => String::from_utf8_lossy(&info).to_string(),
+        other => panic!("INFO replied {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn the_dataset_is_off_limits_while_loading() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    redis.loading.start().await;
+
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, loading_error());
+    assert_eq!(
+        send(&redis, &client, &["SET", "k", "v"]).await,
+        loading_error()
+    );
+    assert_eq!(send(&redis, &client, &["LLEN", "l"]).await, loading_error());
+
+    // What doesn't touch it still works
+    for args in [
+        &["INFO"][..],
+        &["CONFIG", "GET", "dir"],
+        &["SELECT", "1"],
+        &["CLIENT", "ID"],
+        &["PUBLISH", "news", "hi"],
+        &["LASTSAVE"],
+    ] {
+        let reply = send(&redis, &client, args).await;
+        assert!(
+            !matches!(reply, Some(RedisValueRef::Error(_))),
+            "{:?} replied {:?}",
+            args,
+            reply
+        );
+    }
+
+    // As do the writes being loaded
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let loader = Client::new_master(addr, addr, tx);
+    send(&redis, &loader, &["SET", "k", "loaded"]).await;
+
+    redis.loading.finish().await;
+    send(&redis, &client, &["SELECT", "0"]).await;
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::BulkString(Bytes::from("loaded")))
+    );
+}
+
+#[tokio::test]
+async fn info_reports_loading_progress() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let info = info_persistence(&redis, &client).await;
+    assert!(
+        info.lines().any(|l| l.trim_end() == "loading:0"),
+        "{}",
+        info
+    );
+    assert!(!info.contains("loading_total_bytes"), "{}", info);
+
+    redis.loading.start().await;
+    redis.loading.set_total(1000);
+    redis.loading.set_loaded(250);
+    let info = info_persistence(&redis, &client).await;
+    for field in [
+        "loading:1",
+        "loading_total_bytes:1000",
+        "loading_loaded_bytes:250",
+        "loading_loaded_perc:25.00",
+    ] {
+        assert!(
+            info.lines().any(|l| l.trim_end() == field),
+            "no {:?} in {}",
+            field,
+            info
+        );
+    }
+    assert!(info.contains("loading_eta_seconds:"), "{}", info);
+    let start_time: u64 = info
+        .lines()
+        .find_map(|l| l.trim_end().strip_prefix("loading_start_time:"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(start_time > 0);
+
+    redis.loading.finish().await;
+    let info = info_persistence(&redis, &client).await;
+    assert!(
+        info.lines().any(|l| l.trim_end() == "loading:0"),
+        "{}",
+        info
+    );
+}
@@ -0,0 +1,65 @@
+use redis::lzf;
+
+#[test]
+fn compressed_data_decompresses_to_the_input() {
+    let inputs: [Vec<u8>; 4] = [
+        b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+        b"hello hello hello hello hello world world world".to_vec(),
+        // Long enough for matches past MAX_MATCH and several literal runs
+        (0..10_000).map(|i| (i % 251) as u8).collect(),
+        (0..5_000)
+            .flat_map(|i: u32| i.to_string().into_bytes())
+            .collect(),
+    ];
+    for input in inputs {
+        let compressed = lzf::compress(&input).expect("input should compress");
+        assert!(compressed.len() < input.len());
+        assert_eq!(lzf::decompress(&compressed, input.len()).unwrap(), input);
+    }
+}
+
+#[test]
+fn incompressible_data_is_left_alone() {
+    assert_eq!(lzf::compress(b"abcdefghijklmnopqrstuvwxyz"), None);
+    assert_eq!(lzf::compress(b""), None);
+}
+
+#[test]
+fn decompresses_what_redis_wrote() {
+    // redis-server's lzf_compress output for 30 a's: a 2-byte literal run,
+    // a 26-byte back reference to the byte before, and a 2-byte literal run
+    let compressed = [0x01, b'a', b'a', 0xE0, 0x11, 0x00, 0x01, b'a', b'a'];
+    assert_eq!(lzf::decompress(&compressed, 30).unwrap(), vec![b'a'; 30]);
+}
+
+#[test]
+fn rejects_a_back_reference_before_the_start() {
+    // A literal "a", then 3 bytes copied from 2 bytes behind it
+    let compressed = [0x00, b'a', 0x20, 0x01];
+    assert!(lzf::decompress(&compressed, 4).is_err());
+}
+
+#[test]
+fn rejects_output_longer_than_expected() {
+    let literal = [0x03, b'a', b'b', b'c', b'd'];
+    assert!(lzf::decompress(&literal, 3).is_err());
+    let reference = [0x00, b'a', 0xE0, 0x11, 0x00];
+    assert!(lzf::decompress(&reference, 10).is_err());
+}
+
+#[test]
+fn rejects_truncated_or_short_input() {
+    // A literal run of 4 with only 2 bytes after it
+    assert!(lzf::decompress(&[0x03, b'a', b'b'], 4).is_err());
+    // A back reference missing its offset byte
+    assert!(lzf::decompress(&[0x00, b'a', 0x20], 4).is_err());
+    // Valid, but short of the expected length
+    assert!(lzf::decompress(&[0x01, b'a', b'b'], 3).is_err());
+}
+
+#[test]
+fn rejects_a_length_the_input_cant_expand_to() {
+    // Two bytes expand to at most 88 each
+    assert!(lzf::decompress(&[0x00, b'a'], usize::MAX).is_err());
+    assert!(lzf::decompress(&[0x00, b'a'], 177).is_err());
+}
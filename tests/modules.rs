@@ -0,0 +1,190 @@
+use bytes::Bytes;
+use futures::FutureExt;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::module::{command_handler, CommandSpec, DataType, Module, Registry};
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Counters kept by (database, key)
+#[derive(Default)]
+struct Counters(Mutex<HashMap<(usize, Vec<u8>), i64>>);
+
+impl DataType for Counters {
+    fn name(&self) -> &str {
+        "counter"
+    }
+
+    fn contains(&self, db: usize, key: &[u8]) -> bool {
+        self.0.lock().unwrap().contains_key(&(db, key.to_vec()))
+    }
+
+    fn move_key(&self, from: usize, to: usize, key: &[u8]) -> bool {
+        let mut counters = self.0.lock().unwrap();
+        match counters.remove(&(from, key.to_vec())) {
+            Some(n) => {
+                counters.insert((to, key.to_vec()), n);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn swap_db(&self, a: usize, b: usize) {
+        let mut counters = self.0.lock().unwrap();
+        *counters = counters
+            .drain()
+            .map(|((db, key), n)| {
+                let db = if db == a {
+                    b
+                } else if db == b {
+                    a
+                } else {
+                    db
+                };
+                ((db, key), n)
+            })
+            .collect();
+    }
+
+    fn flush(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    fn aux_save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn aux_load(&self, _data: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// CNT.INCR key
+struct CounterModule(Arc<Counters>);
+
+impl Module for CounterModule {
+    fn name(&self) -> &str {
+        "counters"
+    }
+
+    fn load(&self, registry: &mut Registry) -> Result<(), String> {
+        let counters = self.0.clone();
+        registry.create_command(CommandSpec {
+            name: "cnt.incr".to_string(),
+            arity: 2,
+            first_key: 1,
+            last_key: 1,
+            key_step: 1,
+            flags: vec!["write".to_string()],
+            handler: command_handler(move |ctx, args| {
+                let counters = counters.clone();
+                async move {
+                    let mut counters = counters.0.lock().unwrap();
+                    let n = counters
+                        .entry((ctx.client.db(), args[0].to_vec()))
+                        .or_default();
+                    *n += 1;
+                    RedisValueRef::Int(*n)
+                }
+                .boxed()
+            }),
+        })?;
+        registry.create_data_type(self.0.clone())
+    }
+}
+
+async fn server() -> (Arc<Redis>, Client) {
+    let redis = Arc::new(Redis::new());
+    let module = CounterModule(Arc::new(Counters::default()));
+    redis.load_module(&module).await.unwrap();
+
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    (redis, client)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> RedisValueRef {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis)
+        .await
+        .expect("no reply")
+}
+
+async fn type_of(redis: &Arc<Redis>, client: &Client, key: &str) -> RedisValueRef {
+    send(redis, client, &["TYPE", key]).await
+}
+
+fn status(s: &str) -> RedisValueRef {
+    RedisValueRef::String(Bytes::from(s.to_string()))
+}
+
+#[tokio::test]
+async fn module_keys_belong_to_a_database() {
+    let (redis, client) = server().await;
+    assert_eq!(
+        send(&redis, &client, &["CNT.INCR", "c"]).await,
+        RedisValueRef::Int(1)
+    );
+    assert_eq!(type_of(&redis, &client, "c").await, status("counter"));
+
+    send(&redis, &client, &["SELECT", "1"]).await;
+    assert_eq!(type_of(&redis, &client, "c").await, status("none"));
+    assert_eq!(
+        send(&redis, &client, &["CNT.INCR", "c"]).await,
+        RedisValueRef::Int(1)
+    );
+}
+
+#[tokio::test]
+async fn module_commands_reject_keys_of_other_types() {
+    let (redis, client) = server().await;
+    send(&redis, &client, &["SET", "s", "v"]).await;
+    assert_eq!(
+        send(&redis, &client, &["CNT.INCR", "s"]).await,
+        RedisValueRef::Error(Bytes::from(
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        ))
+    );
+    assert_eq!(type_of(&redis, &client, "s").await, status("string"));
+}
+
+#[tokio::test]
+async fn move_and_swapdb_take_module_keys_along() {
+    let (redis, client) = server().await;
+    send(&redis, &client, &["CNT.INCR", "c"]).await;
+
+    assert_eq!(
+        send(&redis, &client, &["MOVE", "c", "1"]).await,
+        RedisValueRef::Int(1)
+    );
+    assert_eq!(type_of(&redis, &client, "c").await, status("none"));
+    send(&redis, &client, &["SELECT", "1"]).await;
+    assert_eq!(type_of(&redis, &client, "c").await, status("counter"));
+
+    // Not onto a key the target already holds, of any type
+    send(&redis, &client, &["SELECT", "0"]).await;
+    send(&redis, &client, &["SET", "c", "v"]).await;
+    assert_eq!(
+        send(&redis, &client, &["MOVE", "c", "1"]).await,
+        RedisValueRef::Int(0)
+    );
+
+    assert_eq!(
+        send(&redis, &client, &["SWAPDB", "0", "1"]).await,
+        status("OK")
+    );
+    assert_eq!(type_of(&redis, &client, "c").await, status("counter"));
+    assert_eq!(
+        send(&redis, &client, &["CNT.INCR", "c"]).await,
+        RedisValueRef::Int(2)
+    );
+}
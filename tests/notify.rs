@@ -0,0 +1,192 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+// A registered connection, and what is pushed to it
+async fn connect(redis: &Redis) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, addr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    (client, rx)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+// The (channel, message) pairs published to a subscriber so far
+fn messages(pushes: &mut mpsc::UnboundedReceiver<RedisValueRef>) -> Vec<(String, String)> {
+    let text = |v: &RedisValueRef| match v {
+        RedisValueRef::BulkString(s) => String::from_utf8_lossy(s).to_string(),
+        other => panic!("not a bulk string: {:?}", other),
+    };
+    let mut all = Vec::new();
+    while let Ok(push) = pushes.try_recv() {
+        if let RedisValueRef::Array(items) = push {
+            if items[0] == bulk("message") {
+                all.push((text(&items[1]), text(&items[2])));
+            }
+        }
+    }
+    all
+}
+
+fn pair(channel: &str, message: &str) -> (String, String) {
+    (channel.to_string(), message.to_string())
+}
+
+#[tokio::test]
+async fn flags_are_parsed_and_reported_like_redis() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    for (set, reported) in [
+        ("KEA", "AKE"),
+        ("Elg", "glE"),
+        ("AKEm", "AmKE"),
+        ("Kg$lshzxetd", "AK"),
+        // Without K or E no event could be delivered
+        ("gl", ""),
+        ("", ""),
+    ] {
+        assert_eq!(
+            send(
+                &redis,
+                &client,
+                &["CONFIG", "SET", "notify-keyspace-events", set]
+            )
+            .await,
+            Some(ok()),
+            "{}",
+            set
+        );
+        assert_eq!(
+            send(
+                &redis,
+                &client,
+                &["CONFIG", "GET", "notify-keyspace-events"]
+            )
+            .await,
+            Some(RedisValueRef::Array(vec![
+                bulk("notify-keyspace-events"),
+                bulk(reported)
+            ])),
+            "{}",
+            set
+        );
+    }
+    assert_eq!(
+        send(
+            &redis,
+            &client,
+            &["CONFIG", "SET", "notify-keyspace-events", "KZ"]
+        )
+        .await,
+        Some(RedisValueRef::Error(Bytes::from(
+            "ERR Invalid argument 'KZ' for CONFIG SET 'notify-keyspace-events'"
+        )))
+    );
+}
+
+#[tokio::test]
+async fn events_reach_keyspace_and_keyevent_channels() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    let (subscriber, mut pushes) = connect(&redis).await;
+    send(
+        &redis,
+        &client,
+        &["CONFIG", "SET", "notify-keyspace-events", "KEA"],
+    )
+    .await;
+    send(
+        &redis,
+        &subscriber,
+        &[
+            "SUBSCRIBE",
+            "__keyspace@0__:k",
+            "__keyspace@0__:l",
+            "__keyevent@0__:set",
+            "__keyevent@0__:expire",
+            "__keyevent@0__:expired",
+            "__keyevent@0__:rpush",
+            "__keyevent@0__:lpop",
+            "__keyevent@0__:del",
+            "__keyevent@1__:set",
+        ],
+    )
+    .await;
+
+    send(&redis, &client, &["SET", "k", "v", "PX", "10"]).await;
+    assert_eq!(
+        messages(&mut pushes),
+        vec![
+            pair("__keyspace@0__:k", "set"),
+            pair("__keyevent@0__:set", "k"),
+            pair("__keyspace@0__:k", "expire"),
+            pair("__keyevent@0__:expire", "k"),
+        ]
+    );
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    redis.db(0).await.kv.expire_cycle().await;
+    assert_eq!(
+        messages(&mut pushes),
+        vec![
+            pair("__keyspace@0__:k", "expired"),
+            pair("__keyevent@0__:expired", "k"),
+        ]
+    );
+
+    send(&redis, &client, &["RPUSH", "l", "a"]).await;
+    send(&redis, &client, &["LPOP", "l"]).await;
+    assert_eq!(
+        messages(&mut pushes),
+        vec![
+            pair("__keyspace@0__:l", "rpush"),
+            pair("__keyevent@0__:rpush", "l"),
+            pair("__keyspace@0__:l", "lpop"),
+            pair("__keyevent@0__:lpop", "l"),
+            pair("__keyspace@0__:l", "del"),
+            pair("__keyevent@0__:del", "l"),
+        ]
+    );
+
+    // Channels carry the database the key is in
+    send(&redis, &client, &["SELECT", "1"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    assert_eq!(messages(&mut pushes), vec![pair("__keyevent@1__:set", "k")]);
+
+    // Classes that aren't enabled send nothing
+    send(
+        &redis,
+        &client,
+        &["CONFIG", "SET", "notify-keyspace-events", "El"],
+    )
+    .await;
+    send(&redis, &client, &["SELECT", "0"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    send(&redis, &client, &["RPUSH", "l", "a"]).await;
+    assert_eq!(
+        messages(&mut pushes),
+        vec![pair("__keyevent@0__:rpush", "l")]
+    );
+}
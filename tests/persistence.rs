@@ -0,0 +1,255 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::persistence;
+use redis::rdb::{parse_rdb, RdbWriter, Value};
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use redis::streams::XInfoSub;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// A directory of its own under the system temp dir
+fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("redisrs-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().to_string()
+}
+
+fn b(s: &str) -> Bytes {
+    Bytes::from(s.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn non_utf8_keys_and_values_survive_a_load_and_save() {
+    let key = Bytes::from_static(b"\xff\xfe\x00key");
+    let value = Bytes::from_static(b"\x80\x00\xc3\x28value");
+    let mut writer = RdbWriter::new(true);
+    writer.select_db(0);
+    writer.string(&key, &value, None);
+    writer.list(b"\xe2\x28\xa1list", [&value]);
+
+    let redis = Redis::new();
+    redis.load_rdb(&writer.finish(true)).await.unwrap();
+    let db = redis.db(0).await;
+    assert_eq!(db.kv.get_entry(&key).await, Some(value.clone()));
+    assert_eq!(
+        db.lists
+            .lrange(&Bytes::from_static(b"\xe2\x28\xa1list"), 0, -1)
+            .await,
+        vec![RedisValueRef::BulkString(value.clone())]
+    );
+
+    let saved = persistence::snapshot(&redis).await.data;
+    let rdb = parse_rdb(&saved, true).unwrap();
+    let mut entries: Vec<_> = rdb.databases[0]
+        .entries
+        .iter()
+        .map(|e| (e.key.clone(), e.value.clone()))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        entries,
+        vec![
+            (
+                Bytes::from_static(b"\xe2\x28\xa1list"),
+                Value::List(vec![value.clone()])
+            ),
+            (key, Value::String(value)),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn saved_dataset_loads_back_after_a_restart() {
+    let dir = temp_dir("save");
+    let redis = Redis::new();
+    redis.persistence.set_dir(dir.clone()).await;
+
+    let db0 = redis.db(0).await;
+    db0.kv.insert_entry(b("plain"), b("v"), None).await;
+    db0.kv
+        .insert_entry(b("ttl"), b("v"), Some((b("PX"), 100_000)))
+        .await;
+    db0.kv
+        .insert_entry(b("gone"), b("v"), Some((b("PX"), 1)))
+        .await;
+    for id in ["1-1", "1-2", "2-0"] {
+        db0.stream.xadd(b("s"), b(id), vec![b("f"), b(id)]).await;
+    }
+    db0.stream
+        .xgroup_create(&b("s"), b("g"), &b("1-1"), false)
+        .await;
+
+    let db3 = redis.db(3).await;
+    db3.kv.insert_entry(b("elsewhere"), b("3"), None).await;
+    db3.hashes.load(b("h"), vec![(b("f"), b("v"))]).await;
+
+    // Let the short TTL pass, so the key isn't saved
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(
+        persistence::save(&redis).await,
+        RedisValueRef::String(b("OK"))
+    );
+
+    let restarted = Redis::new();
+    restarted
+        .load_rdb_file(dir.clone(), "dump.rdb".to_string())
+        .await
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let db0 = restarted.db(0).await;
+    assert_eq!(db0.kv.get_entry(&b("plain")).await, Some(b("v")));
+    assert_eq!(db0.kv.get_entry(&b("ttl")).await, Some(b("v")));
+    assert_eq!(db0.kv.get_entry(&b("gone")).await, None);
+    // plain and ttl, one of them with an expiry
+    assert_eq!(db0.kv.counts().await, (2, 1));
+
+    let ids: Vec<RedisValueRef> = db0
+        .stream
+        .xrange(&b("s"), &b("-"), &b("+"))
+        .await
+        .into_iter()
+        .map(|entry| match entry {
+            RedisValueRef::Array(mut items) => items.remove(0),
+            other => panic!("unexpected entry {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        ids,
+        ["1-1", "1-2", "2-0"].map(|id| RedisValueRef::BulkString(b(id)))
+    );
+    // The group came back with its last delivered ID
+    let RedisValueRef::Array(groups) = db0.stream.xinfo(&b("s"), XInfoSub::Groups).await else {
+        panic!("XINFO GROUPS didn't return an array");
+    };
+    let [RedisValueRef::Array(group)] = groups.as_slice() else {
+        panic!("expected one group, got {:?}", groups);
+    };
+    assert_eq!(group[1], RedisValueRef::BulkString(b("g")));
+    assert_eq!(group[6], RedisValueRef::BulkString(b("last-delivered-id")));
+    assert_eq!(group[7], RedisValueRef::BulkString(b("1-1")));
+
+    let db3 = restarted.db(3).await;
+    assert_eq!(db3.kv.get_entry(&b("elsewhere")).await, Some(b("3")));
+    assert_eq!(db3.hashes.hget(&b("h"), &b("f")).await, Some(b("v")));
+    assert_eq!(restarted.db(1).await.counts().await, (0, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn the_resizedb_hint_counts_only_live_keys() {
+    let redis = Redis::new();
+    let db = redis.db(0).await;
+    db.kv
+        .insert_entry(b("gone"), b("v"), Some((b("PX"), 1)))
+        .await;
+    db.kv
+        .insert_entry(b("later"), b("v"), Some((b("PX"), 60_000)))
+        .await;
+    db.kv.insert_entry(b("kept"), b("v"), None).await;
+    std::thread::sleep(Duration::from_millis(10));
+
+    let saved = persistence::snapshot(&redis).await.data;
+    let rdb = parse_rdb(&saved, true).unwrap();
+    let saved_db = &rdb.databases[0];
+    assert_eq!(saved_db.entries.len(), 2);
+    assert_eq!(saved_db.key_value_hash_size, 2);
+    assert_eq!(saved_db.expire_hash_size, 1);
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+fn info_field(info: &str, name: &str) -> u64 {
+    info.lines()
+        .find_map(|l| l.strip_prefix(&format!("{}:", name)))
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn bgsave_writes_the_dataset_as_it_was_when_it_started() {
+    let dir = temp_dir("bgsave");
+    let redis = Arc::new(Redis::new());
+    redis.persistence.set_dir(dir.clone()).await;
+    const ITEMS: usize = 200_000;
+    let items: Vec<Bytes> = (0..ITEMS).map(|i| b(&format!("item{}", i))).collect();
+    redis.db(0).await.lists.rpush(&b("big"), items).await;
+
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(b("default"), true).await;
+
+    assert_eq!(
+        send(&redis, &client, &["BGSAVE"]).await,
+        Some(RedisValueRef::String(b("Background saving started")))
+    );
+    // Keep writing until the save is done: each step sets both counters at
+    // once and then grows the list
+    let mut step = 0;
+    while redis.persistence.bgsave_in_progress() {
+        let n = step.to_string();
+        send(&redis, &client, &["MSET", "a", &n, "b", &n]).await;
+        send(&redis, &client, &["RPUSH", "big", &n]).await;
+        step += 1;
+    }
+    assert!(redis.persistence.last_bgsave_ok());
+
+    let saved = std::fs::read(format!("{}/dump.rdb", dir)).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let rdb = parse_rdb(&saved, true).unwrap();
+    let value = |key: &str| {
+        rdb.databases[0]
+            .entries
+            .iter()
+            .find(|e| e.key == key)
+            .map(|e| e.value.clone())
+    };
+
+    // The two counters were never saved from different steps
+    assert_eq!(value("a"), value("b"));
+    let Some(Value::List(list)) = value("big") else {
+        panic!("big wasn't saved as a list");
+    };
+    assert!(list.len() >= ITEMS);
+    let pushed: Vec<_> = list[ITEMS..].to_vec();
+    let expected: Vec<_> = (0..pushed.len()).map(|i| b(&i.to_string())).collect();
+    assert_eq!(pushed, expected);
+    // The step that set the saved counters is the last one pushed, or the
+    // one after it if the save started between its two commands
+    match value("a") {
+        None => assert!(pushed.is_empty()),
+        Some(Value::String(a)) => {
+            let a: usize = std::str::from_utf8(&a).unwrap().parse().unwrap();
+            assert!(
+                a == pushed.len() || a + 1 == pushed.len(),
+                "{} {}",
+                a,
+                pushed.len()
+            );
+        }
+        Some(other) => panic!("a was saved as {:?}", other),
+    }
+    // And the live list kept growing past what was saved
+    let live = redis.db(0).await.lists.llen(&b("big")).await as usize;
+    assert_eq!(live, ITEMS + step);
+    assert!(list.len() < live);
+
+    // Pushing to the shared list copied it, all of it
+    let info = redis.persistence.info(0).await;
+    assert!(info_field(&info, "rdb_last_cow_copies") >= 1, "{}", info);
+    assert!(
+        info_field(&info, "rdb_last_cow_copied_items") >= ITEMS as u64,
+        "{}",
+        info
+    );
+}
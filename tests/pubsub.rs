@@ -0,0 +1,282 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::pubsub::key_hash_slot;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+// A registered connection, and what is pushed to it. Pushes are never
+// marked sent, so they count against the output buffer limit.
+async fn connect(redis: &Redis) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, addr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    (client, rx)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+#[tokio::test]
+async fn subscribed_resp2_clients_are_restricted() {
+    let redis = Arc::new(Redis::new());
+    let (client, _pushes) = connect(&redis).await;
+
+    send(&redis, &client, &["SUBSCRIBE", "news"]).await;
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::Error(Bytes::from(
+            "ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"
+        )))
+    );
+    assert_eq!(
+        send(&redis, &client, &["PING"]).await,
+        Some(RedisValueRef::Array(vec![bulk("pong"), bulk("")]))
+    );
+
+    // Once the last subscription is gone, so is the restriction
+    send(&redis, &client, &["UNSUBSCRIBE", "news"]).await;
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::NullBulkString)
+    );
+}
+
+#[tokio::test]
+async fn subscribed_resp3_clients_are_not_restricted() {
+    let redis = Arc::new(Redis::new());
+    let (client, _pushes) = connect(&redis).await;
+    client.set_protocol(3);
+
+    send(&redis, &client, &["SUBSCRIBE", "news"]).await;
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::NullBulkString)
+    );
+    assert_eq!(
+        send(&redis, &client, &["PING"]).await,
+        Some(RedisValueRef::String(Bytes::from("PONG")))
+    );
+}
+
+#[tokio::test]
+async fn subscribers_over_the_output_limit_are_disconnected() {
+    let redis = Arc::new(Redis::new());
+    let (publisher, _) = connect(&redis).await;
+    let (subscriber, mut pushes) = connect(&redis).await;
+    assert_eq!(
+        send(
+            &redis,
+            &publisher,
+            &[
+                "CONFIG",
+                "SET",
+                "client-output-buffer-limit",
+                "pubsub 200 0 0"
+            ]
+        )
+        .await,
+        Some(ok())
+    );
+    send(&redis, &subscriber, &["SUBSCRIBE", "news"]).await;
+
+    assert_eq!(
+        send(&redis, &publisher, &["PUBLISH", "news", "short"]).await,
+        Some(RedisValueRef::Int(1))
+    );
+    assert!(!subscriber.is_killed());
+
+    // The message that takes it over the hard limit is still queued
+    let long = "x".repeat(200);
+    assert_eq!(
+        send(&redis, &publisher, &["PUBLISH", "news", &long]).await,
+        Some(RedisValueRef::Int(1))
+    );
+    assert!(subscriber.is_killed());
+
+    // Nothing more is queued for it
+    send(&redis, &publisher, &["PUBLISH", "news", "after"]).await;
+    let mut messages = Vec::new();
+    while let Ok(push) = pushes.try_recv() {
+        messages.push(push);
+    }
+    assert_eq!(
+        messages.last(),
+        Some(&RedisValueRef::Array(vec![
+            bulk("message"),
+            bulk("news"),
+            bulk(&long)
+        ]))
+    );
+}
+
+#[tokio::test]
+async fn publishes_are_sent_to_replicas() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    let (tx, mut replica) = mpsc::unbounded_channel();
+    redis.add_slave(tx).await;
+
+    send(&redis, &client, &["PUBLISH", "news", "hi"]).await;
+    let mut sent = Vec::new();
+    while let Ok(bytes) = replica.try_recv() {
+        sent.extend(bytes);
+    }
+    assert_eq!(
+        String::from_utf8(sent).unwrap(),
+        "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+    );
+}
+
+fn pushed(pushes: &mut mpsc::UnboundedReceiver<RedisValueRef>) -> Vec<RedisValueRef> {
+    let mut all = Vec::new();
+    while let Ok(push) = pushes.try_recv() {
+        all.push(push);
+    }
+    all
+}
+
+#[tokio::test]
+async fn shard_channels_are_separate_from_channels() {
+    let redis = Arc::new(Redis::new());
+    let (publisher, _) = connect(&redis).await;
+    let (subscriber, mut pushes) = connect(&redis).await;
+
+    send(&redis, &subscriber, &["SSUBSCRIBE", "{user}.a", "{user}.b"]).await;
+    assert_eq!(
+        pushed(&mut pushes),
+        vec![
+            RedisValueRef::Array(vec![
+                bulk("ssubscribe"),
+                bulk("{user}.a"),
+                RedisValueRef::Int(1)
+            ]),
+            RedisValueRef::Array(vec![
+                bulk("ssubscribe"),
+                bulk("{user}.b"),
+                RedisValueRef::Int(2)
+            ]),
+        ]
+    );
+
+    // PUBLISH doesn't reach shard subscribers, SPUBLISH does
+    assert_eq!(
+        send(&redis, &publisher, &["PUBLISH", "{user}.a", "hi"]).await,
+        Some(RedisValueRef::Int(0))
+    );
+    assert_eq!(
+        send(&redis, &publisher, &["SPUBLISH", "{user}.a", "hi"]).await,
+        Some(RedisValueRef::Int(1))
+    );
+    assert_eq!(
+        pushed(&mut pushes),
+        vec![RedisValueRef::Array(vec![
+            bulk("smessage"),
+            bulk("{user}.a"),
+            bulk("hi")
+        ])]
+    );
+
+    assert_eq!(
+        send(&redis, &publisher, &["PUBSUB", "SHARDCHANNELS", "*.a"]).await,
+        Some(RedisValueRef::Array(vec![bulk("{user}.a")]))
+    );
+    assert_eq!(
+        send(&redis, &publisher, &["PUBSUB", "CHANNELS"]).await,
+        Some(RedisValueRef::Array(vec![]))
+    );
+    assert_eq!(
+        send(
+            &redis,
+            &publisher,
+            &["PUBSUB", "SHARDNUMSUB", "{user}.a", "other"]
+        )
+        .await,
+        Some(RedisValueRef::Array(vec![
+            bulk("{user}.a"),
+            RedisValueRef::Int(1),
+            bulk("other"),
+            RedisValueRef::Int(0),
+        ]))
+    );
+
+    // With no arguments every shard channel is dropped
+    send(&redis, &subscriber, &["SUNSUBSCRIBE"]).await;
+    assert_eq!(pushed(&mut pushes).len(), 2);
+    assert_eq!(
+        send(&redis, &publisher, &["PUBSUB", "SHARDCHANNELS"]).await,
+        Some(RedisValueRef::Array(vec![]))
+    );
+    assert_eq!(
+        send(&redis, &publisher, &["SPUBLISH", "{user}.a", "hi"]).await,
+        Some(RedisValueRef::Int(0))
+    );
+}
+
+#[tokio::test]
+async fn shard_channels_must_share_a_slot() {
+    let redis = Arc::new(Redis::new());
+    let (client, mut pushes) = connect(&redis).await;
+    let crossslot = Some(RedisValueRef::Error(Bytes::from(
+        "CROSSSLOT Keys in request don't hash to the same slot",
+    )));
+
+    assert_eq!(
+        send(&redis, &client, &["SSUBSCRIBE", "foo", "bar"]).await,
+        crossslot
+    );
+    assert!(pushed(&mut pushes).is_empty());
+    assert_eq!(
+        send(&redis, &client, &["PUBSUB", "SHARDCHANNELS"]).await,
+        Some(RedisValueRef::Array(vec![]))
+    );
+
+    send(&redis, &client, &["SSUBSCRIBE", "foo"]).await;
+    assert_eq!(
+        send(&redis, &client, &["SUNSUBSCRIBE", "foo", "bar"]).await,
+        crossslot
+    );
+    let (other, _) = connect(&redis).await;
+    assert_eq!(
+        send(&redis, &other, &["PUBSUB", "SHARDNUMSUB", "foo"]).await,
+        Some(RedisValueRef::Array(vec![
+            bulk("foo"),
+            RedisValueRef::Int(1)
+        ]))
+    );
+}
+
+#[test]
+fn key_hash_slot_matches_cluster_slots() {
+    // The CRC16 check value, and slots redis-cli CLUSTER KEYSLOT reports
+    assert_eq!(key_hash_slot(b"123456789"), 0x31c3);
+    assert_eq!(key_hash_slot(b"foo"), 12182);
+    assert_eq!(key_hash_slot(b"bar"), 5061);
+    assert_eq!(key_hash_slot(b""), 0);
+
+    // Only the first non-empty {tag} is hashed
+    assert_eq!(key_hash_slot(b"{user1000}.following"), 3443);
+    assert_eq!(key_hash_slot(b"{user1000}.followers"), 3443);
+    assert_eq!(key_hash_slot(b"foo{bar}{zap}"), 5061);
+    assert_eq!(key_hash_slot(b"foo{{bar}}zap"), 4015);
+    // An empty or unclosed tag means the whole key is hashed
+    assert_eq!(key_hash_slot(b"foo{}{bar}"), 8363);
+    assert_eq!(key_hash_slot(b"foo{bar"), 15278);
+}
@@ -0,0 +1,453 @@
+use bytes::Bytes;
+use redis::listpack::Listpack;
+use redis::rdb::{
+    parse_rdb, ConsumerData, GroupData, PendingData, RdbError, RdbWriter, StreamData, Value,
+};
+
+// A file with one string key, ending in its checksum
+fn small_file(checksum: bool) -> Vec<u8> {
+    let mut writer = RdbWriter::new(false);
+    writer.select_db(0);
+    writer.string(b"key", b"value", None);
+    writer.finish(checksum)
+}
+
+#[test]
+fn a_file_cut_before_its_end_marker_fails_to_load() {
+    let data = small_file(true);
+    // Everything up to, but not including, 0xFF and the checksum
+    let truncated = &data[..data.len() - 9];
+    assert!(matches!(
+        parse_rdb(truncated, true),
+        Err(RdbError::UnexpectedEof)
+    ));
+    assert!(matches!(
+        parse_rdb(truncated, false),
+        Err(RdbError::UnexpectedEof)
+    ));
+}
+
+#[test]
+fn a_short_checksum_fails_only_when_checksums_are_verified() {
+    let data = small_file(true);
+    let truncated = &data[..data.len() - 3];
+    assert!(matches!(
+        parse_rdb(truncated, true),
+        Err(RdbError::UnexpectedEof)
+    ));
+    assert!(parse_rdb(truncated, false).is_ok());
+}
+
+#[test]
+fn a_zero_checksum_means_none_was_computed() {
+    let rdb = parse_rdb(&small_file(false), true).unwrap();
+    assert_eq!(rdb.databases[0].entries.len(), 1);
+}
+
+#[test]
+fn a_flipped_byte_fails_the_checksum() {
+    let data = small_file(true);
+    assert!(parse_rdb(&data, true).is_ok());
+    // The last byte of "value", so the file still parses
+    let pos = data.len() - 10;
+    assert_eq!(data[pos], b'e');
+    let mut corrupt = data.clone();
+    corrupt[pos] ^= 0x01;
+    assert!(matches!(
+        parse_rdb(&corrupt, true),
+        Err(RdbError::ChecksumMismatch)
+    ));
+    // rdbchecksum no loads it regardless
+    assert!(parse_rdb(&corrupt, false).is_ok());
+}
+
+#[test]
+fn a_corrupt_string_length_is_an_error_not_an_allocation() {
+    // A 64-bit length of i64::MAX for the value of "k"
+    let mut length = vec![0x81];
+    length.extend_from_slice(&(i64::MAX as u64).to_be_bytes());
+    length.extend_from_slice(b"value");
+    assert!(matches!(
+        parse_rdb(&file_with(0, &length), false),
+        Err(RdbError::UnexpectedEof)
+    ));
+
+    // LZF data claiming to expand to i64::MAX bytes
+    let mut lzf = vec![0xC3, 0x02, 0x81];
+    lzf.extend_from_slice(&(i64::MAX as u64).to_be_bytes());
+    lzf.extend_from_slice(&[0x00, b'a']);
+    assert!(matches!(
+        parse_rdb(&file_with(0, &lzf), false),
+        Err(RdbError::InvalidFormat(_))
+    ));
+}
+
+// A length-prefixed RDB string, shorter than 16384 bytes
+fn string(s: &[u8]) -> Vec<u8> {
+    let mut out = if s.len() < 64 {
+        vec![s.len() as u8]
+    } else {
+        vec![0x40 | (s.len() >> 8) as u8, s.len() as u8]
+    };
+    out.extend_from_slice(s);
+    out
+}
+
+// A file holding a single key "k" of `value_type`, encoded as `payload`,
+// with no checksum
+fn file_with(value_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = b"REDIS0011\xFE\x00".to_vec();
+    out.push(value_type);
+    out.extend(string(b"k"));
+    out.extend_from_slice(payload);
+    out.push(0xFF);
+    out.extend_from_slice(&[0; 8]);
+    out
+}
+
+fn load_value(value_type: u8, payload: &[u8]) -> Value {
+    let mut rdb = parse_rdb(&file_with(value_type, payload), true).unwrap();
+    rdb.databases.remove(0).entries.remove(0).value
+}
+
+fn bytes(items: &[&str]) -> Vec<Bytes> {
+    items.iter().map(|s| Bytes::from(s.to_string())).collect()
+}
+
+fn pairs(items: &[(&str, &str)]) -> Vec<(Bytes, Bytes)> {
+    items
+        .iter()
+        .map(|(a, b)| (Bytes::from(a.to_string()), Bytes::from(b.to_string())))
+        .collect()
+}
+
+// A ziplist of short strings and small integers, each entry encoded here
+fn ziplist(items: &[&str]) -> Vec<u8> {
+    let mut body = Vec::new();
+    let mut prev = 0;
+    for item in items {
+        let start = body.len();
+        body.push(prev as u8);
+        match item.parse::<u8>() {
+            Ok(i) if i <= 12 => body.push(0xF1 + i),
+            _ => {
+                body.push(item.len() as u8);
+                body.extend_from_slice(item.as_bytes());
+            }
+        }
+        prev = body.len() - start;
+    }
+    let mut out = ((10 + body.len() + 1) as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&((10 + body.len() - prev) as u32).to_le_bytes());
+    out.extend_from_slice(&(items.len() as u16).to_le_bytes());
+    out.extend(body);
+    out.push(0xFF);
+    out
+}
+
+fn listpack(items: &[&str]) -> Vec<u8> {
+    let mut lp = Listpack::new();
+    for item in items {
+        lp.push_str(item.as_bytes());
+    }
+    lp.finish()
+}
+
+#[test]
+fn loads_intsets_of_every_width() {
+    let mut narrow = vec![2, 0, 0, 0, 3, 0, 0, 0];
+    for i in [-1i16, 5, 1000] {
+        narrow.extend_from_slice(&i.to_le_bytes());
+    }
+    assert_eq!(
+        load_value(11, &string(&narrow)),
+        Value::Set(bytes(&["-1", "5", "1000"]))
+    );
+
+    let mut medium = vec![4, 0, 0, 0, 2, 0, 0, 0];
+    for i in [-70000i32, 70000] {
+        medium.extend_from_slice(&i.to_le_bytes());
+    }
+    assert_eq!(
+        load_value(11, &string(&medium)),
+        Value::Set(bytes(&["-70000", "70000"]))
+    );
+
+    let mut wide = vec![8, 0, 0, 0, 1, 0, 0, 0];
+    wide.extend_from_slice(&(1i64 << 40).to_le_bytes());
+    assert_eq!(
+        load_value(11, &string(&wide)),
+        Value::Set(bytes(&["1099511627776"]))
+    );
+
+    // The count says more integers than there are
+    let short = [2, 0, 0, 0, 2, 0, 0, 0, 1, 0];
+    assert!(parse_rdb(&file_with(11, &string(&short)), true).is_err());
+}
+
+#[test]
+fn loads_zipmaps() {
+    let zipmap = [
+        2, // count
+        3, b'f', b'o', b'o', 3, 0, b'b', b'a', b'r', // foo => bar
+        1, b'k', 2, 1, b'v', b'1', 0, // k => v1, one byte of padding
+        0xFF,
+    ];
+    assert_eq!(
+        load_value(9, &string(&zipmap)),
+        Value::Hash(pairs(&[("foo", "bar"), ("k", "v1")]))
+    );
+}
+
+#[test]
+fn loads_ziplist_encoded_values() {
+    assert_eq!(
+        load_value(10, &string(&ziplist(&["a", "7", "bc"]))),
+        Value::List(bytes(&["a", "7", "bc"]))
+    );
+    assert_eq!(
+        load_value(12, &string(&ziplist(&["m", "1.5", "n", "2"]))),
+        Value::SortedSet(vec![(Bytes::from("m"), 1.5), (Bytes::from("n"), 2.0)])
+    );
+    assert_eq!(
+        load_value(13, &string(&ziplist(&["f", "v", "g", "3"]))),
+        Value::Hash(pairs(&[("f", "v"), ("g", "3")]))
+    );
+    // A quicklist of ziplist nodes
+    let mut quicklist = vec![2];
+    quicklist.extend(string(&ziplist(&["a", "b"])));
+    quicklist.extend(string(&ziplist(&["12"])));
+    assert_eq!(
+        load_value(14, &quicklist),
+        Value::List(bytes(&["a", "b", "12"]))
+    );
+    // An odd number of elements can't be member/score pairs
+    let odd = string(&ziplist(&["m", "1", "n"]));
+    assert!(parse_rdb(&file_with(12, &odd), true).is_err());
+}
+
+#[test]
+fn loads_listpack_encoded_values() {
+    assert_eq!(
+        load_value(20, &string(&listpack(&["a", "-3"]))),
+        Value::Set(bytes(&["a", "-3"]))
+    );
+    assert_eq!(
+        load_value(16, &string(&listpack(&["f", "v", "n", "100000"]))),
+        Value::Hash(pairs(&[("f", "v"), ("n", "100000")]))
+    );
+    assert_eq!(
+        load_value(17, &string(&listpack(&["m", "-2.5", "n", "4"]))),
+        Value::SortedSet(vec![(Bytes::from("m"), -2.5), (Bytes::from("n"), 4.0)])
+    );
+}
+
+#[test]
+fn loads_quicklists_with_packed_and_plain_nodes() {
+    let mut quicklist = vec![2];
+    // A packed node, then a plain one holding a single element
+    quicklist.push(2);
+    quicklist.extend(string(&listpack(&["a", "7"])));
+    quicklist.push(1);
+    quicklist.extend(string(b"plain"));
+    assert_eq!(
+        load_value(18, &quicklist),
+        Value::List(bytes(&["a", "7", "plain"]))
+    );
+}
+
+#[test]
+fn loads_sorted_sets_with_text_scores() {
+    let mut payload = vec![3];
+    payload.extend(string(b"a"));
+    payload.extend(string(b"2.5"));
+    // 254 and 255 stand for +inf and -inf
+    payload.extend(string(b"b"));
+    payload.push(254);
+    payload.extend(string(b"c"));
+    payload.push(255);
+    assert_eq!(
+        load_value(3, &payload),
+        Value::SortedSet(vec![
+            (Bytes::from("a"), 2.5),
+            (Bytes::from("b"), f64::INFINITY),
+            (Bytes::from("c"), f64::NEG_INFINITY),
+        ])
+    );
+}
+
+#[test]
+fn rejects_stream_nodes_with_bad_counts() {
+    // count, deleted, master field count, then a master field and terminator
+    let nodes: [&[&str]; 4] = [
+        &["1", "0", "-1", "0"],
+        &["-1", "0", "1", "f", "0"],
+        &["9223372036854775807", "0", "1", "f", "0"],
+        &["1", "9223372036854775807", "1", "f", "0"],
+    ];
+    for node in nodes {
+        let mut payload = vec![1];
+        payload.extend(string(&[0; 16]));
+        payload.extend(string(&listpack(node)));
+        assert!(matches!(
+            parse_rdb(&file_with(15, &payload), true),
+            Err(RdbError::InvalidFormat(_))
+        ));
+    }
+}
+
+fn sample_stream() -> StreamData {
+    let entries = (1..=150u64)
+        .map(|i| {
+            // Most share the first entry's fields; some don't
+            let fields = if i % 10 == 0 {
+                pairs(&[("other", "x"), ("n", "1")])
+            } else {
+                vec![
+                    (Bytes::from("n"), Bytes::from(i.to_string())),
+                    (Bytes::from("name"), Bytes::from(format!("entry-{}", i))),
+                ]
+            };
+            ((1000 + i, i % 3), fields)
+        })
+        .collect();
+    StreamData {
+        entries,
+        last_id: (1150, 5),
+        max_deleted_id: (900, 1),
+        entries_added: 160,
+        groups: vec![GroupData {
+            name: Bytes::from("group"),
+            last_delivered_id: (1002, 2),
+            entries_read: Some(2),
+            pending: vec![
+                PendingData {
+                    id: (1001, 1),
+                    delivery_time: 1_700_000_000_000,
+                    delivery_count: 1,
+                },
+                PendingData {
+                    id: (1002, 2),
+                    delivery_time: 1_700_000_000_500,
+                    delivery_count: 3,
+                },
+            ],
+            consumers: vec![ConsumerData {
+                name: Bytes::from("alice"),
+                seen_time: 1_700_000_001_000,
+                active_time: Some(1_700_000_000_500),
+                pending: vec![(1001, 1), (1002, 2)],
+            }],
+        }],
+    }
+}
+
+#[test]
+fn written_values_of_every_type_load_back() {
+    let many: Vec<Bytes> = (0..200)
+        .map(|i| Bytes::from(format!("member-{}", i)))
+        .collect();
+    let long = Bytes::from("v".repeat(100));
+    let small_list = bytes(&["a", "1", "-20000", "b"]);
+    let big_list: Vec<Bytes> = (0..3000)
+        .map(|i| Bytes::from(format!("item-{}", i)))
+        .collect();
+    let small_zset = vec![(Bytes::from("a"), 1.0), (Bytes::from("b"), 2.5)];
+    let big_zset: Vec<(Bytes, f64)> = many.iter().cloned().zip((0..).map(f64::from)).collect();
+    let small_hash = pairs(&[("f", "v"), ("n", "12")]);
+    let big_hash = vec![(Bytes::from("f"), long.clone())];
+    let stream = sample_stream();
+
+    let mut writer = RdbWriter::new(true);
+    writer.select_db(0);
+    writer.string(b"str", b"hello", None);
+    writer.string(b"int", b"-123456", None);
+    writer.string(b"long", &long, None);
+    writer.list(b"small_list", &small_list);
+    writer.list(b"big_list", &big_list);
+    writer.set(b"small_set", &bytes(&["x", "7"]));
+    writer.set(b"big_set", &many);
+    writer.zset(b"small_zset", small_zset.iter().map(|(m, s)| (m, *s)));
+    writer.zset(b"big_zset", big_zset.iter().map(|(m, s)| (m, *s)));
+    writer.hash(b"small_hash", small_hash.iter().map(|(f, v)| (f, v)));
+    writer.hash(b"big_hash", big_hash.iter().map(|(f, v)| (f, v)));
+    writer.stream(b"stream", &stream);
+    let rdb = parse_rdb(&writer.finish(true), true).unwrap();
+
+    let loaded: std::collections::HashMap<Bytes, Value> = rdb.databases[0]
+        .entries
+        .iter()
+        .map(|e| (e.key.clone(), e.value.clone()))
+        .collect();
+    let get = |key: &str| loaded[&Bytes::from(key.to_string())].clone();
+    assert_eq!(get("str"), Value::String(Bytes::from("hello")));
+    assert_eq!(get("int"), Value::String(Bytes::from("-123456")));
+    assert_eq!(get("long"), Value::String(long.clone()));
+    assert_eq!(get("small_list"), Value::List(small_list));
+    assert_eq!(get("big_list"), Value::List(big_list));
+    assert_eq!(get("small_set"), Value::Set(bytes(&["x", "7"])));
+    assert_eq!(get("big_set"), Value::Set(many));
+    assert_eq!(get("small_zset"), Value::SortedSet(small_zset));
+    // Big sorted sets are saved highest score first
+    let Value::SortedSet(mut members) = get("big_zset") else {
+        panic!("big_zset isn't a sorted set");
+    };
+    members.reverse();
+    assert_eq!(members, big_zset);
+    assert_eq!(get("small_hash"), Value::Hash(small_hash));
+    assert_eq!(get("big_hash"), Value::Hash(big_hash));
+    assert_eq!(get("stream"), Value::Stream(stream));
+}
+
+#[test]
+fn loads_negative_integer_encoded_strings() {
+    let cases: [(&[u8], &str); 3] = [
+        // INT8, INT16 and INT32, little-endian two's complement
+        (&[0xC0, 0x80], "-128"),
+        (&[0xC1, 0x18, 0xFC], "-1000"),
+        (&[0xC2, 0x60, 0x79, 0xFE, 0xFF], "-100000"),
+    ];
+    for (encoded, text) in cases {
+        assert_eq!(
+            load_value(0, encoded),
+            Value::String(Bytes::from(text.to_string()))
+        );
+    }
+}
+
+#[test]
+fn writes_negative_numbers_as_integers_and_reads_them_back() {
+    for text in ["-1", "-128", "-129", "-32768", "-32769", "-2147483648"] {
+        let mut writer = RdbWriter::new(false);
+        writer.select_db(0);
+        writer.string(b"k", text.as_bytes(), None);
+        let data = writer.finish(true);
+        // Integer encodings start with 0xC0 to 0xC2 after the key
+        let key = data.windows(2).position(|w| w == [0x01, b'k']).unwrap();
+        assert!(
+            (0xC0..=0xC2).contains(&data[key + 2]),
+            "{} isn't an integer",
+            text
+        );
+        let mut rdb = parse_rdb(&data, true).unwrap();
+        assert_eq!(
+            rdb.databases.remove(0).entries.remove(0).value,
+            Value::String(Bytes::from(text.to_string()))
+        );
+    }
+}
+
+#[test]
+fn keys_and_values_are_binary_safe() {
+    let key = Bytes::from_static(b"\xff\xfe key \x00 \xc3\x28");
+    let value = Bytes::from_static(b"\x80\x81\x00\xff not utf-8 \xe2\x28\xa1");
+    let mut writer = RdbWriter::new(true);
+    writer.select_db(0);
+    writer.string(&key, &value, None);
+    writer.hash(b"h", [(&key, &value)]);
+    let rdb = parse_rdb(&writer.finish(true), true).unwrap();
+    let entries = &rdb.databases[0].entries;
+    assert_eq!(entries[0].key, key);
+    assert_eq!(entries[0].value, Value::String(value.clone()));
+    assert_eq!(entries[1].value, Value::Hash(vec![(key, value)]));
+}
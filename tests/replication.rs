@@ -0,0 +1,129 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+async fn connect() -> Client {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> RedisValueRef {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis)
+        .await
+        .expect("no reply")
+}
+
+// The commands a replica has been sent so far
+fn received(replica: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<Vec<String>> {
+    let mut stream = Vec::new();
+    while let Ok(bytes) = replica.try_recv() {
+        stream.extend(bytes);
+    }
+    let text = String::from_utf8(stream).unwrap();
+    let mut lines = text.split("\r\n");
+    let mut commands = Vec::new();
+    while let Some(header) = lines.next().filter(|l| !l.is_empty()) {
+        let len: usize = header.strip_prefix('*').unwrap().parse().unwrap();
+        let args = (0..len)
+            .map(|_| {
+                lines.next().unwrap();
+                lines.next().unwrap().to_string()
+            })
+            .collect();
+        commands.push(args);
+    }
+    commands
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writes_reach_replicas_in_order() {
+    let redis = Arc::new(Redis::new());
+    let (tx, mut replica) = mpsc::unbounded_channel();
+    redis.add_slave(tx).await;
+
+    let tasks: Vec<_> = (0..8)
+        .map(|task| {
+            let redis = redis.clone();
+            tokio::spawn(async move {
+                let client = connect().await;
+                for i in 0..500 {
+                    send(&redis, &client, &["RPUSH", "l", &format!("{}-{}", task, i)]).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // The list holds the pushes in the order the master made them
+    let client = connect().await;
+    let RedisValueRef::Array(list) = send(&redis, &client, &["LRANGE", "l", "0", "-1"]).await
+    else {
+        panic!("no list");
+    };
+    let pushed: Vec<RedisValueRef> = received(&mut replica)
+        .into_iter()
+        .filter(|args| args[0] == "RPUSH")
+        .map(|args| bulk(&args[2]))
+        .collect();
+    assert_eq!(pushed.len(), 4000);
+    assert_eq!(pushed, list);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blpop_is_propagated_after_the_push_it_served() {
+    let redis = Arc::new(Redis::new());
+    let (tx, mut replica) = mpsc::unbounded_channel();
+    redis.add_slave(tx).await;
+
+    let waiter = {
+        let redis = redis.clone();
+        tokio::spawn(async move {
+            let client = connect().await;
+            send(&redis, &client, &["BLPOP", "list", "5"]).await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let client = connect().await;
+    send(&redis, &client, &["RPUSH", "list", "a"]).await;
+
+    assert_eq!(
+        waiter.await.unwrap(),
+        RedisValueRef::Array(vec![bulk("list"), bulk("a")])
+    );
+    assert_eq!(
+        received(&mut replica),
+        vec![
+            vec!["SELECT", "0"],
+            vec!["RPUSH", "list", "a"],
+            vec!["LPOP", "list"],
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blpop_times_out_with_a_null_array() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    assert_eq!(
+        send(&redis, &client, &["BLPOP", "list", "0.05"]).await,
+        RedisValueRef::NullArray
+    );
+}
@@ -0,0 +1,459 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use redis::scripting::RunKind;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn error(s: &str) -> RedisValueRef {
+    RedisValueRef::Error(Bytes::from(s.to_string()))
+}
+
+async fn connect() -> Client {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    client
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> RedisValueRef {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis)
+        .await
+        .expect("no reply")
+}
+
+fn error_text(reply: &RedisValueRef) -> String {
+    match reply {
+        RedisValueRef::Error(e) => String::from_utf8_lossy(e).to_string(),
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
+
+// What a replica is sent for each command
+fn resp(commands: &[&[&str]]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for args in commands {
+        out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+        for arg in *args {
+            out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+    }
+    out
+}
+
+// Starts `script` on its own connection and waits until it has run past
+// busy-reply-threshold
+async fn start_busy(redis: &Arc<Redis>, script: &str) -> tokio::task::JoinHandle<RedisValueRef> {
+    redis.running_script.set_busy_reply_threshold(10);
+    let task = {
+        let redis = redis.clone();
+        let script = script.to_string();
+        tokio::spawn(async move {
+            let client = connect().await;
+            send(&redis, &client, &["EVAL", &script, "0"]).await
+        })
+    };
+    let busy = redis.running_script.busy().await;
+    assert!(error_text(&busy).starts_with("BUSY"));
+    task
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripts_cant_create_globals() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let created = send(&redis, &client, &["EVAL", "x = 1 return 1", "0"]).await;
+    assert!(error_text(&created).contains("Script attempted to create global variable 'x'"));
+    let read = send(&redis, &client, &["EVAL", "return y", "0"]).await;
+    assert!(
+        error_text(&read).contains("Script attempted to access nonexistent global variable 'y'")
+    );
+
+    // Locals, and globals that were already there, still work
+    let reply = send(
+        &redis,
+        &client,
+        &["EVAL", "local x = 2 return x + #KEYS", "1", "k"],
+    )
+    .await;
+    assert_eq!(reply, RedisValueRef::Int(3));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn libraries_cant_create_globals() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let code = "#!lua name=lib\nhelper = 1\nredis.register_function('f', function() return 1 end)";
+    let loaded = send(&redis, &client, &["FUNCTION", "LOAD", code]).await;
+    assert!(error_text(&loaded).contains("Script attempted to create global variable 'helper'"));
+
+    let code = "#!lua name=lib\nredis.register_function('f', function() counter = 1 return 1 end)";
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "LOAD", code]).await,
+        RedisValueRef::BulkString(Bytes::from("lib"))
+    );
+    let called = send(&redis, &client, &["FCALL", "f", "0"]).await;
+    assert!(error_text(&called).contains("Script attempted to create global variable 'counter'"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn long_scripts_make_others_busy_until_killed() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let script = start_busy(&redis, "while true do end").await;
+    let busy = send(&redis, &client, &["GET", "k"]).await;
+    assert!(error_text(&busy).starts_with("BUSY Redis is busy running a script"));
+    assert!(error_text(&send(&redis, &client, &["FUNCTION", "KILL"]).await).starts_with("NOTBUSY"));
+
+    assert_eq!(send(&redis, &client, &["SCRIPT", "KILL"]).await, ok());
+    let killed = script.await.unwrap();
+    assert!(error_text(&killed).starts_with("ERR Script killed by user with SCRIPT KILL"));
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        RedisValueRef::NullBulkString
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripts_that_wrote_cant_be_killed() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let script = start_busy(
+        &redis,
+        "redis.call('set', 'k', 'v') for i = 1, 20000000 do end return 1",
+    )
+    .await;
+    let unkillable = send(&redis, &client, &["SCRIPT", "KILL"]).await;
+    assert!(error_text(&unkillable).starts_with("UNKILLABLE"));
+
+    assert_eq!(script.await.unwrap(), RedisValueRef::Int(1));
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        RedisValueRef::BulkString(Bytes::from("v"))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn publishing_is_not_a_write() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let publish = "return redis.call('publish', 'news', 'hi')";
+    assert_eq!(
+        send(&redis, &client, &["EVAL_RO", publish, "0"]).await,
+        RedisValueRef::Int(0)
+    );
+
+    // So a script that published can still be killed
+    let script = start_busy(
+        &redis,
+        "redis.call('publish', 'news', 'hi') while true do end",
+    )
+    .await;
+    assert_eq!(send(&redis, &client, &["SCRIPT", "KILL"]).await, ok());
+    let killed = script.await.unwrap();
+    assert!(error_text(&killed).starts_with("ERR Script killed by user with SCRIPT KILL"));
+}
+
+#[tokio::test]
+async fn nothing_to_kill() {
+    let redis = Redis::new();
+    assert_eq!(
+        redis.running_script.kill(RunKind::Script),
+        error("NOTBUSY No scripts in execution right now.")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scripts_propagate_their_effects() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    let (tx, mut replica) = mpsc::unbounded_channel();
+    redis.add_slave(tx).await;
+
+    let script = "redis.call('set', KEYS[1], ARGV[1]) redis.call('incr', 'n') return 1";
+    assert_eq!(
+        send(&redis, &client, &["EVAL", script, "1", "k", "v"]).await,
+        RedisValueRef::Int(1)
+    );
+    // Sent as the script wrote them; a script that only reads sends nothing
+    send(
+        &redis,
+        &client,
+        &["EVAL", "return redis.call('get', 'k')", "0"],
+    )
+    .await;
+
+    let mut sent = Vec::new();
+    while let Ok(bytes) = replica.try_recv() {
+        sent.extend(bytes);
+    }
+    assert_eq!(
+        String::from_utf8_lossy(&sent),
+        String::from_utf8_lossy(&resp(&[
+            &["SELECT", "0"],
+            &["MULTI"],
+            &["set", "k", "v"],
+            &["incr", "n"],
+            &["EXEC"],
+        ]))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bad_arguments_from_scripts_are_errors() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    for call in [
+        "'lrange', 'l', 'a', 'b'",
+        "'lpop', 'l', 'x'",
+        "'blpop', 'l', 'x'",
+        "'xread', 'block', 'x', 'streams', 's', '0'",
+    ] {
+        let script = format!("return redis.pcall({})['err'] ~= nil", call);
+        assert_eq!(
+            send(&redis, &client, &["EVAL", &script, "0"]).await,
+            RedisValueRef::Int(1),
+            "{}",
+            call
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn functions_run_in_the_vm_their_library_was_loaded_in() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let code = "#!lua name=counter\nlocal calls = 0\nredis.register_function('count', function() calls = calls + 1 return calls end)";
+    send(&redis, &client, &["FUNCTION", "LOAD", code]).await;
+    for n in 1..=3 {
+        assert_eq!(
+            send(&redis, &client, &["FCALL", "count", "0"]).await,
+            RedisValueRef::Int(n)
+        );
+    }
+
+    // Replacing the library starts it over in a new one
+    send(&redis, &client, &["FUNCTION", "LOAD", "REPLACE", code]).await;
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "count", "0"]).await,
+        RedisValueRef::Int(1)
+    );
+}
+
+fn library(name: &str, function: &str) -> String {
+    format!(
+        "#!lua name={}\nredis.register_function('{}', function() return '{}' end)",
+        name, function, name
+    )
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn function_load_replace_and_delete() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "LOAD", &library("one", "f")]).await,
+        bulk("one")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "LOAD", &library("one", "g")]).await,
+        error("ERR Library 'one' already exists")
+    );
+    // Another library can't take over a function name
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "LOAD", &library("two", "f")]).await,
+        error("ERR Function f already exists")
+    );
+
+    assert_eq!(
+        send(
+            &redis,
+            &client,
+            &["FUNCTION", "LOAD", "REPLACE", &library("one", "g")]
+        )
+        .await,
+        bulk("one")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "f", "0"]).await,
+        error("ERR Function not found")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "g", "0"]).await,
+        bulk("one")
+    );
+
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "DELETE", "one"]).await,
+        ok()
+    );
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "DELETE", "one"]).await,
+        error("ERR Library not found")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "g", "0"]).await,
+        error("ERR Function not found")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn function_restore_policies() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    send(&redis, &client, &["FUNCTION", "LOAD", &library("one", "f")]).await;
+    let dump = match send(&redis, &client, &["FUNCTION", "DUMP"]).await {
+        RedisValueRef::BulkString(dump) => dump,
+        other => panic!("FUNCTION DUMP replied {:?}", other),
+    };
+    let restore = |policy: Option<&'static str>| {
+        let mut args = vec![
+            RedisValueRef::String(Bytes::from("FUNCTION")),
+            RedisValueRef::String(Bytes::from("RESTORE")),
+            RedisValueRef::String(dump.clone()),
+        ];
+        if let Some(policy) = policy {
+            args.push(RedisValueRef::String(Bytes::from(policy)));
+        }
+        let redis = redis.clone();
+        async move {
+            let client = connect().await;
+            handle_command(RedisValueRef::Array(args), &client, &redis)
+                .await
+                .unwrap()
+        }
+    };
+
+    // APPEND, the default, refuses libraries that exist and restores nothing
+    send(&redis, &client, &["FUNCTION", "LOAD", &library("two", "g")]).await;
+    assert_eq!(
+        restore(None).await,
+        error("ERR Library 'one' already exists")
+    );
+    assert_eq!(
+        restore(Some("APPEND")).await,
+        error("ERR Library 'one' already exists")
+    );
+
+    // REPLACE swaps in the dumped libraries and keeps the others
+    send(
+        &redis,
+        &client,
+        &["FUNCTION", "LOAD", "REPLACE", &library("one", "h")],
+    )
+    .await;
+    assert_eq!(restore(Some("REPLACE")).await, ok());
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "f", "0"]).await,
+        bulk("one")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "g", "0"]).await,
+        bulk("two")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "h", "0"]).await,
+        error("ERR Function not found")
+    );
+
+    // FLUSH drops everything first
+    assert_eq!(restore(Some("FLUSH")).await, ok());
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "f", "0"]).await,
+        bulk("one")
+    );
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "g", "0"]).await,
+        error("ERR Function not found")
+    );
+
+    // Into an empty server APPEND just loads them
+    send(&redis, &client, &["FUNCTION", "FLUSH"]).await;
+    assert_eq!(restore(None).await, ok());
+    assert_eq!(
+        send(&redis, &client, &["FCALL", "f", "0"]).await,
+        bulk("one")
+    );
+
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "RESTORE", "garbage"]).await,
+        error("ERR payload version or checksum are wrong")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fcall_ro_needs_no_writes_functions() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+    let code = "#!lua name=lib\n\
+        redis.register_function('write', function() return redis.call('SET', 'k', 'v') end)\n\
+        redis.register_function{function_name='sneaky', callback=function() return redis.call('SET', 'k', 'v') end, flags={'no-writes'}}";
+    send(&redis, &client, &["FUNCTION", "LOAD", code]).await;
+
+    assert_eq!(
+        send(&redis, &client, &["FCALL_RO", "write", "0"]).await,
+        error("ERR Can not execute a script with write flag using *_ro command.")
+    );
+    // A no-writes function that writes anyway is stopped at the write
+    let reply = send(&redis, &client, &["FCALL_RO", "sneaky", "0"]).await;
+    assert!(
+        error_text(&reply).contains("Write commands are not allowed from read-only scripts"),
+        "{:?}",
+        reply
+    );
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        RedisValueRef::NullBulkString
+    );
+
+    assert_eq!(send(&redis, &client, &["FCALL", "write", "0"]).await, ok());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn function_load_is_stopped_after_its_timeout() {
+    let redis = Arc::new(Redis::new());
+    let client = connect().await;
+
+    let started = std::time::Instant::now();
+    let reply = send(
+        &redis,
+        &client,
+        &["FUNCTION", "LOAD", "#!lua name=spin\nwhile true do end"],
+    )
+    .await;
+    assert!(
+        error_text(&reply).contains("FUNCTION LOAD timeout"),
+        "{:?}",
+        reply
+    );
+    let took = started.elapsed();
+    assert!(took >= std::time::Duration::from_millis(500), "{:?}", took);
+    assert!(took < std::time::Duration::from_secs(5), "{:?}", took);
+
+    assert_eq!(
+        send(&redis, &client, &["FUNCTION", "DELETE", "spin"]).await,
+        error("ERR Library not found")
+    );
+}
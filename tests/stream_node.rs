@@ -0,0 +1,85 @@
+use bytes::Bytes;
+use redis::stream_node::{StreamEntries, StreamEntry, StreamId, NODE_MAX_ENTRIES};
+
+fn fields(pairs: &[(&str, &str)]) -> StreamEntry {
+    pairs
+        .iter()
+        .map(|(f, v)| (Bytes::from(f.to_string()), Bytes::from(v.to_string())))
+        .collect()
+}
+
+// Entries spread over several nodes, some sharing a millisecond
+fn filled(count: u64) -> Vec<(StreamId, StreamEntry)> {
+    (0..count)
+        .map(|i| {
+            let id = (1000 + i / 3, i % 3);
+            (id, fields(&[("n", &i.to_string())]))
+        })
+        .collect()
+}
+
+fn entries_of(all: &[(StreamId, StreamEntry)]) -> StreamEntries {
+    let mut entries = StreamEntries::new();
+    for (id, fields) in all {
+        entries.push(*id, fields);
+    }
+    entries
+}
+
+#[test]
+fn range_crosses_node_boundaries() {
+    let all = filled(NODE_MAX_ENTRIES as u64 * 2 + 50);
+    let entries = entries_of(&all);
+    assert_eq!(entries.node_count(), 3);
+    assert_eq!(entries.len(), all.len());
+    assert_eq!(entries.iter().collect::<Vec<_>>(), all);
+
+    let expect = |start: StreamId, end: StreamId| -> Vec<(StreamId, StreamEntry)> {
+        all.iter()
+            .filter(|(id, _)| start <= *id && *id <= end)
+            .cloned()
+            .collect()
+    };
+    let last = NODE_MAX_ENTRIES - 1;
+    for (start, end) in [
+        // Within the first node, ending on its last entry
+        (all[10].0, all[last].0),
+        // From the last entry of one node to the first of the next
+        (all[last].0, all[last + 1].0),
+        // From the middle of the first node to the middle of the last
+        (all[50].0, all[2 * NODE_MAX_ENTRIES + 20].0),
+        // Bounds between IDs that exist
+        ((1000, 5), (1040, 7)),
+        // Starting before everything and ending after
+        ((0, 0), (u64::MAX, u64::MAX)),
+    ] {
+        let got: Vec<_> = entries.range(start, end).collect();
+        assert_eq!(got, expect(start, end), "{:?}..={:?}", start, end);
+    }
+    assert_eq!(entries.range((5000, 0), (6000, 0)).count(), 0);
+    assert_eq!(entries.range((1010, 0), (1005, 0)).count(), 0);
+}
+
+#[test]
+fn entries_with_and_without_the_master_fields() {
+    let all = vec![
+        ((5, 0), fields(&[("a", "1"), ("b", "2")])),
+        // The master's field names: only the values are stored
+        ((5, 1), fields(&[("a", "3"), ("b", "4")])),
+        // Other names, order, count or duplicates: stored with their fields
+        ((5, 2), fields(&[("b", "5"), ("a", "6")])),
+        ((6, 0), fields(&[("a", "7")])),
+        ((6, 1), fields(&[("a", "8"), ("b", "9"), ("c", "10")])),
+        ((7, 9), fields(&[("a", "11"), ("a", "12")])),
+        ((7, 10), fields(&[("a", ""), ("b", "")])),
+    ];
+    let entries = entries_of(&all);
+    assert_eq!(entries.node_count(), 1);
+    assert_eq!(entries.iter().collect::<Vec<_>>(), all);
+    assert_eq!(entries.first(), Some(all[0].clone()));
+    assert_eq!(entries.last(), Some(all[6].clone()));
+    assert_eq!(
+        entries.range((5, 1), (6, 1)).collect::<Vec<_>>(),
+        all[1..5].to_vec()
+    );
+}
@@ -0,0 +1,201 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use redis::streams::XInfoSub;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+fn error(s: &str) -> RedisValueRef {
+    RedisValueRef::Error(Bytes::from(s.to_string()))
+}
+
+async fn xadd(redis: &Redis, id: &str) -> RedisValueRef {
+    let db = redis.db(0).await;
+    db.stream
+        .xadd(
+            Bytes::from("s"),
+            Bytes::from(id.to_string()),
+            vec![Bytes::from("a"), Bytes::from("1")],
+        )
+        .await
+}
+
+#[tokio::test]
+async fn xadd_rejects_a_partial_id_below_the_last_ms() {
+    let redis = Redis::new();
+    assert_eq!(xadd(&redis, "5-0").await, bulk("5-0"));
+    assert_eq!(
+        xadd(&redis, "3-*").await,
+        error("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    );
+    assert_eq!(xadd(&redis, "5-*").await, bulk("5-1"));
+    assert_eq!(xadd(&redis, "6-*").await, bulk("6-0"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_xadds_get_increasing_ids() {
+    let redis = Arc::new(Redis::new());
+    let tasks: Vec<_> = (0..8)
+        .map(|_| {
+            let redis = redis.clone();
+            tokio::spawn(async move {
+                for _ in 0..200 {
+                    xadd(&redis, "7-*").await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let db = redis.db(0).await;
+    let entries = db
+        .stream
+        .xrange(&Bytes::from("s"), &Bytes::from("-"), &Bytes::from("+"))
+        .await;
+    let ids: Vec<RedisValueRef> = entries
+        .into_iter()
+        .map(|entry| match entry {
+            RedisValueRef::Array(mut items) => items.remove(0),
+            other => panic!("unexpected entry {:?}", other),
+        })
+        .collect();
+    let expected: Vec<RedisValueRef> = (0..1600).map(|seq| bulk(&format!("7-{}", seq))).collect();
+    assert_eq!(ids, expected);
+}
+
+async fn xinfo_stream(redis: &Redis, options: &[&str]) -> RedisValueRef {
+    let options = options.iter().map(|o| Bytes::from(o.to_string())).collect();
+    let db = redis.db(0).await;
+    db.stream
+        .xinfo(&Bytes::from("s"), XInfoSub::Stream(options))
+        .await
+}
+
+// How many entries an XINFO STREAM FULL reply lists
+fn full_entries(reply: RedisValueRef) -> usize {
+    let RedisValueRef::Array(fields) = reply else {
+        panic!("unexpected reply {:?}", reply);
+    };
+    let at = fields
+        .iter()
+        .position(|f| *f == bulk("entries"))
+        .expect("no entries field");
+    match &fields[at + 1] {
+        RedisValueRef::Array(entries) => entries.len(),
+        other => panic!("unexpected entries {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn xinfo_stream_takes_count_only_after_full() {
+    let redis = Redis::new();
+    for ms in 1..=12 {
+        xadd(&redis, &format!("{}-0", ms)).await;
+    }
+
+    assert_eq!(
+        xinfo_stream(&redis, &["COUNT", "2"]).await,
+        error("ERR unknown subcommand or wrong number of arguments for 'STREAM'. Try XINFO HELP.")
+    );
+    assert_eq!(
+        xinfo_stream(&redis, &["FULL", "COUNT"]).await,
+        error("ERR unknown subcommand or wrong number of arguments for 'STREAM'. Try XINFO HELP.")
+    );
+    assert_eq!(
+        xinfo_stream(&redis, &["FULL", "COUNT", "two"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+
+    assert_eq!(full_entries(xinfo_stream(&redis, &["full"]).await), 10);
+    assert_eq!(
+        full_entries(xinfo_stream(&redis, &["FULL", "count", "2"]).await),
+        2
+    );
+    // 0 lists everything, and a negative count is the default
+    assert_eq!(
+        full_entries(xinfo_stream(&redis, &["FULL", "COUNT", "0"]).await),
+        12
+    );
+    assert_eq!(
+        full_entries(xinfo_stream(&redis, &["FULL", "COUNT", "-1"]).await),
+        10
+    );
+}
+
+async fn send(redis: &Arc<Redis>, args: &[&str]) -> Option<RedisValueRef> {
+    let (tx, _) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Client::new(addr, addr, tx);
+    client.set_user(Bytes::from("default"), true).await;
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), &client, redis).await
+}
+
+#[tokio::test]
+async fn xadd_needs_whole_field_value_pairs() {
+    let redis = Arc::new(Redis::new());
+    let wrong = Some(error("ERR wrong number of arguments for 'xadd' command"));
+    assert_eq!(send(&redis, &["XADD", "s", "*"]).await, wrong);
+    assert_eq!(
+        send(&redis, &["XADD", "s", "1-1", "a", "1", "b"]).await,
+        wrong
+    );
+    assert_eq!(
+        send(&redis, &["TYPE", "s"]).await,
+        Some(RedisValueRef::String(Bytes::from("none")))
+    );
+
+    assert_eq!(
+        send(&redis, &["XADD", "s", "1-1", "a", "1"]).await,
+        Some(bulk("1-1"))
+    );
+}
+
+fn entry(id: &str, fields: &[&str]) -> RedisValueRef {
+    RedisValueRef::Array(vec![
+        bulk(id),
+        RedisValueRef::Array(fields.iter().map(|f| bulk(f)).collect()),
+    ])
+}
+
+#[tokio::test]
+async fn fields_come_back_in_insertion_order() {
+    let redis = Arc::new(Redis::new());
+    // Duplicate fields are kept, as they were given
+    let first = ["z", "1", "a", "2", "m", "3", "a", "4"];
+    let same = ["z", "5", "a", "6", "m", "7", "a", "8"];
+    let other = ["b", "1", "a", "2"];
+    for (id, fields) in [("1-1", &first[..]), ("1-2", &same), ("1-3", &other)] {
+        let mut args = vec!["XADD", "s", id];
+        args.extend(fields);
+        assert_eq!(send(&redis, &args).await, Some(bulk(id)));
+    }
+    let entries = vec![
+        entry("1-1", &first),
+        entry("1-2", &same),
+        entry("1-3", &other),
+    ];
+
+    assert_eq!(
+        send(&redis, &["XRANGE", "s", "-", "+"]).await,
+        Some(RedisValueRef::Array(entries.clone()))
+    );
+    assert_eq!(
+        send(&redis, &["XREAD", "STREAMS", "s", "0"]).await,
+        Some(RedisValueRef::Array(vec![RedisValueRef::Array(vec![
+            bulk("s"),
+            RedisValueRef::Array(entries),
+        ])]))
+    );
+}
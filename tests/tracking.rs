@@ -0,0 +1,220 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+// A registered connection, and what is pushed to it
+async fn connect(redis: &Redis) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, addr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    (client, rx)
+}
+
+// A RESP3 connection with tracking turned on with `options`
+async fn tracking(
+    redis: &Arc<Redis>,
+    options: &[&str],
+) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (client, pushes) = connect(redis).await;
+    client.set_protocol(3);
+    let mut args = vec!["CLIENT", "TRACKING", "ON"];
+    args.extend_from_slice(options);
+    assert_eq!(send(redis, &client, &args).await, Some(ok()));
+    (client, pushes)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+fn drain(pushes: &mut mpsc::UnboundedReceiver<RedisValueRef>) -> Vec<RedisValueRef> {
+    let mut all = Vec::new();
+    while let Ok(push) = pushes.try_recv() {
+        all.push(push);
+    }
+    all
+}
+
+fn invalidate(key: &str) -> RedisValueRef {
+    RedisValueRef::Push(vec![
+        bulk("invalidate"),
+        RedisValueRef::Array(vec![bulk(key)]),
+    ])
+}
+
+#[tokio::test]
+async fn keys_a_client_read_are_invalidated_once() {
+    let redis = Arc::new(Redis::new());
+    let (client, mut pushes) = tracking(&redis, &[]).await;
+    let (other, _) = connect(&redis).await;
+
+    send(&redis, &client, &["GET", "k"]).await;
+    send(&redis, &other, &["SET", "k", "v"]).await;
+    send(&redis, &other, &["SET", "unread", "v"]).await;
+    assert_eq!(drain(&mut pushes), vec![invalidate("k")]);
+
+    // Until it reads the key again
+    send(&redis, &other, &["SET", "k", "w"]).await;
+    assert_eq!(drain(&mut pushes), vec![]);
+    send(&redis, &client, &["GET", "k"]).await;
+    send(&redis, &other, &["SET", "k", "x"]).await;
+    assert_eq!(drain(&mut pushes), vec![invalidate("k")]);
+
+    // Nothing once tracking is off
+    send(&redis, &client, &["GET", "k"]).await;
+    send(&redis, &client, &["CLIENT", "TRACKING", "OFF"]).await;
+    send(&redis, &other, &["SET", "k", "y"]).await;
+    assert_eq!(drain(&mut pushes), vec![]);
+}
+
+#[tokio::test]
+async fn bcast_invalidates_keys_by_prefix_without_reads() {
+    let redis = Arc::new(Redis::new());
+    let (prefixed, mut prefixed_pushes) =
+        tracking(&redis, &["BCAST", "PREFIX", "user:", "PREFIX", "job:"]).await;
+    let (_everything, mut all_pushes) = tracking(&redis, &["BCAST"]).await;
+
+    send(&redis, &prefixed, &["SET", "user:1", "v"]).await;
+    send(&redis, &prefixed, &["SET", "job:1", "v"]).await;
+    send(&redis, &prefixed, &["SET", "other", "v"]).await;
+    assert_eq!(
+        drain(&mut prefixed_pushes),
+        vec![invalidate("user:1"), invalidate("job:1")]
+    );
+    assert_eq!(
+        drain(&mut all_pushes),
+        vec![
+            invalidate("user:1"),
+            invalidate("job:1"),
+            invalidate("other")
+        ]
+    );
+
+    let (client, _) = connect(&redis).await;
+    let error = |s: &str| Some(RedisValueRef::Error(Bytes::from(s.to_string())));
+    assert_eq!(
+        send(
+            &redis,
+            &client,
+            &["CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "a", "PREFIX", "ab"]
+        )
+        .await,
+        error("ERR Prefix 'ab' overlaps with an existing prefix 'a'. Prefixes for a single client must not overlap.")
+    );
+    assert_eq!(
+        send(
+            &redis,
+            &client,
+            &["CLIENT", "TRACKING", "ON", "PREFIX", "a"]
+        )
+        .await,
+        error("ERR PREFIX option requires BCAST mode to be enabled")
+    );
+}
+
+#[tokio::test]
+async fn optin_and_optout_follow_client_caching() {
+    let redis = Arc::new(Redis::new());
+    let (other, _) = connect(&redis).await;
+
+    let (optin, mut pushes) = tracking(&redis, &["OPTIN"]).await;
+    send(&redis, &optin, &["GET", "a"]).await;
+    assert_eq!(
+        send(&redis, &optin, &["CLIENT", "CACHING", "YES"]).await,
+        Some(ok())
+    );
+    send(&redis, &optin, &["GET", "b"]).await;
+    // Only for the command right after it
+    send(&redis, &optin, &["GET", "c"]).await;
+    for key in ["a", "b", "c"] {
+        send(&redis, &other, &["SET", key, "v"]).await;
+    }
+    assert_eq!(drain(&mut pushes), vec![invalidate("b")]);
+
+    let (optout, mut pushes) = tracking(&redis, &["OPTOUT"]).await;
+    assert_eq!(
+        send(&redis, &optout, &["CLIENT", "CACHING", "NO"]).await,
+        Some(ok())
+    );
+    send(&redis, &optout, &["GET", "a"]).await;
+    send(&redis, &optout, &["GET", "b"]).await;
+    for key in ["a", "b"] {
+        send(&redis, &other, &["SET", key, "w"]).await;
+    }
+    assert_eq!(drain(&mut pushes), vec![invalidate("b")]);
+
+    // Caching yes only makes sense with OPTIN
+    assert!(matches!(
+        send(&redis, &optout, &["CLIENT", "CACHING", "YES"]).await,
+        Some(RedisValueRef::Error(_))
+    ));
+}
+
+#[tokio::test]
+async fn noloop_skips_the_clients_own_writes() {
+    let redis = Arc::new(Redis::new());
+    let (client, mut pushes) = tracking(&redis, &["NOLOOP"]).await;
+    let (other, _) = connect(&redis).await;
+
+    send(&redis, &client, &["GET", "k"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    assert_eq!(drain(&mut pushes), vec![]);
+
+    send(&redis, &client, &["GET", "j"]).await;
+    send(&redis, &other, &["SET", "j", "v"]).await;
+    assert_eq!(drain(&mut pushes), vec![invalidate("j")]);
+}
+
+#[tokio::test]
+async fn redirected_invalidations_reach_resp2_clients_over_pubsub() {
+    let redis = Arc::new(Redis::new());
+    let (target, mut target_pushes) = connect(&redis).await;
+    send(&redis, &target, &["SUBSCRIBE", "__redis__:invalidate"]).await;
+    drain(&mut target_pushes);
+
+    let (client, mut pushes) = connect(&redis).await;
+    let redirect = target.id.to_string();
+    assert_eq!(
+        send(
+            &redis,
+            &client,
+            &["CLIENT", "TRACKING", "ON", "REDIRECT", &redirect]
+        )
+        .await,
+        Some(ok())
+    );
+    assert_eq!(
+        send(&redis, &client, &["CLIENT", "GETREDIR"]).await,
+        Some(RedisValueRef::Int(target.id as i64))
+    );
+
+    send(&redis, &client, &["GET", "k"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    assert_eq!(drain(&mut pushes), vec![]);
+    assert_eq!(
+        drain(&mut target_pushes),
+        vec![RedisValueRef::Array(vec![
+            bulk("message"),
+            bulk("__redis__:invalidate"),
+            RedisValueRef::Array(vec![bulk("k")]),
+        ])]
+    );
+}
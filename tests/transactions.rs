@@ -0,0 +1,284 @@
+use bytes::Bytes;
+use redis::client::Client;
+use redis::commands::handle_command;
+use redis::redis::Redis;
+use redis::resp::RedisValueRef;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+fn ok() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("OK"))
+}
+
+fn queued() -> RedisValueRef {
+    RedisValueRef::String(Bytes::from("QUEUED"))
+}
+
+fn bulk(s: &str) -> RedisValueRef {
+    RedisValueRef::BulkString(Bytes::from(s.to_string()))
+}
+
+fn error(s: &str) -> RedisValueRef {
+    RedisValueRef::Error(Bytes::from(s.to_string()))
+}
+
+// A registered connection, and what is pushed to it
+async fn connect(redis: &Redis) -> (Arc<Client>, mpsc::UnboundedReceiver<RedisValueRef>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let addr = "127.0.0.1:6379".parse().unwrap();
+    let client = Arc::new(Client::new(addr, addr, tx));
+    client.set_user(Bytes::from("default"), true).await;
+    redis.add_client(client.clone()).await;
+    (client, rx)
+}
+
+async fn send(redis: &Arc<Redis>, client: &Client, args: &[&str]) -> Option<RedisValueRef> {
+    let args = args
+        .iter()
+        .map(|a| RedisValueRef::String(Bytes::from(a.to_string())))
+        .collect();
+    handle_command(RedisValueRef::Array(args), client, redis).await
+}
+
+#[tokio::test]
+async fn nested_multi_and_watch_inside_multi_dont_abort_exec() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+
+    assert_eq!(send(&redis, &client, &["MULTI"]).await, Some(ok()));
+    assert_eq!(
+        send(&redis, &client, &["MULTI"]).await,
+        Some(error("ERR MULTI calls can not be nested"))
+    );
+    assert_eq!(
+        send(&redis, &client, &["WATCH", "k"]).await,
+        Some(error("ERR WATCH inside MULTI is not allowed"))
+    );
+    assert_eq!(
+        send(&redis, &client, &["SET", "k", "v"]).await,
+        Some(queued())
+    );
+    assert_eq!(
+        send(&redis, &client, &["EXEC"]).await,
+        Some(RedisValueRef::Array(vec![ok()]))
+    );
+    assert_eq!(send(&redis, &client, &["GET", "k"]).await, Some(bulk("v")));
+}
+
+#[tokio::test]
+async fn subscriptions_inside_multi_are_queued() {
+    let redis = Arc::new(Redis::new());
+    let (client, mut pushes) = connect(&redis).await;
+
+    send(&redis, &client, &["MULTI"]).await;
+    assert_eq!(
+        send(&redis, &client, &["SUBSCRIBE", "news"]).await,
+        Some(queued())
+    );
+    assert_eq!(
+        send(&redis, &client, &["SSUBSCRIBE", "a", "b"]).await,
+        Some(queued())
+    );
+    assert!(!redis.pubsub.is_subscribed(client.id).await);
+    assert!(pushes.try_recv().is_err());
+
+    assert_eq!(
+        send(&redis, &client, &["EXEC"]).await,
+        Some(RedisValueRef::Array(vec![error(
+            "CROSSSLOT Keys in request don't hash to the same slot"
+        )]))
+    );
+    assert!(redis.pubsub.is_subscribed_to(client.id, b"news").await);
+    assert_eq!(
+        pushes.try_recv().unwrap(),
+        RedisValueRef::Array(vec![bulk("subscribe"), bulk("news"), RedisValueRef::Int(1)])
+    );
+}
+
+// Runs MULTI, SET k tx, EXEC and returns EXEC's reply
+async fn transaction(redis: &Arc<Redis>, client: &Client) -> Option<RedisValueRef> {
+    send(redis, client, &["MULTI"]).await;
+    send(redis, client, &["SET", "k", "tx"]).await;
+    send(redis, client, &["EXEC"]).await
+}
+
+fn ran() -> Option<RedisValueRef> {
+    Some(RedisValueRef::Array(vec![ok()]))
+}
+
+#[tokio::test]
+async fn a_watched_key_that_changes_aborts_exec() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    let (other, _) = connect(&redis).await;
+
+    assert_eq!(send(&redis, &client, &["WATCH", "k"]).await, Some(ok()));
+    send(&redis, &other, &["SET", "k", "other"]).await;
+    assert_eq!(
+        transaction(&redis, &client).await,
+        Some(RedisValueRef::NullArray)
+    );
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(bulk("other"))
+    );
+
+    // The watching client's own writes count too
+    send(&redis, &client, &["WATCH", "k"]).await;
+    send(&redis, &client, &["SET", "k", "own"]).await;
+    assert_eq!(
+        transaction(&redis, &client).await,
+        Some(RedisValueRef::NullArray)
+    );
+
+    // A key of the same name in another database doesn't
+    send(&redis, &client, &["WATCH", "k"]).await;
+    send(&redis, &other, &["SELECT", "1"]).await;
+    send(&redis, &other, &["SET", "k", "elsewhere"]).await;
+    assert_eq!(transaction(&redis, &client).await, ran());
+}
+
+#[tokio::test]
+async fn unwatch_exec_and_discard_forget_watched_keys() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    let (other, _) = connect(&redis).await;
+
+    send(&redis, &client, &["WATCH", "k"]).await;
+    assert_eq!(send(&redis, &client, &["UNWATCH"]).await, Some(ok()));
+    send(&redis, &other, &["SET", "k", "other"]).await;
+    assert_eq!(transaction(&redis, &client).await, ran());
+
+    // Watches end with the EXEC that checked them
+    send(&redis, &client, &["WATCH", "k"]).await;
+    assert_eq!(transaction(&redis, &client).await, ran());
+    send(&redis, &other, &["SET", "k", "other"]).await;
+    assert_eq!(transaction(&redis, &client).await, ran());
+
+    // And with DISCARD
+    send(&redis, &client, &["WATCH", "k"]).await;
+    send(&redis, &client, &["MULTI"]).await;
+    assert_eq!(send(&redis, &client, &["DISCARD"]).await, Some(ok()));
+    send(&redis, &other, &["SET", "k", "other"]).await;
+    assert_eq!(transaction(&redis, &client).await, ran());
+}
+
+#[tokio::test]
+async fn flushes_swapdb_and_expiry_touch_watched_keys() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+
+    send(&redis, &client, &["WATCH", "k"]).await;
+    redis.flush_all().await;
+    assert_eq!(
+        transaction(&redis, &client).await,
+        Some(RedisValueRef::NullArray)
+    );
+
+    send(&redis, &client, &["WATCH", "k"]).await;
+    assert_eq!(
+        send(&redis, &client, &["SWAPDB", "0", "1"]).await,
+        Some(ok())
+    );
+    assert_eq!(
+        transaction(&redis, &client).await,
+        Some(RedisValueRef::NullArray)
+    );
+
+    send(&redis, &client, &["SET", "k", "v", "PX", "10"]).await;
+    send(&redis, &client, &["WATCH", "k"]).await;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    redis.db(0).await.kv.expire_cycle().await;
+    assert_eq!(
+        transaction(&redis, &client).await,
+        Some(RedisValueRef::NullArray)
+    );
+}
+
+#[tokio::test]
+async fn a_queueing_error_aborts_exec() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+
+    send(&redis, &client, &["MULTI"]).await;
+    assert_eq!(
+        send(&redis, &client, &["SET", "k", "v"]).await,
+        Some(queued())
+    );
+    assert!(matches!(
+        send(&redis, &client, &["NOSUCHCOMMAND", "x"]).await,
+        Some(RedisValueRef::Error(_))
+    ));
+    assert!(matches!(
+        send(&redis, &client, &["GET"]).await,
+        Some(RedisValueRef::Error(_))
+    ));
+    assert_eq!(
+        send(&redis, &client, &["EXEC"]).await,
+        Some(error(
+            "EXECABORT Transaction discarded because of previous errors."
+        ))
+    );
+    assert_eq!(
+        send(&redis, &client, &["GET", "k"]).await,
+        Some(RedisValueRef::NullBulkString)
+    );
+    // The transaction is over
+    assert_eq!(
+        send(&redis, &client, &["EXEC"]).await,
+        Some(error("ERR EXEC without MULTI"))
+    );
+}
+
+#[tokio::test]
+async fn runtime_errors_dont_stop_exec() {
+    let redis = Arc::new(Redis::new());
+    let (client, _) = connect(&redis).await;
+
+    send(&redis, &client, &["MULTI"]).await;
+    send(&redis, &client, &["SET", "k", "v"]).await;
+    send(&redis, &client, &["INCR", "k"]).await;
+    send(&redis, &client, &["SET", "j", "w"]).await;
+    let Some(RedisValueRef::Array(replies)) = send(&redis, &client, &["EXEC"]).await else {
+        panic!("EXEC didn't run");
+    };
+    assert_eq!(replies.len(), 3);
+    assert_eq!(replies[0], ok());
+    assert!(matches!(replies[1], RedisValueRef::Error(_)));
+    assert_eq!(replies[2], ok());
+    assert_eq!(send(&redis, &client, &["GET", "j"]).await, Some(bulk("w")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn nothing_runs_in_the_middle_of_exec() {
+    let redis = Arc::new(Redis::new());
+    let writer = {
+        let redis = redis.clone();
+        tokio::spawn(async move {
+            let (client, _) = connect(&redis).await;
+            for _ in 0..500 {
+                send(&redis, &client, &["RPUSH", "l", "x"]).await;
+            }
+        })
+    };
+    let (client, _) = connect(&redis).await;
+    for _ in 0..200 {
+        send(&redis, &client, &["MULTI"]).await;
+        send(&redis, &client, &["RPUSH", "l", "a"]).await;
+        send(&redis, &client, &["RPUSH", "l", "b"]).await;
+        send(&redis, &client, &["EXEC"]).await;
+    }
+    writer.await.unwrap();
+
+    let Some(RedisValueRef::Array(list)) = send(&redis, &client, &["LRANGE", "l", "0", "-1"]).await
+    else {
+        panic!("no list");
+    };
+    assert_eq!(list.len(), 900);
+    for (i, item) in list.iter().enumerate() {
+        if *item == bulk("a") {
+            assert_eq!(list[i + 1], bulk("b"), "something ran between a and b");
+        }
+    }
+}
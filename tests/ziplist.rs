@@ -0,0 +1,88 @@
+use bytes::Bytes;
+use redis::listpack::Entry;
+use redis::ziplist;
+
+// Wraps encoded entries, each with its previous-entry length already in
+// front, in a ziplist header and terminator
+fn ziplist(entries: &[&[u8]]) -> Vec<u8> {
+    let body: Vec<u8> = entries.concat();
+    let total = 10 + body.len() + 1;
+    let tail = 10 + body.len() - entries.last().map_or(0, |e| e.len());
+    let mut out = (total as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&(tail as u32).to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    out.extend_from_slice(&body);
+    out.push(0xFF);
+    out
+}
+
+#[test]
+fn decodes_every_integer_encoding() {
+    let data = ziplist(&[
+        // 4-bit immediates, 0 and 12
+        &[0x00, 0xF1],
+        &[0x02, 0xFD],
+        // int8
+        &[0x02, 0xFE, 0x80],
+        // int16
+        &[0x03, 0xC0, 0xE0, 0xB1],
+        // int24
+        &[0x04, 0xF0, 0xC0, 0xB4, 0xB3],
+        // int32
+        &[0x05, 0xD0, 0x00, 0xE1, 0xF5, 0x05],
+        // int64
+        &[0x06, 0xE0, 0, 0, 0, 0, 0, 0, 0, 0x80],
+    ]);
+    let entries: Vec<Option<i64>> = ziplist::decode(&data)
+        .unwrap()
+        .iter()
+        .map(Entry::as_int)
+        .collect();
+    assert_eq!(
+        entries,
+        [0, 12, -128, -20000, -5_000_000, 100_000_000, i64::MIN].map(Some)
+    );
+}
+
+#[test]
+fn decodes_every_string_encoding_and_long_previous_lengths() {
+    let medium = "m".repeat(300);
+    let long = "l".repeat(5);
+
+    // 6-bit length
+    let short_entry = [0x00, 0x02, b'a', b'b'];
+    // 14-bit length, big-endian
+    let mut medium_entry = vec![0x04, 0x41, 0x2C];
+    medium_entry.extend_from_slice(medium.as_bytes());
+    // 32-bit length, big-endian; the entry before is 303 bytes, so its
+    // length takes 0xFE and 4 bytes
+    let mut long_entry = vec![0xFE, 0x2F, 0x01, 0x00, 0x00, 0x80];
+    long_entry.extend_from_slice(&5u32.to_be_bytes());
+    long_entry.extend_from_slice(long.as_bytes());
+    // And an integer after a 5-byte previous length too
+    let int_entry = [0xFE, 0x0F, 0x00, 0x00, 0x00, 0xF3];
+
+    let data = ziplist(&[&short_entry, &medium_entry, &long_entry, &int_entry]);
+    assert_eq!(
+        ziplist::decode(&data).unwrap(),
+        vec![
+            Entry::Str(Bytes::from("ab")),
+            Entry::Str(Bytes::from(medium)),
+            Entry::Str(Bytes::from(long)),
+            Entry::Int(2),
+        ]
+    );
+}
+
+#[test]
+fn rejects_malformed_ziplists() {
+    let mut data = ziplist(&[&[0x00, 0xF1]]);
+    data[0] += 1;
+    assert!(ziplist::decode(&data).is_err());
+    // A string running past the end
+    assert!(ziplist::decode(&ziplist(&[&[0x00, 0x05, b'a']])).is_err());
+    // An int24 missing its bytes
+    assert!(ziplist::decode(&ziplist(&[&[0x00, 0xF0]])).is_err());
+    // 0xFF ends the list, so it isn't an entry encoding
+    assert!(ziplist::decode(&ziplist(&[&[0x00, 0xFF]])).is_err());
+}